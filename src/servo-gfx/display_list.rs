@@ -29,7 +29,10 @@ pub enum DisplayItem {
     // TODO: don't copy text runs, ever.
     Text(DisplayItemData, ~SendableTextRun, Range, Color),
     Image(DisplayItemData, ARC<~Image>),
-    Border(DisplayItemData, Au, Color)
+    Border(DisplayItemData, Au, Color),
+    /// Items drawn together into an offscreen buffer, which is then blended
+    /// at the given opacity, as CSS 'opacity' requires
+    OpacityGroup(DisplayItemData, float, ~DisplayList)
 }
 
 impl DisplayItem {
@@ -38,7 +41,8 @@ impl DisplayItem {
             SolidColor(ref d, _) => d,
             Text(ref d, _, _, _) => d,
             Image(ref d, _) => d,
-            Border(ref d, _, _) => d
+            Border(ref d, _, _) => d,
+            OpacityGroup(ref d, _, _) => d
         }
     }
     
@@ -57,6 +61,9 @@ impl DisplayItem {
                 ctx.draw_image(self.d().bounds, clone_arc(img));
             }
             &Border(_, width, color) => ctx.draw_border(&self.d().bounds, width, color),
+            &OpacityGroup(_, opacity, ref list) => {
+                ctx.draw_opacity_group(&self.d().bounds, opacity, *list)
+            }
         }
 
         debug!("%?", {
@@ -79,6 +86,11 @@ impl DisplayItem {
         Text(DisplayItemData::new(bounds), move run, move range, color)
     }
 
+    static pure fn new_OpacityGroup(bounds: &Rect<Au>, opacity: float,
+                                    list: ~DisplayList) -> DisplayItem {
+        OpacityGroup(DisplayItemData::new(bounds), opacity, move list)
+    }
+
     // ARC should be cloned into ImageData, but Images are not sendable
     static pure fn new_Image(bounds: &Rect<Au>, image: ARC<~Image>) -> DisplayItem {
         Image(DisplayItemData::new(bounds), move image)
@@ -163,6 +163,51 @@ pub impl FontStyle : cmp::Eq {
 pub type SpecifiedFontStyle = FontStyle;
 pub type UsedFontStyle = FontStyle;
 
+// The horizontal shear applied to glyphs of a synthetic oblique face,
+// roughly a 11 degree slant.
+pub const SYNTHETIC_OBLIQUE_SHEAR: float = 0.2;
+
+/**
+The emboldening and slanting a font applies to its face because no face
+matching the requested weight or style was available. Synthetic bold draws
+every glyph twice, the second time shifted right, and widens its advance to
+match; synthetic oblique shears the glyphs.
+*/
+pub struct FontSynthesis {
+    bold: bool,
+    oblique: bool,
+}
+
+pub impl FontSynthesis {
+    static pure fn new(face_is_bold: bool, face_is_italic: bool,
+                       style: &UsedFontStyle) -> FontSynthesis {
+        FontSynthesis {
+            bold: style.weight.is_bold() && !face_is_bold,
+            oblique: (style.italic || style.oblique) && !face_is_italic,
+        }
+    }
+
+    // How far the second copy of each glyph is drawn from the first.
+    pure fn bold_offset(&self, pt_size: float) -> Au {
+        if !self.bold {
+            return Au(0);
+        }
+        // one pixel per 20pt, but always at least one pixel
+        let px = if pt_size > 20f { pt_size / 20f } else { 1f };
+        Au::from_frac_px(px)
+    }
+
+    pure fn shear(&self) -> float {
+        if self.oblique { SYNTHETIC_OBLIQUE_SHEAR } else { 0f }
+    }
+
+    // The advance of `glyph_count` glyphs whose unsynthesized advances add
+    // up to `advance`.
+    pure fn advance_for_glyphs(&self, advance: Au, glyph_count: uint, pt_size: float) -> Au {
+        advance + self.bold_offset(pt_size).scale_by(glyph_count as float)
+    }
+}
+
 // FIXME: move me to layout
 struct ResolvedFont {
     group: @FontGroup,
@@ -269,6 +314,7 @@ pub struct Font {
     priv mut shaper: Option<@Shaper>,
     style: UsedFontStyle,
     metrics: FontMetrics,
+    synthesis: FontSynthesis,
     backend: BackendType,
 }
 
@@ -284,6 +330,7 @@ pub impl Font {
         };
         
         let metrics = handle.get_metrics();
+        let synthesis = FontSynthesis::new(handle.boldness().is_bold(), handle.is_italic(), style);
         // TODO(Issue #179): convert between specified and used font style here?
 
         return Ok(@Font {
@@ -292,6 +339,7 @@ pub impl Font {
             shaper: None,
             style: copy *style,
             metrics: move metrics,
+            synthesis: synthesis,
             backend: backend,
         });
     }
@@ -299,6 +347,7 @@ pub impl Font {
     static fn new_from_adopted_handle(_fctx: &FontContext, handle: FontHandle,
                                       style: &SpecifiedFontStyle, backend: BackendType) -> @Font {
         let metrics = handle.get_metrics();
+        let synthesis = FontSynthesis::new(handle.boldness().is_bold(), handle.is_italic(), style);
 
         @Font {
             handle : move handle,
@@ -306,6 +355,7 @@ pub impl Font {
             shaper: None,
             style: copy *style,
             metrics: move metrics,
+            synthesis: synthesis,
             backend: backend,
         }
     }
@@ -389,7 +439,10 @@ pub impl Font {
                     AzGlyphBuffer};
         use azure::azure::bindgen::{AzCreateColorPattern,
                                     AzDrawTargetFillGlyphs,
+                                    AzDrawTargetGetTransform,
+                                    AzDrawTargetSetTransform,
                                     AzReleaseColorPattern};
+        use azure::struct__AzMatrix;
 
         let target = rctx.get_draw_target();
         let azfontref = self.get_azure_font();
@@ -402,6 +455,7 @@ pub impl Font {
             fields: 0x0200 as uint16_t
         };
 
+        let bold_offset = self.synthesis.bold_offset(self.style.pt_size);
         let mut origin = copy baseline_origin;
         let azglyphs = DVec();
         azglyphs.reserve(range.length());
@@ -417,8 +471,21 @@ pub impl Font {
                     y: (origin.y + glyph_offset.y).to_px() as AzFloat
                 }
             };
-            origin = Point2D(origin.x + glyph_advance, origin.y);
-            azglyphs.push(move azglyph)
+            azglyphs.push(move azglyph);
+
+            // Synthetic bold smears the glyph by drawing it again further right
+            if self.synthesis.bold {
+                let azglyph = struct__AzGlyph {
+                    mIndex: glyph.index() as uint32_t,
+                    mPosition: struct__AzPoint {
+                        x: (origin.x + glyph_offset.x + bold_offset).to_px() as AzFloat,
+                        y: (origin.y + glyph_offset.y).to_px() as AzFloat
+                    }
+                };
+                azglyphs.push(move azglyph);
+            }
+
+            origin = Point2D(origin.x + glyph_advance + bold_offset, origin.y);
         };
 
         let azglyph_buf_len = azglyphs.len();
@@ -432,6 +499,29 @@ pub impl Font {
             }
         };
 
+        // Synthetic oblique shears the glyphs about the baseline, on top of
+        // whatever transform the draw target already has.
+        let mut saved = struct__AzMatrix {
+            _11: 1f as AzFloat, _12: 0f as AzFloat,
+            _21: 0f as AzFloat, _22: 1f as AzFloat,
+            _31: 0f as AzFloat, _32: 0f as AzFloat
+        };
+        if self.synthesis.oblique {
+            AzDrawTargetGetTransform(target.azure_draw_target, ptr::to_mut_unsafe_ptr(&mut saved));
+            let shear = self.synthesis.shear() as AzFloat;
+            let baseline = baseline_origin.y.to_px() as AzFloat;
+            // The shear is applied first, then the saved transform
+            let matrix = struct__AzMatrix {
+                _11: saved._11,
+                _12: saved._12,
+                _21: saved._21 - shear * saved._11,
+                _22: saved._22 - shear * saved._12,
+                _31: shear * baseline * saved._11 + saved._31,
+                _32: shear * baseline * saved._12 + saved._32
+            };
+            AzDrawTargetSetTransform(target.azure_draw_target, ptr::to_unsafe_ptr(&matrix));
+        }
+
         // TODO(Issue #64): this call needs to move into azure_hl.rs
         AzDrawTargetFillGlyphs(target.azure_draw_target,
                                azfontref,
@@ -439,15 +529,22 @@ pub impl Font {
                                azure_pattern,
                                ptr::to_unsafe_ptr(&options),
                                ptr::null());
+
+        if self.synthesis.oblique {
+            AzDrawTargetSetTransform(target.azure_draw_target, ptr::to_unsafe_ptr(&saved));
+        }
     }
 
     fn measure_text(run: &TextRun, range: &const Range) -> RunMetrics {
         // TODO(Issue #199): alter advance direction for RTL
         // TODO(Issue #98): using inter-char and inter-word spacing settings  when measuring text
         let mut advance = Au(0);
+        let mut glyph_count = 0;
         for run.glyphs.iter_glyphs_for_char_range(range) |_i, glyph| {
             advance += glyph.advance();
+            glyph_count += 1;
         }
+        let advance = self.synthesis.advance_for_glyphs(advance, glyph_count, self.style.pt_size);
         let mut bounds = Rect(Point2D(Au(0), -self.metrics.ascent),
                               Size2D(advance, self.metrics.ascent + self.metrics.descent));
 
@@ -555,3 +652,48 @@ fn should_be_able_to_create_instances_in_multiple_threads() {
 }
 
 */
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use font_context;
+    use font_context::FontContext;
+
+    use azure::azure_hl::CairoBackend;
+
+    #[test]
+    fn should_measure_synthetic_bold_runs_wider() {
+        let mut bold_style = font_context::dummy_style();
+        bold_style.weight = FontWeight700;
+
+        let regular = FontSynthesis::new(false, false, &font_context::dummy_style());
+        let bold = FontSynthesis::new(false, false, &bold_style);
+        assert !regular.bold && bold.bold;
+
+        // A real bold face needs no smearing
+        assert !FontSynthesis::new(true, false, &bold_style).bold;
+
+        let advance = Au::from_px(50);
+        assert bold.advance_for_glyphs(advance, 5, bold_style.pt_size)
+            > regular.advance_for_glyphs(advance, 5, bold_style.pt_size);
+        assert regular.advance_for_glyphs(advance, 5, bold_style.pt_size) == advance;
+    }
+
+    #[test]
+    fn should_shear_italics_without_an_italic_face() {
+        let fctx = FontContext::new(CairoBackend, false);
+        let mut style = font_context::dummy_style();
+        style.italic = true;
+
+        // The test font only has an upright face
+        let font = result::unwrap(Font::new_from_buffer(&fctx, font_context::test_font_bin(),
+                                                        &style, CairoBackend));
+        assert font.synthesis.oblique;
+        assert font.synthesis.shear() == SYNTHETIC_OBLIQUE_SHEAR;
+
+        let upright = result::unwrap(Font::new_from_buffer(&fctx, font_context::test_font_bin(),
+                                                           &font_context::dummy_style(),
+                                                           CairoBackend));
+        assert upright.synthesis.shear() == 0f;
+    }
+}
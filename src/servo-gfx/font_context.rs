@@ -1,5 +1,5 @@
 use font::{Font, FontDescriptor, FontGroup, FontStyle, SelectorPlatformIdentifier};
-use font::{SelectorStubDummy, SpecifiedFontStyle, UsedFontStyle};
+use font::{FontWeight400, SelectorStubDummy, SpecifiedFontStyle, UsedFontStyle};
 use font_list::FontList;
 use native::FontHandle;
 use util::cache::Cache;
@@ -19,7 +19,7 @@ use font_context;
 // TODO(Issue #164): delete, and get default font from font list
 const TEST_FONT: [u8 * 33004] = include_bin!("JosefinSans-SemiBold.ttf");
 
-fn test_font_bin() -> ~[u8] {
+pub fn test_font_bin() -> ~[u8] {
     return vec::from_fn(33004, |i| TEST_FONT[i]);
 }
 
@@ -61,6 +61,27 @@ pub impl FontContextHandle {
     }
 }
 
+/**
+The font of text whose style doesn't choose one, and the families tried
+when none of a style's families are available, e.g. from user preferences.
+*/
+pub struct FontDefaults {
+    family: ~str,
+    /// In pt
+    size: float,
+    fallback_families: ~[~str],
+}
+
+pub impl FontDefaults {
+    static fn new() -> FontDefaults {
+        FontDefaults {
+            family: ~"serif",
+            size: 16f,
+            fallback_families: ~[~"sans-serif"],
+        }
+    }
+}
+
 #[allow(non_implicitly_copyable_typarams)]
 pub struct FontContext {
     instance_cache: MonoCache<FontDescriptor, @Font>,
@@ -68,11 +89,17 @@ pub struct FontContext {
     handle: FontContextHandle,
     backend: BackendType,
     generic_fonts: LinearMap<~str,~str>,
+    defaults: FontDefaults,
 }
 
 #[allow(non_implicitly_copyable_typarams)]
 pub impl FontContext {
     static fn new(backend: BackendType, needs_font_list: bool) -> FontContext {
+        FontContext::new_with_defaults(backend, needs_font_list, FontDefaults::new())
+    }
+
+    static fn new_with_defaults(backend: BackendType, needs_font_list: bool,
+                                defaults: FontDefaults) -> FontContext {
         let handle = FontContextHandle::new();
         let font_list = if needs_font_list { Some(FontList::new(&handle)) } else { None };
 
@@ -91,6 +118,18 @@ pub impl FontContext {
             handle: move handle,
             backend: backend,
             generic_fonts: move generic_fonts,
+            defaults: move defaults,
+        }
+    }
+
+    /// The style of text whose style doesn't choose a font
+    fn default_style(&self) -> FontStyle {
+        FontStyle {
+            pt_size: self.defaults.size,
+            weight: FontWeight400,
+            italic: false,
+            oblique: false,
+            families: copy self.defaults.family,
         }
     }
 
@@ -135,25 +174,13 @@ pub impl FontContext {
         debug!("(create font group) --- starting ---");
 
         // TODO(Issue #193): make iteration over 'font-family' more robust.
+        let mut families = ~[];
         for str::split_char_each(style.families, ',') |family| {
-            let family_name = str::trim(family);
-            let transformed_family_name = self.transform_family(family_name);
-            debug!("(create font group) transformed family is `%s`", transformed_family_name);
-
-            let list = self.get_font_list();
-
-            let result = list.find_font_in_family(transformed_family_name, style);
-            let mut found = false;
-            do result.iter |font_entry| {
-                found = true;
-                // TODO(Issue #203): route this instantion through FontContext's Font instance cache.
-                let instance = Font::new_from_existing_handle(&self, &font_entry.handle, style, self.backend);
-                do result::iter(&instance) |font: &@Font| { fonts.push(*font); }
-            };
-
-            if !found {
-                debug!("(create font group) didn't find `%s`", transformed_family_name);
-            }
+            families.push(str::trim(family));
+        }
+        self.add_fonts_in_families(families, style, &fonts);
+        if fonts.len() == 0 {
+            self.add_fonts_in_families(self.defaults.fallback_families, style, &fonts);
         }
 
         // TODO(Issue #194): *always* attach a fallback font to the
@@ -161,7 +188,7 @@ pub impl FontContext {
 
         // assert fonts.len() > 0;
         if fonts.len() == 0 {
-            let desc = FontDescriptor::new(font_context::dummy_style(), SelectorStubDummy);
+            let desc = FontDescriptor::new(copy *style, SelectorStubDummy);
             debug!("(create font group) trying descriptor `%?`", desc);
             match self.get_font_by_descriptor(&desc) {
                 Ok(instance) => fonts.push(instance),
@@ -177,6 +204,33 @@ pub impl FontContext {
         @FontGroup::new(style.families.to_managed(), &used_style, dvec::unwrap(move fonts))
     }
 
+    // Adds the fonts found for each family to `fonts`. Without a font list none are.
+    priv fn add_fonts_in_families(families: &[~str], style: &SpecifiedFontStyle,
+                                  fonts: &DVec<@Font>) {
+        if self.font_list.is_none() {
+            return;
+        }
+        for families.each |family_name| {
+            let transformed_family_name = self.transform_family(*family_name);
+            debug!("(create font group) transformed family is `%s`", transformed_family_name);
+
+            let list = self.get_font_list();
+
+            let result = list.find_font_in_family(transformed_family_name, style);
+            let mut found = false;
+            do result.iter |font_entry| {
+                found = true;
+                // TODO(Issue #203): route this instantion through FontContext's Font instance cache.
+                let instance = Font::new_from_existing_handle(&self, &font_entry.handle, style, self.backend);
+                do result::iter(&instance) |font: &@Font| { fonts.push(*font); }
+            };
+
+            if !found {
+                debug!("(create font group) didn't find `%s`", transformed_family_name);
+            }
+        }
+    }
+
     priv fn create_font_instance(desc: &FontDescriptor) -> Result<@Font, ()> {
         return match &desc.selector {
             &SelectorStubDummy => {
@@ -196,3 +250,37 @@ pub impl FontContext {
         };
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use geometry::Au;
+    use text::text_run::TextRun;
+    use util::range::Range;
+
+    use azure::azure_hl::CairoBackend;
+
+    // The width of `text` in the default font of a context with the given default size
+    fn default_width(size: float, text: ~str) -> Au {
+        let mut defaults = FontDefaults::new();
+        defaults.size = size;
+        let fctx = FontContext::new_with_defaults(CairoBackend, false, move defaults);
+        let style = fctx.default_style();
+        assert style.pt_size == size;
+
+        let group = fctx.get_resolved_font_for_style(&style);
+        let run = TextRun::new(group.fonts[0], move text);
+        run.metrics_for_range(&const Range::new(0, run.char_len())).advance_width
+    }
+
+    #[test]
+    fn should_scale_text_with_the_default_size() {
+        let small = default_width(10f, ~"hello world");
+        let large = default_width(20f, ~"hello world");
+        assert small > Au(0);
+        // Glyph advances are rounded, so allow a little slack per glyph
+        let slack = Au::from_px(11);
+        assert large > small;
+        assert large <= small * Au(2) + slack && large + slack >= small * Au(2);
+    }
+}
@@ -131,6 +131,17 @@ pub impl FontFamily {
             }
         }
 
+        // No exact match, so settle for a face with the right slant (or
+        // else any face) and let the font synthesize the rest.
+        for self.entries.each |entry| {
+            if style.italic == entry.is_italic() {
+                return Some(*entry);
+            }
+        }
+
+        if self.entries.len() > 0 {
+            return Some(self.entries.get_elt(0));
+        }
         return None;
     }
 }
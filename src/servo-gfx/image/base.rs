@@ -1,3 +1,5 @@
+use image::exif;
+use image::header;
 use stb_image = stb_image::image;
 
 // FIXME: Images must not be copied every frame. Instead we should atomically
@@ -9,18 +11,200 @@ pub fn Image(width: uint, height: uint, depth: uint, data: ~[u8]) -> Image {
     stb_image::new_image(width, height, depth, move data)
 }
 
+/// One frame of a possibly animated image, shown for `delay_ms` before the next
+pub struct ImageFrame {
+    image: Image,
+    delay_ms: uint
+}
+
 const TEST_IMAGE: [u8 * 4962] = include_bin!("test.jpeg");
 
 pub fn test_image_bin() -> ~[u8] {
     return vec::from_fn(4962, |i| TEST_IMAGE[i]);
 }
 
+/**
+Encodes a `width` by `height` image filled with `color`, given as RGBA, as an
+uncompressed PNG. Unlike `test_image_bin`, tests know exactly which pixels it
+decodes to.
+*/
+#[cfg(test)]
+pub fn test_image_with_color(width: uint, height: uint, color: (u8, u8, u8, u8)) -> ~[u8] {
+    let (r, g, b, a) = color;
+    // Each scanline starts with its filter type, 0 for none
+    let mut pixels = ~[];
+    for height.times {
+        pixels.push(0);
+        for width.times {
+            pixels.push_all([r, g, b, a]);
+        }
+    }
+
+    // A zlib stream of stored deflate blocks, which hold at most 65535 bytes
+    let mut zlib = ~[0x78u8, 0x01];
+    let mut start = 0;
+    loop {
+        let len = uint::min(pixels.len() - start, 0xFFFF);
+        let last = start + len == pixels.len();
+        zlib.push(if last { 1 } else { 0 });
+        zlib.push_all([len as u8, (len >> 8) as u8, (!len) as u8, ((!len) >> 8) as u8]);
+        zlib.push_all(vec::view(pixels, start, start + len));
+        start += len;
+        if last {
+            break;
+        }
+    }
+    push_u32_be(&mut zlib, adler32(pixels));
+
+    let mut header = ~[];
+    push_u32_be(&mut header, width as u32);
+    push_u32_be(&mut header, height as u32);
+    // 8 bit RGBA, deflated, filtered per scanline and not interlaced
+    header.push_all([8, 6, 0, 0, 0]);
+
+    let mut png = ~[0x89u8, 0x50, 0x4E, 0x47, 0x0D, 0x0A, 0x1A, 0x0A];
+    push_png_chunk(&mut png, "IHDR", header);
+    push_png_chunk(&mut png, "IDAT", zlib);
+    push_png_chunk(&mut png, "IEND", []);
+    png
+}
+
+#[cfg(test)]
+fn push_png_chunk(png: &mut ~[u8], kind: &str, data: &[u8]) {
+    push_u32_be(png, data.len() as u32);
+    let mut checked = str::to_bytes(kind);
+    checked.push_all(data);
+    png.push_all(checked);
+    push_u32_be(png, crc32(checked));
+}
+
+#[cfg(test)]
+fn push_u32_be(bytes: &mut ~[u8], n: u32) {
+    bytes.push_all([(n >> 24) as u8, (n >> 16) as u8, (n >> 8) as u8, n as u8]);
+}
+
+#[cfg(test)]
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xFFFFFFFFu32;
+    for bytes.each |byte| {
+        crc ^= *byte as u32;
+        for 8.times {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xEDB88320u32 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+fn adler32(bytes: &[u8]) -> u32 {
+    let mut a = 1u32;
+    let mut b = 0u32;
+    for bytes.each |byte| {
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+/// Images declaring more pixels than this are not decoded by default. At four
+/// bytes per pixel this is 256MB.
+pub const DEFAULT_MAX_PIXELS: uint = 64 * 1024 * 1024;
+
+/// How decoded pixels store transparency
+#[deriving_eq]
+pub enum AlphaMode {
+    /// Color channels are independent of alpha. What decoders produce.
+    Straight,
+    /// Color channels are already multiplied by alpha, as compositors want
+    Premultiplied
+}
+
+pub enum DecodeError {
+    /// The image declares more pixels than the decoder is allowed to allocate
+    TooLarge,
+    /// The data isn't an image we can decode
+    Malformed,
+    /// The image declares no pixels, so there is nothing to allocate or show
+    Corrupt,
+    /// The data isn't in any format that can be decoded
+    Unsupported,
+    /// The data ends before the image does
+    Incomplete
+}
+
 pub fn load_from_memory(buffer: &[u8]) -> Option<Image> {
+    load_from_memory_(buffer, true)
+}
+
+/// Like load_from_memory, but saying why the image couldn't be decoded
+pub fn try_load_from_memory(buffer: &[u8]) -> Result<Image, DecodeError> {
+    decode_with_limit(buffer, true, DEFAULT_MAX_PIXELS)
+}
+
+/// Decodes an image with its pixels in the given alpha mode
+pub fn load_from_memory_with_alpha(buffer: &[u8], alpha_mode: AlphaMode) -> Option<Image> {
+    do load_from_memory(buffer).map |image| {
+        convert_alpha(image, alpha_mode)
+    }
+}
+
+/// Returns a copy of a straight alpha image in the given alpha mode
+pub fn convert_alpha(image: &Image, alpha_mode: AlphaMode) -> Image {
+    assert image.depth == 4;
+    let data = match alpha_mode {
+        Straight => copy image.data,
+        Premultiplied => {
+            do vec::from_fn(image.data.len()) |i| {
+                let alpha = image.data[i - i % 4 + 3] as uint;
+                if i % 4 == 3 {
+                    alpha as u8
+                } else {
+                    ((image.data[i] as uint * alpha + 127) / 255) as u8
+                }
+            }
+        }
+    };
+    Image(image.width, image.height, image.depth, move data)
+}
+
+/// Decodes an image, rotating and flipping it upright according to its EXIF
+/// orientation if `respect_orientation` is set.
+pub fn load_from_memory_(buffer: &[u8], respect_orientation: bool) -> Option<Image> {
+    match decode_with_limit(buffer, respect_orientation, DEFAULT_MAX_PIXELS) {
+        Ok(move image) => Some(move image),
+        Err(_) => None
+    }
+}
+
+/**
+Decodes an image unless its header declares more than `max_pixels` pixels,
+in which case it fails with `TooLarge` before anything is allocated, or a
+width or height of zero, in which case it fails with `Corrupt`.
+*/
+pub fn decode_with_limit(buffer: &[u8], respect_orientation: bool,
+                         max_pixels: uint) -> Result<Image, DecodeError> {
+    match header::dimensions(buffer) {
+        Some((width, height)) if width == 0 || height == 0 => {
+            debug!("image: refusing to decode a %ux%u image", width, height);
+            return Err(Corrupt);
+        }
+        Some((width, height)) if width > max_pixels / height => {
+            debug!("image: refusing to decode a %ux%u image", width, height);
+            return Err(TooLarge);
+        }
+        _ => ()
+    }
+    decode(buffer, respect_orientation)
+}
+
+fn decode(buffer: &[u8], respect_orientation: bool) -> Result<Image, DecodeError> {
 
     // Can't remember why we do this. Maybe it's what cairo wants
     const FORCE_DEPTH: uint = 4;
 
     match stb_image::load_from_memory_with_depth(buffer, FORCE_DEPTH, true) {
+        // Formats the header reader doesn't know may still declare no pixels
+        stb_image::ImageU8(ref image) if image.width == 0 || image.height == 0 => Err(Corrupt),
         stb_image::ImageU8(image) => {
             assert image.depth == 4;
             // Do color space conversion :(
@@ -31,16 +215,198 @@ pub fn load_from_memory(buffer: &[u8]) -> Option<Image> {
                     0 => image.data[pixel * 4 + 2],
                     1 => image.data[pixel * 4 + 1],
                     2 => image.data[pixel * 4 + 0],
-                    3 => 0xffu8,
+                    3 => image.data[pixel * 4 + 3],
                     _ => fail!()
                 }
             };
 
             assert image.data.len() == data.len();
 
-            Some(Image(image.width, image.height, image.depth, move data))
+            let image = Image(image.width, image.height, image.depth, move data);
+            if !respect_orientation {
+                return Ok(move image);
+            }
+            match exif::orientation(buffer) {
+                Some(orientation) if orientation != 1 => {
+                    Ok(exif::apply_orientation(&image, orientation))
+                }
+                _ => Ok(move image)
+            }
         }
         stb_image::ImageF32(_image) => fail!(~"HDR images not implemented"),
-        stb_image::Error => None
+        stb_image::Error => Err(Malformed)
+    }
+}
+
+/// The identity of a decoded image: its pixel buffer and dimensions. Two
+/// images have the same identity only if they share a buffer.
+#[deriving_eq]
+pub struct ImageId {
+    data: *u8,
+    width: uint,
+    height: uint
+}
+
+pub trait ImageMethods {
+    pure fn id(&self) -> ImageId;
+    pure fn same_image(&self, other: &Image) -> bool;
+    pure fn pixels_equal(&self, other: &Image) -> bool;
+    pure fn content_hash(&self) -> u64;
+}
+
+impl Image: ImageMethods {
+    pure fn id(&self) -> ImageId {
+        ImageId {
+            data: vec::raw::to_ptr(self.data),
+            width: self.width,
+            height: self.height
+        }
+    }
+
+    /// Reference equality. Cheap, but false for separately decoded copies
+    /// of the same image.
+    pure fn same_image(&self, other: &Image) -> bool {
+        self.id() == other.id()
+    }
+
+    /// Content equality. Compares every pixel.
+    pure fn pixels_equal(&self, other: &Image) -> bool {
+        self.width == other.width && self.height == other.height &&
+            self.depth == other.depth && self.data == other.data
+    }
+
+    /// A hash of the dimensions and pixels (64-bit FNV-1a), stable across
+    /// runs, for keying maps by image content
+    pure fn content_hash(&self) -> u64 {
+        let mut hash = 0xcbf29ce484222325u64;
+        let dimensions = [self.width as u64, self.height as u64, self.depth as u64];
+        for dimensions.each |n| {
+            for uint::range(0, 8) |i| {
+                hash = (hash ^ ((*n >> (i * 8)) & 0xff)) * 0x100000001b3u64;
+            }
+        }
+        for self.data.each |b| {
+            hash = (hash ^ (*b as u64)) * 0x100000001b3u64;
+        }
+        hash
+    }
+}
+
+#[test]
+fn should_hash_identical_decodes_equally() {
+    let first = load_from_memory(test_image_bin()).get();
+    let second = load_from_memory(test_image_bin()).get();
+
+    assert first.content_hash() == second.content_hash();
+    assert first.pixels_equal(&second);
+    assert !first.same_image(&second);
+    assert first.same_image(&first);
+}
+
+#[test]
+fn should_premultiply_alpha_when_asked() {
+    // A 2x1 RGBA PNG holding (255, 0, 0, 128) and (200, 100, 50, 64)
+    let buffer = ~[0x89u8, 0x50, 0x4E, 0x47, 0x0D, 0x0A, 0x1A, 0x0A, 0x00, 0x00, 0x00, 0x0D,
+                   0x49, 0x48, 0x44, 0x52, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x01,
+                   0x08, 0x06, 0x00, 0x00, 0x00, 0xF4, 0x22, 0x7F, 0x8A, 0x00, 0x00, 0x00,
+                   0x14, 0x49, 0x44, 0x41, 0x54, 0x78, 0x01, 0x01, 0x09, 0x00, 0xF6, 0xFF,
+                   0x00, 0xFF, 0x00, 0x00, 0x80, 0xC8, 0x64, 0x32, 0x40, 0x0F, 0x71, 0x03,
+                   0x1E, 0xB6, 0x4A, 0x23, 0x74, 0x00, 0x00, 0x00, 0x00, 0x49, 0x45, 0x4E,
+                   0x44, 0xAE, 0x42, 0x60, 0x82];
+
+    let straight = load_from_memory_with_alpha(buffer, Straight).get();
+    let premultiplied = load_from_memory_with_alpha(buffer, Premultiplied).get();
+
+    // Decoded pixels are BGRA
+    assert straight.data == ~[0, 0, 255, 128, 50, 100, 200, 64];
+    for uint::range(0, 8) |i| {
+        let alpha = straight.data[i - i % 4 + 3] as float;
+        let expected = if i % 4 == 3 {
+            alpha
+        } else {
+            float::round((straight.data[i] as float) * alpha / 255.0)
+        };
+        assert premultiplied.data[i] as float == expected;
+    }
+    assert load_from_memory(buffer).get().pixels_equal(&straight);
+}
+
+#[test]
+fn should_refuse_to_decode_enormous_images() {
+    // A PNG signature and IHDR chunk declaring a 100000x100000 image, and no
+    // pixel data. Decoding it would need 40GB.
+    let buffer = ~[0x89u8, 0x50, 0x4E, 0x47, 0x0D, 0x0A, 0x1A, 0x0A,
+                   0, 0, 0, 13, 0x49, 0x48, 0x44, 0x52,
+                   0, 0x01, 0x86, 0xA0, 0, 0x01, 0x86, 0xA0,
+                   8, 6, 0, 0, 0];
+    assert header::dimensions(buffer) == Some((100000, 100000));
+    match decode_with_limit(buffer, true, DEFAULT_MAX_PIXELS) {
+        Err(TooLarge) => (),
+        _ => fail!(~"expected TooLarge")
+    }
+    assert load_from_memory(buffer).is_none();
+}
+
+#[test]
+fn should_decode_images_within_the_limit() {
+    match decode_with_limit(test_image_bin(), true, DEFAULT_MAX_PIXELS) {
+        Ok(_) => (),
+        Err(_) => fail!(~"expected the test image to decode")
+    }
+    match decode_with_limit(test_image_bin(), true, 1) {
+        Err(TooLarge) => (),
+        _ => fail!(~"expected TooLarge")
     }
 }
+
+#[test]
+fn should_decode_a_one_pixel_image() {
+    // A 1x1 RGBA PNG holding (10, 20, 30, 255)
+    let buffer = ~[0x89u8, 0x50, 0x4E, 0x47, 0x0D, 0x0A, 0x1A, 0x0A, 0x00, 0x00, 0x00, 0x0D,
+                   0x49, 0x48, 0x44, 0x52, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01,
+                   0x08, 0x06, 0x00, 0x00, 0x00, 0x1F, 0x15, 0xC4, 0x89, 0x00, 0x00, 0x00,
+                   0x0D, 0x49, 0x44, 0x41, 0x54, 0x78, 0x9C, 0x63, 0xE0, 0x12, 0x91, 0xFB,
+                   0x0F, 0x00, 0x01, 0xA4, 0x01, 0x3C, 0x93, 0x8B, 0x0E, 0xB7, 0x00, 0x00,
+                   0x00, 0x00, 0x49, 0x45, 0x4E, 0x44, 0xAE, 0x42, 0x60, 0x82];
+    match decode_with_limit(buffer, true, DEFAULT_MAX_PIXELS) {
+        Ok(image) => {
+            assert image.width == 1 && image.height == 1;
+            // Decoded pixels are BGRA
+            assert image.data == ~[30, 20, 10, 255];
+        }
+        Err(_) => fail!(~"expected a 1x1 image")
+    }
+}
+
+#[test]
+fn should_reject_images_without_pixels_as_corrupt() {
+    // A PNG signature and IHDR chunk declaring a 0x0 image, and no pixel data
+    let buffer = ~[0x89u8, 0x50, 0x4E, 0x47, 0x0D, 0x0A, 0x1A, 0x0A,
+                   0, 0, 0, 13, 0x49, 0x48, 0x44, 0x52,
+                   0, 0, 0, 0, 0, 0, 0, 0,
+                   8, 6, 0, 0, 0];
+    match decode_with_limit(buffer, true, DEFAULT_MAX_PIXELS) {
+        Err(Corrupt) => (),
+        _ => fail!(~"expected Corrupt")
+    }
+    assert load_from_memory(buffer).is_none();
+}
+
+#[test]
+fn should_decode_test_images_to_their_color() {
+    let image = load_from_memory(test_image_with_color(3, 2, (10, 20, 30, 255))).get();
+    assert image.width == 3 && image.height == 2;
+    // Decoded pixels are BGRA
+    for uint::range(0, 6) |i| {
+        assert vec::slice(image.data, i * 4, i * 4 + 4) == ~[30, 20, 10, 255];
+    }
+
+    // Large enough to need more than one deflate block
+    let buffer = test_image_with_color(200, 100, (255, 128, 0, 64));
+    assert header::dimensions(buffer) == Some((200, 100));
+    let image = load_from_memory(buffer).get();
+    assert image.width == 200 && image.height == 100;
+    assert image.pixels_equal(&Image(200, 100, 4, vec::from_fn(200 * 100 * 4, |i| {
+        [0u8, 128, 255, 64][i % 4]
+    })));
+}
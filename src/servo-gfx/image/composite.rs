@@ -0,0 +1,79 @@
+/*!
+Software compositing of decoded images, which hold 32-bit BGRA pixels with
+premultiplied alpha, like the buffers Azure renders into
+*/
+
+use image::base::Image;
+
+/**
+Draws `src` over `dest` with its top left corner at (x, y), with every pixel
+of `src` scaled by `opacity` first. Parts of `src` outside `dest` are clipped.
+*/
+pub fn composite(dest: &mut Image, src: &Image, x: int, y: int, opacity: float) {
+    assert dest.depth == 4 && src.depth == 4;
+
+    for uint::range(0, src.height) |src_y| {
+        let dest_y = y + src_y as int;
+        if dest_y < 0 || dest_y >= dest.height as int {
+            loop;
+        }
+        for uint::range(0, src.width) |src_x| {
+            let dest_x = x + src_x as int;
+            if dest_x < 0 || dest_x >= dest.width as int {
+                loop;
+            }
+
+            let s = (src_y * src.width + src_x) * 4;
+            let d = (dest_y as uint * dest.width + dest_x as uint) * 4;
+            let src_alpha = (src.data[s + 3] as float) / 255.0 * opacity;
+            for uint::range(0, 4) |channel| {
+                let value = (src.data[s + channel] as float) * opacity +
+                    (dest.data[d + channel] as float) * (1.0 - src_alpha);
+                dest.data[d + channel] = float::round(float::fmin(value, 255.0)) as u8;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use image::base::Image;
+
+    fn solid_image(width: uint, height: uint, bgra: [u8 * 4]) -> Image {
+        Image(width, height, 4, vec::from_fn(width * height * 4, |i| bgra[i % 4]))
+    }
+
+    fn pixel(image: &Image, x: uint, y: uint) -> ~[u8] {
+        let i = (y * image.width + x) * 4;
+        vec::slice(image.data, i, i + 4)
+    }
+
+    #[test]
+    fn should_composite_opacity_groups_as_a_whole() {
+        let white = [255, 255, 255, 255];
+        let red = [0, 0, 255, 255];
+        let blue = [255, 0, 0, 255];
+
+        // An opaque red parent with an opaque blue child overlapping its right half
+        let parent = solid_image(4, 2, red);
+        let child = solid_image(2, 2, blue);
+
+        // As a group at 50%, the child hides the parent before the group is blended
+        let mut group = solid_image(4, 2, [0, 0, 0, 0]);
+        composite(&mut group, &parent, 0, 0, 1.0);
+        composite(&mut group, &child, 2, 0, 1.0);
+        let mut grouped = solid_image(4, 2, white);
+        composite(&mut grouped, &group, 0, 0, 0.5);
+
+        // Blended one at a time, the parent shows through the child
+        let mut separate = solid_image(4, 2, white);
+        composite(&mut separate, &parent, 0, 0, 0.5);
+        composite(&mut separate, &child, 2, 0, 0.5);
+
+        assert pixel(&grouped, 0, 0) == ~[128, 128, 255, 255];
+        assert pixel(&grouped, 3, 1) == ~[255, 128, 128, 255];
+        assert pixel(&separate, 0, 0) == pixel(&grouped, 0, 0);
+        assert pixel(&separate, 3, 1) == ~[192, 64, 128, 255];
+    }
+}
@@ -0,0 +1,188 @@
+/*!
+Support for the EXIF orientation tag that cameras write into JPEG files.
+
+Images taken by a rotated camera are stored unrotated, with an orientation
+tag describing the transform needed to display them upright.
+*/
+
+use image::base::Image;
+
+const ORIENTATION_TAG: u16 = 0x0112;
+
+/**
+Finds the EXIF orientation (1 to 8) of a JPEG file, if it has one.
+
+Returns None for non-JPEG data and for files without a valid tag.
+*/
+pub fn orientation(buffer: &[u8]) -> Option<u8> {
+    // Start of image
+    if buffer.len() < 2 || buffer[0] != 0xFF || buffer[1] != 0xD8 {
+        return None;
+    }
+
+    let mut i = 2;
+    while i + 4 <= buffer.len() {
+        if buffer[i] != 0xFF {
+            return None;
+        }
+        let marker = buffer[i + 1];
+        // Start of scan or end of image: no more metadata
+        if marker == 0xDA || marker == 0xD9 {
+            return None;
+        }
+
+        let length = read_u16(buffer, i + 2, false) as uint;
+        let segment_end = i + 2 + length;
+        if length < 2 || segment_end > buffer.len() {
+            return None;
+        }
+
+        // APP1, holding the EXIF data
+        if marker == 0xE1 {
+            let segment = vec::view(buffer, i + 4, segment_end);
+            match orientation_from_exif(segment) {
+                Some(value) => return Some(value),
+                None => ()
+            }
+        }
+
+        i = segment_end;
+    }
+
+    None
+}
+
+fn orientation_from_exif(segment: &[u8]) -> Option<u8> {
+    let header = [0x45u8, 0x78, 0x69, 0x66, 0, 0]; // "Exif\0\0"
+    if segment.len() < 14 {
+        return None;
+    }
+    for header.eachi |i, byte| {
+        if segment[i] != *byte {
+            return None;
+        }
+    }
+
+    let tiff = vec::view(segment, 6, segment.len());
+    let little_endian = match (tiff[0], tiff[1]) {
+        (0x49, 0x49) => true,  // "II"
+        (0x4D, 0x4D) => false, // "MM"
+        _ => return None
+    };
+
+    let ifd = read_u32(tiff, 4, little_endian) as uint;
+    if ifd + 2 > tiff.len() {
+        return None;
+    }
+
+    let entry_count = read_u16(tiff, ifd, little_endian) as uint;
+    for uint::range(0, entry_count) |n| {
+        let entry = ifd + 2 + n * 12;
+        if entry + 12 > tiff.len() {
+            return None;
+        }
+        if read_u16(tiff, entry, little_endian) == ORIENTATION_TAG {
+            let value = read_u16(tiff, entry + 8, little_endian);
+            return if value >= 1 && value <= 8 { Some(value as u8) } else { None };
+        }
+    }
+
+    None
+}
+
+fn read_u16(buffer: &[u8], offset: uint, little_endian: bool) -> u16 {
+    let (a, b) = (buffer[offset] as u16, buffer[offset + 1] as u16);
+    if little_endian { a | (b << 8) } else { (a << 8) | b }
+}
+
+fn read_u32(buffer: &[u8], offset: uint, little_endian: bool) -> u32 {
+    let (a, b) = (read_u16(buffer, offset, little_endian) as u32,
+                  read_u16(buffer, offset + 2, little_endian) as u32);
+    if little_endian { a | (b << 16) } else { (a << 16) | b }
+}
+
+/**
+Transforms an image stored with the given EXIF orientation so that it is upright.
+
+Orientations 5 through 8 swap the width and height of the image.
+*/
+pub fn apply_orientation(image: &Image, orientation: u8) -> Image {
+    let (w, h, depth) = (image.width, image.height, image.depth);
+    let (out_w, out_h) = if orientation >= 5 { (h, w) } else { (w, h) };
+
+    let mut data = vec::with_capacity(out_w * out_h * depth);
+    for uint::range(0, out_h) |y| {
+        for uint::range(0, out_w) |x| {
+            // The source pixel that lands at (x, y)
+            let (sx, sy) = match orientation {
+                2 => (w - 1 - x, y),            // mirrored horizontally
+                3 => (w - 1 - x, h - 1 - y),    // rotated 180°
+                4 => (x, h - 1 - y),            // mirrored vertically
+                5 => (y, x),                    // transposed
+                6 => (y, h - 1 - x),            // rotated 90° clockwise
+                7 => (w - 1 - y, h - 1 - x),    // transversed
+                8 => (w - 1 - y, x),            // rotated 90° counterclockwise
+                _ => (x, y)
+            };
+            let src = (sy * w + sx) * depth;
+            for uint::range(0, depth) |c| {
+                data.push(image.data[src + c]);
+            }
+        }
+    }
+
+    Image(out_w, out_h, depth, move data)
+}
+
+#[cfg(test)]
+fn exif_jpeg(orientation: u8) -> ~[u8] {
+    // SOI, then an APP1 segment holding a big-endian TIFF header with one IFD entry
+    ~[0xFF, 0xD8,
+      0xFF, 0xE1, 0x00, 0x22,
+      0x45, 0x78, 0x69, 0x66, 0x00, 0x00,
+      0x4D, 0x4D, 0x00, 0x2A, 0x00, 0x00, 0x00, 0x08,
+      0x00, 0x01,
+      0x01, 0x12, 0x00, 0x03, 0x00, 0x00, 0x00, 0x01, 0x00, orientation, 0x00, 0x00,
+      0x00, 0x00, 0x00, 0x00,
+      0xFF, 0xD9]
+}
+
+#[cfg(test)]
+fn test_image() -> Image {
+    // 3x2, one byte per pixel:
+    // 1 2 3
+    // 4 5 6
+    Image(3, 2, 1, ~[1, 2, 3, 4, 5, 6])
+}
+
+#[test]
+fn should_read_orientation_from_exif() {
+    assert orientation(exif_jpeg(6)) == Some(6);
+    assert orientation(exif_jpeg(2)) == Some(2);
+}
+
+#[test]
+fn should_ignore_invalid_orientation() {
+    assert orientation(exif_jpeg(9)) == None;
+    assert orientation(~[0xFF, 0xD8, 0xFF, 0xD9]) == None;
+    assert orientation(~[0x89, 0x50, 0x4E, 0x47]) == None;
+}
+
+#[test]
+fn should_rotate_90_degrees() {
+    let rotated = apply_orientation(&test_image(), 6);
+    assert rotated.width == 2;
+    assert rotated.height == 3;
+    // 4 1
+    // 5 2
+    // 6 3
+    assert rotated.data == ~[4, 1, 5, 2, 6, 3];
+}
+
+#[test]
+fn should_mirror() {
+    let mirrored = apply_orientation(&test_image(), 2);
+    assert mirrored.width == 3;
+    assert mirrored.height == 2;
+    assert mirrored.data == ~[3, 2, 1, 6, 5, 4];
+}
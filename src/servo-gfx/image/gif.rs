@@ -0,0 +1,487 @@
+/*!
+Decoding of every frame of a GIF, which stb_image doesn't do: it stops at the
+first. Each frame is drawn onto the canvas left by the frames before it, as
+their disposal methods leave it, so that the frames can be shown as they are.
+*/
+
+use image::base::{Corrupt, DEFAULT_MAX_PIXELS, DecodeError, Image, ImageFrame, Incomplete};
+use image::base::{Malformed, TooLarge};
+#[cfg(test)]
+use image::base::test_image_bin;
+
+/// The longest LZW code in a GIF, in bits
+const MAX_CODE_SIZE: uint = 12;
+
+// How a frame's area of the canvas is left once the frame has been shown
+enum Disposal {
+    /// Left as the frame drew it
+    Keep,
+    /// Cleared to transparent
+    RestoreBackground,
+    /// Put back as it was before the frame was drawn
+    RestorePrevious
+}
+
+// What a graphic control extension says about the frame that follows it
+struct FrameControl {
+    delay_ms: uint,
+    transparent: Option<u8>,
+    disposal: Disposal
+}
+
+pure fn default_control() -> FrameControl {
+    FrameControl { delay_ms: 0, transparent: None, disposal: Keep }
+}
+
+/**
+Decodes every frame of a GIF into a BGRA image the size of the canvas, with
+how long each is shown. Fails if the data isn't a GIF, declares a canvas
+with no pixels or too many to allocate, or has no frame that can be read, in
+which case `Incomplete` says the data ran out before one could be.
+*/
+pub fn decode_frames(buffer: &[u8]) -> Result<~[ImageFrame], DecodeError> {
+    if buffer.len() < 3 || vec::view(buffer, 0, 3) != [0x47u8, 0x49, 0x46] { // "GIF"
+        return Err(Malformed);
+    }
+    if buffer.len() < 13 {
+        return Err(Incomplete);
+    }
+    let width = read_u16_le(buffer, 6);
+    let height = read_u16_le(buffer, 8);
+    if width == 0 || height == 0 {
+        debug!("gif: refusing to decode a %ux%u image", width, height);
+        return Err(Corrupt);
+    }
+    if width > DEFAULT_MAX_PIXELS / height {
+        debug!("gif: refusing to decode a %ux%u image", width, height);
+        return Err(TooLarge);
+    }
+
+    let flags = buffer[10];
+    let mut pos = 13;
+    let global_colors = if flags & 0x80 != 0 {
+        match read_color_table(buffer, &mut pos, flags) {
+            Some(move colors) => Some(move colors),
+            None => return Err(Incomplete)
+        }
+    } else {
+        None
+    };
+
+    let mut canvas = vec::from_elem(width * height * 4, 0u8);
+    let mut frames = ~[];
+    let mut control = default_control();
+    // Why no frame could be read, should none be
+    let mut failure = Incomplete;
+    while pos < buffer.len() {
+        let introducer = buffer[pos];
+        pos += 1;
+        match introducer {
+            // Extension
+            0x21 => {
+                if pos >= buffer.len() {
+                    break;
+                }
+                let label = buffer[pos];
+                pos += 1;
+                let data = match read_sub_blocks(buffer, &mut pos) {
+                    Some(move data) => move data,
+                    None => break
+                };
+                // Graphic control extension
+                if label == 0xF9 && data.len() >= 4 {
+                    control = FrameControl {
+                        delay_ms: read_u16_le(data, 1) * 10,
+                        transparent: if data[0] & 1 != 0 { Some(data[3]) } else { None },
+                        disposal: match (data[0] >> 2) & 7 {
+                            2 => RestoreBackground,
+                            3 => RestorePrevious,
+                            _ => Keep
+                        }
+                    };
+                }
+            }
+            // Image descriptor
+            0x2C => {
+                match read_frame(buffer, &mut pos, width, height, &global_colors, &control,
+                                 &mut canvas) {
+                    Ok(move image) => {
+                        frames.push(ImageFrame { image: move image, delay_ms: control.delay_ms });
+                    }
+                    Err(error) => {
+                        debug!("gif: stopping at a frame that couldn't be read");
+                        failure = error;
+                        break;
+                    }
+                }
+                control = default_control();
+            }
+            // Trailer
+            0x3B => {
+                failure = Malformed;
+                break;
+            }
+            _ => {
+                debug!("gif: stopping at unknown block %u", introducer as uint);
+                failure = Malformed;
+                break;
+            }
+        }
+    }
+
+    if frames.is_empty() { Err(failure) } else { Ok(move frames) }
+}
+
+/**
+Reads the frame whose image descriptor starts at `pos`, draws it onto the
+canvas and returns a copy of the canvas as it then looks. The canvas is then
+disposed of as the frame's control asks, ready for the next frame.
+*/
+fn read_frame(buffer: &[u8], pos: &mut uint, width: uint, height: uint,
+              global_colors: &Option<~[u8]>, control: &FrameControl,
+              canvas: &mut ~[u8]) -> Result<Image, DecodeError> {
+    if *pos + 9 > buffer.len() {
+        return Err(Incomplete);
+    }
+    let left = read_u16_le(buffer, *pos);
+    let top = read_u16_le(buffer, *pos + 2);
+    let frame_width = read_u16_le(buffer, *pos + 4);
+    let frame_height = read_u16_le(buffer, *pos + 6);
+    let flags = buffer[*pos + 8];
+    *pos += 9;
+
+    let local_colors = if flags & 0x80 != 0 {
+        match read_color_table(buffer, pos, flags) {
+            Some(move colors) => Some(move colors),
+            None => return Err(Incomplete)
+        }
+    } else {
+        None
+    };
+    let colors = match (&local_colors, global_colors) {
+        (&Some(ref colors), _) | (&None, &Some(ref colors)) => colors,
+        (&None, &None) => return Err(Malformed)
+    };
+
+    if *pos >= buffer.len() {
+        return Err(Incomplete);
+    }
+    let min_code_size = buffer[*pos] as uint;
+    *pos += 1;
+    let data = match read_sub_blocks(buffer, pos) {
+        Some(move data) => move data,
+        None => return Err(Incomplete)
+    };
+    let indices = match lzw_decode(data, min_code_size, frame_width * frame_height) {
+        Some(move indices) => move indices,
+        None => return Err(Malformed)
+    };
+
+    let previous = match control.disposal {
+        RestorePrevious => Some(copy *canvas),
+        Keep | RestoreBackground => None
+    };
+
+    let rows = frame_rows(frame_height, flags & 0x40 != 0);
+    for indices.eachi |i, index| {
+        let x = left + i % frame_width;
+        let y = top + rows[i / frame_width];
+        let index = *index as uint;
+        if x >= width || y >= height || Some(index as u8) == control.transparent ||
+                index * 3 + 2 >= colors.len() {
+            loop;
+        }
+        // Decoded pixels are BGRA
+        let offset = (y * width + x) * 4;
+        (*canvas)[offset] = colors[index * 3 + 2];
+        (*canvas)[offset + 1] = colors[index * 3 + 1];
+        (*canvas)[offset + 2] = colors[index * 3];
+        (*canvas)[offset + 3] = 255;
+    }
+    let image = Image(width, height, 4, copy *canvas);
+
+    match move previous {
+        Some(move previous) => *canvas = move previous,
+        None => ()
+    }
+    match control.disposal {
+        RestoreBackground => {
+            for uint::range(top, uint::min(top + frame_height, height)) |y| {
+                for uint::range(left, uint::min(left + frame_width, width)) |x| {
+                    let offset = (y * width + x) * 4;
+                    for uint::range(offset, offset + 4) |i| {
+                        (*canvas)[i] = 0;
+                    }
+                }
+            }
+        }
+        Keep | RestorePrevious => ()
+    }
+    Ok(move image)
+}
+
+// The canvas row each row of a frame's pixels belongs in, from the frame's top
+fn frame_rows(height: uint, interlaced: bool) -> ~[uint] {
+    if !interlaced {
+        return vec::from_fn(height, |y| y);
+    }
+    // Every 8th row from 0, every 8th from 4, every 4th from 2, then every 2nd from 1
+    let mut rows = ~[];
+    for [(0u, 8u), (4, 8), (2, 4), (1, 2)].each |&(start, step)| {
+        let mut y = start;
+        while y < height {
+            rows.push(y);
+            y += step;
+        }
+    }
+    rows
+}
+
+/**
+Decompresses LZW-coded color indices, stopping at the end code or once
+`pixel_count` have been produced. Frames cut short produce fewer; the
+pixels they are missing are left undrawn.
+*/
+fn lzw_decode(data: &[u8], min_code_size: uint, pixel_count: uint) -> Option<~[u8]> {
+    if min_code_size < 2 || min_code_size > 8 {
+        return None;
+    }
+    let table_size = 1 << MAX_CODE_SIZE;
+    let clear = 1 << min_code_size;
+    let end = clear + 1;
+
+    // Each code's string is the string of its prefix code followed by its suffix
+    let mut prefixes = vec::from_elem(table_size, 0u);
+    let mut suffixes = vec::from_elem(table_size, 0u8);
+    let mut firsts = vec::from_elem(table_size, 0u8);
+    let mut lengths = vec::from_elem(table_size, 0u);
+    for uint::range(0, clear) |code| {
+        suffixes[code] = code as u8;
+        firsts[code] = code as u8;
+        lengths[code] = 1;
+    }
+
+    let mut next = end + 1;
+    let mut code_size = min_code_size + 1;
+    let mut previous: Option<uint> = None;
+    let mut indices = ~[];
+    let mut bits = 0u;
+    let mut bit_count = 0u;
+    let mut pos = 0;
+    while indices.len() < pixel_count {
+        while bit_count < code_size && pos < data.len() {
+            bits |= (data[pos] as uint) << bit_count;
+            bit_count += 8;
+            pos += 1;
+        }
+        if bit_count < code_size {
+            break;
+        }
+        let code = bits & ((1 << code_size) - 1);
+        bits >>= code_size;
+        bit_count -= code_size;
+
+        if code == clear {
+            next = end + 1;
+            code_size = min_code_size + 1;
+            previous = None;
+            loop;
+        }
+        if code == end {
+            break;
+        }
+
+        match previous {
+            None if code < clear => (),
+            None => return None,
+            Some(previous) => {
+                // A code one past the table is the previous string and its own first index
+                let first = if code < next {
+                    firsts[code]
+                } else if code == next && next < table_size {
+                    firsts[previous]
+                } else {
+                    return None
+                };
+                if next < table_size {
+                    prefixes[next] = previous;
+                    suffixes[next] = first;
+                    firsts[next] = firsts[previous];
+                    lengths[next] = lengths[previous] + 1;
+                    next += 1;
+                    if next == 1 << code_size && code_size < MAX_CODE_SIZE {
+                        code_size += 1;
+                    }
+                }
+            }
+        }
+
+        // Write the string backwards from its last index
+        let start = indices.len();
+        for lengths[code].times {
+            indices.push(0);
+        }
+        let mut i = indices.len();
+        let mut string_code = code;
+        while i > start {
+            i -= 1;
+            indices[i] = suffixes[string_code];
+            string_code = prefixes[string_code];
+        }
+        previous = Some(code);
+    }
+
+    if indices.len() > pixel_count {
+        indices.truncate(pixel_count);
+    }
+    Some(move indices)
+}
+
+// Reads the RGB color table of the size `flags` declares, starting at `pos`
+fn read_color_table(buffer: &[u8], pos: &mut uint, flags: u8) -> Option<~[u8]> {
+    let len = 3 * (2 << (flags & 7));
+    if *pos + len > buffer.len() {
+        return None;
+    }
+    let colors = vec::slice(buffer, *pos, *pos + len);
+    *pos += len;
+    Some(move colors)
+}
+
+// Concatenates the data sub-blocks starting at `pos`, up to the empty one that ends them
+fn read_sub_blocks(buffer: &[u8], pos: &mut uint) -> Option<~[u8]> {
+    let mut data = ~[];
+    loop {
+        if *pos >= buffer.len() {
+            return None;
+        }
+        let len = buffer[*pos] as uint;
+        *pos += 1;
+        if len == 0 {
+            return Some(move data);
+        }
+        if *pos + len > buffer.len() {
+            return None;
+        }
+        data.push_all(vec::view(buffer, *pos, *pos + len));
+        *pos += len;
+    }
+}
+
+fn read_u16_le(buffer: &[u8], offset: uint) -> uint {
+    buffer[offset] as uint | (buffer[offset + 1] as uint << 8)
+}
+
+/**
+Encodes a GIF of `width` by `height` frames, each filled with a color, given
+as RGB, and shown for a delay in ms, which must be a multiple of 10. Tests
+know exactly which frames it decodes to.
+*/
+#[cfg(test)]
+pub fn test_gif_with_frames(width: uint, height: uint, frames: &[((u8, u8, u8), uint)])
+                         -> ~[u8] {
+    let mut gif = str::to_bytes("GIF89a");
+    push_u16_le(&mut gif, width);
+    push_u16_le(&mut gif, height);
+    // No global color table
+    gif.push_all([0, 0, 0]);
+
+    for frames.each |&((r, g, b), delay_ms)| {
+        assert delay_ms % 10 == 0;
+        // A graphic control extension for the delay, with no transparency
+        gif.push_all([0x21, 0xF9, 4, 0]);
+        push_u16_le(&mut gif, delay_ms / 10);
+        gif.push_all([0, 0]);
+
+        gif.push(0x2C);
+        push_u16_le(&mut gif, 0);
+        push_u16_le(&mut gif, 0);
+        push_u16_le(&mut gif, width);
+        push_u16_le(&mut gif, height);
+        // A local color table of two colors, the frame's and black
+        gif.push(0x80);
+        gif.push_all([r, g, b, 0, 0, 0]);
+
+        // Each index after a clear code, so that codes stay 3 bits long
+        let mut codes = ~[];
+        for (width * height).times {
+            codes.push_all([4u, 0]);
+        }
+        codes.push(5);
+        let mut packed = ~[];
+        let mut bits = 0u;
+        let mut bit_count = 0u;
+        for codes.each |code| {
+            bits |= *code << bit_count;
+            bit_count += 3;
+            while bit_count >= 8 {
+                packed.push(bits as u8);
+                bits >>= 8;
+                bit_count -= 8;
+            }
+        }
+        if bit_count > 0 {
+            packed.push(bits as u8);
+        }
+
+        // The minimum code size, then the codes in sub-blocks of at most 255 bytes
+        gif.push(2);
+        let mut start = 0;
+        while start < packed.len() {
+            let end = uint::min(start + 255, packed.len());
+            gif.push((end - start) as u8);
+            gif.push_all(vec::view(packed, start, end));
+            start = end;
+        }
+        gif.push(0);
+    }
+    gif.push(0x3B);
+    gif
+}
+
+#[cfg(test)]
+fn push_u16_le(bytes: &mut ~[u8], n: uint) {
+    bytes.push_all([n as u8, (n >> 8) as u8]);
+}
+
+#[test]
+fn should_decode_every_frame_of_an_animated_gif() {
+    let gif = test_gif_with_frames(3, 2, [((10, 20, 30), 20), ((40, 50, 60), 300)]);
+    let frames = decode_frames(gif).get();
+    assert frames.len() == 2;
+    assert frames[0].delay_ms == 20;
+    assert frames[1].delay_ms == 300;
+    for frames.each |frame| {
+        assert frame.image.width == 3 && frame.image.height == 2;
+    }
+    // Decoded pixels are BGRA
+    assert vec::slice(frames[0].image.data, 0, 4) == ~[30, 20, 10, 255];
+    assert vec::slice(frames[1].image.data, 20, 24) == ~[60, 50, 40, 255];
+}
+
+#[test]
+fn should_not_decode_frames_of_other_formats() {
+    match decode_frames(test_image_bin()) {
+        Err(Malformed) => (),
+        _ => fail!(~"expected Malformed")
+    }
+}
+
+#[test]
+fn should_tell_truncated_gifs_from_malformed_ones() {
+    let gif = test_gif_with_frames(3, 2, [((10, 20, 30), 20)]);
+    for [6, gif.len() - 4].each |&len| {
+        match decode_frames(vec::view(gif, 0, len)) {
+            Err(Incomplete) => (),
+            _ => fail!(fmt!("expected Incomplete for the first %u bytes", len))
+        }
+    }
+
+    // An unknown block where the first frame should be
+    let mut garbled = vec::slice(gif, 0, 13);
+    garbled.push_all([0x99u8, 0, 0, 0]);
+    match decode_frames(garbled) {
+        Err(Malformed) => (),
+        _ => fail!(~"expected Malformed")
+    }
+}
@@ -0,0 +1,132 @@
+/*!
+Reads the format and dimensions an image declares in its header, without
+decoding it, so that bytes that aren't an image and absurdly large images can
+be rejected before any pixels are allocated. Dimensions are read from PNG, GIF
+and JPEG headers.
+*/
+
+/// An image format, as told by the signature an image's bytes start with
+#[deriving_eq]
+pub enum ImageFormat {
+    PNGFormat,
+    JPEGFormat,
+    GIFFormat,
+    BMPFormat,
+    UnknownFormat
+}
+
+/// The format of an image, told by its signature alone
+pub pure fn sniff_image_format(data: &[u8]) -> ImageFormat {
+    let starts_with = |signature: &[u8]| {
+        data.len() >= signature.len() && vec::view(data, 0, signature.len()) == signature
+    };
+    if starts_with([0x89u8, 0x50, 0x4E, 0x47, 0x0D, 0x0A, 0x1A, 0x0A]) {
+        PNGFormat
+    } else if starts_with([0xFFu8, 0xD8, 0xFF]) {
+        JPEGFormat
+    } else if starts_with([0x47u8, 0x49, 0x46, 0x38, 0x37, 0x61]) ||
+              starts_with([0x47u8, 0x49, 0x46, 0x38, 0x39, 0x61]) {
+        // "GIF87a" or "GIF89a"
+        GIFFormat
+    } else if starts_with([0x42u8, 0x4D]) {
+        // "BM"
+        BMPFormat
+    } else {
+        UnknownFormat
+    }
+}
+
+/// Returns the (width, height) declared by an image's header, or None if the
+/// format isn't recognized or the header is truncated.
+pub fn dimensions(buffer: &[u8]) -> Option<(uint, uint)> {
+    if is_png(buffer) {
+        png_dimensions(buffer)
+    } else if is_gif(buffer) {
+        gif_dimensions(buffer)
+    } else if buffer.len() >= 2 && buffer[0] == 0xFF && buffer[1] == 0xD8 {
+        jpeg_dimensions(buffer)
+    } else {
+        None
+    }
+}
+
+fn is_png(buffer: &[u8]) -> bool {
+    let signature = [0x89u8, 0x50, 0x4E, 0x47, 0x0D, 0x0A, 0x1A, 0x0A];
+    buffer.len() >= 8 && vec::view(buffer, 0, 8) == signature
+}
+
+fn is_gif(buffer: &[u8]) -> bool {
+    buffer.len() >= 4 && vec::view(buffer, 0, 4) == [0x47u8, 0x49, 0x46, 0x38] // "GIF8"
+}
+
+// The IHDR chunk always comes first, right after the signature
+fn png_dimensions(buffer: &[u8]) -> Option<(uint, uint)> {
+    if buffer.len() < 24 || vec::view(buffer, 12, 16) != [0x49u8, 0x48, 0x44, 0x52] {
+        return None;
+    }
+    Some((read_u32_be(buffer, 16) as uint, read_u32_be(buffer, 20) as uint))
+}
+
+// The logical screen descriptor follows the 6 byte signature
+fn gif_dimensions(buffer: &[u8]) -> Option<(uint, uint)> {
+    if buffer.len() < 10 {
+        return None;
+    }
+    let width = buffer[6] as uint | (buffer[7] as uint << 8);
+    let height = buffer[8] as uint | (buffer[9] as uint << 8);
+    Some((width, height))
+}
+
+// Walks the segments up to the first start of frame marker
+fn jpeg_dimensions(buffer: &[u8]) -> Option<(uint, uint)> {
+    let mut i = 2;
+    while i + 4 <= buffer.len() {
+        if buffer[i] != 0xFF {
+            return None;
+        }
+        let marker = buffer[i + 1];
+        // Start of scan or end of image: no frame header
+        if marker == 0xDA || marker == 0xD9 {
+            return None;
+        }
+
+        let length = read_u16_be(buffer, i + 2) as uint;
+        if length < 2 {
+            return None;
+        }
+
+        // SOF0 to SOF15, except DHT, JPG and DAC which share the range
+        if marker >= 0xC0 && marker <= 0xCF &&
+           marker != 0xC4 && marker != 0xC8 && marker != 0xCC {
+            if i + 9 > buffer.len() {
+                return None;
+            }
+            let height = read_u16_be(buffer, i + 5) as uint;
+            let width = read_u16_be(buffer, i + 7) as uint;
+            return Some((width, height));
+        }
+
+        i += 2 + length;
+    }
+    None
+}
+
+fn read_u16_be(buffer: &[u8], offset: uint) -> u16 {
+    (buffer[offset] as u16 << 8) | buffer[offset + 1] as u16
+}
+
+fn read_u32_be(buffer: &[u8], offset: uint) -> u32 {
+    (read_u16_be(buffer, offset) as u32 << 16) | read_u16_be(buffer, offset + 2) as u32
+}
+
+#[test]
+fn should_sniff_image_formats() {
+    use image::base::{test_image_bin, test_image_with_color};
+
+    assert sniff_image_format(test_image_with_color(1, 1, (0, 0, 0, 255))) == PNGFormat;
+    assert sniff_image_format(test_image_bin()) == JPEGFormat;
+    assert sniff_image_format([0x47u8, 0x49, 0x46, 0x38, 0x39, 0x61, 1, 0, 1, 0]) == GIFFormat;
+    assert sniff_image_format(str::to_bytes("BM")) == BMPFormat;
+    assert sniff_image_format([0x12u8, 0x9A, 0x3C, 0x00, 0xFE, 0x47]) == UnknownFormat;
+    assert sniff_image_format([]) == UnknownFormat;
+}
@@ -1,5 +1,7 @@
 use image::base::Image;
-use resource::image_cache_task::{ImageCacheTask, ImageReady, ImageNotReady, ImageFailed};
+use resource::image_cache_task::{DecodePriority, ImageCacheTask, ImageReady, ImageReadyAnimated};
+use resource::image_cache_task::{ImageNotReady, first_frame};
+use resource::image_cache_task::{ImageFailed};
 use resource::image_cache_task;
 use resource::local_image_cache::LocalImageCache;
 
@@ -11,9 +13,9 @@ use std::arc::{ARC, clone, get};
 // FIXME: Nasty coupling to resource here. This should probably be factored out into an interface
 // and use dependency injection.
 
-/** A struct to store image data. The image will be loaded once, the
-    first time it is requested, and an arc will be stored.  Clones of
-    this arc are given out on demand.
+/** A struct to store image data. The image is not loaded until layout
+    requests it, since it may never come near the viewport. Once loaded an
+    arc is stored, and clones of it are given out on demand.
  */
 pub struct ImageHolder {
     url: Url,
@@ -25,24 +27,26 @@ pub struct ImageHolder {
 impl ImageHolder {
 	static pub fn new(url: Url, local_image_cache: @LocalImageCache) -> ImageHolder {
 		debug!("ImageHolder::new() %?", url.to_str());
-		let holder = ImageHolder {
+		ImageHolder {
 			url: move url,
 			image: None,
 			cached_size: Size2D(0,0),
 			local_image_cache: local_image_cache,
-		};
-
-		// Tell the image cache we're going to be interested in this url
-		// FIXME: These two messages must be sent to prep an image for use
-		// but they are intended to be spread out in time. Ideally prefetch
-		// should be done as early as possible and decode only once we
-		// are sure that the image will be used.
-		local_image_cache.prefetch(&holder.url);
-		local_image_cache.decode(&holder.url);
-
-		move holder
+		}
 	}
 
+    /**
+    Starts loading the image, or raises the priority of a load already in
+    progress. Layout calls this once the image is in or near the viewport,
+    and reflows when the image becomes available.
+    */
+    fn request(priority: DecodePriority) {
+        self.local_image_cache.prefetch(&self.url);
+        self.local_image_cache.decode_with_priority(&self.url, priority);
+        // Arranges for a reflow once the image is ready
+        self.get_image();
+    }
+
     /**
     This version doesn't perform any computation, but may be stale w.r.t.
     newly-available image data that determines size.
@@ -71,6 +75,11 @@ impl ImageHolder {
     fn get_image() -> Option<ARC<~Image>> {
         debug!("get_image() %?", self.url);
 
+        // Nothing to get until layout has requested the image
+        if !self.local_image_cache.is_prefetched(&self.url) {
+            return None;
+        }
+
         // If this is the first time we've called this function, load
         // the image and store it for the future
         if self.image.is_none() {
@@ -78,11 +87,15 @@ impl ImageHolder {
                 ImageReady(move image) => {
                     self.image = Some(move image);
                 }
+                // Animations aren't drawn yet, so show the first frame
+                ImageReadyAnimated(frames) => {
+                    self.image = Some(first_frame(&frames));
+                }
                 ImageNotReady => {
                     debug!("image not ready for %s", self.url.to_str());
                 }
-                ImageFailed => {
-                    debug!("image decoding failed for %s", self.url.to_str());
+                ImageFailed(reason) => {
+                    debug!("image loading failed for %s: %?", self.url.to_str(), reason);
                 }
             }
         }
@@ -0,0 +1,148 @@
+/*!
+Scaling of decoded images, e.g. for `<img>` elements drawn at a size other
+than their natural one
+*/
+
+use image::base::Image;
+
+use geom::size::Size2D;
+
+pub enum ResizeFilter {
+    /// Each pixel takes the value of the nearest source pixel. Fast and blocky.
+    Nearest,
+    /// Each pixel interpolates between the four nearest source pixels
+    Bilinear,
+    /// Each pixel averages the source pixels it covers. Best for downscaling.
+    Box
+}
+
+pub trait ImageResizing {
+    fn resize(&self, new_size: Size2D<uint>, filter: ResizeFilter) -> Image;
+}
+
+impl Image : ImageResizing {
+    /**
+    Returns a copy of the image scaled to `new_size`. The result has the same
+    number of channels as the image, each of which is filtered separately.
+    */
+    fn resize(&self, new_size: Size2D<uint>, filter: ResizeFilter) -> Image {
+        let depth = self.depth;
+        if self.width == 0 || self.height == 0 {
+            return Image(new_size.width, new_size.height, depth,
+                         vec::from_elem(new_size.width * new_size.height * depth, 0u8));
+        }
+
+        let mut data = vec::with_capacity(new_size.width * new_size.height * depth);
+        for uint::range(0, new_size.height) |y| {
+            for uint::range(0, new_size.width) |x| {
+                for uint::range(0, depth) |channel| {
+                    let value = match filter {
+                        Nearest => nearest(self, x, y, channel, new_size),
+                        Bilinear => bilinear(self, x, y, channel, new_size),
+                        Box => box_average(self, x, y, channel, new_size)
+                    };
+                    data.push(value);
+                }
+            }
+        }
+        Image(new_size.width, new_size.height, depth, move data)
+    }
+}
+
+pure fn channel_at(image: &Image, x: uint, y: uint, channel: uint) -> u8 {
+    image.data[(y * image.width + x) * image.depth + channel]
+}
+
+// The source pixel whose center is nearest that of the destination pixel
+pure fn nearest(image: &Image, x: uint, y: uint, channel: uint, new_size: Size2D<uint>) -> u8 {
+    let src_x = (2 * x + 1) * image.width / (2 * new_size.width);
+    let src_y = (2 * y + 1) * image.height / (2 * new_size.height);
+    channel_at(image, src_x, src_y, channel)
+}
+
+pure fn bilinear(image: &Image, x: uint, y: uint, channel: uint, new_size: Size2D<uint>) -> u8 {
+    // The position of the destination pixel's center in source pixels
+    let src_x = source_position(x, image.width, new_size.width);
+    let src_y = source_position(y, image.height, new_size.height);
+    let x0 = src_x as uint;
+    let y0 = src_y as uint;
+    let x1 = uint::min(x0 + 1, image.width - 1);
+    let y1 = uint::min(y0 + 1, image.height - 1);
+    let fx = src_x - (x0 as float);
+    let fy = src_y - (y0 as float);
+
+    let top = lerp(channel_at(image, x0, y0, channel), channel_at(image, x1, y0, channel), fx);
+    let bottom = lerp(channel_at(image, x0, y1, channel), channel_at(image, x1, y1, channel), fx);
+    float::round(top + (bottom - top) * fy) as u8
+}
+
+pure fn box_average(image: &Image, x: uint, y: uint, channel: uint,
+                    new_size: Size2D<uint>) -> u8 {
+    // The source pixels covered by the destination pixel, at least one
+    let x0 = x * image.width / new_size.width;
+    let x1 = uint::max(x0 + 1, ((x + 1) * image.width + new_size.width - 1) / new_size.width);
+    let y0 = y * image.height / new_size.height;
+    let y1 = uint::max(y0 + 1,
+                       ((y + 1) * image.height + new_size.height - 1) / new_size.height);
+
+    let mut sum = 0u;
+    for uint::range(y0, y1) |src_y| {
+        for uint::range(x0, x1) |src_x| {
+            sum += channel_at(image, src_x, src_y, channel) as uint;
+        }
+    }
+    let count = (x1 - x0) * (y1 - y0);
+    ((sum + count / 2) / count) as u8
+}
+
+// Maps a destination pixel's center to source coordinates, clamped to the image
+pure fn source_position(dest: uint, src_len: uint, dest_len: uint) -> float {
+    let position = ((dest as float) + 0.5) * (src_len as float) / (dest_len as float) - 0.5;
+    float::fmax(0.0, float::fmin(position, (src_len - 1) as float))
+}
+
+pure fn lerp(a: u8, b: u8, t: float) -> float {
+    (a as float) + ((b as float) - (a as float)) * t
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use image::base::Image;
+
+    use geom::size::Size2D;
+
+    // A single channel image with a black left column and a white right one
+    fn two_columns(height: uint) -> Image {
+        Image(2, height, 1, vec::from_fn(2 * height, |i| if i % 2 == 0 { 0 } else { 255 }))
+    }
+
+    #[test]
+    fn should_upscale_with_interpolation() {
+        let image = two_columns(2);
+        let nearest = image.resize(Size2D(4u, 4u), Nearest);
+        let bilinear = image.resize(Size2D(4u, 4u), Bilinear);
+
+        assert nearest.width == 4 && nearest.height == 4 && nearest.depth == 1;
+        assert bilinear.width == 4 && bilinear.height == 4 && bilinear.depth == 1;
+        assert nearest.data == ~[0, 0, 255, 255, 0, 0, 255, 255,
+                                 0, 0, 255, 255, 0, 0, 255, 255];
+
+        // Between the columns, bilinear blends where nearest picks a side
+        assert nearest.data[1] == 0;
+        assert bilinear.data[1] == 64;
+        assert bilinear.data[0] == 0 && bilinear.data[3] == 255;
+    }
+
+    #[test]
+    fn should_downscale_by_averaging() {
+        let image = Image(4, 2, 2, vec::from_fn(16, |i| (i / 2 * 10) as u8));
+        let boxed = image.resize(Size2D(2u, 1u), Box);
+        assert boxed.width == 2 && boxed.height == 1 && boxed.depth == 2;
+        // Each channel of each destination pixel averages a 2x2 block
+        assert boxed.data == ~[25, 25, 45, 45];
+
+        let nearest = image.resize(Size2D(2u, 1u), Nearest);
+        assert nearest.width == 2 && nearest.height == 1;
+    }
+}
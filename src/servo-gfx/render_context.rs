@@ -1,7 +1,9 @@
 use compositor::LayerBuffer;
+use display_list::DisplayList;
 use font_context::FontContext;
 use geometry::Au;
 use image::base::Image;
+use image::composite::composite;
 use opts::Opts;
 use text::TextRun;
 
@@ -11,6 +13,7 @@ use azure::{AzDrawOptions, AzFloat};
 use core::dvec::DVec;
 use core::libc::types::common::c99::uint16_t;
 use core::ptr::to_unsafe_ptr;
+use geom::matrix2d::Matrix2D;
 use geom::point::Point2D;
 use geom::rect::Rect;
 use geom::size::Size2D;
@@ -48,6 +51,7 @@ impl RenderContext  {
         self.canvas.draw_target.stroke_rect(&rect, &pattern, &stroke_opts, &draw_opts);
     }
 
+    /// Draws an image, whose pixels must have premultiplied alpha
     pub fn draw_image(&self, bounds: Rect<Au>, image: ARC<~Image>) {
         let image = arc::get(&image);
         let size = Size2D(image.width as i32, image.height as i32);
@@ -65,6 +69,57 @@ impl RenderContext  {
                                      draw_surface_options, draw_options);
     }
 
+    /**
+    Draws a display list as a group: the list is rendered into an offscreen
+    buffer covering `bounds`, which is then blended with what has been drawn
+    so far at `opacity`. Items in the group are opaque to each other.
+    */
+    pub fn draw_opacity_group(&self, bounds: &Rect<Au>, opacity: float, list: &DisplayList) {
+        let x = bounds.origin.x.to_px();
+        let y = bounds.origin.y.to_px();
+        let width = bounds.size.width.to_px();
+        let height = bounds.size.height.to_px();
+        if width <= 0 || height <= 0 {
+            return;
+        }
+
+        // Render the group into a transparent buffer at its own origin
+        let group_buffer = LayerBuffer {
+            draw_target: DrawTarget::new_with_data(self.opts.render_backend,
+                                                   vec::from_elem((width * height * 4) as uint,
+                                                                  0u8),
+                                                   0,
+                                                   Size2D(width as i32, height as i32),
+                                                   (width * 4) as i32,
+                                                   B8G8R8A8),
+            rect: Rect(Point2D(x as uint, y as uint), Size2D(width as uint, height as uint)),
+            stride: width as uint
+        };
+        let matrix: Matrix2D<AzFloat> = Matrix2D::identity();
+        let matrix = matrix.translate(&-(x as AzFloat), &-(y as AzFloat));
+        group_buffer.draw_target.set_transform(&matrix);
+
+        let group_ctx = RenderContext {
+            canvas: &group_buffer,
+            font_ctx: self.font_ctx,
+            opts: self.opts
+        };
+        list.draw_into_context(&group_ctx);
+        let group = read_pixels(&group_buffer.draw_target, width as uint, height as uint);
+
+        // Blend the group into the canvas as drawn so far, and put that back
+        let canvas_origin = self.canvas.rect.origin;
+        let canvas_height = self.canvas.rect.size.height;
+        let mut canvas = read_pixels(&self.canvas.draw_target, self.canvas.stride, canvas_height);
+        composite(&mut canvas, &group, x - canvas_origin.x as int, y - canvas_origin.y as int,
+                  opacity);
+        let canvas_bounds = Rect(Point2D(Au::from_px(canvas_origin.x as int),
+                                         Au::from_px(canvas_origin.y as int)),
+                                 Size2D(Au::from_px(self.canvas.stride as int),
+                                        Au::from_px(canvas_height as int)));
+        self.draw_image(canvas_bounds, ARC(~canvas));
+    }
+
     fn clear(&self) {
         let pattern = ColorPattern(Color(1f as AzFloat, 1f as AzFloat, 1f as AzFloat, 1f as AzFloat));
         let rect = Rect(Point2D(self.canvas.rect.origin.x as AzFloat,
@@ -75,6 +130,15 @@ impl RenderContext  {
     }
 }
 
+// Copies the pixels a draw target has rendered so far
+fn read_pixels(draw_target: &DrawTarget, width: uint, height: uint) -> Image {
+    let mut data = ~[];
+    do draw_target.snapshot().get_data_surface().with_data |pixels| {
+        data = vec::from_slice(pixels);
+    }
+    Image(width, height, 4, move data)
+}
+
 trait to_float {
     fn to_float() -> float;
 }
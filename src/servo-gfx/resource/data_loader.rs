@@ -0,0 +1,166 @@
+/*!
+
+Loads `data:` URLs (RFC 2397), whose payload is embedded in the URL itself
+
+*/
+
+use pipes::Chan;
+use resource::resource_task::{ProgressMsg, Meta, Payload, Done, LoaderTask, LoadFailed};
+use std::net::url::{Url, to_str};
+
+pub fn factory() -> LoaderTask {
+	let f: LoaderTask = |url, _headers, progress_chan| {
+		assert url.scheme == ~"data";
+		match parse_data_url(to_str(&url)) {
+			Some((move mime_type, move data)) => {
+				progress_chan.send(Meta(move mime_type));
+				progress_chan.send(Payload(move data));
+				progress_chan.send(Done(Ok(())));
+			}
+			None => {
+				debug!("data_loader: malformed data url %s", to_str(&url));
+				progress_chan.send(Done(Err(LoadFailed)));
+			}
+		}
+	};
+	f
+}
+
+/**
+Splits a `data:` URL into its MIME type and decoded payload. The MIME type
+defaults to `text/plain;charset=US-ASCII` when omitted. Returns None if the
+URL is malformed.
+*/
+pub fn parse_data_url(url: &str) -> Option<(~str, ~[u8])> {
+    if !str::starts_with(url, "data:") {
+        return None;
+    }
+    let url = str::slice(url, 5, url.len());
+
+    let comma = match str::find_char(url, ',') {
+        Some(i) => i,
+        None => return None
+    };
+    let header = str::slice(url, 0, comma);
+    let payload = str::slice(url, comma + 1, url.len());
+
+    let (mime_type, is_base64) = if str::ends_with(header, ";base64") {
+        (str::slice(header, 0, header.len() - 7), true)
+    } else {
+        (header, false)
+    };
+    let mime_type = if mime_type.is_empty() {
+        ~"text/plain;charset=US-ASCII"
+    } else {
+        mime_type
+    };
+
+    let data = match percent_decode(payload) {
+        Some(move data) => if is_base64 { base64_decode(data) } else { Some(move data) },
+        None => None
+    };
+
+    match move data {
+        Some(move data) => Some((move mime_type, move data)),
+        None => None
+    }
+}
+
+fn percent_decode(s: &str) -> Option<~[u8]> {
+    let bytes = str::to_bytes(s);
+    let mut data = ~[];
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == '%' as u8 {
+            if i + 2 >= bytes.len() {
+                return None;
+            }
+            match (hex_value(bytes[i + 1]), hex_value(bytes[i + 2])) {
+                (Some(hi), Some(lo)) => data.push(hi << 4 | lo),
+                _ => return None
+            }
+            i += 3;
+        } else {
+            data.push(bytes[i]);
+            i += 1;
+        }
+    }
+    Some(move data)
+}
+
+pure fn hex_value(b: u8) -> Option<u8> {
+    match b as char {
+        '0' .. '9' => Some(b - ('0' as u8)),
+        'a' .. 'f' => Some(b - ('a' as u8) + 10),
+        'A' .. 'F' => Some(b - ('A' as u8) + 10),
+        _ => None
+    }
+}
+
+pure fn base64_value(b: u8) -> Option<u8> {
+    match b as char {
+        'A' .. 'Z' => Some(b - ('A' as u8)),
+        'a' .. 'z' => Some(b - ('a' as u8) + 26),
+        '0' .. '9' => Some(b - ('0' as u8) + 52),
+        '+' => Some(62),
+        '/' => Some(63),
+        _ => None
+    }
+}
+
+// Unlike std::base64 this reports malformed input instead of failing.
+fn base64_decode(encoded: &[u8]) -> Option<~[u8]> {
+    let mut data = ~[];
+    let mut acc = 0u32;
+    let mut bits = 0u;
+    let mut padding = 0u;
+
+    for encoded.each |b| {
+        let b = *b;
+        if b == '=' as u8 {
+            padding += 1;
+            loop;
+        }
+        if padding > 0 {
+            // Nothing may follow the padding
+            return None;
+        }
+        match base64_value(b) {
+            Some(v) => {
+                acc = acc << 6 | (v as u32);
+                bits += 6;
+                if bits >= 8 {
+                    bits -= 8;
+                    data.push((acc >> bits) as u8);
+                }
+            }
+            None => return None
+        }
+    }
+
+    if padding > 2 || bits >= 6 {
+        return None;
+    }
+    Some(move data)
+}
+
+#[test]
+fn should_parse_base64_data_url() {
+    let (mime_type, data) = parse_data_url("data:text/plain;base64,SGVsbG8=").get();
+    assert mime_type == ~"text/plain";
+    assert data == str::to_bytes("Hello");
+}
+
+#[test]
+fn should_parse_percent_encoded_data_url() {
+    let (mime_type, data) = parse_data_url("data:,a%20b%2C").get();
+    assert mime_type == ~"text/plain;charset=US-ASCII";
+    assert data == str::to_bytes("a b,");
+}
+
+#[test]
+fn should_reject_malformed_data_urls() {
+    assert parse_data_url("data:text/plain;base64").is_none();
+    assert parse_data_url("data:text/plain;base64,SGV*").is_none();
+    assert parse_data_url("data:,%zz").is_none();
+}
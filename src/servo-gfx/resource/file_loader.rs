@@ -1,13 +1,13 @@
 use pipes::Chan;
 use task::spawn;
-use resource::resource_task::{ProgressMsg, Payload, Done, LoaderTask};
+use resource::resource_task::{ProgressMsg, Payload, Done, LoaderTask, LoadFailed};
 use std::net::url::Url;
 use io::{file_reader, ReaderUtil};
 
 const READ_SIZE: uint = 1024;
 
 pub fn factory() -> LoaderTask {
-	let f: LoaderTask = |url, progress_chan| {
+	let f: LoaderTask = |url, _headers, progress_chan| {
 		assert url.scheme == ~"file";
 		do spawn {
 			// FIXME: Resolve bug prevents us from moving the path out of the URL.
@@ -20,7 +20,7 @@ pub fn factory() -> LoaderTask {
 					progress_chan.send(Done(Ok(())));
 				}
 				Err(*) => {
-					progress_chan.send(Done(Err(())));
+					progress_chan.send(Done(Err(LoadFailed)));
 				}
 			};
 		}
@@ -1,18 +1,22 @@
 use pipes::{Chan, SharedChan};
 use task::spawn;
-use resource::resource_task::{ProgressMsg, Payload, Done, LoaderTask};
+use resource::resource_task::{ProgressMsg, Payload, Done, LoaderTask, LoadFailed};
 use std::cell::Cell;
 use std::net::url::Url;
 use http_client;
 use http_client::{uv_http_request};
 
 pub fn factory() -> LoaderTask {
-	let f: LoaderTask = |url, progress_chan| {
+	let f: LoaderTask = |url, headers, progress_chan| {
 		assert url.scheme == ~"http";
 
 		let progress_chan = SharedChan(progress_chan);
-		do spawn {
+		do spawn |move headers| {
 			debug!("http_loader: requesting via http: %?", copy url);
+			// TODO: send the request headers once http_client accepts them
+			for headers.each |&(ref name, ref value)| {
+				debug!("http_loader: request header %s: %s", *name, *value);
+			}
 			let request = uv_http_request(copy url);
 			let errored = @mut false;
 			let url = copy url;
@@ -31,7 +35,7 @@ pub fn factory() -> LoaderTask {
 						http_client::Error(*) => {
 							debug!("http_loader: error loading %?", url);
 							*errored = true;
-							progress_chan.send(Done(Err(())));
+							progress_chan.send(Done(Err(LoadFailed)));
 						}
 					}
 				}
@@ -1,40 +1,225 @@
-use image::base::{Image, load_from_memory, test_image_bin};
+use image::base::{AlphaMode, Image, ImageFrame, ImageMethods, Premultiplied, Straight};
+use image::base::{Corrupt, DecodeError, Incomplete, Malformed, TooLarge, Unsupported};
+use image::base::convert_alpha;
+use image::base::{load_from_memory, try_load_from_memory};
+#[cfg(test)]
+use image::base::{test_image_bin, test_image_with_color};
+use image::gif;
+#[cfg(test)]
+use image::gif::test_gif_with_frames;
+use image::header;
+use image::header::{BMPFormat, GIFFormat, JPEGFormat, PNGFormat, UnknownFormat};
+use image::resize::{Box, ImageResizing};
+use resource::data_loader::parse_data_url;
 use resource::resource_task;
-use resource::resource_task::ResourceTask;
-use util::url::{make_url, UrlMap, url_map};
+use resource::resource_task::{LoadFailed, NetworkError, ResourceTask, Timeouts, no_timeouts};
+#[cfg(test)]
+use test_support::mock_resource_task;
+use util::url::{make_url, UrlMap, UrlMapMethods, url_map};
 
 use clone_arc = std::arc::clone;
+use core::io::WriterUtil;
 use core::pipes::{Chan, Port, SharedChan, stream};
 use core::task::spawn;
 use resource::util::spawn_listener;
 use core::to_str::ToStr;
 use core::util::replace;
+use geom::size::Size2D;
 use std::arc::ARC;
-use std::net::url::Url;
+use std::arc;
+use std::oldmap::HashMap;
+use std::sha1;
+use std::net::url::{Url, to_str};
 use std::cell::Cell;
+use std::timer;
+use std::uv_global_loop;
 
 pub enum Msg {
     /// Tell the cache that we may need a particular image soon. Must be posted
-    /// before Decode
+    /// before Decode. Equivalent to PrefetchWithPriority with DEFAULT_FETCH_PRIORITY.
     pub Prefetch(Url),
 
-    // FIXME: We can probably get rid of this Cell now
-    /// Used be the prefetch tasks to post back image binaries
-    priv StorePrefetchedImageData(Url, Result<Cell<~[u8]>, ()>),
+    /// Like Prefetch, but fetches waiting for others to finish start in order
+    /// of priority, highest first, and in the order they were requested among
+    /// equal priorities. Prefetching an image that is waiting again raises its
+    /// priority instead of fetching it twice.
+    pub PrefetchWithPriority(Url, u8),
+
+    /// Limit the number of images fetched at once. Fetches beyond the limit
+    /// wait for an earlier one to finish.
+    pub SetMaxFetches(uint),
+
+    /// Used be the prefetch tasks to post back image binaries, with what the
+    /// response said about them
+    priv StorePrefetchedImageData(Url, Result<(ARC<~[u8]>, ResponseInfo), NetworkError>),
+
+    /// Used by the prefetch tasks to post back an image they found in the disk
+    /// cache, which isn't fetched
+    priv StoreCachedImage(Url, ARC<~Image>),
+
+    /// Ask the server whether a decoded image has changed, with the ETag or
+    /// Last-Modified date it was served with. The image is kept if it hasn't,
+    /// and fetched and decoded again if it has.
+    pub Revalidate(Url),
+
+    /// Used by the revalidation tasks to post back the bytes of an image
+    /// that changed, or None if it hasn't
+    priv StoreRevalidation(Url, Result<Option<(ARC<~[u8]>, ResponseInfo)>, NetworkError>),
+
+    /// Posted once a prefetch or revalidation task has ended, however it
+    /// ended, so that another can start
+    priv FetchEnded,
+
+    /// Used by the prefetch tasks to post back the dimensions an image's
+    /// header declares, as soon as enough of it has arrived
+    priv ImageSizeKnown(Url, Size2D<uint>),
+
+    /// Register the candidates of an `<img srcset>` under the URL the image is
+    /// known by, and prefetch the candidate for a display of the given density
+    pub PrefetchSrcset(Url, ~[ImageCandidate], float),
+
+    /// Request the candidates registered under a URL with PrefetchSrcset
+    pub GetSrcset(Url, Chan<~[ImageCandidate]>),
 
     /// Tell the cache to decode an image. Must be posted before GetImage/WaitForImage
     pub Decode(Url),
 
-    /// Used by the decoder tasks to post decoded images back to the cache
-    priv StoreImage(Url, Option<ARC<~Image>>),
+    /// Like Decode, but with an explicit priority. Requesting a decode that is
+    /// already pending or running raises its priority instead of starting
+    /// another one. Decode is equivalent to HighPriority.
+    pub DecodeWithPriority(Url, DecodePriority),
+
+    /// Choose the alpha mode of images decoded afterwards. Straight by default.
+    pub SetAlphaMode(AlphaMode),
+
+    /// Request the priority of a pending or running decode
+    pub GetDecodePriority(Url, Chan<Option<DecodePriority>>),
+
+    /// Tell the cache to decode every image that has been prefetched but not
+    /// yet decoded, e.g. before printing or taking a screenshot
+    pub DecodeAll,
+
+    /// Used by the decoder tasks to post decoded images back to the cache,
+    /// or why they couldn't be decoded
+    priv StoreImage(Url, Result<~[ImageFrame], ImageFailure>),
 
     /// Request an Image object for a URL. If the image is not is not immediately
     /// available then ImageNotReady is returned.
     pub GetImage(Url, Chan<ImageResponseMsg>),
 
+    /// Like GetImage, but answers ImageNotReady for any image that isn't
+    /// decoded, including ones never prefetched or never asked to be decoded
+    pub GetImageIfDecoded(Url, Chan<ImageResponseMsg>),
+
     /// Wait for an image to become available (or fail to load).
     pub WaitForImage(Url, Chan<ImageResponseMsg>),
 
+    /// Like WaitForImage, but give up after a timeout in ms. A client that
+    /// gives up is sent ImageFailed(Some(TimedOut)) and nothing after.
+    pub WaitForImageWithTimeout(Url, uint, Chan<ImageResponseMsg>),
+
+    /// Used by the timer tasks of WaitForImageWithTimeout to post back
+    /// that the waiter with an id has waited long enough
+    priv WaitTimedOut(Url, uint),
+
+    /// Like SubscribeReady, but if the image is first fetched for this
+    /// request, be sent ImageReady with what can be decoded of its bytes as
+    /// more of them arrive. The last response is the one SubscribeReady
+    /// would send, after which the channel is closed.
+    ///
+    /// The bytes are decoded from the start each time, so they are decoded
+    /// again only once they have grown by half. Only images the decoder can
+    /// make something of before all their bytes are in, such as progressive
+    /// JPEGs, are sent early; others are sent once they have arrived whole.
+    pub GetImageProgressive(Url, Chan<ImageResponseMsg>),
+
+    /// Used by the prefetch tasks of GetImageProgressive to post back the
+    /// image decoded from the bytes that have arrived so far
+    priv StorePartialImage(Url, ARC<~Image>),
+
+    /// Like SubscribeReady, but be sent the image scaled down to fit within a
+    /// width and height, keeping its aspect ratio, e.g. for thumbnails. The
+    /// first frame of animated images is sent. Images that fit already are
+    /// sent as they are. The scaled image is kept for requests of the same size.
+    pub GetScaledImage(Url, uint, uint, Chan<ImageResponseMsg>),
+
+    /// Used by the decoder and scaling tasks to post back an image scaled to
+    /// fit within a width and height
+    priv StoreScaledImage(Url, (uint, uint), ARC<~Image>),
+
+    /// Request the width and height an image's header declares, without
+    /// decoding it. Waits for the header to arrive if need be; None if the
+    /// image hasn't been prefetched, fails to load or has no header that can
+    /// be read.
+    pub GetImageSize(Url, Chan<Option<(uint, uint)>>),
+
+    /// Give up on fetching an image if the resource task stalls for longer
+    /// than the timeouts allow. Applies to fetches started afterwards.
+    pub SetLoadTimeouts(Timeouts),
+
+    /// Be told exactly once when an image becomes available or fails to load.
+    /// Unlike WaitForImage this may be sent before Prefetch and Decode, and
+    /// starts them as needed.
+    pub SubscribeReady(Url, Chan<ImageResponseMsg>),
+
+    /// Be sent the current frame of an animated image each time it changes
+    pub SubscribeAnimation(Url, Chan<ImageResponseMsg>),
+
+    /// Be told about changes to an image, as the mode chooses, starting its
+    /// prefetch and decode as needed. A subscription ends once its port is
+    /// dropped.
+    pub Subscribe(Url, Chan<ImageUpdate>, SubscribeMode),
+
+    /// Move every decoded animated image whose current frame has been shown
+    /// for its delay on to its next frame, as of `now` in milliseconds. The
+    /// clock is the caller's; the first message starts it for each image.
+    pub AdvanceAnimations(u64),
+
+    /// Request every URL known to the cache along with a summary of its state
+    pub ListUrls(Chan<~[(Url, ImageStateTag)]>),
+
+    /// Request the number of images in each state and the memory they take
+    pub GetStats(Chan<CacheStats>),
+
+    /// Limit the total size in bytes of decoded images held by the cache. When the
+    /// limit is exceeded the least recently used unpinned images are evicted.
+    pub SetMemoryBudget(Option<uint>),
+
+    /// Keep the decoded image for a URL in the cache regardless of the memory
+    /// budget, prefetching and decoding it if necessary
+    pub Pin(Url),
+
+    /// Allow a pinned image to be evicted again
+    pub Unpin(Url),
+
+    /// Make bytes available under a `blob:` URL. Images loaded from the URL
+    /// are served from memory instead of through the resource task.
+    pub RegisterBlob(Url, ~[u8]),
+
+    /// Forget the bytes registered under a `blob:` URL, along with any image
+    /// cached for it. Subsequent loads of the URL fail.
+    pub RevokeBlob(Url),
+
+    /// Be told once the cache has processed every message sent before this
+    /// one. Work handed off to prefetch and decode tasks may still be
+    /// outstanding, and messages they post may not have been processed.
+    pub Sync(Chan<()>),
+
+    /// Forget every image, as if the cache had just started, and be told once
+    /// done. The results of fetches and decodes in flight are dropped, and
+    /// clients waiting on images are told they failed.
+    pub Clear(Chan<()>),
+
+    /// Stop loading an image, e.g. because the page that wanted it is gone.
+    /// The result of its fetch or decode is dropped, and clients waiting on
+    /// it are told it failed. Images already loaded are left alone.
+    pub Cancel(Url),
+
+    /// Forget an image in whatever state it is in, so that the next Prefetch
+    /// fetches it again, e.g. when the page is reloaded. Work in flight is
+    /// cancelled as by Cancel.
+    pub Purge(Url),
+
     /// For testing
     priv OnMsg(fn~(msg: &Msg)),
 
@@ -44,31 +229,56 @@ pub enum Msg {
 
 pub enum ImageResponseMsg {
     ImageReady(ARC<~Image>),
+    /// Every frame of an animated image, for clients that show the frames themselves
+    ImageReadyAnimated(ARC<~[ImageFrame]>),
     ImageNotReady,
-    ImageFailed
+    ImageFailed(Option<ImageFailure>)
+}
+
+/// Why an image failed, e.g. to decide between retrying and showing it as broken
+#[deriving_eq]
+pub enum ImageFailure {
+    /// The image's bytes couldn't be fetched
+    NetworkFailure(NetworkError),
+    /// The bytes were fetched but aren't an image that can be decoded
+    DecodeFailure,
+    /// The bytes aren't in any format the cache can decode
+    UnsupportedFormat,
+    /// The bytes end before the image does, e.g. because the connection dropped
+    Truncated,
+    /// The client stopped waiting for the image, which may still load
+    TimedOut
 }
 
 impl ImageResponseMsg {
     pure fn clone() -> ImageResponseMsg {
         match &self {
           &ImageReady(ref img) => ImageReady(unsafe { clone_arc(img) }),
+          &ImageReadyAnimated(ref frames) => ImageReadyAnimated(unsafe { clone_arc(frames) }),
           &ImageNotReady => ImageNotReady,
-          &ImageFailed => ImageFailed
+          &ImageFailed(reason) => ImageFailed(reason)
         }
     }
 }
 
 impl ImageResponseMsg: cmp::Eq {
+    /// Ready images are equal if their pixels are, even if decoded separately
     pure fn eq(&self, other: &ImageResponseMsg) -> bool {
-        // FIXME: Bad copies
-        match (self.clone(), other.clone()) {
-          (ImageReady(*), ImageReady(*)) => fail!(~"unimplemented comparison"),
-          (ImageNotReady, ImageNotReady) => true,
-          (ImageFailed, ImageFailed) => true,
+        match (self, other) {
+          (&ImageReady(ref a), &ImageReady(ref b)) => arc::get(a).pixels_equal(&**arc::get(b)),
+          (&ImageReadyAnimated(ref a), &ImageReadyAnimated(ref b)) => {
+              let (a, b) = (arc::get(a), arc::get(b));
+              a.len() == b.len() && do vec::all2(*a, *b) |a, b| {
+                  a.delay_ms == b.delay_ms && a.image.pixels_equal(&b.image)
+              }
+          }
+          (&ImageNotReady, &ImageNotReady) => true,
+          (&ImageFailed(a), &ImageFailed(b)) => a == b,
 
-          (ImageReady(*), _)
-          | (ImageNotReady, _)
-          | (ImageFailed, _) => false
+          (&ImageReady(*), _)
+          | (&ImageReadyAnimated(*), _)
+          | (&ImageNotReady, _)
+          | (&ImageFailed(*), _) => false
         }
     }
     pure fn ne(&self, other: &ImageResponseMsg) -> bool {
@@ -76,17 +286,314 @@ impl ImageResponseMsg: cmp::Eq {
     }
 }
 
+/// Which changes to an image a subscriber is told about
+#[deriving_eq]
+pub enum SubscribeMode {
+    /// Only the first time the image becomes available or fails to load
+    Once,
+    /// Every change of state, and every frame an animated image moves on to
+    Continuous
+}
+
+/// A change to an image, as sent to subscribers
+pub enum ImageUpdate {
+    /// The image moved on to a state in which it isn't available yet
+    UpdatePending(ImageStateTag),
+    /// The image became available, or an animated image moved on to this frame
+    UpdateReady(ARC<~Image>),
+    UpdateFailed(ImageFailure),
+    /// The image's header declared its dimensions, though it may still be loading
+    UpdateSize(Size2D<uint>)
+}
+
+impl ImageUpdate {
+    pure fn clone() -> ImageUpdate {
+        match &self {
+          &UpdatePending(tag) => UpdatePending(tag),
+          &UpdateReady(ref img) => UpdateReady(unsafe { clone_arc(img) }),
+          &UpdateFailed(reason) => UpdateFailed(reason),
+          &UpdateSize(size) => UpdateSize(size)
+        }
+    }
+
+    /// Whether the image won't change again until something else is requested of the cache
+    pure fn is_terminal() -> bool {
+        match self {
+          UpdatePending(*) | UpdateSize(*) => false,
+          UpdateReady(*) | UpdateFailed(*) => true
+        }
+    }
+}
+
+// The tag of the state an update reports
+pure fn state_tag(update: &ImageUpdate) -> Option<ImageStateTag> {
+    match *update {
+        UpdatePending(tag) => Some(tag),
+        UpdateReady(*) => Some(DecodedTag),
+        UpdateFailed(*) => Some(FailedTag),
+        UpdateSize(*) => None
+    }
+}
+
+/// A client told about changes to an image
+struct Subscriber {
+    chan: Chan<ImageUpdate>,
+    mode: SubscribeMode
+}
+
+/// One of the images an `<img srcset>` offers, for displays of a pixel density
+#[deriving_eq]
+pub struct ImageCandidate {
+    url: Url,
+    density: float
+}
+
+/**
+The candidate to show on a display of `density`: the least dense one that is
+at least that dense, or failing that the densest.
+*/
+pub pure fn select_candidate(candidates: &[ImageCandidate], density: float)
+                          -> Option<ImageCandidate> {
+    let mut best: Option<uint> = None;
+    for candidates.eachi |i, candidate| {
+        best = match best {
+            None => Some(i),
+            Some(current) => {
+                let current_density = candidates[current].density;
+                let better = if current_density < density {
+                    candidate.density > current_density
+                } else {
+                    candidate.density >= density && candidate.density < current_density
+                };
+                if better { Some(i) } else { best }
+            }
+        };
+    }
+    best.map(|&i| copy candidates[i])
+}
+
+/// How urgently an image is needed, e.g. low for images that are offscreen
+#[deriving_eq]
+pub enum DecodePriority {
+    LowPriority,
+    HighPriority
+}
+
+/// A simplified view of the state of an image in the cache, for inspection
+/// by clients such as test harnesses
+#[deriving_eq]
+pub enum ImageStateTag {
+    PrefetchingTag,
+    PrefetchedTag,
+    DecodingTag,
+    DecodedTag,
+    FailedTag
+}
+
+/// How many URLs the cache knows in each state, for debugging and tuning the memory budget
+#[deriving_eq]
+pub struct CacheStats {
+    init: uint,
+    prefetching: uint,
+    prefetched: uint,
+    decoding: uint,
+    decoded: uint,
+    failed: uint,
+    /// The size in bytes of the decoded images held, every frame of animated
+    /// ones and every scaled copy included
+    decoded_bytes: uint
+}
+
+/// Whether an image may be kept once it has been handed to the clients waiting for it
+#[deriving_eq]
+enum CachePolicy {
+    MayStore,
+    /// The response said `Cache-Control: no-store`, so the image is fetched
+    /// again for every request
+    NoStore
+}
+
+/// What a response told about an image, to ask the server later whether it has changed
+#[deriving_eq]
+struct Validators {
+    etag: Option<~str>,
+    last_modified: Option<~str>
+}
+
+pure fn no_validators() -> Validators {
+    Validators { etag: None, last_modified: None }
+}
+
+impl Validators {
+    /// The headers that make a load conditional on the image having changed
+    pure fn request_headers(&self) -> ~[(~str, ~str)] {
+        let mut headers = ~[];
+        match self.etag {
+            Some(ref etag) => headers.push((~"If-None-Match", copy *etag)),
+            None => ()
+        }
+        match self.last_modified {
+            Some(ref date) => headers.push((~"If-Modified-Since", copy *date)),
+            None => ()
+        }
+        headers
+    }
+}
+
+/// What the response an image was fetched with said about it
+struct ResponseInfo {
+    policy: CachePolicy,
+    /// The URL the fetch was redirected to, if it was
+    resolved: Option<Url>,
+    validators: Validators
+}
+
+/// Frames shown for less than this are shown for this long, as in other browsers
+const MIN_FRAME_DELAY_MS: uint = 10;
+
+/// The number of scaled copies kept of each image, the oldest being dropped first
+const MAX_SCALED_IMAGES: uint = 4;
+
+/// How much the bytes of a progressive image must grow, in halves of what was
+/// last decoded, before they are decoded again. Growing them geometrically
+/// keeps the decoding of an image's prefixes proportional to its size.
+const PARTIAL_DECODE_GROWTH: uint = 3;
+
+/// Which frame of a decoded animated image is showing, and since when
+#[deriving_eq]
+struct AnimationClock {
+    current: uint,
+    /// When the current frame was first shown, once the clock has started
+    frame_start_ms: Option<u64>
+}
+
+/// A clock showing the first frame, which starts with the first AdvanceAnimations
+const ANIMATION_START: AnimationClock = AnimationClock { current: 0, frame_start_ms: None };
+
+/// The clock of an animation with these frames, moved on to `now_ms`
+fn advance_clock(frames: &[ImageFrame], clock: AnimationClock, now_ms: u64) -> AnimationClock {
+    let mut start_ms = match clock.frame_start_ms {
+        Some(start_ms) => start_ms,
+        None => return AnimationClock { current: clock.current, frame_start_ms: Some(now_ms) }
+    };
+    let mut current = clock.current;
+
+    // Whole loops bring the animation back to the frame it is on, so
+    // they are skipped rather than stepped through a frame at a time
+    let loop_ms = do frames.foldl(0u64) |total, frame| {
+        *total + uint::max(frame.delay_ms, MIN_FRAME_DELAY_MS) as u64
+    };
+    if now_ms > start_ms {
+        let elapsed_ms = now_ms - start_ms;
+        start_ms += elapsed_ms - elapsed_ms % loop_ms;
+    }
+    loop {
+        let delay = uint::max(frames[current].delay_ms, MIN_FRAME_DELAY_MS) as u64;
+        if now_ms < start_ms + delay {
+            break;
+        }
+        start_ms += delay;
+        current = (current + 1) % frames.len();
+    }
+    AnimationClock { current: current, frame_start_ms: Some(start_ms) }
+}
+
 pub type ImageCacheTask = SharedChan<Msg>;
 
-type DecoderFactory = ~fn() -> ~fn(&[u8]) -> Option<Image>;
+/// Creates decoders, which say why bytes couldn't be decoded
+type DecoderFactory = ~fn() -> ~fn(&[u8]) -> Result<Image, DecodeError>;
+
+/// Creates decoders that can produce every frame of an animated image
+type FrameDecoderFactory = ~fn() -> ~fn(&[u8]) -> Result<~[ImageFrame], DecodeError>;
+
+/// The number of images decoded at once unless the cache is created with another limit
+pub const DEFAULT_MAX_DECODES: uint = 4;
+
+/// The number of images fetched at once unless the cache is told another limit
+pub const DEFAULT_MAX_FETCHES: uint = 8;
+
+/// The priority of fetches started by Prefetch
+pub const DEFAULT_FETCH_PRIORITY: u8 = 128;
+
+/// How often a failed fetch is tried again before the image fails
+pub struct Retries {
+    /// The number of attempts after the first
+    max_retries: uint,
+    /// How long to wait before the first retry, in ms. Each later retry waits twice as long.
+    backoff_ms: uint
+}
+
+pub pure fn no_retries() -> Retries {
+    Retries { max_retries: 0, backoff_ms: 0 }
+}
+
+/// The longest a retry waits, however many retries came before it
+const MAX_RETRY_BACKOFF_MS: uint = 60 * 1000;
+
+/// How long to wait before the retry after `retried` others, doubling up to the maximum
+pure fn retry_backoff_ms(retries: &Retries, retried: uint) -> uint {
+    let mut backoff_ms = uint::min(retries.backoff_ms, MAX_RETRY_BACKOFF_MS);
+    let mut doubled = 0;
+    while doubled < retried && backoff_ms < MAX_RETRY_BACKOFF_MS {
+        backoff_ms = uint::min(backoff_ms * 2, MAX_RETRY_BACKOFF_MS);
+        doubled += 1;
+    }
+    backoff_ms
+}
+
+/// How an image cache task decodes, retries and stores images
+pub struct ImageCacheOptions {
+    /// The number of images decoded at once
+    max_decodes: uint,
+    /// How often failed fetches are tried again
+    retries: Retries,
+    /// A directory decoded images are written to, and read back from instead of
+    /// being fetched again, by this cache or a later one
+    disk_cache: Option<Path>
+}
+
+pub fn default_options() -> ImageCacheOptions {
+    ImageCacheOptions {
+        max_decodes: DEFAULT_MAX_DECODES,
+        retries: no_retries(),
+        disk_cache: None
+    }
+}
 
 pub fn ImageCacheTask(resource_task: ResourceTask) -> ImageCacheTask {
-    ImageCacheTask_(resource_task, default_decoder_factory)
+    ImageCacheTask_frames(resource_task, default_frame_decoder_factory, default_options())
 }
 
+/// An image cache that decodes with the given decoders, as `options` says
 pub fn ImageCacheTask_(resource_task: ResourceTask,
-                       decoder_factory: DecoderFactory)
+                       decoder_factory: DecoderFactory,
+                       options: ImageCacheOptions)
                     -> ImageCacheTask {
+    let frame_decoder_factory = fn~(move decoder_factory)
+                                    -> ~fn(&[u8]) -> Result<~[ImageFrame], DecodeError> {
+        let decode = decoder_factory();
+        fn~(data: &[u8], move decode) -> Result<~[ImageFrame], DecodeError> {
+            match decode(data) {
+                Ok(move image) => Ok(~[ImageFrame { image: move image, delay_ms: 0 }]),
+                Err(error) => Err(error)
+            }
+        }
+    };
+    ImageCacheTask_frames(resource_task, move frame_decoder_factory, move options)
+}
+
+/// Like ImageCacheTask_, with decoders that may produce several frames per image
+pub fn ImageCacheTask_frames(resource_task: ResourceTask,
+                             decoder_factory: FrameDecoderFactory,
+                             options: ImageCacheOptions)
+                          -> ImageCacheTask {
+    let ImageCacheOptions {
+        max_decodes: max_decodes,
+        retries: retries,
+        disk_cache: disk_cache
+    } = move options;
+    assert max_decodes > 0;
+
     // FIXME: Doing some dancing to avoid copying decoder_factory, our test
     // version of which contains an uncopyable type which rust will currently
     // copy unsoundly
@@ -96,6 +603,12 @@ pub fn ImageCacheTask_(resource_task: ResourceTask,
     let chan = SharedChan(move chan);
     let port_cell = Cell(move port);
     let chan_cell = Cell(chan.clone());
+    let disk_writer = match disk_cache {
+        Some(*) => Some(spawn_disk_cache_writer()),
+        None => None
+    };
+    let disk_cache_cell = Cell(move disk_cache);
+    let disk_writer_cell = Cell(move disk_writer);
 
     do spawn {
         ImageCache {
@@ -105,6 +618,39 @@ pub fn ImageCacheTask_(resource_task: ResourceTask,
             chan: chan_cell.take(),
             state_map: url_map(),
             wait_map: url_map(),
+            timed_wait_map: url_map(),
+            next_waiter_id: 0,
+            progressive_wait_map: url_map(),
+            scaled_wait_map: url_map(),
+            scaled_images: url_map(),
+            size_wait_map: url_map(),
+            memory_budget: None,
+            decoded_bytes: 0,
+            decoded_order: ~[],
+            pinned: url_map(),
+            blobs: url_map(),
+            decode_priorities: url_map(),
+            max_decodes: max_decodes,
+            retries: retries,
+            disk_cache: disk_cache_cell.take(),
+            disk_writer: disk_writer_cell.take(),
+            decodes_in_flight: 0,
+            queued_decodes: ~[],
+            max_fetches: DEFAULT_MAX_FETCHES,
+            fetches_in_flight: 0,
+            queued_fetches: ~[],
+            queued_revalidations: ~[],
+            no_store: url_map(),
+            srcsets: url_map(),
+            animation_subscribers: url_map(),
+            subscribers: url_map(),
+            sizes: url_map(),
+            content_hashes: url_map(),
+            validators: url_map(),
+            decoded_by_hash: HashMap(),
+            alpha_mode: Straight,
+            load_timeouts: no_timeouts(),
+            cancelled: url_map(),
             need_exit: None
         }.run();
     }
@@ -112,6 +658,11 @@ pub fn ImageCacheTask_(resource_task: ResourceTask,
     move chan
 }
 
+/**
+An image cache whose GetImage waits for the image instead of answering
+ImageNotReady. Messages reach the cache in the order they are sent, so
+GetImage may follow Prefetch and Decode straight away.
+*/
 fn SyncImageCacheTask(resource_task: ResourceTask) -> ImageCacheTask {
     let (port, chan) = stream();
     let port_cell = Cell(move port);
@@ -143,7 +694,7 @@ struct ImageCache {
     /// A handle to the resource task for fetching the image binaries
     resource_task: ResourceTask,
     /// Creates image decoders
-    decoder_factory: DecoderFactory,
+    decoder_factory: FrameDecoderFactory,
     /// The port on which we'll receive client requests
     port: Port<Msg>,
     /// A copy of the shared chan to give to child tasks
@@ -152,16 +703,114 @@ struct ImageCache {
     state_map: UrlMap<ImageState>,
     /// List of clients waiting on a WaitForImage response
     wait_map: UrlMap<@mut ~[Chan<ImageResponseMsg>]>,
+    /// Clients waiting on a WaitForImageWithTimeout response, by id
+    timed_wait_map: UrlMap<@mut ~[(uint, Chan<ImageResponseMsg>)]>,
+    /// The id of the next client to wait with a timeout
+    mut next_waiter_id: uint,
+    /// Clients waiting on GetImageProgressive responses
+    progressive_wait_map: UrlMap<@mut ~[Chan<ImageResponseMsg>]>,
+    /// Clients waiting on GetScaledImage responses, with the size each wants the image to fit
+    scaled_wait_map: UrlMap<@mut ~[((uint, uint), Chan<ImageResponseMsg>)]>,
+    /// The scaled copies of decoded images, with the size each was scaled to fit
+    scaled_images: UrlMap<@mut ~[((uint, uint), ARC<~Image>)]>,
+    /// Clients waiting on a GetImageSize response
+    size_wait_map: UrlMap<@mut ~[Chan<Option<(uint, uint)>>]>,
+    /// The maximum number of bytes of decoded images to keep, if any
+    mut memory_budget: Option<uint>,
+    /// The number of bytes of decoded images and their scaled copies currently held
+    mut decoded_bytes: uint,
+    /// Decoded URLs, least recently used first, in the order they are considered
+    /// for eviction
+    mut decoded_order: ~[Url],
+    /// URLs whose decoded images must not be evicted
+    pinned: UrlMap<()>,
+    /// Bytes registered under `blob:` URLs
+    blobs: UrlMap<@ARC<~[u8]>>,
+    /// The priorities of pending and running decodes
+    decode_priorities: UrlMap<DecodePriority>,
+    /// The number of decoder tasks allowed to run at once
+    max_decodes: uint,
+    /// How often failed fetches are tried again
+    retries: Retries,
+    /// The directory decoded images are kept in between sessions, if any
+    disk_cache: Option<Path>,
+    /// Writes and removes the files of the disk cache, in the order asked
+    disk_writer: Option<Chan<DiskCacheMsg>>,
+    /// The number of decoder tasks running
+    mut decodes_in_flight: uint,
+    /// Images in the Decoding state waiting for a decoder task, with their bytes
+    mut queued_decodes: ~[(Url, ARC<~[u8]>)],
+    /// The number of prefetch tasks allowed to run at once
+    mut max_fetches: uint,
+    /// The number of prefetch tasks running
+    mut fetches_in_flight: uint,
+    /// Images in the Prefetching state waiting for a prefetch task, with their
+    /// priorities, oldest first
+    mut queued_fetches: ~[(Url, u8)],
+    /// Decoded images waiting for a revalidation task, oldest first
+    mut queued_revalidations: ~[Url],
+    /// URLs being loaded whose images must not be kept once decoded
+    no_store: UrlMap<()>,
+    /// The srcset candidates registered under each URL
+    srcsets: UrlMap<@~[ImageCandidate]>,
+    /// Clients to send each new frame of an animated image to
+    animation_subscribers: UrlMap<@mut ~[Chan<ImageResponseMsg>]>,
+    /// Clients told about changes to each image
+    subscribers: UrlMap<@mut ~[Subscriber]>,
+    /// The dimensions declared by the headers of images being fetched or fetched already
+    sizes: UrlMap<Size2D<uint>>,
+    /// The digest of the bytes fetched for each URL that may be kept
+    content_hashes: UrlMap<~str>,
+    /// The validators of the responses images that may be kept were fetched with
+    validators: UrlMap<Validators>,
+    /// Decoded single-frame images by the digest of the bytes they were
+    /// decoded from, to share between URLs that serve the same bytes
+    decoded_by_hash: HashMap<~str, @ARC<~Image>>,
+    /// The alpha mode decoded images are converted to
+    mut alpha_mode: AlphaMode,
+    /// Passed to the resource task with each fetch
+    mut load_timeouts: Timeouts,
+    /// For each URL, the number of fetches and decodes still running whose
+    /// results must be dropped because the cache was cleared or the image cancelled
+    cancelled: UrlMap<uint>,
     mut need_exit: Option<Chan<()>>,
 }
 
 enum ImageState {
     Init,
     Prefetching(AfterPrefetch),
-    Prefetched(@Cell<~[u8]>),
+    /// The bytes are shared with the decoder task without being copied
+    Prefetched(@ARC<~[u8]>),
     Decoding,
     Decoded(@ARC<~Image>),
-    Failed
+    /// Every frame of an animated image, the one showing, and since when
+    DecodedAnimated(@ARC<~[ImageFrame]>, @ARC<~Image>, AnimationClock),
+    Failed(ImageFailure)
+}
+
+impl ImageState {
+    pure fn tag(&self) -> Option<ImageStateTag> {
+        match *self {
+            Init => None,
+            Prefetching(*) => Some(PrefetchingTag),
+            Prefetched(*) => Some(PrefetchedTag),
+            Decoding => Some(DecodingTag),
+            Decoded(*) | DecodedAnimated(*) => Some(DecodedTag),
+            Failed(*) => Some(FailedTag)
+        }
+    }
+
+    // What subscribers are told when an image moves to this state
+    pure fn update(&self) -> Option<ImageUpdate> {
+        match *self {
+            Init => None,
+            Decoded(image) | DecodedAnimated(_, image, _) => {
+                Some(UpdateReady(unsafe { clone_arc(image) }))
+            }
+            Failed(reason) => Some(UpdateFailed(reason)),
+            Prefetching(*) | Prefetched(*) | Decoding => self.tag().map(|&tag| UpdatePending(tag))
+        }
+    }
 }
 
 enum AfterPrefetch {
@@ -187,15 +836,115 @@ impl ImageCache {
 
             match move msg {
                 Prefetch(move url) => self.prefetch(move url),
+                PrefetchWithPriority(move url, priority) => {
+                    self.prefetch_with_priority(move url, priority)
+                }
+                SetMaxFetches(limit) => {
+                    assert limit > 0;
+                    self.max_fetches = limit;
+                    while self.fetches_in_flight < self.max_fetches {
+                        if !self.start_queued_fetch() {
+                            break;
+                        }
+                    }
+                }
                 StorePrefetchedImageData(move url, move data) => {
                     self.store_prefetched_image_data(move url, move data);
                 }
-                Decode(move url) => self.decode(move url),
+                StoreCachedImage(move url, move image) => {
+                    self.store_cached_image(move url, move image)
+                }
+                ImageSizeKnown(move url, size) => self.store_image_size(move url, size),
+                PrefetchSrcset(move url, move candidates, density) => {
+                    self.prefetch_srcset(move url, move candidates, density)
+                }
+                GetSrcset(move url, move response) => {
+                    response.send(match self.srcsets.find(&url) {
+                        Some(candidates) => copy *candidates,
+                        None => ~[]
+                    });
+                }
+                Decode(move url) => self.decode_with_priority(move url, HighPriority),
+                DecodeWithPriority(move url, priority) => {
+                    self.decode_with_priority(move url, priority)
+                }
+                GetDecodePriority(move url, move response) => {
+                    response.send(self.decode_priorities.find(&url));
+                }
+                DecodeAll => self.decode_all(),
                 StoreImage(move url, move image) => self.store_image(move url, move image),
                 GetImage(move url, move response) => self.get_image(move url, move response),
+                GetImageIfDecoded(move url, move response) => {
+                    self.get_image_if_decoded(move url, move response)
+                }
                 WaitForImage(move url, move response) => {
                     self.wait_for_image(move url, move response)
                 }
+                WaitForImageWithTimeout(move url, timeout_ms, move response) => {
+                    self.wait_for_image_with_timeout(move url, timeout_ms, move response)
+                }
+                WaitTimedOut(move url, id) => self.wait_timed_out(move url, id),
+                GetImageProgressive(move url, move response) => {
+                    self.get_image_progressive(move url, move response)
+                }
+                StorePartialImage(move url, move image) => {
+                    self.store_partial_image(move url, move image)
+                }
+                GetScaledImage(move url, width, height, move response) => {
+                    self.get_scaled_image(move url, (width, height), move response)
+                }
+                StoreScaledImage(move url, max_size, move image) => {
+                    self.store_scaled_image(move url, max_size, move image)
+                }
+                Revalidate(move url) => self.revalidate(move url),
+                StoreRevalidation(move url, move result) => {
+                    self.store_revalidation(move url, move result)
+                }
+                FetchEnded => {
+                    // The task is done, so another can start
+                    self.fetches_in_flight -= 1;
+                    self.start_queued_fetch();
+                }
+                GetImageSize(move url, move response) => {
+                    self.get_image_size(move url, move response)
+                }
+                SubscribeReady(move url, move response) => {
+                    self.subscribe_ready(move url, move response)
+                }
+                SubscribeAnimation(move url, move response) => {
+                    let subscribers =
+                        self.animation_subscribers.find_or_insert(move url, @mut ~[]);
+                    vec::push(&mut *subscribers, move response);
+                }
+                Subscribe(move url, move response, mode) => {
+                    self.subscribe(move url, move response, mode)
+                }
+                AdvanceAnimations(now_ms) => self.advance_animations(now_ms),
+                ListUrls(move response) => self.list_urls(move response),
+                GetStats(move response) => response.send(self.stats()),
+                SetLoadTimeouts(timeouts) => self.load_timeouts = timeouts,
+                SetAlphaMode(alpha_mode) => {
+                    // Images decoded already are in the old mode
+                    self.decoded_by_hash.clear();
+                    self.alpha_mode = alpha_mode;
+                }
+                SetMemoryBudget(budget) => {
+                    self.memory_budget = budget;
+                    self.evict_to_budget();
+                }
+                Pin(move url) => self.pin(move url),
+                Unpin(move url) => self.unpin(move url),
+                RegisterBlob(move url, move data) => {
+                    self.blobs.insert(move url, @ARC(move data));
+                }
+                RevokeBlob(move url) => self.revoke_blob(move url),
+                Sync(move response) => response.send(()),
+                Clear(move response) => {
+                    self.clear();
+                    response.send(());
+                }
+                Cancel(move url) => self.cancel(move url),
+                Purge(move url) => self.purge(move url),
                 OnMsg(move handler) => msg_handlers.push(handler),
                 Exit(move response) => {
                     assert self.need_exit.is_none();
@@ -208,18 +957,20 @@ impl ImageCache {
             match move need_exit {
               Some(move response) => {
                 // Wait until we have no outstanding requests and subtasks
-                // before exiting
+                // before exiting. Cancelled subtasks don't matter, since
+                // nothing is waiting on them.
                 let mut can_exit = true;
                 for self.state_map.each_value |state| {
                     match *state {
                         Prefetching(*) => can_exit = false,
                         Decoding => can_exit = false,
 
-                        Init | Prefetched(*) | Decoded(*) | Failed => ()
+                        Init | Prefetched(*) | Decoded(*) | DecodedAnimated(*) | Failed(*) => ()
                     }
                 }
 
                 if can_exit {
+                    self.sync_disk_writer();
                     response.send(());
                     break;
                 } else {
@@ -239,55 +990,289 @@ impl ImageCache {
     }
 
     priv fn set_state(url: Url, state: ImageState) {
-        self.state_map.insert(move url, move state);
+        let update = state.update();
+        // The previous state is read in the same lookup that replaces it
+        let previous_tag = match self.state_map.replace(copy url, move state) {
+            Some(previous) => previous.tag(),
+            None => None
+        };
+        match move update {
+            // Subscribers don't see what is to happen after a prefetch, but do
+            // see each frame an animated image moves on to
+            Some(move update) if update.is_terminal() || state_tag(&update) != previous_tag => {
+                self.notify_subscribers(&url, move update)
+            }
+            Some(*) | None => ()
+        }
     }
 
     priv fn prefetch(url: Url) {
+        self.prefetch_with_priority(move url, DEFAULT_FETCH_PRIORITY)
+    }
+
+    priv fn prefetch_with_priority(url: Url, priority: u8) {
         match self.get_state(copy url) {
+            Init if url.scheme == ~"blob" => {
+                // Blob bytes are already in memory, so there is nothing to fetch
+                match self.blobs.find(&url) {
+                    Some(data) => self.set_state(move url, Prefetched(@clone_arc(data))),
+                    None => {
+                        debug!("image_cache_task: no blob registered for %s", url.to_str());
+                        self.set_state(move url, Failed(NetworkFailure(LoadFailed)));
+                    }
+                }
+            }
+
             Init => {
-                let to_cache = self.chan.clone();
-                let resource_task = self.resource_task.clone();
-                let url_cell = Cell(copy url);
+                self.set_state(copy url, Prefetching(DoNotDecode));
+                if self.fetches_in_flight < self.max_fetches {
+                    self.spawn_fetch(move url);
+                } else {
+                    debug!("image_cache_task: queueing fetch of %s", url.to_str());
+                    self.queued_fetches.push((move url, priority));
+                }
+            }
 
-                do spawn {
-                    let url = url_cell.take();
-                    debug!("image_cache_task: started fetch for %s", url.to_str());
+            Prefetching(*) => {
+                // Only ever raise the priority of a fetch that is waiting
+                let queued = self.queued_fetches.position(|entry| {
+                    match *entry { (ref queued, _) => *queued == url }
+                });
+                match queued {
+                    Some(i) => {
+                        let (_, queued_priority) = self.queued_fetches[i];
+                        if priority > queued_priority {
+                            self.queued_fetches[i] = (move url, priority);
+                        }
+                    }
+                    None => ()
+                }
+            }
 
-                    let image = load_image_data(copy url, resource_task.clone());
+            Prefetched(*) | Decoding | Decoded(*) | DecodedAnimated(*) | Failed(*) => {
+                // We've already begun working on this image
+            }
+        }
+    }
 
-                    let result = if image.is_ok() {
-                        Ok(Cell(result::unwrap(move image)))
-                    } else {
-                        Err(())
-                    };
-                    to_cache.send(StorePrefetchedImageData(copy url, move result));
-                    debug!("image_cache_task: ended fetch for %s", (copy url).to_str());
+    priv fn spawn_fetch(url: Url) {
+        let to_cache = self.chan.clone();
+        let resource_task = self.resource_task.clone();
+        let timeouts = self.load_timeouts;
+        let retries = self.retries;
+        let url_cell = Cell(copy url);
+        // Clients waiting on a progressive image see it decoded as its bytes arrive
+        let decode_partial = if self.progressive_wait_map.contains_key(&url) {
+            Some((self.decoder_factory)())
+        } else {
+            None
+        };
+        let alpha_mode = self.alpha_mode;
+        let cache_file = self.disk_cache_file(&url);
+        let failed = StorePrefetchedImageData(copy url, Err(LoadFailed));
+
+        do self.spawn_fetch_task(move failed) |move decode_partial, move cache_file| {
+            let url = url_cell.take();
+            debug!("image_cache_task: started fetch for %s", url.to_str());
+
+            // An image decoded in an earlier session is read back instead
+            match cache_file {
+                Some(ref path) => {
+                    match read_cached_image(path, &url) {
+                        Some(move image) => {
+                            to_cache.try_send(StoreCachedImage(copy url, ARC(~move image)));
+                            return;
+                        }
+                        None => ()
+                    }
                 }
+                None => ()
+            }
 
-                self.set_state(move url, Prefetching(DoNotDecode));
+            // How many bytes the last partial image was decoded from
+            let mut decoded_len = 0;
+            let fetch = || {
+                load_image_data(copy url, resource_task.clone(), timeouts,
+                                &no_validators(), |size| {
+                    to_cache.try_send(ImageSizeKnown(copy url, size));
+                }, |data| {
+                    match decode_partial {
+                        Some(_) if data.len() * 2 < decoded_len * PARTIAL_DECODE_GROWTH => {
+                            // Too little has arrived since the last decode to be worth another
+                        }
+                        Some(ref decode) => {
+                            decoded_len = data.len();
+                            match (*decode)(data) {
+                                Ok(ref frames) if !frames.is_empty() => {
+                                    let image = match alpha_mode {
+                                        Straight => copy frames[0].image,
+                                        _ => convert_alpha(&frames[0].image, alpha_mode)
+                                    };
+                                    to_cache.try_send(StorePartialImage(copy url,
+                                                                        ARC(~image)));
+                                }
+                                Ok(*) | Err(*) => ()
+                            }
+                        }
+                        None => ()
+                    }
+                })
+            };
+            // Waiters aren't told of failures until the retries run out
+            let mut image = fetch();
+            let mut retried = 0;
+            while image.is_err() && retried < retries.max_retries {
+                let backoff_ms = retry_backoff_ms(&retries, retried);
+                debug!("image_cache_task: retrying fetch for %s in %u ms",
+                       url.to_str(), backoff_ms);
+                if backoff_ms > 0 {
+                    timer::sleep(uv_global_loop::get(), backoff_ms);
+                }
+                retried += 1;
+                image = fetch();
             }
 
-            Prefetching(*) | Prefetched(*) | Decoding | Decoded(*) | Failed => {
-                // We've already begun working on this image
+            let result = match move image {
+                Ok(Some((move data, move info))) => Ok((ARC(move data), move info)),
+                // Nothing was asked of the server that it could answer so
+                Ok(None) => Err(LoadFailed),
+                Err(error) => Err(error)
+            };
+            // The cache may have exited if the fetch was cancelled
+            to_cache.try_send(StorePrefetchedImageData(copy url, move result));
+            debug!("image_cache_task: ended fetch for %s", (copy url).to_str());
+        }
+    }
+
+    /**
+    Runs a prefetch or revalidation task, counting it against the limit on
+    fetches until it ends. Should the task fail before it posts back how the
+    fetch went, `failed` is posted in its place.
+    */
+    priv fn spawn_fetch_task(failed: Msg, fetch: ~fn()) {
+        self.fetches_in_flight += 1;
+        let mut exit = None;
+        task::task().unlinked().future_result(|+result| exit = Some(move result)).spawn(move fetch);
+        let exit = option::unwrap(move exit);
+
+        let to_cache = self.chan.clone();
+        let failed_cell = Cell(move failed);
+        do spawn |move exit| {
+            match exit.recv() {
+                task::Success => (),
+                task::Failure => {
+                    debug!("image_cache_task: a fetch task failed");
+                    to_cache.try_send(failed_cell.take());
+                }
+            }
+            to_cache.try_send(FetchEnded);
+        }
+    }
+
+    /**
+    Starts the queued fetch of the highest priority, the oldest among equals,
+    or failing that the oldest queued revalidation. Returns whether a task
+    was started.
+    */
+    priv fn start_queued_fetch() -> bool {
+        if self.queued_fetches.is_empty() {
+            // Revalidations wait behind the fetches clients are waiting on
+            while !self.queued_revalidations.is_empty() {
+                let url = self.queued_revalidations.shift();
+                match self.get_state(copy url) {
+                    Decoded(*) | DecodedAnimated(*) => {
+                        self.spawn_revalidation(move url);
+                        return true;
+                    }
+                    // The image was forgotten while its revalidation waited
+                    Init | Prefetching(*) | Prefetched(*) | Decoding | Failed(*) => ()
+                }
+            }
+            return false;
+        }
+        let mut next = 0;
+        for self.queued_fetches.eachi |i, entry| {
+            let (_, priority) = *entry;
+            let (_, next_priority) = self.queued_fetches[next];
+            if priority > next_priority {
+                next = i;
             }
         }
+        let (url, _) = self.queued_fetches.remove(next);
+        self.spawn_fetch(move url);
+        true
     }
 
-    priv fn store_prefetched_image_data(url: Url, data: Result<Cell<~[u8]>, ()>) {
+    // Drops the queued fetch of an image, if it hasn't started yet
+    priv fn dequeue_fetch(url: &Url) -> bool {
+        let queued = self.queued_fetches.position(|entry| {
+            match *entry { (ref queued, _) => *queued == *url }
+        });
+        match queued {
+            Some(i) => {
+                self.queued_fetches.remove(i);
+                true
+            }
+            None => false
+        }
+    }
+
+    priv fn prefetch_srcset(url: Url, candidates: ~[ImageCandidate], density: float) {
+        match select_candidate(candidates, density) {
+            Some(candidate) => self.prefetch(copy candidate.url),
+            None => ()
+        }
+        self.srcsets.insert(move url, @move candidates);
+    }
+
+    priv fn store_prefetched_image_data(url: Url,
+                                        data: Result<(ARC<~[u8]>, ResponseInfo), NetworkError>) {
+        if self.take_cancelled(&url) {
+            return;
+        }
+
         match self.get_state(copy url) {
           Prefetching(next_step) => {
-            match data {
-              Ok(data_cell) => {
-                let data = data_cell.take();
-                self.set_state(copy url, Prefetched(@Cell(move data)));
+            match move data {
+              Ok((move data, move info)) => {
+                let ResponseInfo {
+                    policy: policy, resolved: move resolved, validators: move validators
+                } = move info;
+                if policy == NoStore {
+                    self.no_store.insert(copy url, ());
+                } else {
+                    let hash = bytes_digest(*arc::get(&data));
+                    self.content_hashes.insert(copy url, copy hash);
+                    self.validators.insert(copy url, move validators);
+                    match move resolved {
+                        Some(move target) => {
+                            self.store_redirect_target(target, &data, copy hash)
+                        }
+                        None => ()
+                    }
+                    match self.decoded_by_hash.find(&hash) {
+                        Some(image) => {
+                            // The same bytes have been decoded for another URL
+                            debug!("image_cache_task: sharing a decoded image with %s",
+                                   url.to_str());
+                            self.store_ready_image(move url, image);
+                            return;
+                        }
+                        None => ()
+                    }
+                }
+                self.set_state(copy url, Prefetched(@move data));
+                self.purge_size_waiters(copy url);
                 match next_step {
                   DoDecode => self.decode(move url),
                   _ => ()
                 }
               }
-              Err(*) => {
-                self.set_state(copy url, Failed);
-                self.purge_waiters(move url, || ImageFailed);
+              Err(error) => {
+                let reason = NetworkFailure(error);
+                self.set_state(copy url, Failed(reason));
+                self.purge_size_waiters(copy url);
+                self.purge_waiters(move url, || ImageFailed(Some(reason)));
               }
             }
           }
@@ -296,12 +1281,76 @@ impl ImageCache {
           | Prefetched(*)
           | Decoding
           | Decoded(*)
-          | Failed => {
+          | DecodedAnimated(*)
+          | Failed(*) => {
             fail!(~"wrong state for storing prefetched image")
           }
         }
     }
 
+    priv fn store_cached_image(url: Url, image: ARC<~Image>) {
+        if self.take_cancelled(&url) {
+            return;
+        }
+
+        match self.get_state(copy url) {
+            Prefetching(*) => {
+                debug!("image_cache_task: found %s in the disk cache", url.to_str());
+                self.decode_priorities.remove(&url);
+                self.store_ready_image(move url, @move image);
+            }
+            Init | Prefetched(*) | Decoding | Decoded(*) | DecodedAnimated(*) | Failed(*) => {
+                fail!(~"wrong state for storing a cached image")
+            }
+        }
+    }
+
+    // Files the bytes fetched for a URL under the URL the fetch was redirected
+    // to as well, so that prefetching that URL doesn't fetch them again. Each
+    // URL goes on from there by itself, but the bytes are decoded only once.
+    priv fn store_redirect_target(target: Url, data: &ARC<~[u8]>, hash: ~str) {
+        match self.get_state(copy target) {
+            Init => {
+                debug!("image_cache_task: storing a redirect to %s", target.to_str());
+                self.content_hashes.insert(copy target, move hash);
+                self.set_state(move target, Prefetched(@clone_arc(data)));
+            }
+            // Fetched already, or being fetched
+            Prefetching(*) | Prefetched(*) | Decoding | Decoded(*) | DecodedAnimated(*)
+            | Failed(*) => ()
+        }
+    }
+
+    // Takes a URL straight to the Decoded state with an image decoded for
+    // another URL or in an earlier session
+    priv fn store_ready_image(url: Url, image: @ARC<~Image>) {
+        self.set_state(copy url, Decoded(@clone_arc(image)));
+        // Each URL counts the image against the budget, as each keeps it alive
+        self.decoded_bytes += image_size_in_bytes(image);
+        self.decoded_order.push(copy url);
+        self.purge_size_waiters(copy url);
+        self.purge_waiters(move url, || ImageReady(clone_arc(image)));
+        self.evict_to_budget();
+    }
+
+    priv fn store_image_size(url: Url, size: Size2D<uint>) {
+        // The header of a fetch started before the cache was cleared or the image cancelled
+        if self.cancelled.contains_key(&url) {
+            return;
+        }
+
+        match self.get_state(copy url) {
+            Prefetching(*) => {
+                self.sizes.insert(copy url, size);
+                self.notify_subscribers(&url, UpdateSize(size));
+                self.purge_size_waiters(move url);
+            }
+            Init | Prefetched(*) | Decoding | Decoded(*) | DecodedAnimated(*) | Failed(*) => {
+                fail!(~"wrong state for storing the size of an image")
+            }
+        }
+    }
+
     priv fn decode(url: Url) {
         match self.get_state(copy url) {
             Init => fail!(~"decoding image before prefetch"),
@@ -315,84 +1364,334 @@ impl ImageCache {
                 // We don't have the data yet, but the decode request is queued up
             }
 
-            Prefetched(data_cell) => {
-                assert !data_cell.is_empty();
-
-                let data = data_cell.take();
-                let to_cache = self.chan.clone();
-                let url_cell = Cell(copy url);
-                let decode = (self.decoder_factory)();
-
-                do spawn |move url_cell, move decode, move data, move to_cache| {
-                    let url = url_cell.take();
-                    debug!("image_cache_task: started image decode for %s", url.to_str());
-                    let image = decode(data);
-                    let image = if image.is_some() {
-                        Some(ARC(~option::unwrap(move image)))
-                    } else {
-                        None
-                    };
-                    to_cache.send(StoreImage(copy url, move image));
-                    debug!("image_cache_task: ended image decode for %s", url.to_str());
+            Prefetched(data) => {
+                let shared = match self.content_hashes.find(&url) {
+                    Some(hash) => self.decoded_by_hash.find(&hash),
+                    None => None
+                };
+                match shared {
+                    Some(image) => {
+                        // The same bytes have been decoded for another URL
+                        // since they were fetched, e.g. one that redirected here
+                        debug!("image_cache_task: sharing a decoded image with %s",
+                               url.to_str());
+                        self.decode_priorities.remove(&url);
+                        self.store_ready_image(move url, image);
+                        return;
+                    }
+                    None => ()
                 }
 
-                self.set_state(move url, Decoding);
+                let data = clone_arc(data);
+                if !self.sizes.contains_key(&url) {
+                    match header::dimensions(*arc::get(&data)) {
+                        Some((width, height)) => {
+                            self.sizes.insert(copy url, Size2D(width, height));
+                        }
+                        None => ()
+                    }
+                }
+                self.set_state(copy url, Decoding);
+                if self.decodes_in_flight < self.max_decodes {
+                    self.spawn_decode(move url, move data);
+                } else {
+                    debug!("image_cache_task: queueing decode of %s", url.to_str());
+                    self.queued_decodes.push((move url, move data));
+                }
             }
 
-            Decoding | Decoded(*) | Failed => {
+            Decoding | Decoded(*) | DecodedAnimated(*) | Failed(*) => {
                 // We've already begun decoding
             }
         }
     }
 
-    priv fn store_image(url: Url, image: Option<ARC<~Image>>) {
-
-        match self.get_state(copy url) {
-          Decoding => {
-            match image {
-              Some(image) => {
-                self.set_state(copy url, Decoded(@clone_arc(&image)));
-                self.purge_waiters(move url, || ImageReady(clone_arc(&image)) );
-              }
-              None => {
-                self.set_state(copy url, Failed);
-                self.purge_waiters(move url, || ImageFailed );
-              }
+    priv fn spawn_decode(url: Url, data: ARC<~[u8]>) {
+        let to_cache = self.chan.clone();
+        let url_cell = Cell(move url);
+        let decode = (self.decoder_factory)();
+        let alpha_mode = self.alpha_mode;
+        // Clients that ask for a scaled image later have it scaled once it is decoded
+        let max_sizes = self.scaled_sizes_waited_on(&url);
+        self.decodes_in_flight += 1;
+
+        do spawn |move url_cell, move decode, move data, move to_cache, move max_sizes| {
+            let url = url_cell.take();
+            debug!("image_cache_task: started image decode for %s", url.to_str());
+            let frames = match decode(*arc::get(&data)) {
+                Ok(move frames) => {
+                    if frames.is_empty() {
+                        Err(DecodeFailure)
+                    } else {
+                        Ok(do vec::map_consume(move frames) |frame| {
+                            match alpha_mode {
+                                // Decoders produce straight alpha
+                                Straight => move frame,
+                                _ => ImageFrame {
+                                    image: convert_alpha(&frame.image, alpha_mode),
+                                    delay_ms: frame.delay_ms
+                                }
+                            }
+                        })
+                    }
+                }
+                Err(error) => Err(decode_failure(error))
+            };
+            match frames {
+                Ok(ref frames) => send_scaled_images(&to_cache, &url, &frames[0].image, max_sizes),
+                Err(_) => ()
             }
-          }
-
-          Init
-          | Prefetching(*)
-          | Prefetched(*)
-          | Decoded(*)
-          | Failed => {
-            fail!(~"incorrect state in store_image")
-          }
+            to_cache.try_send(StoreImage(copy url, move frames));
+            debug!("image_cache_task: ended image decode for %s", url.to_str());
         }
-
     }
 
-    priv fn purge_waiters(url: Url, f: fn() -> ImageResponseMsg) {
-          Some(waiters) => {
-            let waiters = &mut *waiters;
-            let mut new_waiters = ~[];
-            new_waiters <-> *waiters;
-
-            for new_waiters.each |response| {
-                response.send(f());
+    // Starts the queued decode of the highest priority, if any
+    priv fn start_queued_decode() {
+        if self.queued_decodes.is_empty() {
+            return;
+        }
+        let next = match self.queued_decodes.position(|entry| {
+            match *entry {
+                (ref url, _) => self.decode_priorities.find(url) != Some(LowPriority)
             }
+        }) {
+            Some(i) => i,
+            None => 0
+        };
+        let (url, data) = self.queued_decodes.remove(next);
+        self.spawn_decode(move url, move data);
+    }
 
-            *waiters <-> new_waiters;
-            self.wait_map.remove(&url);
-          }
-          None => ()
+    // Drops the queued decode of an image, if it hasn't started yet
+    priv fn dequeue_decode(url: &Url) -> bool {
+        match self.queued_decodes.position(|entry| match *entry { (ref queued, _) => *queued == *url }) {
+            Some(i) => {
+                self.queued_decodes.remove(i);
+                true
+            }
+            None => false
         }
     }
 
-
-    priv fn get_image(url: Url, response: Chan<ImageResponseMsg>) {
+    priv fn decode_with_priority(url: Url, priority: DecodePriority) {
         match self.get_state(copy url) {
-          Init => fail!(~"request for image before prefetch"),
+            Prefetching(*) | Prefetched(*) | Decoding => {
+                // Only ever raise the priority of a decode that's already been requested
+                let raise = match self.decode_priorities.find(&url) {
+                    Some(HighPriority) => false,
+                    Some(LowPriority) | None => true
+                };
+                if raise {
+                    self.decode_priorities.insert(copy url, priority);
+                }
+            }
+            Init | Decoded(*) | DecodedAnimated(*) | Failed(*) => ()
+        }
+
+        self.decode(move url);
+    }
+
+    priv fn decode_all() {
+        let mut prefetched = ~[];
+        for self.state_map.each |url, state| {
+            match *state {
+                Prefetched(*) => prefetched.push(copy *url),
+                _ => ()
+            }
+        }
+
+        for prefetched.each |url| {
+            self.decode(copy *url);
+        }
+    }
+
+    priv fn store_image(url: Url, frames: Result<~[ImageFrame], ImageFailure>) {
+        // The decoder task is done, so another can start
+        self.decodes_in_flight -= 1;
+        self.start_queued_decode();
+
+        if self.take_cancelled(&url) {
+            return;
+        }
+
+        self.decode_priorities.remove(&url);
+
+        match self.get_state(copy url) {
+          Decoding if self.no_store.contains_key(&url) => {
+            // Serve the waiters, then forget the image so the next request fetches it again
+            self.no_store.remove(&url);
+            self.state_map.remove(&url);
+            match move frames {
+              Ok(move frames) => {
+                let image = ARC(~copy frames[0].image);
+                self.notify_subscribers(&url, UpdateReady(clone_arc(&image)));
+                if frames.len() > 1 {
+                    let frames = ARC(move frames);
+                    self.purge_waiters(move url, || ImageReadyAnimated(clone_arc(&frames)))
+                } else {
+                    self.purge_waiters(move url, || ImageReady(clone_arc(&image)))
+                }
+              }
+              Err(reason) => {
+                self.notify_subscribers(&url, UpdateFailed(reason));
+                self.purge_waiters(move url, || ImageFailed(Some(reason)))
+              }
+            }
+          }
+
+          Decoding => {
+            match move frames {
+              Ok(move frames) => {
+                if frames.len() > 1 {
+                    for frames.each |frame| {
+                        self.decoded_bytes += frame.image.data.len();
+                    }
+                    // The frame showing is copied out, to hand to clients on its own
+                    let image = ARC(~copy frames[0].image);
+                    let frames = ARC(move frames);
+                    self.set_state(copy url, DecodedAnimated(@clone_arc(&frames),
+                                                             @clone_arc(&image),
+                                                             ANIMATION_START));
+                    self.decoded_order.push(copy url);
+                    self.purge_waiters(move url, || ImageReadyAnimated(clone_arc(&frames)));
+                } else {
+                    let mut frames = move frames;
+                    let image = match frames.pop() {
+                        ImageFrame { image: move image, _ } => ARC(~move image)
+                    };
+                    self.set_state(copy url, Decoded(@clone_arc(&image)));
+                    self.decoded_bytes += image_size_in_bytes(&image);
+                    match self.content_hashes.find(&url) {
+                        Some(move hash) => {
+                            self.decoded_by_hash.insert(move hash, @clone_arc(&image));
+                        }
+                        None => ()
+                    }
+                    self.write_disk_cache(&url, &image);
+                    self.decoded_order.push(copy url);
+                    self.purge_waiters(move url, || ImageReady(clone_arc(&image)) );
+                }
+                self.evict_to_budget();
+              }
+              Err(reason) => {
+                debug!("image_cache_task: couldn't decode %s: %?", url.to_str(), reason);
+                self.set_state(copy url, Failed(reason));
+                self.purge_waiters(move url, || ImageFailed(Some(reason)));
+              }
+            }
+          }
+
+          Init
+          | Prefetching(*)
+          | Prefetched(*)
+          | Decoded(*)
+          | DecodedAnimated(*)
+          | Failed(*) => {
+            fail!(~"incorrect state in store_image")
+          }
+        }
+
+    }
+
+    /// Moves each animation on by as many frames as have elapsed since its
+    /// current frame was shown, and sends the new frames to subscribers
+    priv fn advance_animations(now_ms: u64) {
+        let mut advanced = ~[];
+        for self.state_map.each |url, state| {
+            match *state {
+                DecodedAnimated(frames, image, clock) => {
+                    let new_clock = advance_clock(*arc::get(frames), clock, now_ms);
+                    if new_clock != clock {
+                        advanced.push((copy *url, frames, image, clock, new_clock));
+                    }
+                }
+                _ => ()
+            }
+        }
+
+        for advanced.each |&(url, frames, image, clock, new_clock)| {
+            if new_clock.current == clock.current {
+                // Only the clock started, which nobody is told about
+                self.state_map.insert(copy url, DecodedAnimated(frames, image, new_clock));
+                loop;
+            }
+
+            let image = ARC(~copy arc::get(frames)[new_clock.current].image);
+            self.set_state(copy url, DecodedAnimated(frames, @clone_arc(&image), new_clock));
+            match self.animation_subscribers.find(&url) {
+                Some(subscribers) => {
+                    for subscribers.each |subscriber| {
+                        subscriber.send(ImageReady(clone_arc(&image)));
+                    }
+                }
+                None => ()
+            }
+        }
+    }
+
+    priv fn purge_waiters(url: Url, f: fn() -> ImageResponseMsg) {
+        if self.scaled_wait_map.contains_key(&url) {
+            match f() {
+                // Waiters are answered once their sizes are scaled to
+                ImageReady(move image) => self.spawn_scale(copy url, move image),
+                ImageReadyAnimated(ref frames) => self.spawn_scale(copy url, first_frame(frames)),
+                ImageFailed(reason) => {
+                    for self.scaled_wait_map.get(&url).each |waiter| {
+                        match *waiter {
+                            (_, ref response) => response.send(ImageFailed(reason))
+                        }
+                    }
+                    self.scaled_wait_map.remove(&url);
+                }
+                ImageNotReady => ()
+            }
+        }
+
+        match self.progressive_wait_map.find(&url) {
+            Some(waiters) => {
+                for waiters.each |waiter| {
+                    waiter.try_send(f());
+                }
+                // Which closes their channels
+                self.progressive_wait_map.remove(&url);
+            }
+            None => ()
+        }
+
+        match self.timed_wait_map.find(&url) {
+            Some(waiters) => {
+                for waiters.each |waiter| {
+                    match *waiter {
+                        (_, ref response) => response.send(f())
+                    }
+                }
+                self.timed_wait_map.remove(&url);
+            }
+            None => ()
+        }
+
+        match self.wait_map.find(&url) {
+          Some(waiters) => {
+            let waiters = &mut *waiters;
+            let mut new_waiters = ~[];
+            new_waiters <-> *waiters;
+
+            for new_waiters.each |response| {
+                response.send(f());
+            }
+
+            *waiters <-> new_waiters;
+            self.wait_map.remove(&url);
+          }
+          None => ()
+        }
+    }
+
+
+    priv fn get_image(url: Url, response: Chan<ImageResponseMsg>) {
+        match self.get_state(copy url) {
+          Init => fail!(~"request for image before prefetch"),
 
           Prefetching(DoDecode) => {
             response.send(ImageNotReady);
@@ -406,15 +1705,43 @@ impl ImageCache {
           }
 
           Decoded(image) => {
+            self.touch(&url);
             response.send(ImageReady(clone_arc(image)));
           }
 
-          Failed => {
-            response.send(ImageFailed);
+          DecodedAnimated(frames, _, _) => {
+            self.touch(&url);
+            response.send(ImageReadyAnimated(clone_arc(frames)));
+          }
+
+          Failed(reason) => {
+            response.send(ImageFailed(Some(reason)));
           }
         }
     }
 
+    priv fn get_image_if_decoded(url: Url, response: Chan<ImageResponseMsg>) {
+        match self.get_state(copy url) {
+            Decoded(image) => {
+                self.touch(&url);
+                response.send(ImageReady(clone_arc(image)));
+            }
+
+            DecodedAnimated(frames, _, _) => {
+                self.touch(&url);
+                response.send(ImageReadyAnimated(clone_arc(frames)));
+            }
+
+            Failed(reason) => {
+                response.send(ImageFailed(Some(reason)));
+            }
+
+            Init | Prefetching(*) | Prefetched(*) | Decoding => {
+                response.send(ImageNotReady);
+            }
+        }
+    }
+
     priv fn wait_for_image(url: Url, response: Chan<ImageResponseMsg>) {
         match self.get_state(copy url) {
             Init => fail!(~"request for image before prefetch"),
@@ -423,169 +1750,2821 @@ impl ImageCache {
 
             Prefetching(DoDecode) | Decoding => {
                 // We don't have this image yet
-                match self.wait_map.find(&url) {
-                    Some(waiters) => {
-                        vec::push(&mut *waiters, move response);
-                    }
-                    None => {
-                        self.wait_map.insert(move url, @mut ~[move response]);
-                    }
-                }
+                let waiters = self.wait_map.find_or_insert(move url, @mut ~[]);
+                vec::push(&mut *waiters, move response);
             }
 
             Decoded(image) => {
+                self.touch(&url);
                 response.send(ImageReady(clone_arc(image)));
             }
 
-            Failed => {
-                response.send(ImageFailed);
+            DecodedAnimated(frames, _, _) => {
+                self.touch(&url);
+                response.send(ImageReadyAnimated(clone_arc(frames)));
+            }
+
+            Failed(reason) => {
+                response.send(ImageFailed(Some(reason)));
             }
         }
     }
 
-}
 
+    priv fn wait_for_image_with_timeout(url: Url, timeout_ms: uint,
+                                        response: Chan<ImageResponseMsg>) {
+        match self.get_state(copy url) {
+            Prefetching(DoDecode) | Decoding => {
+                let id = self.next_waiter_id;
+                self.next_waiter_id += 1;
+                let waiters = self.timed_wait_map.find_or_insert(copy url, @mut ~[]);
+                vec::push(&mut *waiters, (id, move response));
 
-trait ImageCacheTaskClient {
-    fn exit();
-}
+                let to_cache = self.chan.clone();
+                let url_cell = Cell(move url);
+                do spawn |move to_cache, move url_cell| {
+                    timer::sleep(uv_global_loop::get(), timeout_ms);
+                    // The cache may have exited once the image arrived
+                    to_cache.try_send(WaitTimedOut(url_cell.take(), id));
+                }
+            }
+            // Answered at once, or an error, as for WaitForImage
+            _ => self.wait_for_image(move url, move response)
+        }
+    }
 
-impl ImageCacheTask: ImageCacheTaskClient {
+    // Gives up on a waiter, unless it has been answered already
+    priv fn wait_timed_out(url: Url, id: uint) {
+        let waiters = match self.timed_wait_map.find(&url) {
+            Some(waiters) => waiters,
+            None => return
+        };
+        match waiters.position(|&(waiter_id, _)| waiter_id == id) {
+            Some(i) => {
+                let (_, response) = vec::remove(&mut *waiters, i);
+                response.send(ImageFailed(Some(TimedOut)));
+            }
+            None => ()
+        }
+        if waiters.is_empty() {
+            self.timed_wait_map.remove(&url);
+        }
+    }
 
-    fn exit() {
-        let (response_port, response_chan) = stream();
-        self.send(Exit(move response_chan));
-        response_port.recv();
+    priv fn get_image_size(url: Url, response: Chan<Option<(uint, uint)>>) {
+        match self.image_size(copy url) {
+            Some(size) => response.send(size),
+            None => {
+                let waiters = self.size_wait_map.find_or_insert(move url, @mut ~[]);
+                vec::push(&mut *waiters, move response);
+            }
+        }
     }
 
-}
+    /**
+    The size of an image if it can be told yet, reading it from the image's
+    header if the header hasn't been read already. The header of an image
+    being decoded has been read if it can be.
+    */
+    priv fn image_size(url: Url) -> Option<Option<(uint, uint)>> {
+        match self.sizes.find(&url) {
+            Some(size) => return Some(Some((size.width, size.height))),
+            None => ()
+        }
 
-fn load_image_data(url: Url, resource_task: ResourceTask) -> Result<~[u8], ()> {
-    let (response_port, response_chan) = stream();
-    resource_task.send(resource_task::Load(move url, response_chan));
+        match self.get_state(copy url) {
+            Init => Some(None),
+            Prefetching(*) => None,
+            Decoding => Some(None),
+            Prefetched(data) => {
+                let size = header::dimensions(*arc::get(data));
+                match size {
+                    Some((width, height)) => {
+                        self.sizes.insert(move url, Size2D(width, height));
+                    }
+                    None => ()
+                }
+                Some(size)
+            }
+            Decoded(image) | DecodedAnimated(_, image, _) => {
+                Some(Some((arc::get(image).width, arc::get(image).height)))
+            }
+            Failed(*) => Some(None)
+        }
+    }
 
-    let mut image_data = ~[];
+    // Answers the clients waiting on the size of an image, if it can be told
+    priv fn purge_size_waiters(url: Url) {
+        let size = match self.size_wait_map.find(&url) {
+            Some(_) => match self.image_size(copy url) {
+                Some(size) => size,
+                None => return
+            },
+            None => return
+        };
+        self.send_size_to_waiters(&url, size);
+    }
 
-    loop {
-        match response_port.recv() {
-            resource_task::Payload(data) => {
-                image_data += data;
+    // Sends a size to the clients waiting on an image's, if any
+    priv fn send_size_to_waiters(url: &Url, size: Option<(uint, uint)>) {
+        match self.size_wait_map.find(url) {
+            Some(waiters) => {
+                for waiters.each |response| {
+                    response.send(size);
+                }
+                self.size_wait_map.remove(url);
             }
-            resource_task::Done(result::Ok(*)) => {
-                return Ok(move image_data);
+            None => ()
+        }
+    }
+
+    priv fn subscribe_ready(url: Url, response: Chan<ImageResponseMsg>) {
+        self.prefetch(copy url);
+        self.decode(copy url);
+        self.wait_for_image(move url, move response);
+    }
+
+    priv fn get_image_progressive(url: Url, response: Chan<ImageResponseMsg>) {
+        match self.get_state(copy url) {
+            Init | Prefetching(*) | Prefetched(*) | Decoding => {
+                // Before prefetching, so that a fetch started now decodes as it goes
+                let waiters = self.progressive_wait_map.find_or_insert(copy url, @mut ~[]);
+                vec::push(&mut *waiters, move response);
+                self.prefetch(copy url);
+                self.decode(move url);
             }
-            resource_task::Done(result::Err(*)) => {
-                return Err(());
+            Decoded(*) | DecodedAnimated(*) | Failed(*) => {
+                self.wait_for_image(move url, move response)
             }
         }
     }
-}
 
-fn default_decoder_factory() -> ~fn(&[u8]) -> Option<Image> {
-    fn~(data: &[u8]) -> Option<Image> { load_from_memory(data) }
-}
+    priv fn store_partial_image(url: Url, image: ARC<~Image>) {
+        match self.get_state(copy url) {
+            // Partial images from a cancelled fetch, or that come after the
+            // whole image, are dropped
+            Prefetching(*) => {
+                match self.progressive_wait_map.find(&url) {
+                    Some(waiters) => {
+                        for waiters.each |waiter| {
+                            waiter.try_send(ImageReady(clone_arc(&image)));
+                        }
+                    }
+                    None => ()
+                }
+            }
+            Init | Prefetched(*) | Decoding | Decoded(*) | DecodedAnimated(*) | Failed(*) => ()
+        }
+    }
 
-#[cfg(test)]
-fn mock_resource_task(on_load: ~fn(resource: Chan<resource_task::ProgressMsg>)) -> ResourceTask {
-    do spawn_listener |port: Port<resource_task::ControlMsg>, move on_load| {
-        loop {
-            match port.recv() {
-              resource_task::Load(_, response) => {
-                on_load(response);
-              }
-              resource_task::Exit => break
+    priv fn get_scaled_image(url: Url, max_size: (uint, uint),
+                             response: Chan<ImageResponseMsg>) {
+        match self.scaled_image(&url, max_size) {
+            Some(move image) => {
+                response.send(ImageReady(move image));
+                return;
+            }
+            None => ()
+        }
+
+        let already_scaling = self.scaled_sizes_waited_on(&url).contains(&max_size);
+        match self.get_state(copy url) {
+            Failed(reason) => response.send(ImageFailed(Some(reason))),
+            Decoded(image) | DecodedAnimated(_, image, _) => {
+                self.touch(&url);
+                let waiters = self.scaled_wait_map.find_or_insert(copy url, @mut ~[]);
+                vec::push(&mut *waiters, (max_size, move response));
+                if !already_scaling {
+                    self.spawn_scale(move url, clone_arc(image));
+                }
+            }
+            Init | Prefetching(*) | Prefetched(*) | Decoding => {
+                // Before decoding, so that a decode started now scales the image as well
+                let waiters = self.scaled_wait_map.find_or_insert(copy url, @mut ~[]);
+                vec::push(&mut *waiters, (max_size, move response));
+                self.prefetch(copy url);
+                self.decode(move url);
+            }
+        }
+    }
+
+    // The image scaled to fit within `max_size` already, if any
+    priv fn scaled_image(url: &Url, max_size: (uint, uint)) -> Option<ARC<~Image>> {
+        match self.scaled_images.find(url) {
+            Some(images) => {
+                for images.each |scaled| {
+                    match *scaled {
+                        (size, ref image) if size == max_size => {
+                            return Some(clone_arc(image))
+                        }
+                        _ => ()
+                    }
+                }
+                None
+            }
+            None => None
+        }
+    }
+
+    // The sizes clients are waiting on an image to be scaled to fit, once each
+    priv fn scaled_sizes_waited_on(url: &Url) -> ~[(uint, uint)] {
+        let mut sizes = ~[];
+        match self.scaled_wait_map.find(url) {
+            Some(waiters) => {
+                for waiters.each |waiter| {
+                    match *waiter {
+                        (size, _) if !sizes.contains(&size) => sizes.push(size),
+                        _ => ()
+                    }
+                }
+            }
+            None => ()
+        }
+        sizes
+    }
+
+    // Scales a decoded image to the sizes clients are waiting on, off the cache's task
+    priv fn spawn_scale(url: Url, image: ARC<~Image>) {
+        let to_cache = self.chan.clone();
+        let max_sizes = self.scaled_sizes_waited_on(&url);
+        let url_cell = Cell(move url);
+
+        do spawn |move to_cache, move url_cell, move image, move max_sizes| {
+            let url = url_cell.take();
+            send_scaled_images(&to_cache, &url, &**arc::get(&image), max_sizes);
+        }
+    }
+
+    priv fn store_scaled_image(url: Url, max_size: (uint, uint), image: ARC<~Image>) {
+        // Scaled by the decode of a fetch started before the cache was cleared
+        // or the image cancelled
+        if self.cancelled.contains_key(&url) {
+            return;
+        }
+
+        match self.get_state(copy url) {
+            // Scaled from an image that is still current
+            Decoding | Decoded(*) | DecodedAnimated(*) if !self.no_store.contains_key(&url) => {
+                let images = self.scaled_images.find_or_insert(copy url, @mut ~[]);
+                if images.len() == MAX_SCALED_IMAGES {
+                    let (_, oldest) = images.shift();
+                    self.decoded_bytes -= image_size_in_bytes(&oldest);
+                }
+                vec::push(&mut *images, (max_size, clone_arc(&image)));
+                self.decoded_bytes += image_size_in_bytes(&image);
+                self.evict_to_budget();
+            }
+            _ => ()
+        }
+
+        match self.scaled_wait_map.find(&url) {
+            Some(waiters) => {
+                let mut waiting = ~[];
+                for vec::consume(replace(&mut *waiters, ~[])) |_, waiter| {
+                    match move waiter {
+                        (size, move response) => {
+                            if size == max_size {
+                                response.send(ImageReady(clone_arc(&image)));
+                            } else {
+                                waiting.push((size, move response));
+                            }
+                        }
+                    }
+                }
+                if waiting.is_empty() {
+                    self.scaled_wait_map.remove(&url);
+                } else {
+                    *waiters = move waiting;
+                }
+            }
+            None => ()
+        }
+    }
+
+    priv fn revalidate(url: Url) {
+        match self.get_state(copy url) {
+            Decoded(*) | DecodedAnimated(*) => {
+                if self.queued_revalidations.contains(&url) {
+                    // Already waiting to be revalidated
+                } else if self.fetches_in_flight < self.max_fetches {
+                    self.spawn_revalidation(move url);
+                } else {
+                    debug!("image_cache_task: queueing revalidation of %s", url.to_str());
+                    self.queued_revalidations.push(move url);
+                }
+            }
+            // Images that aren't decoded yet are being fetched already, or are
+            // fetched when they are next requested
+            Init | Prefetching(*) | Prefetched(*) | Decoding | Failed(*) => ()
+        }
+    }
+
+    priv fn spawn_revalidation(url: Url) {
+        let to_cache = self.chan.clone();
+        let resource_task = self.resource_task.clone();
+        let timeouts = self.load_timeouts;
+        let validators = self.validators.find(&url).get_or_default(no_validators());
+        // The image is kept as it is
+        let failed = StoreRevalidation(copy url, Err(LoadFailed));
+        let url_cell = Cell(move url);
+
+        do self.spawn_fetch_task(move failed) |move validators| {
+            let url = url_cell.take();
+            debug!("image_cache_task: revalidating %s", url.to_str());
+            let result = load_image_data(copy url, resource_task.clone(), timeouts,
+                                         &validators, |_| (), |_| ());
+            let result = match move result {
+                Ok(Some((move data, move info))) => Ok(Some((ARC(move data), move info))),
+                Ok(None) => Ok(None),
+                Err(error) => Err(error)
+            };
+            to_cache.try_send(StoreRevalidation(move url, move result));
+        }
+    }
+
+    priv fn store_revalidation(url: Url,
+                               result: Result<Option<(ARC<~[u8]>, ResponseInfo)>, NetworkError>) {
+        match self.get_state(copy url) {
+            Decoded(*) | DecodedAnimated(*) => (),
+            // The image was forgotten while it was being revalidated
+            Init | Prefetching(*) | Prefetched(*) | Decoding | Failed(*) => return
+        }
+
+        match move result {
+            Ok(Some((move data, move info))) => {
+                let ResponseInfo { policy: policy, validators: move validators, _ } = move info;
+                let hash = bytes_digest(*arc::get(&data));
+                if policy == MayStore && self.content_hashes.find(&url) == Some(copy hash) {
+                    debug!("image_cache_task: %s was served again unchanged", url.to_str());
+                    self.validators.insert(move url, move validators);
+                    return;
+                }
+
+                debug!("image_cache_task: %s has changed", url.to_str());
+                self.forget_decoded_image(&url);
+                self.remove_from_disk_cache(&url);
+                self.sizes.remove(&url);
+                if policy == NoStore {
+                    self.no_store.insert(copy url, ());
+                } else {
+                    self.content_hashes.insert(copy url, hash);
+                    self.validators.insert(copy url, move validators);
+                }
+                self.set_state(copy url, Prefetched(@move data));
+                self.decode(move url);
+            }
+            Ok(None) => debug!("image_cache_task: %s hasn't changed", url.to_str()),
+            Err(error) => {
+                debug!("image_cache_task: keeping %s, which couldn't be revalidated: %?",
+                       url.to_str(), error);
+            }
+        }
+    }
+
+    priv fn subscribe(url: Url, response: Chan<ImageUpdate>, mode: SubscribeMode) {
+        // Tell the new subscriber where the image has got to already
+        match self.get_state(copy url).update() {
+            Some(move update) => {
+                let terminal = update.is_terminal();
+                if mode == Continuous || terminal {
+                    if !response.try_send(move update) {
+                        return;
+                    }
+                }
+                if mode == Once && terminal {
+                    return;
+                }
+            }
+            None => ()
+        }
+        if mode == Continuous {
+            match self.sizes.find(&url) {
+                Some(size) => {
+                    if !response.try_send(UpdateSize(size)) {
+                        return;
+                    }
+                }
+                None => ()
+            }
+        }
+
+        let subscribers = self.subscribers.find_or_insert(copy url, @mut ~[]);
+        vec::push(&mut *subscribers, Subscriber { chan: move response, mode: mode });
+        self.prefetch(copy url);
+        self.decode(move url);
+    }
+
+    // Sends an update to the subscribers it concerns, dropping those that are
+    // done with it or whose ports are gone
+    priv fn notify_subscribers(url: &Url, update: ImageUpdate) {
+        match self.subscribers.find(url) {
+            Some(subscribers) => {
+                let terminal = update.is_terminal();
+                let mut current = ~[];
+                current <-> *subscribers;
+                for vec::consume(move current) |_, subscriber| {
+                    let keep = match subscriber.mode {
+                        Once if !terminal => true,
+                        Once => {
+                            subscriber.chan.try_send(update.clone());
+                            false
+                        }
+                        Continuous => subscriber.chan.try_send(update.clone())
+                    };
+                    if keep {
+                        subscribers.push(move subscriber);
+                    }
+                }
+                if subscribers.is_empty() {
+                    self.subscribers.remove(url);
+                }
             }
+            None => ()
+        }
+    }
+
+    priv fn pin(url: Url) {
+        self.pinned.insert(copy url, ());
+        self.prefetch(copy url);
+        self.decode(move url);
+    }
+
+    priv fn unpin(url: Url) {
+        self.pinned.remove(&url);
+        self.evict_to_budget();
+    }
+
+    /// Evicts the least recently used unpinned decoded images until the decoded
+    /// images fit in the memory budget. Evicted URLs go back to the Init state.
+    priv fn evict_to_budget() {
+        let budget = match self.memory_budget {
+            Some(budget) => budget,
+            None => return
+        };
+
+        let mut i = 0;
+        while self.decoded_bytes > budget && i < self.decoded_order.len() {
+            let url = copy self.decoded_order[i];
+            if self.pinned.contains_key(&url) {
+                i += 1;
+                loop;
+            }
+
+            debug!("image_cache_task: evicting %s", url.to_str());
+            self.forget_decoded_image(&url);
+        }
+    }
+
+    // Moves a decoded image to the back of the eviction order
+    priv fn touch(url: &Url) {
+        match self.decoded_order.position(|u| *u == *url) {
+            Some(i) => {
+                let url = self.decoded_order.remove(i);
+                self.decoded_order.push(move url);
+            }
+            None => fail!(~"decoded image missing from the eviction order")
+        }
+    }
+
+    // The disk cache file of the image decoded from a URL, if there is a disk
+    // cache. Blob URLs don't outlive the session that registered them.
+    priv fn disk_cache_file(url: &Url) -> Option<Path> {
+        match self.disk_cache {
+            Some(ref dir) if url.scheme != ~"blob" => {
+                Some(disk_cache_path(dir, url, self.alpha_mode))
+            }
+            Some(*) | None => None
+        }
+    }
+
+    priv fn write_disk_cache(url: &Url, image: &ARC<~Image>) {
+        match (self.disk_cache_file(url), &self.disk_writer) {
+            (Some(move path), &Some(ref writer)) => {
+                writer.send(WriteCachedImage(move path, copy *url, clone_arc(image)))
+            }
+            _ => ()
+        }
+    }
+
+    // Deletes the disk cache files of a URL, in every alpha mode
+    priv fn remove_from_disk_cache(url: &Url) {
+        match (&self.disk_cache, &self.disk_writer) {
+            (&Some(ref dir), &Some(ref writer)) => {
+                for [Straight, Premultiplied].each |alpha_mode| {
+                    writer.send(RemoveCachedImage(disk_cache_path(dir, url, *alpha_mode)));
+                }
+            }
+            _ => ()
+        }
+    }
+
+    // Waits for the disk cache writes asked for so far to finish
+    priv fn sync_disk_writer() {
+        match self.disk_writer {
+            Some(ref writer) => {
+                let (response_port, response_chan) = stream();
+                writer.send(SyncDiskCache(move response_chan));
+                response_port.recv();
+            }
+            None => ()
+        }
+    }
+
+    /// Drops a decoded image, returning its URL to the Init state
+    priv fn forget_decoded_image(url: &Url) {
+        match self.get_state(copy *url) {
+            Decoded(image) => self.decoded_bytes -= image_size_in_bytes(image),
+            DecodedAnimated(frames, _, _) => {
+                for arc::get(frames).each |frame| {
+                    self.decoded_bytes -= frame.image.data.len();
+                }
+            }
+            _ => fail!(~"forgetting an image that isn't decoded")
+        }
+        self.state_map.remove(url);
+        match self.decoded_order.position(|u| *u == *url) {
+            Some(i) => { self.decoded_order.remove(i); }
+            None => fail!(~"decoded image missing from the eviction order")
+        }
+        // Other URLs sharing the image keep it, but don't lend it out again
+        match self.content_hashes.find(url) {
+            Some(hash) => {
+                self.decoded_by_hash.remove(&hash);
+                self.content_hashes.remove(url);
+            }
+            None => ()
+        }
+        self.validators.remove(url);
+        match self.scaled_images.find(url) {
+            Some(images) => {
+                for images.each |&(_, ref image)| {
+                    self.decoded_bytes -= image_size_in_bytes(image);
+                }
+                self.scaled_images.remove(url);
+            }
+            None => ()
+        }
+    }
+
+    priv fn revoke_blob(url: Url) {
+        self.blobs.remove(&url);
+
+        match self.get_state(copy url) {
+            Decoded(*) | DecodedAnimated(*) => self.forget_decoded_image(&url),
+            Prefetched(*) | Failed(*) => { self.state_map.remove(&url); }
+            // An in-flight decode finishes with the bytes it already has
+            Init | Prefetching(*) | Decoding => ()
+        }
+    }
+
+    /// Forgets every image. In-flight work is cancelled and its waiters fail.
+    priv fn clear() {
+        let mut in_flight = ~[];
+        for self.state_map.each |url, state| {
+            match *state {
+                Prefetching(*) | Decoding => in_flight.push(copy *url),
+                Init | Prefetched(*) | Decoded(*) | DecodedAnimated(*) | Failed(*) => ()
+            }
+        }
+        for in_flight.each |url| {
+            if !self.dequeue_decode(url) && !self.dequeue_fetch(url) {
+                self.mark_cancelled(url);
+            }
+        }
+
+        let mut waited_on = ~[];
+        for self.wait_map.each_key |url| {
+            waited_on.push(copy *url);
+        }
+        for self.timed_wait_map.each_key |url| {
+            if !waited_on.contains(url) {
+                waited_on.push(copy *url);
+            }
+        }
+        for self.progressive_wait_map.each_key |url| {
+            if !waited_on.contains(url) {
+                waited_on.push(copy *url);
+            }
+        }
+        for self.scaled_wait_map.each_key |url| {
+            if !waited_on.contains(url) {
+                waited_on.push(copy *url);
+            }
+        }
+        for waited_on.each |url| {
+            self.purge_waiters(copy *url, || ImageFailed(None));
+        }
+        let mut waited_on_sizes = ~[];
+        for self.size_wait_map.each_key |url| {
+            waited_on_sizes.push(copy *url);
+        }
+        for waited_on_sizes.each |url| {
+            self.send_size_to_waiters(url, None);
+        }
+
+        self.state_map.clear();
+        self.decode_priorities.clear();
+        self.no_store.clear();
+        self.srcsets.clear();
+        self.animation_subscribers.clear();
+        self.subscribers.clear();
+        self.sizes.clear();
+        self.content_hashes.clear();
+        self.validators.clear();
+        self.scaled_images.clear();
+        self.decoded_by_hash.clear();
+        self.decoded_order = ~[];
+        self.decoded_bytes = 0;
+    }
+
+    /// Forgets an image that is being fetched or decoded, dropping the result
+    /// of the work in flight. Its waiters fail.
+    priv fn cancel(url: Url) {
+        match self.get_state(copy url) {
+            Prefetching(*) | Decoding => {
+                if !self.dequeue_decode(&url) && !self.dequeue_fetch(&url) {
+                    self.mark_cancelled(&url);
+                }
+                self.purge_waiters(copy url, || ImageFailed(None));
+                self.send_size_to_waiters(&url, None);
+                self.state_map.remove(&url);
+                self.decode_priorities.remove(&url);
+                self.no_store.remove(&url);
+                self.subscribers.remove(&url);
+                self.sizes.remove(&url);
+                self.content_hashes.remove(&url);
+                self.validators.remove(&url);
+            }
+            Init | Prefetched(*) | Decoded(*) | DecodedAnimated(*) | Failed(*) => ()
+        }
+    }
+
+    priv fn purge(url: Url) {
+        self.remove_from_disk_cache(&url);
+        match self.get_state(copy url) {
+            Prefetching(*) | Decoding => self.cancel(move url),
+            Decoded(*) | DecodedAnimated(*) => self.forget_decoded_image(&url),
+            Prefetched(*) | Failed(*) => {
+                self.state_map.remove(&url);
+                self.no_store.remove(&url);
+                self.sizes.remove(&url);
+                self.content_hashes.remove(&url);
+                self.validators.remove(&url);
+            }
+            Init => ()
+        }
+    }
+
+    // Drops the result of the fetch or decode of a URL that is in flight
+    priv fn mark_cancelled(url: &Url) {
+        let count = self.cancelled.find(url).get_or_default(0);
+        self.cancelled.insert(copy *url, count + 1);
+    }
+
+    // Whether a finished fetch or decode was started before the cache was
+    // cleared or the image cancelled, in which case its result is to be dropped
+    priv fn take_cancelled(url: &Url) -> bool {
+        match self.cancelled.find(url) {
+            Some(1) => {
+                self.cancelled.remove(url);
+                true
+            }
+            Some(count) => {
+                self.cancelled.insert(copy *url, count - 1);
+                true
+            }
+            None => false
+        }
+    }
+
+    priv fn stats() -> CacheStats {
+        let mut stats = CacheStats {
+            init: 0, prefetching: 0, prefetched: 0, decoding: 0, decoded: 0, failed: 0,
+            decoded_bytes: self.decoded_bytes
+        };
+        for self.state_map.each_value |state| {
+            match *state {
+                Init => stats.init += 1,
+                Prefetching(*) => stats.prefetching += 1,
+                Prefetched(*) => stats.prefetched += 1,
+                Decoding => stats.decoding += 1,
+                Decoded(*) | DecodedAnimated(*) => stats.decoded += 1,
+                Failed(*) => stats.failed += 1
+            }
+        }
+        stats
+    }
+
+    priv fn list_urls(response: Chan<~[(Url, ImageStateTag)]>) {
+        let mut urls = ~[];
+        for self.state_map.each |url, state| {
+            match state.tag() {
+                Some(tag) => urls.push((copy *url, tag)),
+                None => ()
+            }
+        }
+        response.send(move urls);
+    }
+}
+
+
+pub trait ImageCacheTaskClient {
+    fn exit();
+    fn get_decoded(url: Url) -> Option<ARC<~Image>>;
+    fn wait_decoded(url: Url) -> Result<ARC<~Image>, ()>;
+}
+
+impl ImageCacheTask: ImageCacheTaskClient {
+
+    fn exit() {
+        let (response_port, response_chan) = stream();
+        self.send(Exit(move response_chan));
+        response_port.recv();
+    }
+
+    /// The image if it has been decoded, without waiting for it, or None
+    /// whatever state it is in otherwise. Animated images give their first frame.
+    fn get_decoded(url: Url) -> Option<ARC<~Image>> {
+        let (response_port, response_chan) = stream();
+        self.send(GetImageIfDecoded(move url, move response_chan));
+        match response_port.recv() {
+            ImageReady(move image) => Some(move image),
+            ImageReadyAnimated(frames) => Some(first_frame(&frames)),
+            ImageNotReady | ImageFailed(*) => None
+        }
+    }
+
+    /// Waits for the image to be decoded, as WaitForImage does
+    fn wait_decoded(url: Url) -> Result<ARC<~Image>, ()> {
+        let (response_port, response_chan) = stream();
+        self.send(WaitForImage(move url, move response_chan));
+        match response_port.recv() {
+            ImageReady(move image) => Ok(move image),
+            ImageReadyAnimated(frames) => Ok(first_frame(&frames)),
+            ImageNotReady => fail!(~"WaitForImage answered ImageNotReady"),
+            ImageFailed(*) => Err(())
+        }
+    }
+
+}
+
+/**
+The image scaled down to fit within `max_size`, keeping its aspect ratio, or
+a copy of it if it fits already
+*/
+fn scale_to_fit(image: &Image, max_size: (uint, uint)) -> Image {
+    let (max_width, max_height) = max_size;
+    if image.width <= max_width && image.height <= max_height {
+        return copy *image;
+    }
+    let scale = float::fmin((max_width as float) / (image.width as float),
+                            (max_height as float) / (image.height as float));
+    let width = uint::max(1, ((image.width as float) * scale) as uint);
+    let height = uint::max(1, ((image.height as float) * scale) as uint);
+    image.resize(Size2D(width, height), Box)
+}
+
+// Scales an image to fit within each size and posts the results back to the cache
+fn send_scaled_images(to_cache: &SharedChan<Msg>, url: &Url, image: &Image,
+                      max_sizes: &[(uint, uint)]) {
+    for max_sizes.each |&max_size| {
+        let scaled = scale_to_fit(image, max_size);
+        to_cache.try_send(StoreScaledImage(copy *url, max_size, ARC(~move scaled)));
+    }
+}
+
+/// A copy of the first frame of an animated image, for clients that show one frame
+pub fn first_frame(frames: &ARC<~[ImageFrame]>) -> ARC<~Image> {
+    ARC(~copy arc::get(frames)[0].image)
+}
+
+pure fn image_size_in_bytes(image: &ARC<~Image>) -> uint {
+    arc::get(image).data.len()
+}
+
+/**
+The SHA-1 digest of fetched bytes, in hex. Images are shared between URLs
+whose bytes have the same digest, so it must be collision resistant.
+*/
+fn bytes_digest(data: &[u8]) -> ~str {
+    let sha = sha1::sha1();
+    sha.input(data);
+    sha.result_str()
+}
+
+// The file in a disk cache directory holding the image decoded from a URL.
+// Images converted to other alpha modes are kept apart.
+fn disk_cache_path(dir: &Path, url: &Url, alpha_mode: AlphaMode) -> Path {
+    let alpha = match alpha_mode {
+        Straight => ~"straight",
+        Premultiplied => ~"premultiplied"
+    };
+    let key = bytes_digest(str::to_bytes(to_str(url) + ~" " + alpha));
+    dir.push(key + ~".img")
+}
+
+/// Work for the disk cache writer, which does it in the order asked
+enum DiskCacheMsg {
+    /// Write the image decoded from a URL to a file
+    WriteCachedImage(Path, Url, ARC<~Image>),
+    /// Delete a file, if there is one
+    RemoveCachedImage(Path),
+    /// Reply once the work asked for before is done
+    SyncDiskCache(Chan<()>)
+}
+
+// Spawns the task that writes and deletes disk cache files, so that the
+// image cache doesn't wait on the disk. It exits with the image cache.
+fn spawn_disk_cache_writer() -> Chan<DiskCacheMsg> {
+    let (port, chan) = stream();
+    do spawn |move port| {
+        loop {
+            match port.try_recv() {
+                Some(WriteCachedImage(move path, move url, move image)) => {
+                    match write_cached_image(&path, &url, arc::get(&image)) {
+                        Ok(()) => (),
+                        Err(move e) => {
+                            debug!("image_cache_task: couldn't write %s: %s", path.to_str(), e)
+                        }
+                    }
+                }
+                Some(RemoveCachedImage(move path)) => {
+                    if os::path_exists(&path) {
+                        os::remove_file(&path);
+                    }
+                }
+                Some(SyncDiskCache(move response)) => response.send(()),
+                None => break
+            }
+        }
+    }
+    move chan
+}
+
+/**
+Writes the pixels of a decoded image to a file, after the URL it was decoded
+from and its width, height and depth. The URL and the numbers are preceded by
+their length as a big-endian u32, and the numbers are big-endian u32s.
+*/
+fn write_cached_image(path: &Path, url: &Url, image: &Image) -> Result<(), ~str> {
+    let writer = match io::file_writer(path, ~[io::Create, io::Truncate]) {
+        Ok(move writer) => move writer,
+        Err(move e) => return Err(move e)
+    };
+    let url = str::to_bytes(to_str(url));
+    writer.write_be_u32(url.len() as u32);
+    writer.write(url);
+    for [image.width, image.height, image.depth].each |n| {
+        writer.write_be_u32(*n as u32);
+    }
+    writer.write(image.data);
+    Ok(())
+}
+
+/**
+Reads an image written by `write_cached_image`, if the file holds one decoded
+from `url`. A different URL means the file name's digest collided.
+*/
+fn read_cached_image(path: &Path, url: &Url) -> Option<Image> {
+    let bytes = match io::read_whole_file(path) {
+        Ok(move bytes) => move bytes,
+        Err(*) => return None
+    };
+    let read_u32 = |i: uint| {
+        (bytes[i] as uint << 24) | (bytes[i + 1] as uint << 16) |
+            (bytes[i + 2] as uint << 8) | bytes[i + 3] as uint
+    };
+    if bytes.len() < 4 {
+        return None;
+    }
+    let url_end = 4 + read_u32(0);
+    if url_end + 12 > bytes.len() ||
+            vec::view(bytes, 4, url_end) != str::to_bytes(to_str(url)) {
+        debug!("image_cache_task: ignoring cache file %s of another url", path.to_str());
+        return None;
+    }
+
+    let (width, height, depth) = (read_u32(url_end), read_u32(url_end + 4),
+                                  read_u32(url_end + 8));
+    let pixels_start = url_end + 12;
+    // The length is divided out, as multiplying the dimensions may overflow
+    let pixels_len = bytes.len() - pixels_start;
+    if width == 0 || depth == 0 || pixels_len % depth != 0 ||
+            pixels_len / depth % width != 0 || pixels_len / depth / width != height {
+        debug!("image_cache_task: ignoring truncated cache file %s", path.to_str());
+        return None;
+    }
+    Some(Image(width, height, depth, vec::slice(bytes, pixels_start, bytes.len())))
+}
+
+/// The `Accept` header of image loads, listing the formats that can be decoded
+const IMAGE_ACCEPT: &static/str = "image/png,image/jpeg,image/gif,image/bmp,image/*;q=0.8,*/*;q=0.5";
+
+/**
+Fetches the bytes of an image. `size_known` is called once with the
+dimensions the image's header declares, as soon as enough bytes have arrived
+to read them, which is usually with the first payload. `data_received` is
+called with all the bytes that have arrived after each payload. The bytes of
+`data:` URLs are taken from the URL without going through the resource task.
+
+The load is conditional on the image having changed since `validators` were
+given, if there are any; None means it hasn't.
+*/
+fn load_image_data(url: Url, resource_task: ResourceTask, timeouts: Timeouts,
+                   validators: &Validators, size_known: fn(Size2D<uint>),
+                   data_received: fn(&[u8]))
+                -> Result<Option<(~[u8], ResponseInfo)>, NetworkError> {
+    if url.scheme == ~"data" {
+        return match parse_data_url(to_str(&url)) {
+            Some((_, move data)) => {
+                match header::dimensions(data) {
+                    Some((width, height)) => size_known(Size2D(width, height)),
+                    None => ()
+                }
+                Ok(Some((move data, ResponseInfo {
+                    policy: MayStore,
+                    resolved: None,
+                    validators: no_validators()
+                })))
+            }
+            None => {
+                debug!("image_cache_task: malformed data url %s", to_str(&url));
+                Err(LoadFailed)
+            }
+        };
+    }
+
+    let (response_port, response_chan) = stream();
+    let headers = ~[(~"Accept", IMAGE_ACCEPT.to_str())] + validators.request_headers();
+    resource_task.send(resource_task::LoadWithHeaders(move url, move headers, timeouts,
+                                                      response_chan));
+
+    let mut image_data = ~[];
+    let mut policy = MayStore;
+    let mut resolved = None;
+    let mut response_validators = no_validators();
+    let mut not_modified = false;
+    let mut size_reported = false;
+    // The resource task assembles ranges, so any that get here can't be read
+    let mut unassembled = false;
+
+    loop {
+        match response_port.recv() {
+            resource_task::Meta(*) => (),
+            resource_task::Redirected(move target) => resolved = Some(move target),
+            resource_task::NotModified => not_modified = true,
+            resource_task::Header(name, value) => {
+                if is_no_store(name, value) {
+                    policy = NoStore;
+                }
+                match str::to_lower(name) {
+                    ~"etag" => response_validators.etag = Some(copy value),
+                    ~"last-modified" => response_validators.last_modified = Some(copy value),
+                    _ => ()
+                }
+            }
+            resource_task::PartialContent(*) => unassembled = true,
+            resource_task::Payload(_) if unassembled => (),
+            resource_task::Payload(data) => {
+                image_data += data;
+                if !size_reported {
+                    match header::dimensions(image_data) {
+                        Some((width, height)) => {
+                            size_known(Size2D(width, height));
+                            size_reported = true;
+                        }
+                        None => ()
+                    }
+                }
+                data_received(image_data);
+            }
+            resource_task::Done(result::Ok(*)) if unassembled => return Err(LoadFailed),
+            resource_task::Done(result::Ok(*)) => {
+                if not_modified {
+                    return Ok(None);
+                }
+                return Ok(Some((move image_data, ResponseInfo {
+                    policy: policy,
+                    resolved: move resolved,
+                    validators: move response_validators
+                })));
+            }
+            resource_task::Done(result::Err(error)) => {
+                return Err(error);
+            }
+        }
+    }
+}
+
+// Whether a response header forbids keeping the response
+pure fn is_no_store(name: &str, value: &str) -> bool {
+    str::to_lower(name) == ~"cache-control" &&
+        str::split_char(value, ',').any(|directive| {
+            str::to_lower(str::trim(*directive)) == ~"no-store"
+        })
+}
+
+// Bytes that aren't in a format that can be decoded fail without being handed to the decoder
+fn default_decoder_factory() -> ~fn(&[u8]) -> Result<Image, DecodeError> {
+    fn~(data: &[u8]) -> Result<Image, DecodeError> {
+        match header::sniff_image_format(data) {
+            // stb_image decodes each of the formats that can be sniffed
+            PNGFormat | JPEGFormat | GIFFormat | BMPFormat => try_load_from_memory(data),
+            UnknownFormat => {
+                debug!("image_cache_task: not decoding bytes of an unknown format");
+                Err(Unsupported)
+            }
+        }
+    }
+}
+
+// Like default_decoder_factory, but decoding every frame of animated GIFs
+fn default_frame_decoder_factory() -> ~fn(&[u8]) -> Result<~[ImageFrame], DecodeError> {
+    let decode = default_decoder_factory();
+    fn~(data: &[u8], move decode) -> Result<~[ImageFrame], DecodeError> {
+        match header::sniff_image_format(data) {
+            GIFFormat => gif::decode_frames(data),
+            PNGFormat | JPEGFormat | BMPFormat | UnknownFormat => {
+                match decode(data) {
+                    Ok(move image) => Ok(~[ImageFrame { image: move image, delay_ms: 0 }]),
+                    Err(error) => Err(error)
+                }
+            }
+        }
+    }
+}
+
+/// Why an image failed, given why its bytes didn't decode
+pure fn decode_failure(error: DecodeError) -> ImageFailure {
+    match error {
+        Unsupported => UnsupportedFormat,
+        Incomplete => Truncated,
+        TooLarge | Malformed | Corrupt => DecodeFailure
+    }
+}
+
+#[test]
+fn should_exit_on_request() {
+
+    let mock_resource_task = mock_resource_task(|_url, _response| ());
+
+    let image_cache_task = ImageCacheTask(mock_resource_task);
+    let _url = make_url(~"file", None);
+
+    image_cache_task.exit();
+    mock_resource_task.send(resource_task::Exit);
+}
+
+#[test]
+#[should_fail]
+fn should_fail_if_unprefetched_image_is_requested() {
+
+    let mock_resource_task = mock_resource_task(|_url, _response| ());
+
+    let image_cache_task = ImageCacheTask(mock_resource_task);
+    let url = make_url(~"file", None);
+
+    let (chan, port) = stream();
+    image_cache_task.send(GetImage(move url, move chan));
+    port.recv();
+}
+
+#[test]
+fn should_request_url_from_resource_task_on_prefetch() {
+    let url_requested = Port();
+    let url_requested_chan = url_requested.chan();
+
+    let mock_resource_task = do mock_resource_task |_url, response| {
+        url_requested_chan.send(());
+        response.send(resource_task::Done(result::Ok(())));
+    };
+
+    let image_cache_task = ImageCacheTask(mock_resource_task);
+    let url = make_url(~"file", None);
+
+    image_cache_task.send(Prefetch(move url));
+    url_requested.recv();
+    image_cache_task.exit();
+    mock_resource_task.send(resource_task::Exit);
+}
+
+
+#[test]
+#[should_fail]
+fn should_fail_if_requesting_decode_of_an_unprefetched_image() {
+
+    let mock_resource_task = mock_resource_task(|_url, _response| ());
+
+    let image_cache_task = ImageCacheTask(mock_resource_task);
+    let url = make_url(~"file", None);
+
+    image_cache_task.send(Decode(move url));
+    image_cache_task.exit();
+}
+
+#[test]
+#[should_fail]
+fn should_fail_if_requesting_image_before_requesting_decode() {
+
+    let mock_resource_task = do mock_resource_task |_url, response| {
+        response.send(resource_task::Done(result::Ok(())));
+    };
+
+    let image_cache_task = ImageCacheTask(mock_resource_task);
+    let url = make_url(~"file", None);
+
+    image_cache_task.send(Prefetch(copy url));
+    // no decode message
+
+    let (chan, _port) = stream();
+    image_cache_task.send(GetImage(move url, move chan));
+
+    image_cache_task.exit();
+    mock_resource_task.send(resource_task::Exit);
+}
+
+#[test]
+fn should_not_request_url_from_resource_task_on_multiple_prefetches() {
+    let url_requested = comm::Port();
+    let url_requested_chan = url_requested.chan();
+
+    let mock_resource_task = do mock_resource_task |_url, response| {
+        url_requested_chan.send(());
+        response.send(resource_task::Done(result::Ok(())));
+    };
+
+    let image_cache_task = ImageCacheTask(mock_resource_task);
+    let url = make_url(~"file", None);
+
+    image_cache_task.send(Prefetch(copy url));
+    image_cache_task.send(Prefetch(move url));
+    url_requested.recv();
+    image_cache_task.exit();
+    mock_resource_task.send(resource_task::Exit);
+    assert !url_requested.peek()
+}
+
+#[test]
+fn should_return_image_not_ready_if_data_has_not_arrived() {
+
+    let (wait_chan, wait_port) = pipes::stream();
+
+    let mock_resource_task = do mock_resource_task |_url, response, move wait_port| {
+        // Don't send the data until after the client requests
+        // the image
+        wait_port.recv();
+        response.send(resource_task::Payload(test_image_bin()));
+        response.send(resource_task::Done(result::Ok(())));
+    };
+
+    let image_cache_task = ImageCacheTask(mock_resource_task);
+    let url = make_url(~"file", None);
+
+    image_cache_task.send(Prefetch(copy url));
+    image_cache_task.send(Decode(copy url));
+    let (response_chan, response_port) = stream();
+    image_cache_task.send(GetImage(move url, move response_chan));
+    assert response_port.recv() == ImageNotReady;
+    wait_chan.send(());
+    image_cache_task.exit();
+    mock_resource_task.send(resource_task::Exit);
+}
+
+#[test]
+fn should_return_decoded_image_data_if_data_has_arrived() {
+
+    let mock_resource_task = do mock_resource_task |_url, response| {
+        response.send(resource_task::Payload(test_image_bin()));
+        response.send(resource_task::Done(result::Ok(())));
+    };
+
+    let image_cache_task = ImageCacheTask(mock_resource_task);
+    let url = make_url(~"file", None);
+
+    let wait_for_image = comm::Port();
+    let wait_for_image_chan = wait_for_image.chan();
+
+    image_cache_task.send(OnMsg(|msg| {
+        match *msg {
+          StoreImage(*) => wait_for_image_chan.send(()),
+          _ => ()
+        }
+    }));
+
+    image_cache_task.send(Prefetch(copy url));
+    image_cache_task.send(Decode(copy url));
+
+    // Wait until our mock resource task has sent the image to the image cache
+    wait_for_image_chan.recv();
+
+    let (response_chan, response_port) = stream();
+    image_cache_task.send(GetImage(move url, move response_chan));
+    match response_port.recv() {
+      ImageReady(_) => (),
+      _ => fail
+    }
+
+    image_cache_task.exit();
+    mock_resource_task.send(resource_task::Exit);
+}
+
+#[test]
+fn should_return_decoded_image_data_for_multiple_requests() {
+
+    let mock_resource_task = do mock_resource_task |_url, response| {
+        response.send(resource_task::Payload(test_image_bin()));
+        response.send(resource_task::Done(result::Ok(())));
+    };
+
+    let image_cache_task = ImageCacheTask(mock_resource_task);
+    let url = make_url(~"file", None);
+
+    let wait_for_image = comm::Port();
+    let wait_for_image_chan = wait_for_image.chan();
+
+    image_cache_task.send(OnMsg(|msg| {
+        match *msg {
+          StoreImage(*) => wait_for_image_chan.send(()),
+          _ => ()
+        }
+    }));
+
+    image_cache_task.send(Prefetch(copy url));
+    image_cache_task.send(Decode(copy url));
+
+    // Wait until our mock resource task has sent the image to the image cache
+    wait_for_image.recv();
+
+    for iter::repeat(2) {
+        let (response_chan, response_port) = stream();
+        image_cache_task.send(GetImage(copy url, move response_chan));
+        match response_port.recv() {
+          ImageReady(_) => (),
+          _ => fail
+        }
+    }
+
+    image_cache_task.exit();
+    mock_resource_task.send(resource_task::Exit);
+}
+
+#[test]
+fn should_not_request_image_from_resource_task_if_image_is_already_available() {
+
+    let image_bin_sent = comm::Port();
+    let image_bin_sent_chan = image_bin_sent.chan();
+
+    let resource_task_exited = comm::Port();
+    let resource_task_exited_chan = resource_task_exited.chan();
+
+    let mock_resource_task = do spawn_listener |port: comm::Port<resource_task::ControlMsg>| {
+        loop {
+            match port.recv() {
+                resource_task::Load(_, response) |
+                resource_task::LoadWithTimeouts(_, _, response) |
+                resource_task::LoadWithHeaders(_, _, _, response) => {
+                    response.send(resource_task::Payload(test_image_bin()));
+                    response.send(resource_task::Done(result::Ok(())));
+                    image_bin_sent_chan.send(());
+                }
+                resource_task::SetLoadsPerOrigin(*) => (),
+                resource_task::Exit => {
+                    resource_task_exited_chan.send(());
+                    break
+                }
+            }
+        }
+    };
+
+    let image_cache_task = ImageCacheTask(mock_resource_task);
+    let url = make_url(~"file", None);
+
+    image_cache_task.send(Prefetch(copy url));
+
+    // Wait until our mock resource task has sent the image to the image cache
+    image_bin_sent.recv();
+
+    image_cache_task.send(Prefetch(copy url));
+
+    image_cache_task.exit();
+    mock_resource_task.send(resource_task::Exit);
+
+    resource_task_exited.recv();
+
+    // Our resource task should not have received another request for the image
+    // because it's already cached
+    assert !image_bin_sent.peek();
+}
+
+#[test]
+fn should_not_request_image_from_resource_task_if_image_fetch_already_failed() {
+
+    let image_bin_sent = comm::Port();
+    let image_bin_sent_chan = image_bin_sent.chan();
+
+    let resource_task_exited = comm::Port();
+    let resource_task_exited_chan = resource_task_exited.chan();
+
+    let mock_resource_task = do spawn_listener |port: comm::Port<resource_task::ControlMsg>| {
+        loop {
+            match port.recv() {
+                resource_task::Load(_, response) |
+                resource_task::LoadWithTimeouts(_, _, response) |
+                resource_task::LoadWithHeaders(_, _, _, response) => {
+                    response.send(resource_task::Payload(test_image_bin()));
+                    response.send(resource_task::Done(result::Err(resource_task::LoadFailed)));
+                    image_bin_sent_chan.send(());
+                }
+                resource_task::SetLoadsPerOrigin(*) => (),
+                resource_task::Exit => {
+                    resource_task_exited_chan.send(());
+                    break
+                }
+            }
+        }
+    };
+
+    let image_cache_task = ImageCacheTask(mock_resource_task);
+    let url = make_url(~"file", None);
+
+    image_cache_task.send(Prefetch(copy url));
+    image_cache_task.send(Decode(copy url));
+
+    // Wait until our mock resource task has sent the image to the image cache
+    image_bin_sent.recv();
+
+    image_cache_task.send(Prefetch(copy url));
+    image_cache_task.send(Decode(copy url));
+
+    image_cache_task.exit();
+    mock_resource_task.send(resource_task::Exit);
+
+    resource_task_exited.recv();
+
+    // Our resource task should not have received another request for the image
+    // because it's already cached
+    assert !image_bin_sent.peek();
+}
+
+#[test]
+fn should_return_failed_if_image_bin_cannot_be_fetched() {
+
+    let mock_resource_task = do mock_resource_task |_url, response| {
+        response.send(resource_task::Payload(test_image_bin()));
+        // ERROR fetching image
+        response.send(resource_task::Done(result::Err(resource_task::LoadFailed)));
+    };
+
+    let image_cache_task = ImageCacheTask(mock_resource_task);
+    let url = make_url(~"file", None);
+
+    let wait_for_prefetech = comm::Port();
+    let wait_for_prefetech_chan = wait_for_prefetech.chan();
+
+    image_cache_task.send(OnMsg(|msg| {
+        match *msg {
+          StorePrefetchedImageData(*) => wait_for_prefetech_chan.send(()),
+          _ => ()
+        }
+    }));
+
+    image_cache_task.send(Prefetch(copy url));
+    image_cache_task.send(Decode(copy url));
+
+    // Wait until our mock resource task has sent the image to the image cache
+    wait_for_prefetech.recv();
+
+    let (response_chan, response_port) = stream();
+    image_cache_task.send(GetImage(move url, move response_chan));
+    match response_port.recv() {
+      ImageFailed(*) => (),
+      _ => fail
+    }
+
+    image_cache_task.exit();
+    mock_resource_task.send(resource_task::Exit);
+}
+
+#[test]
+fn should_return_failed_for_multiple_get_image_requests_if_image_bin_cannot_be_fetched() {
+
+    let mock_resource_task = do mock_resource_task |_url, response | {
+        response.send(resource_task::Payload(test_image_bin()));
+        // ERROR fetching image
+        response.send(resource_task::Done(result::Err(resource_task::LoadFailed)));
+    };
+
+    let image_cache_task = ImageCacheTask(mock_resource_task);
+    let url = make_url(~"file", None);
+
+    let wait_for_prefetech = comm::Port();
+    let wait_for_prefetech_chan = wait_for_prefetech.chan();
+
+    image_cache_task.send(OnMsg(|msg| {
+        match *msg {
+          StorePrefetchedImageData(*) => wait_for_prefetech_chan.send(()),
+          _ => ()
+        }
+    }));
+
+    image_cache_task.send(Prefetch(copy url));
+    image_cache_task.send(Decode(copy url));
+
+    // Wait until our mock resource task has sent the image to the image cache
+    wait_for_prefetech.recv();
+
+    let (response_chan, response_port) = stream();
+    image_cache_task.send(GetImage(copy url, move response_chan));
+    match response_port.recv() {
+      ImageFailed(*) => (),
+      _ => fail
+    }
+
+    // And ask again, we should get the same response
+    let (response_chan, response_port) = stream();
+    image_cache_task.send(GetImage(move url, move response_chan));
+    match response_port.recv() {
+      ImageFailed(*) => (),
+      _ => fail
+    }
+
+    image_cache_task.exit();
+    mock_resource_task.send(resource_task::Exit);
+}
+
+#[test]
+fn should_return_not_ready_if_image_is_still_decoding() {
+
+    let (wait_to_decode_chan, wait_to_decode_port) = pipes::stream();
+
+    let mock_resource_task = do mock_resource_task |_url, response| {
+        response.send(resource_task::Payload(test_image_bin()));
+        response.send(resource_task::Done(result::Ok(())));
+    };
+
+    let wait_to_decode_port_cell = Cell(move wait_to_decode_port);
+    let decoder_factory = fn~(move wait_to_decode_port_cell) -> ~fn(&[u8]) -> Result<Image, DecodeError> {
+        let wait_to_decode_port = wait_to_decode_port_cell.take();
+        fn~(data: &[u8], move wait_to_decode_port) -> Result<Image, DecodeError> {
+            // Don't decode until after the client requests the image
+            wait_to_decode_port.recv();
+            try_load_from_memory(data)
+        }
+    };
+
+    let image_cache_task = ImageCacheTask_(mock_resource_task, move decoder_factory,
+                                           default_options());
+    let url = make_url(~"file", None);
+
+    let wait_for_prefetech = comm::Port();
+    let wait_for_prefetech_chan = wait_for_prefetech.chan();
+
+    image_cache_task.send(OnMsg(|msg| {
+        match *msg {
+          StorePrefetchedImageData(*) => wait_for_prefetech_chan.send(()),
+          _ => ()
+        }
+    }));
+
+    image_cache_task.send(Prefetch(copy url));
+    image_cache_task.send(Decode(copy url));
+
+    // Wait until our mock resource task has sent the image to the image cache
+    wait_for_prefetech.recv();
+
+    // Make the request
+    let (response_chan, response_port) = stream();
+    image_cache_task.send(GetImage(move url, move response_chan));
+
+    match response_port.recv() {
+      ImageNotReady => (),
+      _ => fail
+    }
+
+    // Now decode
+    wait_to_decode_chan.send(());
+
+    image_cache_task.exit();
+    mock_resource_task.send(resource_task::Exit);
+}
+
+#[test]
+fn should_return_failed_if_image_decode_fails() {
+
+    let mock_resource_task = do mock_resource_task |_url, response| {
+        // Bogus data
+        response.send(resource_task::Payload(~[]));
+        response.send(resource_task::Done(result::Ok(())));
+    };
+
+    let image_cache_task = ImageCacheTask(mock_resource_task);
+    let url = make_url(~"file", None);
+
+    let wait_for_decode = comm::Port();
+    let wait_for_decode_chan = wait_for_decode.chan();
+
+    image_cache_task.send(OnMsg(|msg| {
+        match *msg {
+          StoreImage(*) => wait_for_decode_chan.send(()),
+          _ => ()
+        }
+    }));
+
+    image_cache_task.send(Prefetch(copy url));
+    image_cache_task.send(Decode(copy url));
+
+    // Wait until our mock resource task has sent the image to the image cache
+    wait_for_decode.recv();
+
+    // Make the request
+    let (response_chan, response_port) = stream();
+    image_cache_task.send(GetImage(move url, move response_chan));
+
+    match response_port.recv() {
+      ImageFailed(*) => (),
+      _ => fail
+    }
+
+    image_cache_task.exit();
+    mock_resource_task.send(resource_task::Exit);
+}
+
+#[test]
+fn should_return_image_on_wait_if_image_is_already_loaded() {
+
+    let mock_resource_task = do mock_resource_task |_url, response| {
+        response.send(resource_task::Payload(test_image_bin()));
+        response.send(resource_task::Done(result::Ok(())));
+    };
+
+    let image_cache_task = ImageCacheTask(mock_resource_task);
+    let url = make_url(~"file", None);
+
+    let wait_for_decode = comm::Port();
+    let wait_for_decode_chan = wait_for_decode.chan();
+
+    image_cache_task.send(OnMsg(|msg| {
+        match *msg {
+          StoreImage(*) => wait_for_decode_chan.send(()),
+          _ => ()
+        }
+    }));
+
+    image_cache_task.send(Prefetch(copy url));
+    image_cache_task.send(Decode(copy url));
+
+    // Wait until our mock resource task has sent the image to the image cache
+    wait_for_decode.recv();
+
+    let (response_chan, response_port) = stream();
+    image_cache_task.send(WaitForImage(move url, move response_chan));
+    match response_port.recv() {
+      ImageReady(*) => (),
+      _ => fail
+    }
+
+    image_cache_task.exit();
+    mock_resource_task.send(resource_task::Exit);
+}
+
+#[test]
+fn should_return_image_on_wait_if_image_is_not_yet_loaded() {
+
+    let (wait_chan, wait_port) = pipes::stream();
+
+    let mock_resource_task = do mock_resource_task |_url, response, move wait_port| {
+        wait_port.recv();
+        response.send(resource_task::Payload(test_image_bin()));
+        response.send(resource_task::Done(result::Ok(())));
+    };
+
+    let image_cache_task = ImageCacheTask(mock_resource_task);
+    let url = make_url(~"file", None);
+
+    image_cache_task.send(Prefetch(copy url));
+    image_cache_task.send(Decode(copy url));
+
+    let (response_chan, response_port) = stream();
+    image_cache_task.send(WaitForImage(move url, move response_chan));
+
+    wait_chan.send(());
+
+    match response_port.recv() {
+      ImageReady(*) => (),
+      _ => fail
+    }
+
+    image_cache_task.exit();
+    mock_resource_task.send(resource_task::Exit);
+}
+
+#[test]
+fn should_return_image_failed_on_wait_if_image_fails_to_load() {
+
+    let (wait_chan, wait_port) = pipes::stream();
+
+    let mock_resource_task = do mock_resource_task |_url, response, move wait_port| {
+        wait_port.recv();
+        response.send(resource_task::Payload(test_image_bin()));
+        response.send(resource_task::Done(result::Err(resource_task::LoadFailed)));
+    };
+
+    let image_cache_task = ImageCacheTask(mock_resource_task);
+    let url = make_url(~"file", None);
+
+    image_cache_task.send(Prefetch(copy url));
+    image_cache_task.send(Decode(copy url));
+
+    let (response_chan, response_port) = stream();
+    image_cache_task.send(WaitForImage(move url, move response_chan));
+
+    wait_chan.send(());
+
+    match response_port.recv() {
+      ImageFailed(*) => (),
+      _ => fail
+    }
+
+    image_cache_task.exit();
+    mock_resource_task.send(resource_task::Exit);
+}
+
+#[test]
+fn sync_cache_should_wait_for_images() {
+    let mock_resource_task = do mock_resource_task |_url, response| {
+        response.send(resource_task::Payload(test_image_bin()));
+        response.send(resource_task::Done(result::Ok(())));
+    };
+
+    let image_cache_task = SyncImageCacheTask(mock_resource_task);
+    let url = make_url(~"file", None);
+
+    image_cache_task.send(Prefetch(copy url));
+    image_cache_task.send(Decode(copy url));
+
+    let (response_chan, response_port) = stream();
+    image_cache_task.send(GetImage(move url, move response_chan));
+    match response_port.recv() {
+      ImageReady(_) => (),
+      _ => fail
+    }
+
+    image_cache_task.exit();
+    mock_resource_task.send(resource_task::Exit);
+}
+
+#[test]
+fn sync_cache_should_answer_images_requested_right_after_decode() {
+    let mock_resource_task = do mock_resource_task |_url, response| {
+        response.send(resource_task::Payload(test_image_bin()));
+        response.send(resource_task::Done(result::Ok(())));
+    };
+
+    let image_cache_task = SyncImageCacheTask(mock_resource_task);
+
+    for uint::range(0, 10) |i| {
+        let url = make_url(fmt!("file%u", i), None);
+        image_cache_task.send(Prefetch(copy url));
+        image_cache_task.send(Decode(copy url));
+
+        let (response_port, response_chan) = stream();
+        image_cache_task.send(GetImage(move url, move response_chan));
+        match response_port.recv() {
+          ImageReady(_) => (),
+          _ => fail
+        }
+    }
+
+    image_cache_task.exit();
+    mock_resource_task.send(resource_task::Exit);
+}
+
+#[test]
+fn should_list_cached_urls_with_their_states() {
+    let mock_resource_task = do mock_resource_task |_url, response| {
+        response.send(resource_task::Payload(test_image_bin()));
+        response.send(resource_task::Done(result::Ok(())));
+    };
+
+    let image_cache_task = ImageCacheTask(mock_resource_task);
+    let decoded_url = make_url(~"file1", None);
+    let prefetched_url = make_url(~"file2", None);
+
+    let wait_for_image = comm::Port();
+    let wait_for_image_chan = wait_for_image.chan();
+
+    image_cache_task.send(OnMsg(|msg| {
+        match *msg {
+          StorePrefetchedImageData(*) | StoreImage(*) => wait_for_image_chan.send(()),
+          _ => ()
+        }
+    }));
+
+    image_cache_task.send(Prefetch(copy decoded_url));
+    image_cache_task.send(Prefetch(copy prefetched_url));
+    image_cache_task.send(Decode(copy decoded_url));
+
+    // Two prefetches and one decode
+    for iter::repeat(3) {
+        wait_for_image.recv();
+    }
+
+    let (response_chan, response_port) = stream();
+    image_cache_task.send(ListUrls(move response_chan));
+    let urls = response_port.recv();
+
+    assert urls.len() == 2;
+    assert urls.contains(&(move decoded_url, DecodedTag));
+    assert urls.contains(&(move prefetched_url, PrefetchedTag));
+
+    image_cache_task.exit();
+    mock_resource_task.send(resource_task::Exit);
+}
+
+#[test]
+fn should_not_evict_pinned_images() {
+    let mock_resource_task = do mock_resource_task |_url, response| {
+        response.send(resource_task::Payload(test_image_bin()));
+        response.send(resource_task::Done(result::Ok(())));
+    };
+
+    // Every image decodes to 4 bytes
+    let decoder_factory = fn~() -> ~fn(&[u8]) -> Result<Image, DecodeError> {
+        fn~(_data: &[u8]) -> Result<Image, DecodeError> { Ok(Image(1, 1, 4, ~[0, 0, 0, 0])) }
+    };
+
+    let image_cache_task = ImageCacheTask_(mock_resource_task, move decoder_factory,
+                                           default_options());
+    let pinned_url = make_url(~"pinned", None);
+    let urls = ~[make_url(~"file1", None), make_url(~"file2", None), make_url(~"file3", None)];
+
+    let wait_for_image = comm::Port();
+    let wait_for_image_chan = wait_for_image.chan();
+
+    image_cache_task.send(OnMsg(|msg| {
+        match *msg {
+          StoreImage(*) => wait_for_image_chan.send(()),
+          _ => ()
+        }
+    }));
+
+    // Room for two images
+    image_cache_task.send(SetMemoryBudget(Some(8)));
+
+    image_cache_task.send(Pin(copy pinned_url));
+    wait_for_image.recv();
+
+    for urls.each |url| {
+        image_cache_task.send(Prefetch(copy *url));
+        image_cache_task.send(Decode(copy *url));
+        wait_for_image.recv();
+    }
+
+    let (response_chan, response_port) = stream();
+    image_cache_task.send(ListUrls(move response_chan));
+    let listed = response_port.recv();
+
+    assert listed.len() == 2;
+    assert listed.contains(&(move pinned_url, DecodedTag));
+    assert listed.contains(&(copy urls[2], DecodedTag));
+
+    image_cache_task.exit();
+    mock_resource_task.send(resource_task::Exit);
+}
+
+#[test]
+fn should_evict_the_least_recently_used_image() {
+    let mock_resource_task = do mock_resource_task |_url, response| {
+        response.send(resource_task::Payload(test_image_bin()));
+        response.send(resource_task::Done(result::Ok(())));
+    };
+
+    // Every image decodes to 4 bytes
+    let decoder_factory = fn~() -> ~fn(&[u8]) -> Result<Image, DecodeError> {
+        fn~(_data: &[u8]) -> Result<Image, DecodeError> { Ok(Image(1, 1, 4, ~[0, 0, 0, 0])) }
+    };
+
+    let image_cache_task = ImageCacheTask_(mock_resource_task, move decoder_factory,
+                                           default_options());
+    let urls = ~[make_url(~"file1", None), make_url(~"file2", None), make_url(~"file3", None)];
+
+    let wait_for_image = comm::Port();
+    let wait_for_image_chan = wait_for_image.chan();
+
+    image_cache_task.send(OnMsg(|msg| {
+        match *msg {
+          StoreImage(*) => wait_for_image_chan.send(()),
+          _ => ()
+        }
+    }));
+
+    // Room for two images
+    image_cache_task.send(SetMemoryBudget(Some(8)));
+
+    for urls.view(0, 2).each |url| {
+        image_cache_task.send(Prefetch(copy *url));
+        image_cache_task.send(Decode(copy *url));
+        wait_for_image.recv();
+    }
+
+    // Using the first image makes the second the least recently used
+    let (response_chan, response_port) = stream();
+    image_cache_task.send(GetImage(copy urls[0], move response_chan));
+    match response_port.recv() {
+      ImageReady(*) => (),
+      _ => fail
+    }
+
+    image_cache_task.send(Prefetch(copy urls[2]));
+    image_cache_task.send(Decode(copy urls[2]));
+    wait_for_image.recv();
+
+    let (response_chan, response_port) = stream();
+    image_cache_task.send(ListUrls(move response_chan));
+    let listed = response_port.recv();
+
+    assert listed.len() == 2;
+    assert listed.contains(&(copy urls[0], DecodedTag));
+    assert listed.contains(&(copy urls[2], DecodedTag));
+
+    image_cache_task.exit();
+    mock_resource_task.send(resource_task::Exit);
+}
+
+#[test]
+fn should_notify_subscriber_once_when_image_is_ready() {
+    let mock_resource_task = do mock_resource_task |_url, response| {
+        response.send(resource_task::Payload(test_image_bin()));
+        response.send(resource_task::Done(result::Ok(())));
+    };
+
+    let image_cache_task = ImageCacheTask(mock_resource_task);
+    let url = make_url(~"file", None);
+
+    // No prefetch or decode
+    let (response_chan, response_port) = stream();
+    image_cache_task.send(SubscribeReady(move url, move response_chan));
+
+    match response_port.recv() {
+      ImageReady(*) => (),
+      _ => fail
+    }
+
+    image_cache_task.exit();
+    mock_resource_task.send(resource_task::Exit);
+    assert !response_port.peek();
+}
+
+#[test]
+fn should_notify_subscriber_when_image_fails() {
+    let mock_resource_task = do mock_resource_task |_url, response| {
+        response.send(resource_task::Done(result::Err(resource_task::LoadFailed)));
+    };
+
+    let image_cache_task = ImageCacheTask(mock_resource_task);
+    let url = make_url(~"file", None);
+
+    let (response_chan, response_port) = stream();
+    image_cache_task.send(SubscribeReady(move url, move response_chan));
+    assert response_port.recv() == ImageFailed(Some(NetworkFailure(LoadFailed)));
+
+    image_cache_task.exit();
+    mock_resource_task.send(resource_task::Exit);
+}
+
+#[test]
+fn waiters_should_receive_distinct_failure_reasons() {
+    let undecodable_resource_task = do mock_resource_task |_url, response| {
+        response.send(resource_task::Payload(~[1, 2, 3]));
+        response.send(resource_task::Done(result::Ok(())));
+    };
+    let failing_resource_task = do mock_resource_task |_url, response| {
+        response.send(resource_task::Done(result::Err(resource_task::Timeout)));
+    };
+
+    let decode_url = make_url(~"file", None);
+    let image_cache_task = ImageCacheTask(undecodable_resource_task.clone());
+    image_cache_task.send(Prefetch(copy decode_url));
+    image_cache_task.send(Decode(copy decode_url));
+    let (decode_port, decode_chan) = stream();
+    image_cache_task.send(WaitForImage(copy decode_url, move decode_chan));
+    assert decode_port.recv() == ImageFailed(Some(UnsupportedFormat));
+
+    let network_url = make_url(~"file", None);
+    let failing_cache_task = ImageCacheTask(failing_resource_task.clone());
+    failing_cache_task.send(Prefetch(copy network_url));
+    failing_cache_task.send(Decode(copy network_url));
+    let (network_port, network_chan) = stream();
+    failing_cache_task.send(WaitForImage(copy network_url, move network_chan));
+    assert network_port.recv() == ImageFailed(Some(NetworkFailure(resource_task::Timeout)));
+
+    // Later requests get the same reason as the waiters did
+    let (get_port, get_chan) = stream();
+    failing_cache_task.send(GetImage(move network_url, move get_chan));
+    assert get_port.recv() == ImageFailed(Some(NetworkFailure(resource_task::Timeout)));
+
+    image_cache_task.exit();
+    failing_cache_task.exit();
+    undecodable_resource_task.send(resource_task::Exit);
+    failing_resource_task.send(resource_task::Exit);
+}
+
+#[test]
+fn should_tell_truncated_images_from_corrupt_ones() {
+    let (bytes_port, bytes_chan) = stream();
+    let mock_resource_task = do mock_resource_task |_url, response, move bytes_port| {
+        response.send(resource_task::Payload(bytes_port.recv()));
+        response.send(resource_task::Done(result::Ok(())));
+    };
+
+    let image_cache_task = ImageCacheTask(mock_resource_task);
+
+    // Cut off in the middle of the pixels
+    let gif = test_gif_with_frames(4, 4, [((0, 0, 0), 0)]);
+    let truncated_url = make_url(~"truncated.gif", None);
+    image_cache_task.send(Prefetch(copy truncated_url));
+    image_cache_task.send(Decode(copy truncated_url));
+    bytes_chan.send(vec::slice(gif, 0, gif.len() - 4));
+    let (response_port, response_chan) = stream();
+    image_cache_task.send(WaitForImage(move truncated_url, move response_chan));
+    assert response_port.recv() == ImageFailed(Some(Truncated));
+
+    // Whole, but with a zlib header that isn't one
+    let corrupt_url = make_url(~"corrupt.png", None);
+    image_cache_task.send(Prefetch(copy corrupt_url));
+    image_cache_task.send(Decode(copy corrupt_url));
+    let mut corrupt = test_image_with_color(4, 4, (0, 0, 0, 255));
+    // After the signature, the IHDR chunk and the length and type of the IDAT chunk
+    corrupt[41] = 0;
+    corrupt[42] = 0;
+    bytes_chan.send(move corrupt);
+    let (response_port, response_chan) = stream();
+    image_cache_task.send(WaitForImage(move corrupt_url, move response_chan));
+    assert response_port.recv() == ImageFailed(Some(DecodeFailure));
+
+    image_cache_task.exit();
+    mock_resource_task.send(resource_task::Exit);
+}
+
+#[test]
+fn should_fetch_no_store_images_again_for_each_request() {
+    let loads = comm::Port();
+    let loads_chan = loads.chan();
+    let mock_resource_task = do mock_resource_task |_url, response| {
+        response.send(resource_task::Header(~"Cache-Control", ~"private, No-Store"));
+        response.send(resource_task::Payload(test_image_bin()));
+        response.send(resource_task::Done(result::Ok(())));
+        loads_chan.send(());
+    };
+
+    let image_cache_task = ImageCacheTask(mock_resource_task);
+    let url = make_url(~"file", None);
+
+    for iter::repeat(2) {
+        let (response_port, response_chan) = stream();
+        image_cache_task.send(SubscribeReady(copy url, move response_chan));
+        match response_port.recv() {
+          ImageReady(*) => (),
+          _ => fail!(~"bleh")
+        }
+        loads.recv();
+    }
+
+    // Nothing is kept between requests
+    let (list_port, list_chan) = stream();
+    image_cache_task.send(ListUrls(move list_chan));
+    assert list_port.recv().is_empty();
+
+    image_cache_task.exit();
+    mock_resource_task.send(resource_task::Exit);
+}
+
+#[test]
+fn should_decode_all_prefetched_images_on_decode_all() {
+    let mock_resource_task = do mock_resource_task |_url, response| {
+        response.send(resource_task::Payload(test_image_bin()));
+        response.send(resource_task::Done(result::Ok(())));
+    };
+
+    let image_cache_task = ImageCacheTask(mock_resource_task);
+    let urls = ~[make_url(~"file1", None), make_url(~"file2", None), make_url(~"file3", None)];
+
+    let wait_for_prefetch = comm::Port();
+    let wait_for_prefetch_chan = wait_for_prefetch.chan();
+    let wait_for_image = comm::Port();
+    let wait_for_image_chan = wait_for_image.chan();
+
+    image_cache_task.send(OnMsg(|msg| {
+        match *msg {
+          StorePrefetchedImageData(*) => wait_for_prefetch_chan.send(()),
+          StoreImage(*) => wait_for_image_chan.send(()),
+          _ => ()
+        }
+    }));
+
+    for urls.each |url| {
+        image_cache_task.send(Prefetch(copy *url));
+    }
+    for iter::repeat(3) {
+        wait_for_prefetch.recv();
+    }
+
+    image_cache_task.send(DecodeAll);
+    for iter::repeat(3) {
+        wait_for_image.recv();
+    }
+
+    let (response_chan, response_port) = stream();
+    image_cache_task.send(ListUrls(move response_chan));
+    let listed = response_port.recv();
+
+    assert listed.len() == 3;
+    for urls.each |url| {
+        assert listed.contains(&(copy *url, DecodedTag));
+    }
+
+    image_cache_task.exit();
+    mock_resource_task.send(resource_task::Exit);
+}
+
+#[test]
+fn should_load_registered_blobs_until_revoked() {
+    let mock_resource_task = do mock_resource_task |_url, _response| {
+        fail!(~"blobs should not be loaded through the resource task");
+    };
+
+    let image_cache_task = ImageCacheTask(mock_resource_task);
+    let url = make_url(~"blob://image", None);
+
+    image_cache_task.send(RegisterBlob(copy url, test_image_bin()));
+    image_cache_task.send(Prefetch(copy url));
+    image_cache_task.send(Decode(copy url));
+
+    let (response_chan, response_port) = stream();
+    image_cache_task.send(WaitForImage(copy url, move response_chan));
+    match response_port.recv() {
+      ImageReady(*) => (),
+      _ => fail!(~"bleh")
+    }
+
+    image_cache_task.send(RevokeBlob(copy url));
+    image_cache_task.send(Prefetch(copy url));
+    image_cache_task.send(Decode(copy url));
+
+    let (response_chan, response_port) = stream();
+    image_cache_task.send(WaitForImage(copy url, move response_chan));
+    assert response_port.recv() == ImageFailed(Some(NetworkFailure(LoadFailed)));
+
+    image_cache_task.exit();
+    mock_resource_task.send(resource_task::Exit);
+}
+
+#[test]
+fn sync_should_wait_for_queued_messages() {
+    let mock_resource_task = do mock_resource_task |_url, response| {
+        response.send(resource_task::Payload(test_image_bin()));
+        response.send(resource_task::Done(result::Ok(())));
+    };
+
+    let image_cache_task = ImageCacheTask(mock_resource_task);
+    let urls = ~[make_url(~"file1", None), make_url(~"file2", None), make_url(~"file3", None)];
+
+    for urls.each |url| {
+        image_cache_task.send(Prefetch(copy *url));
+    }
+
+    let (sync_port, sync_chan) = stream();
+    image_cache_task.send(Sync(move sync_chan));
+    sync_port.recv();
+
+    let (response_port, response_chan) = stream();
+    image_cache_task.send(ListUrls(move response_chan));
+    let listed = response_port.recv();
+
+    assert listed.len() == 3;
+    for urls.each |url| {
+        assert listed.contains(&(copy *url, PrefetchingTag))
+            || listed.contains(&(copy *url, PrefetchedTag));
+    }
+
+    image_cache_task.exit();
+    mock_resource_task.send(resource_task::Exit);
+}
+
+#[test]
+fn should_raise_the_priority_of_a_pending_decode() {
+    let (load_port, load_chan) = stream();
+    let mock_resource_task = do mock_resource_task |_url, response, move load_port| {
+        load_port.recv();
+        response.send(resource_task::Payload(test_image_bin()));
+        response.send(resource_task::Done(result::Ok(())));
+    };
+
+    // Counts the decodes started
+    let decodes = comm::Port();
+    let decodes_chan = decodes.chan();
+    let decoder_factory = fn~(move decodes_chan) -> ~fn(&[u8]) -> Result<Image, DecodeError> {
+        decodes_chan.send(());
+        fn~(data: &[u8]) -> Result<Image, DecodeError> { try_load_from_memory(data) }
+    };
+
+    let image_cache_task = ImageCacheTask_(mock_resource_task, move decoder_factory,
+                                           default_options());
+    let url = make_url(~"file", None);
+
+    image_cache_task.send(Prefetch(copy url));
+    image_cache_task.send(DecodeWithPriority(copy url, LowPriority));
+
+    let (priority_port, priority_chan) = stream();
+    image_cache_task.send(GetDecodePriority(copy url, move priority_chan));
+    assert priority_port.recv() == Some(LowPriority);
+
+    image_cache_task.send(DecodeWithPriority(copy url, HighPriority));
+    image_cache_task.send(DecodeWithPriority(copy url, LowPriority));
+
+    let (priority_port, priority_chan) = stream();
+    image_cache_task.send(GetDecodePriority(copy url, move priority_chan));
+    assert priority_port.recv() == Some(HighPriority);
+
+    load_chan.send(());
+
+    let (response_port, response_chan) = stream();
+    image_cache_task.send(WaitForImage(copy url, move response_chan));
+    match response_port.recv() {
+      ImageReady(*) => (),
+      _ => fail
+    }
+
+    // Only one decode ran
+    decodes.recv();
+    assert !decodes.peek();
+
+    image_cache_task.exit();
+    mock_resource_task.send(resource_task::Exit);
+}
+
+#[test]
+fn request_response_should_match_hand_written_requests() {
+    let mock_resource_task = do mock_resource_task |_url, response| {
+        response.send(resource_task::Payload(test_image_bin()));
+        response.send(resource_task::Done(result::Ok(())));
+    };
+
+    let image_cache_task = ImageCacheTask(mock_resource_task);
+    let url = make_url(~"file", None);
+
+    let wait_for_image = comm::Port();
+    let wait_for_image_chan = wait_for_image.chan();
+
+    image_cache_task.send(OnMsg(|msg| {
+        match *msg {
+          StoreImage(*) => wait_for_image_chan.send(()),
+          _ => ()
+        }
+    }));
+
+    image_cache_task.send(Prefetch(copy url));
+    image_cache_task.send(Decode(copy url));
+    wait_for_image.recv();
+
+    let (response_port, response_chan) = stream();
+    image_cache_task.send(GetImage(copy url, move response_chan));
+    let hand_written = response_port.recv();
+    match (request_response!(image_cache_task, GetImage, copy url), hand_written) {
+      (ImageReady(ref image), ImageReady(ref hand_written)) => {
+        assert arc::get(image).data == arc::get(hand_written).data
+      }
+      _ => fail
+    }
+
+    let (response_port, response_chan) = stream();
+    image_cache_task.send(ListUrls(move response_chan));
+    let hand_written = response_port.recv();
+    assert request_response!(image_cache_task, ListUrls) == hand_written;
+    assert hand_written == ~[(move url, DecodedTag)];
+
+    let (response_port, response_chan) = stream();
+    image_cache_task.send(GetStats(move response_chan));
+    let hand_written = response_port.recv();
+    assert request_response!(image_cache_task, GetStats) == hand_written;
+    assert hand_written.decoded == 1;
+
+    image_cache_task.exit();
+    mock_resource_task.send(resource_task::Exit);
+}
+
+#[test]
+fn should_advance_animated_images_with_the_clock() {
+    let mock_resource_task = do mock_resource_task |_url, response| {
+        response.send(resource_task::Payload(~[0]));
+        response.send(resource_task::Done(result::Ok(())));
+    };
+
+    // A black frame for 20ms, then a white one for 30ms
+    let decoder_factory = fn~() -> ~fn(&[u8]) -> Result<~[ImageFrame], DecodeError> {
+        fn~(_data: &[u8]) -> Result<~[ImageFrame], DecodeError> {
+            Ok(~[ImageFrame { image: Image(1, 1, 4, ~[0, 0, 0, 255]), delay_ms: 20 },
+                   ImageFrame { image: Image(1, 1, 4, ~[255, 255, 255, 255]), delay_ms: 30 }])
+        }
+    };
+
+    let image_cache_task = ImageCacheTask_frames(mock_resource_task, move decoder_factory,
+                                                 default_options());
+    let url = make_url(~"file", None);
+
+    let (ready_port, ready_chan) = stream();
+    image_cache_task.send(SubscribeReady(copy url, move ready_chan));
+    match ready_port.recv() {
+      ImageReadyAnimated(frames) => assert arc::get(&frames).len() == 2,
+      _ => fail!(~"expected every frame")
+    }
+
+    let (frame_port, frame_chan) = stream();
+    image_cache_task.send(SubscribeAnimation(copy url, move frame_chan));
+    image_cache_task.send(AdvanceAnimations(1000));
+    image_cache_task.send(AdvanceAnimations(1010));
+    let (sync_port, sync_chan) = stream();
+    image_cache_task.send(Sync(move sync_chan));
+    sync_port.recv();
+    assert !frame_port.peek();
+
+    image_cache_task.send(AdvanceAnimations(1025));
+    match frame_port.recv() {
+      ImageReady(image) => assert arc::get(&image).data == ~[255, 255, 255, 255],
+      _ => fail!(~"expected the second frame")
+    }
+    let (get_port, get_chan) = stream();
+    image_cache_task.send(GetImage(copy url, move get_chan));
+    match get_port.recv() {
+      ImageReadyAnimated(frames) => assert arc::get(&frames)[0].image.data == ~[0, 0, 0, 255],
+      _ => fail!(~"expected every frame")
+    }
+
+    // The second frame started at 1020, so by 1050 the animation has wrapped
+    image_cache_task.send(AdvanceAnimations(1055));
+    match frame_port.recv() {
+      ImageReady(image) => assert arc::get(&image).data == ~[0, 0, 0, 255],
+      _ => fail!(~"expected the first frame again")
+    }
+
+    // A clock far ahead skips the whole loops instead of stepping through them
+    image_cache_task.send(AdvanceAnimations(1050 + 50 * (1u64 << 40) + 25));
+    match frame_port.recv() {
+      ImageReady(image) => assert arc::get(&image).data == ~[255, 255, 255, 255],
+      _ => fail!(~"expected the second frame again")
+    }
+
+    image_cache_task.exit();
+    mock_resource_task.send(resource_task::Exit);
+}
+
+#[test]
+fn should_get_and_wait_for_decoded_images_through_the_client() {
+    let mock_resource_task = do mock_resource_task |_url, response| {
+        response.send(resource_task::Payload(test_image_bin()));
+        response.send(resource_task::Done(result::Ok(())));
+    };
+
+    let image_cache_task = ImageCacheTask(mock_resource_task);
+    let url = make_url(~"file", None);
+
+    let wait_for_decode = comm::Port();
+    let wait_for_decode_chan = wait_for_decode.chan();
+
+    image_cache_task.send(OnMsg(|msg| {
+        match *msg {
+          StoreImage(*) => wait_for_decode_chan.send(()),
+          _ => ()
+        }
+    }));
+
+    image_cache_task.send(Prefetch(copy url));
+    image_cache_task.send(Decode(copy url));
+    assert image_cache_task.wait_decoded(copy url).is_ok();
+
+    wait_for_decode.recv();
+    assert image_cache_task.get_decoded(move url).is_some();
+
+    image_cache_task.exit();
+    mock_resource_task.send(resource_task::Exit);
+}
+
+#[test]
+fn should_return_nothing_from_the_client_for_images_not_yet_decoded() {
+    let mock_resource_task = do mock_resource_task |_url, response| {
+        response.send(resource_task::Payload(test_image_bin()));
+        response.send(resource_task::Done(result::Ok(())));
+    };
+
+    let image_cache_task = ImageCacheTask(mock_resource_task);
+    let url = make_url(~"file", None);
+
+    // Neither prefetched nor decoded
+    assert image_cache_task.get_decoded(copy url).is_none();
+
+    let wait_for_prefetch = comm::Port();
+    let wait_for_prefetch_chan = wait_for_prefetch.chan();
+
+    image_cache_task.send(OnMsg(|msg| {
+        match *msg {
+          StorePrefetchedImageData(*) => wait_for_prefetch_chan.send(()),
+          _ => ()
+        }
+    }));
+
+    // Prefetched but not decoded
+    image_cache_task.send(Prefetch(copy url));
+    wait_for_prefetch.recv();
+    assert image_cache_task.get_decoded(move url).is_none();
+
+    image_cache_task.exit();
+    mock_resource_task.send(resource_task::Exit);
+}
+
+#[test]
+fn should_return_nothing_from_the_client_for_failed_images() {
+    let mock_resource_task = do mock_resource_task |_url, response| {
+        response.send(resource_task::Done(result::Err(resource_task::LoadFailed)));
+    };
+
+    let image_cache_task = ImageCacheTask(mock_resource_task);
+    let url = make_url(~"file", None);
+
+    image_cache_task.send(Prefetch(copy url));
+    image_cache_task.send(Decode(copy url));
+    assert image_cache_task.wait_decoded(copy url).is_err();
+    assert image_cache_task.get_decoded(move url).is_none();
+
+    image_cache_task.exit();
+    mock_resource_task.send(resource_task::Exit);
+}
+
+#[test]
+fn should_forget_every_image_on_clear() {
+    let (wait_port, wait_chan) = stream();
+
+    let mock_resource_task = do mock_resource_task |url, response, move wait_port| {
+        // Keep the late fetch in flight until the cache has been cleared
+        if str::contains(url.path, "late") {
+            wait_port.recv();
+        }
+        response.send(resource_task::Payload(test_image_bin()));
+        response.send(resource_task::Done(result::Ok(())));
+    };
+
+    let image_cache_task = ImageCacheTask(mock_resource_task);
+
+    let stored = comm::Port();
+    let stored_chan = stored.chan();
+    image_cache_task.send(OnMsg(|msg| {
+        match *msg {
+          StorePrefetchedImageData(*) => stored_chan.send(()),
+          _ => ()
+        }
+    }));
+
+    let decoded_url = make_url(~"http://example.com/decoded.png", None);
+    let prefetched_url = make_url(~"http://example.com/prefetched.png", None);
+    let late_url = make_url(~"http://example.com/late.png", None);
+    for [&decoded_url, &prefetched_url, &late_url].each |url| {
+        image_cache_task.send(Prefetch(copy **url));
+    }
+    image_cache_task.send(Decode(copy decoded_url));
+    match request_response!(image_cache_task, WaitForImage, copy decoded_url) {
+        ImageReady(*) => (),
+        _ => fail!(~"expected the image to load")
+    }
+    stored.recv();
+    stored.recv();
+
+    let urls = request_response!(image_cache_task, ListUrls);
+    assert urls.len() == 3;
+    assert urls.contains(&(copy decoded_url, DecodedTag));
+    assert urls.contains(&(copy prefetched_url, PrefetchedTag));
+    assert urls.contains(&(copy late_url, PrefetchingTag));
+
+    let (waiter_port, waiter_chan) = stream();
+    image_cache_task.send(WaitForImage(copy late_url, move waiter_chan));
+    request_response!(image_cache_task, Clear);
+    assert waiter_port.recv() == ImageFailed(None);
+    assert request_response!(image_cache_task, ListUrls).is_empty();
+
+    // The fetch that was in flight finishes without bringing its image back
+    wait_chan.send(());
+    stored.recv();
+    assert request_response!(image_cache_task, ListUrls).is_empty();
+
+    image_cache_task.exit();
+    mock_resource_task.send(resource_task::Exit);
+}
+
+#[test]
+fn should_send_every_change_to_continuous_subscribers() {
+    let (wait_port, wait_chan) = stream();
+
+    let mock_resource_task = do mock_resource_task |_url, response, move wait_port| {
+        wait_port.recv();
+        response.send(resource_task::Payload(~[0]));
+        response.send(resource_task::Done(result::Ok(())));
+    };
+
+    // A black frame for 20ms, then a white one
+    let decoder_factory = fn~() -> ~fn(&[u8]) -> Result<~[ImageFrame], DecodeError> {
+        fn~(_data: &[u8]) -> Result<~[ImageFrame], DecodeError> {
+            Ok(~[ImageFrame { image: Image(1, 1, 4, ~[0, 0, 0, 255]), delay_ms: 20 },
+                   ImageFrame { image: Image(1, 1, 4, ~[255, 255, 255, 255]), delay_ms: 20 }])
+        }
+    };
+
+    let image_cache_task = ImageCacheTask_frames(mock_resource_task, move decoder_factory,
+                                                 default_options());
+    let url = make_url(~"file", None);
+
+    let (continuous_port, continuous_chan) = stream();
+    image_cache_task.send(Subscribe(copy url, move continuous_chan, Continuous));
+    let (once_port, once_chan) = stream();
+    image_cache_task.send(Subscribe(copy url, move once_chan, Once));
+    // Dropping the port of a subscription ends it without troubling the cache
+    let (dropped_port, dropped_chan) = stream::<ImageUpdate>();
+    image_cache_task.send(Subscribe(copy url, move dropped_chan, Continuous));
+    {
+        let _dropped = move dropped_port;
+    }
+    wait_chan.send(());
+
+    let mut pending = ~[];
+    let mut first_frame = None;
+    while first_frame.is_none() {
+        match continuous_port.recv() {
+          UpdatePending(tag) => pending.push(tag),
+          UpdateReady(move image) => first_frame = Some(move image),
+          UpdateFailed(*) => fail!(~"expected the image to load"),
+          UpdateSize(*) => fail!(~"expected the bytes to have no header")
+        }
+    }
+    assert pending == ~[PrefetchingTag, PrefetchedTag, DecodingTag];
+    assert arc::get(first_frame.get_ref()).data == ~[0, 0, 0, 255];
+    match once_port.recv() {
+      UpdateReady(image) => assert arc::get(&image).data == ~[0, 0, 0, 255],
+      _ => fail!(~"expected the image")
+    }
+
+    image_cache_task.send(AdvanceAnimations(1000));
+    image_cache_task.send(AdvanceAnimations(1020));
+    match continuous_port.recv() {
+      UpdateReady(image) => assert arc::get(&image).data == ~[255, 255, 255, 255],
+      _ => fail!(~"expected the second frame")
+    }
+
+    // A Once subscription ends with the first image
+    let (sync_port, sync_chan) = stream();
+    image_cache_task.send(Sync(move sync_chan));
+    sync_port.recv();
+    assert !once_port.peek();
+
+    image_cache_task.exit();
+    mock_resource_task.send(resource_task::Exit);
+}
+
+#[test]
+fn should_report_the_size_once_the_header_arrives() {
+    let (wait_port, wait_chan) = stream();
+
+    // The header of the test image is in its first 512 bytes
+    let mock_resource_task = do mock_resource_task |_url, response, move wait_port| {
+        let data = test_image_bin();
+        response.send(resource_task::Payload(vec::slice(data, 0, 512)));
+        wait_port.recv();
+        response.send(resource_task::Payload(vec::slice(data, 512, data.len())));
+        response.send(resource_task::Done(result::Ok(())));
+    };
+
+    let image_cache_task = ImageCacheTask(mock_resource_task);
+    let url = make_url(~"file", None);
+
+    let (update_port, update_chan) = stream();
+    image_cache_task.send(Subscribe(copy url, move update_chan, Continuous));
+
+    match update_port.recv() {
+      UpdatePending(PrefetchingTag) => (),
+      _ => fail!(~"expected the fetch to start")
+    }
+    match update_port.recv() {
+      UpdateSize(size) => assert size == Size2D(450u, 337u),
+      _ => fail!(~"expected the size before the rest of the image")
+    }
+    wait_chan.send(());
+
+    let mut image = None;
+    while image.is_none() {
+        match update_port.recv() {
+          UpdatePending(*) => (),
+          UpdateReady(move ready) => image = Some(move ready),
+          UpdateFailed(*) => fail!(~"expected the image to load"),
+          UpdateSize(*) => fail!(~"expected the size only once")
+        }
+    }
+    let image = image.get();
+    assert arc::get(&image).width == 450 && arc::get(&image).height == 337;
+
+    image_cache_task.exit();
+    mock_resource_task.send(resource_task::Exit);
+}
+
+#[test]
+fn should_fail_waiters_when_a_prefetch_is_cancelled() {
+    let (wait_chan, wait_port) = pipes::stream();
+
+    let mock_resource_task = do mock_resource_task |_url, response, move wait_port| {
+        // Keep the fetch in flight until the image has been cancelled
+        wait_port.recv();
+        response.send(resource_task::Payload(test_image_bin()));
+        response.send(resource_task::Done(result::Ok(())));
+    };
+
+    let image_cache_task = ImageCacheTask(mock_resource_task);
+    let url = make_url(~"file", None);
+
+    image_cache_task.send(Prefetch(copy url));
+    image_cache_task.send(Decode(copy url));
+    let (response_chan, response_port) = stream();
+    image_cache_task.send(WaitForImage(copy url, move response_chan));
+    image_cache_task.send(Cancel(copy url));
+
+    assert response_port.recv() == ImageFailed(None);
+
+    // The fetch still in flight doesn't keep the cache from exiting
+    image_cache_task.exit();
+    wait_chan.send(());
+    mock_resource_task.send(resource_task::Exit);
+}
+
+#[test]
+fn should_fail_waiters_when_a_decode_is_cancelled() {
+    let (wait_to_decode_chan, wait_to_decode_port) = pipes::stream();
+
+    let mock_resource_task = do mock_resource_task |_url, response| {
+        response.send(resource_task::Payload(test_image_bin()));
+        response.send(resource_task::Done(result::Ok(())));
+    };
+
+    let wait_to_decode_port_cell = Cell(move wait_to_decode_port);
+    let decoder_factory = fn~(move wait_to_decode_port_cell) -> ~fn(&[u8]) -> Result<Image, DecodeError> {
+        let wait_to_decode_port = wait_to_decode_port_cell.take();
+        fn~(data: &[u8], move wait_to_decode_port) -> Result<Image, DecodeError> {
+            // Don't decode until the image has been cancelled
+            wait_to_decode_port.recv();
+            try_load_from_memory(data)
+        }
+    };
+
+    let image_cache_task = ImageCacheTask_(mock_resource_task, move decoder_factory,
+                                           default_options());
+    let url = make_url(~"file", None);
+
+    let wait_for_prefetech = comm::Port();
+    let wait_for_prefetech_chan = wait_for_prefetech.chan();
+
+    image_cache_task.send(OnMsg(|msg| {
+        match *msg {
+          StorePrefetchedImageData(*) => wait_for_prefetech_chan.send(()),
+          _ => ()
         }
-    }
-}
+    }));
 
-#[test]
-fn should_exit_on_request() {
+    image_cache_task.send(Prefetch(copy url));
+    image_cache_task.send(Decode(copy url));
+    wait_for_prefetech.recv();
+
+    let (response_chan, response_port) = stream();
+    image_cache_task.send(WaitForImage(copy url, move response_chan));
+    image_cache_task.send(Cancel(copy url));
+    assert response_port.recv() == ImageFailed(None);
 
-    let mock_resource_task = mock_resource_task(|_response| () );
+    // The decode finishes without bringing the image back
+    wait_to_decode_chan.send(());
+    let (sync_port, sync_chan) = stream();
+    image_cache_task.send(Sync(move sync_chan));
+    sync_port.recv();
 
-    let image_cache_task = ImageCacheTask(mock_resource_task);
-    let _url = make_url(~"file", None);
+    let (list_port, list_chan) = stream();
+    image_cache_task.send(ListUrls(move list_chan));
+    assert list_port.recv().is_empty();
 
     image_cache_task.exit();
     mock_resource_task.send(resource_task::Exit);
 }
 
 #[test]
-#[should_fail]
-fn should_fail_if_unprefetched_image_is_requested() {
+fn should_read_image_sizes_from_headers() {
+    let (wait_chan, wait_port) = pipes::stream();
 
-    let mock_resource_task = mock_resource_task(|_response| () );
+    let mock_resource_task = do mock_resource_task |_url, response, move wait_port| {
+        // The first load is of a PNG, the second of bytes that aren't an image
+        match wait_port.recv() {
+            true => response.send(resource_task::Payload(
+                test_image_with_color(3, 2, (0, 0, 0, 255)))),
+            false => response.send(resource_task::Payload(~[0u8, 1, 2, 3]))
+        }
+        response.send(resource_task::Done(result::Ok(())));
+    };
 
     let image_cache_task = ImageCacheTask(mock_resource_task);
-    let url = make_url(~"file", None);
+    let png_url = make_url(~"file.png", None);
+    let bogus_url = make_url(~"bogus", None);
+
+    // The size is waited for when asked before the bytes arrive
+    image_cache_task.send(Prefetch(copy png_url));
+    let (size_port, size_chan) = stream();
+    image_cache_task.send(GetImageSize(copy png_url, move size_chan));
+    wait_chan.send(true);
+    assert size_port.recv() == Some((3, 2));
+
+    let (size_port, size_chan) = stream();
+    image_cache_task.send(GetImageSize(copy png_url, move size_chan));
+    assert size_port.recv() == Some((3, 2));
+
+    image_cache_task.send(Prefetch(copy bogus_url));
+    let (size_port, size_chan) = stream();
+    image_cache_task.send(GetImageSize(copy bogus_url, move size_chan));
+    wait_chan.send(false);
+    assert size_port.recv() == None;
+
+    // Nothing is known of images that haven't been prefetched
+    let (size_port, size_chan) = stream();
+    image_cache_task.send(GetImageSize(make_url(~"unknown.png", None), move size_chan));
+    assert size_port.recv() == None;
 
-    let (chan, port) = stream();
-    image_cache_task.send(GetImage(move url, move chan));
-    port.recv();
+    image_cache_task.exit();
+    mock_resource_task.send(resource_task::Exit);
 }
 
 #[test]
-fn should_request_url_from_resource_task_on_prefetch() {
-    let url_requested = Port();
-    let url_requested_chan = url_requested.chan();
+fn ready_images_with_the_same_pixels_should_be_equal() {
+    let decode = |data: ~[u8]| ImageReady(ARC(~load_from_memory(data).unwrap()));
+    assert decode(test_image_bin()) == decode(test_image_bin());
+    assert decode(test_image_bin()) != decode(test_image_with_color(1, 1, (0, 0, 0, 255)));
+    assert decode(test_image_bin()) != ImageNotReady;
+}
 
-    let mock_resource_task = do mock_resource_task |response| {
-        url_requested_chan.send(());
+#[test]
+fn should_limit_the_number_of_concurrent_decodes() {
+    let mock_resource_task = do mock_resource_task |_url, response| {
+        response.send(resource_task::Payload(test_image_bin()));
         response.send(resource_task::Done(result::Ok(())));
     };
 
-    let image_cache_task = ImageCacheTask(mock_resource_task);
-    let url = make_url(~"file", None);
+    // Each decoder says it has started, then waits to be told to finish
+    let (started_port, started_chan) = stream();
+    let started_chan = SharedChan(move started_chan);
+    let decoder_factory = fn~(move started_chan) -> ~fn(&[u8]) -> Result<Image, DecodeError> {
+        let started_chan = started_chan.clone();
+        fn~(data: &[u8], move started_chan) -> Result<Image, DecodeError> {
+            let (finish_port, finish_chan) = stream();
+            started_chan.send(move finish_chan);
+            finish_port.recv();
+            try_load_from_memory(data)
+        }
+    };
+
+    let options = ImageCacheOptions { max_decodes: 2, retries: no_retries(), disk_cache: None };
+    let image_cache_task = ImageCacheTask_(mock_resource_task, move decoder_factory, options);
+    let urls = do vec::from_fn(5) |i| { make_url(fmt!("file%u", i), None) };
+    let mut responses = ~[];
+    for urls.each |url| {
+        image_cache_task.send(Prefetch(copy *url));
+        image_cache_task.send(Decode(copy *url));
+        let (response_port, response_chan) = stream();
+        image_cache_task.send(WaitForImage(copy *url, move response_chan));
+        responses.push(move response_port);
+    }
+
+    let mut running = ~[started_port.recv(), started_port.recv()];
+    let mut finished = 0;
+    while finished < urls.len() {
+        // No other decode starts while two are running
+        let (sync_port, sync_chan) = stream();
+        image_cache_task.send(Sync(move sync_chan));
+        sync_port.recv();
+        assert !started_port.peek();
+
+        running.shift().send(());
+        finished += 1;
+        if finished + running.len() < urls.len() {
+            running.push(started_port.recv());
+        }
+    }
+
+    for responses.each |response| {
+        match response.recv() {
+          ImageReady(*) => (),
+          _ => fail
+        }
+    }
 
-    image_cache_task.send(Prefetch(move url));
-    url_requested.recv();
     image_cache_task.exit();
     mock_resource_task.send(resource_task::Exit);
 }
 
-
 #[test]
-#[should_fail]
-fn should_fail_if_requesting_decode_of_an_unprefetched_image() {
+fn should_decode_identical_bytes_from_different_urls_once() {
+    let mock_resource_task = do mock_resource_task |_url, response| {
+        response.send(resource_task::Payload(test_image_bin()));
+        response.send(resource_task::Done(result::Ok(())));
+    };
 
-    let mock_resource_task = mock_resource_task(|_response| () );
+    // Each decoder reports that it ran
+    let (decoded_port, decoded_chan) = stream();
+    let decoded_chan = SharedChan(move decoded_chan);
+    let decoder_factory = fn~(move decoded_chan) -> ~fn(&[u8]) -> Result<Image, DecodeError> {
+        let decoded_chan = decoded_chan.clone();
+        fn~(data: &[u8], move decoded_chan) -> Result<Image, DecodeError> {
+            decoded_chan.send(());
+            try_load_from_memory(data)
+        }
+    };
 
-    let image_cache_task = ImageCacheTask(mock_resource_task);
-    let url = make_url(~"file", None);
+    let image_cache_task = ImageCacheTask_(mock_resource_task, move decoder_factory,
+                                           default_options());
+    let first_url = make_url(~"http://example.com/sprite.png", None);
+    let second_url = make_url(~"http://cdn.example.com/sprite.png?v=2", None);
+
+    let mut images = ~[];
+    for [first_url, second_url].each |url| {
+        image_cache_task.send(Prefetch(copy *url));
+        image_cache_task.send(Decode(copy *url));
+        let (response_port, response_chan) = stream();
+        image_cache_task.send(WaitForImage(copy *url, move response_chan));
+        match response_port.recv() {
+          ImageReady(move image) => images.push(move image),
+          _ => fail
+        }
+    }
+
+    assert arc::get(&images[0]).same_image(&**arc::get(&images[1]));
+    decoded_port.recv();
+    assert !decoded_port.peek();
 
-    image_cache_task.send(Decode(move url));
     image_cache_task.exit();
+    mock_resource_task.send(resource_task::Exit);
 }
 
 #[test]
-#[should_fail]
-fn should_fail_if_requesting_image_before_requesting_decode() {
-
-    let mock_resource_task = do mock_resource_task |response| {
-        response.send(resource_task::Done(result::Ok(())));
+fn should_retry_failed_fetches() {
+    // The first two loads fail, the third succeeds
+    let (outcome_port, outcome_chan) = stream();
+    for [false, false, true].each |outcome| {
+        outcome_chan.send(*outcome);
+    }
+    let mock_resource_task = do mock_resource_task |_url, response, move outcome_port| {
+        if outcome_port.recv() {
+            response.send(resource_task::Payload(test_image_bin()));
+            response.send(resource_task::Done(result::Ok(())));
+        } else {
+            response.send(resource_task::Done(result::Err(resource_task::LoadFailed)));
+        }
     };
 
-    let image_cache_task = ImageCacheTask(mock_resource_task);
+    let options = ImageCacheOptions {
+        max_decodes: DEFAULT_MAX_DECODES,
+        retries: Retries { max_retries: 2, backoff_ms: 1 },
+        disk_cache: None
+    };
+    let image_cache_task = ImageCacheTask_(mock_resource_task, default_decoder_factory, options);
     let url = make_url(~"file", None);
 
     image_cache_task.send(Prefetch(copy url));
-    // no decode message
+    image_cache_task.send(Decode(copy url));
+    let (response_port, response_chan) = stream();
+    image_cache_task.send(WaitForImage(copy url, move response_chan));
+    match response_port.recv() {
+      ImageReady(*) => (),
+      _ => fail
+    }
 
-    let (chan, _port) = stream();
-    image_cache_task.send(GetImage(move url, move chan));
+    let (list_port, list_chan) = stream();
+    image_cache_task.send(ListUrls(move list_chan));
+    assert list_port.recv() == ~[(move url, DecodedTag)];
 
     image_cache_task.exit();
     mock_resource_task.send(resource_task::Exit);
 }
 
 #[test]
-fn should_not_request_url_from_resource_task_on_multiple_prefetches() {
-    let url_requested = comm::Port();
-    let url_requested_chan = url_requested.chan();
+fn should_cap_the_retry_backoff() {
+    let retries = Retries { max_retries: 100, backoff_ms: 500 };
+    assert retry_backoff_ms(&retries, 0) == 500;
+    assert retry_backoff_ms(&retries, 3) == 4000;
+    assert retry_backoff_ms(&retries, 7) == MAX_RETRY_BACKOFF_MS;
+    // Shifting by this many would overflow
+    assert retry_backoff_ms(&retries, 99) == MAX_RETRY_BACKOFF_MS;
+    assert retry_backoff_ms(&no_retries(), 99) == 0;
+}
 
-    let mock_resource_task = do mock_resource_task |response| {
-        url_requested_chan.send(());
+#[test]
+fn should_count_images_by_state() {
+    let mock_resource_task = do mock_resource_task |_url, response| {
+        response.send(resource_task::Payload(test_image_bin()));
         response.send(resource_task::Done(result::Ok(())));
     };
 
@@ -593,81 +4572,113 @@ fn should_not_request_url_from_resource_task_on_multiple_prefetches() {
     let url = make_url(~"file", None);
 
     image_cache_task.send(Prefetch(copy url));
-    image_cache_task.send(Prefetch(move url));
-    url_requested.recv();
+    image_cache_task.send(Decode(copy url));
+    let (response_port, response_chan) = stream();
+    image_cache_task.send(WaitForImage(copy url, move response_chan));
+    let image = match response_port.recv() {
+      ImageReady(move image) => move image,
+      _ => fail
+    };
+
+    let (stats_port, stats_chan) = stream();
+    image_cache_task.send(GetStats(move stats_chan));
+    assert stats_port.recv() == CacheStats {
+        init: 0, prefetching: 0, prefetched: 0, decoding: 0, decoded: 1, failed: 0,
+        decoded_bytes: image_size_in_bytes(&image)
+    };
+
     image_cache_task.exit();
     mock_resource_task.send(resource_task::Exit);
-    assert !url_requested.peek()
 }
 
 #[test]
-fn should_return_image_not_ready_if_data_has_not_arrived() {
-
-    let (wait_chan, wait_port) = pipes::stream();
+fn should_load_data_urls_without_the_resource_task() {
+    use std::base64::ToBase64;
 
-    let mock_resource_task = do mock_resource_task |response, move wait_port| {
-        // Don't send the data until after the client requests
-        // the image
-        wait_port.recv();
-        response.send(resource_task::Payload(test_image_bin()));
-        response.send(resource_task::Done(result::Ok(())));
+    // Any load that reaches the resource task fails
+    let mock_resource_task = do mock_resource_task |_url, response| {
+        response.send(resource_task::Done(result::Err(resource_task::LoadFailed)));
     };
 
-    let image_cache_task = ImageCacheTask(mock_resource_task);
-    let url = make_url(~"file", None);
+    let png = test_image_with_color(2, 2, (255, 0, 0, 255));
+    let encoded = png.to_base64();
+    let url = make_url(~"data:image/png;base64," + encoded, None);
+    match load_image_data(url, mock_resource_task.clone(), no_timeouts(), &no_validators(),
+                          |_size| (), |_data| ()) {
+        Ok(Some((data, info))) => {
+            assert data == png;
+            assert info.policy == MayStore;
+        }
+        Ok(None) | Err(*) => fail!(~"the data url should load")
+    }
+
+    // Cut off in the middle of a byte
+    let truncated = str::slice(encoded, 0, (encoded.len() - 4) / 4 * 4 + 1);
+    let url = make_url(~"data:image/png;base64," + truncated, None);
+    assert load_image_data(url, mock_resource_task.clone(), no_timeouts(), &no_validators(),
+                           |_size| (), |_data| ()).is_err();
 
-    image_cache_task.send(Prefetch(copy url));
-    image_cache_task.send(Decode(copy url));
-    let (response_chan, response_port) = stream();
-    image_cache_task.send(GetImage(move url, move response_chan));
-    assert response_port.recv() == ImageNotReady;
-    wait_chan.send(());
-    image_cache_task.exit();
     mock_resource_task.send(resource_task::Exit);
 }
 
 #[test]
-fn should_return_decoded_image_data_if_data_has_arrived() {
+fn should_fetch_purged_images_again() {
+    let image_bin_sent = comm::Port();
+    let image_bin_sent_chan = image_bin_sent.chan();
 
-    let mock_resource_task = do mock_resource_task |response| {
+    let mock_resource_task = do mock_resource_task |_url, response| {
         response.send(resource_task::Payload(test_image_bin()));
         response.send(resource_task::Done(result::Ok(())));
+        image_bin_sent_chan.send(());
     };
 
     let image_cache_task = ImageCacheTask(mock_resource_task);
     let url = make_url(~"file", None);
 
-    let wait_for_image = comm::Port();
-    let wait_for_image_chan = wait_for_image.chan();
-
-    image_cache_task.send(OnMsg(|msg| {
-        match *msg {
-          StoreImage(*) => wait_for_image_chan.send(()),
-          _ => ()
-        }
-    }));
-
     image_cache_task.send(Prefetch(copy url));
     image_cache_task.send(Decode(copy url));
+    let (response_port, response_chan) = stream();
+    image_cache_task.send(WaitForImage(copy url, move response_chan));
+    match response_port.recv() {
+      ImageReady(*) => (),
+      _ => fail
+    }
+    image_bin_sent.recv();
 
-    // Wait until our mock resource task has sent the image to the image cache
-    wait_for_image_chan.recv();
+    image_cache_task.send(Purge(copy url));
+    let (list_port, list_chan) = stream();
+    image_cache_task.send(ListUrls(move list_chan));
+    assert list_port.recv().is_empty();
 
-    let (response_chan, response_port) = stream();
-    image_cache_task.send(GetImage(move url, move response_chan));
+    // The image is fetched again rather than served from the cache
+    image_cache_task.send(Prefetch(copy url));
+    image_cache_task.send(Decode(copy url));
+    let (response_port, response_chan) = stream();
+    image_cache_task.send(WaitForImage(copy url, move response_chan));
     match response_port.recv() {
-      ImageReady(_) => (),
+      ImageReady(*) => (),
       _ => fail
     }
+    image_bin_sent.recv();
 
     image_cache_task.exit();
     mock_resource_task.send(resource_task::Exit);
 }
 
 #[test]
-fn should_return_decoded_image_data_for_multiple_requests() {
+fn should_not_decode_bytes_of_an_unknown_format() {
+    let decode = default_decoder_factory();
+    assert decode(test_image_bin()).is_some();
+    assert decode([0x12u8, 0x9A, 0x3C, 0x00, 0xFE, 0x47]).is_none();
+}
+
+#[test]
+fn should_time_out_waiting_for_an_image() {
+    let (wait_chan, wait_port) = pipes::stream();
 
-    let mock_resource_task = do mock_resource_task |response| {
+    let mock_resource_task = do mock_resource_task |_url, response, move wait_port| {
+        // Hang until the waiter has given up
+        wait_port.recv();
         response.send(resource_task::Payload(test_image_bin()));
         response.send(resource_task::Done(result::Ok(())));
     };
@@ -675,160 +4686,176 @@ fn should_return_decoded_image_data_for_multiple_requests() {
     let image_cache_task = ImageCacheTask(mock_resource_task);
     let url = make_url(~"file", None);
 
-    let wait_for_image = comm::Port();
-    let wait_for_image_chan = wait_for_image.chan();
-
-    image_cache_task.send(OnMsg(|msg| {
-        match *msg {
-          StoreImage(*) => wait_for_image_chan.send(()),
-          _ => ()
-        }
-    }));
-
     image_cache_task.send(Prefetch(copy url));
     image_cache_task.send(Decode(copy url));
+    let (timed_port, timed_chan) = stream();
+    image_cache_task.send(WaitForImageWithTimeout(copy url, 10, move timed_chan));
+    let (response_port, response_chan) = stream();
+    image_cache_task.send(WaitForImage(copy url, move response_chan));
 
-    // Wait until our mock resource task has sent the image to the image cache
-    wait_for_image.recv();
+    assert timed_port.recv() == ImageFailed(Some(TimedOut));
 
-    for iter::repeat(2) {
-        let (response_chan, response_port) = stream();
-        image_cache_task.send(GetImage(copy url, move response_chan));
-        match response_port.recv() {
-          ImageReady(_) => (),
-          _ => fail
-        }
+    // The image still loads for the client without a timeout, and the
+    // client that gave up hears nothing more
+    wait_chan.send(());
+    match response_port.recv() {
+      ImageReady(*) => (),
+      _ => fail
     }
+    assert timed_port.try_recv().is_none();
 
     image_cache_task.exit();
     mock_resource_task.send(resource_task::Exit);
 }
 
 #[test]
-fn should_not_request_image_from_resource_task_if_image_is_already_available() {
-
-    let image_bin_sent = comm::Port();
-    let image_bin_sent_chan = image_bin_sent.chan();
-
-    let resource_task_exited = comm::Port();
-    let resource_task_exited_chan = resource_task_exited.chan();
+fn should_hand_the_fetched_bytes_to_the_decoder_intact() {
+    let png = test_image_with_color(3, 2, (10, 20, 30, 255));
+    let png_cell = Cell(copy png);
+    let mock_resource_task = do mock_resource_task |_url, response, move png_cell| {
+        let png = png_cell.take();
+        response.send(resource_task::Payload(copy png));
+        png_cell.put_back(move png);
+        response.send(resource_task::Done(result::Ok(())));
+    };
 
-    let mock_resource_task = do spawn_listener |port: comm::Port<resource_task::ControlMsg>| {
-        loop {
-            match port.recv() {
-                resource_task::Load(_, response) => {
-                    response.send(resource_task::Payload(test_image_bin()));
-                    response.send(resource_task::Done(result::Ok(())));
-                    image_bin_sent_chan.send(());
-                }
-                resource_task::Exit => {
-                    resource_task_exited_chan.send(());
-                    break
-                }
-            }
+    let expected_cell = Cell(copy png);
+    let decoder_factory = fn~(move expected_cell) -> ~fn(&[u8]) -> Result<Image, DecodeError> {
+        let expected = expected_cell.take();
+        fn~(data: &[u8], move expected) -> Result<Image, DecodeError> {
+            assert data == expected;
+            try_load_from_memory(data)
         }
     };
 
-    let image_cache_task = ImageCacheTask(mock_resource_task);
-    let url = make_url(~"file", None);
-
-    image_cache_task.send(Prefetch(copy url));
-
-    // Wait until our mock resource task has sent the image to the image cache
-    image_bin_sent.recv();
+    let image_cache_task = ImageCacheTask_(mock_resource_task, move decoder_factory,
+                                           default_options());
+    let url = make_url(~"file.png", None);
 
     image_cache_task.send(Prefetch(copy url));
+    image_cache_task.send(Decode(copy url));
+    let (response_port, response_chan) = stream();
+    image_cache_task.send(WaitForImage(copy url, move response_chan));
+    assert response_port.recv() == ImageReady(ARC(~load_from_memory(png).unwrap()));
 
     image_cache_task.exit();
     mock_resource_task.send(resource_task::Exit);
-
-    resource_task_exited.recv();
-
-    // Our resource task should not have received another request for the image
-    // because it's already cached
-    assert !image_bin_sent.peek();
 }
 
 #[test]
-fn should_not_request_image_from_resource_task_if_image_fetch_already_failed() {
-
-    let image_bin_sent = comm::Port();
-    let image_bin_sent_chan = image_bin_sent.chan();
-
-    let resource_task_exited = comm::Port();
-    let resource_task_exited_chan = resource_task_exited.chan();
-
-    let mock_resource_task = do spawn_listener |port: comm::Port<resource_task::ControlMsg>| {
-        loop {
-            match port.recv() {
-                resource_task::Load(_, response) => {
-                    response.send(resource_task::Payload(test_image_bin()));
-                    response.send(resource_task::Done(result::Err(())));
-                    image_bin_sent_chan.send(());
-                }
-                resource_task::Exit => {
-                    resource_task_exited_chan.send(());
-                    break
-                }
-            }
-        }
+fn should_read_decoded_images_back_from_the_disk_cache() {
+    let dir = std::tempfile::mkdtemp(&os::tmpdir(), "image-cache").get();
+    let url = make_url(~"file.png", None);
+    let png = test_image_with_color(3, 2, (10, 20, 30, 255));
+    let expected = ImageReady(ARC(~load_from_memory(png).unwrap()));
+    let options = ImageCacheOptions {
+        max_decodes: DEFAULT_MAX_DECODES,
+        retries: no_retries(),
+        disk_cache: Some(copy dir)
     };
 
-    let image_cache_task = ImageCacheTask(mock_resource_task);
-    let url = make_url(~"file", None);
-
+    // Prime the disk cache
+    let png_cell = Cell(move png);
+    let mock_resource_task = do mock_resource_task |_url, response, move png_cell| {
+        let png = png_cell.take();
+        response.send(resource_task::Payload(copy png));
+        png_cell.put_back(move png);
+        response.send(resource_task::Done(result::Ok(())));
+    };
+    let image_cache_task = ImageCacheTask_(mock_resource_task, default_decoder_factory,
+                                           copy options);
     image_cache_task.send(Prefetch(copy url));
     image_cache_task.send(Decode(copy url));
+    let (response_port, response_chan) = stream();
+    image_cache_task.send(WaitForImage(copy url, move response_chan));
+    assert response_port.recv() == expected;
+    image_cache_task.exit();
+    mock_resource_task.send(resource_task::Exit);
 
-    // Wait until our mock resource task has sent the image to the image cache
-    image_bin_sent.recv();
-
+    // A fresh cache finds the image without loading it
+    let url_requested = comm::Port();
+    let url_requested_chan = url_requested.chan();
+    let mock_resource_task = do mock_resource_task |_url, response| {
+        url_requested_chan.send(());
+        response.send(resource_task::Done(result::Err(resource_task::LoadFailed)));
+    };
+    let image_cache_task = ImageCacheTask_(mock_resource_task, default_decoder_factory,
+                                           copy options);
     image_cache_task.send(Prefetch(copy url));
     image_cache_task.send(Decode(copy url));
-
+    let (response_port, response_chan) = stream();
+    image_cache_task.send(WaitForImage(copy url, move response_chan));
+    assert response_port.recv() == expected;
     image_cache_task.exit();
     mock_resource_task.send(resource_task::Exit);
+    assert !url_requested.peek();
 
-    resource_task_exited.recv();
+    // Purging the image deletes its file
+    let mock_resource_task = do mock_resource_task |_url, response| {
+        response.send(resource_task::Done(result::Err(resource_task::LoadFailed)));
+    };
+    let image_cache_task = ImageCacheTask_(mock_resource_task, default_decoder_factory,
+                                           copy options);
+    image_cache_task.send(Purge(copy url));
+    image_cache_task.exit();
+    mock_resource_task.send(resource_task::Exit);
+    assert os::list_dir_path(&dir).is_empty();
 
-    // Our resource task should not have received another request for the image
-    // because it's already cached
-    assert !image_bin_sent.peek();
+    os::remove_dir(&dir);
 }
 
 #[test]
-fn should_return_failed_if_image_bin_cannot_be_fetched() {
+fn should_only_read_disk_cache_files_written_for_the_url() {
+    let dir = std::tempfile::mkdtemp(&os::tmpdir(), "image-cache").get();
+    let path = dir.push("image.img");
+    let url = make_url(~"http://example.com/a.png", None);
+    let image = load_from_memory(test_image_with_color(3, 2, (10, 20, 30, 255))).get();
+
+    assert write_cached_image(&path, &url, &image).is_ok();
+    assert read_cached_image(&path, &url).get().pixels_equal(&image);
+    assert read_cached_image(&path, &make_url(~"http://example.com/b.png", None)).is_none();
+
+    // Dimensions whose product wraps around to 0 don't pass for no pixels
+    {
+        let writer = io::file_writer(&path, ~[io::Create, io::Truncate]).get();
+        let url_bytes = str::to_bytes(to_str(&url));
+        writer.write_be_u32(url_bytes.len() as u32);
+        writer.write(url_bytes);
+        for [0x80000000u, 0x80000000, 4].each |n| {
+            writer.write_be_u32(*n as u32);
+        }
+    }
+    assert read_cached_image(&path, &url).is_none();
 
-    let mock_resource_task = do mock_resource_task |response| {
-        response.send(resource_task::Payload(test_image_bin()));
-        // ERROR fetching image
-        response.send(resource_task::Done(result::Err(())));
+    os::remove_file(&path);
+    os::remove_dir(&dir);
+}
+
+#[test]
+fn should_answer_with_every_frame_of_an_animated_gif() {
+    let mock_resource_task = do mock_resource_task |_url, response| {
+        let gif = test_gif_with_frames(2, 2, [((255, 0, 0), 20), ((0, 0, 255), 70)]);
+        response.send(resource_task::Payload(move gif));
+        response.send(resource_task::Done(result::Ok(())));
     };
 
     let image_cache_task = ImageCacheTask(mock_resource_task);
-    let url = make_url(~"file", None);
-
-    let wait_for_prefetech = comm::Port();
-    let wait_for_prefetech_chan = wait_for_prefetech.chan();
-
-    image_cache_task.send(OnMsg(|msg| {
-        match *msg {
-          StorePrefetchedImageData(*) => wait_for_prefetech_chan.send(()),
-          _ => ()
-        }
-    }));
+    let url = make_url(~"file.gif", None);
 
     image_cache_task.send(Prefetch(copy url));
     image_cache_task.send(Decode(copy url));
-
-    // Wait until our mock resource task has sent the image to the image cache
-    wait_for_prefetech.recv();
-
-    let (response_chan, response_port) = stream();
-    image_cache_task.send(GetImage(move url, move response_chan));
+    let (response_port, response_chan) = stream();
+    image_cache_task.send(WaitForImage(copy url, move response_chan));
     match response_port.recv() {
-      ImageFailed => (),
-      _ => fail
+      ImageReadyAnimated(frames) => {
+        let frames = arc::get(&frames);
+        assert frames.len() == 2;
+        assert frames[0].delay_ms == 20 && frames[1].delay_ms == 70;
+        // Decoded pixels are BGRA
+        assert vec::slice(frames[0].image.data, 0, 4) == ~[0, 0, 255, 255];
+        assert vec::slice(frames[1].image.data, 0, 4) == ~[255, 0, 0, 255];
+      }
+      _ => fail!(~"expected every frame")
     }
 
     image_cache_task.exit();
@@ -836,152 +4863,267 @@ fn should_return_failed_if_image_bin_cannot_be_fetched() {
 }
 
 #[test]
-fn should_return_failed_for_multiple_get_image_requests_if_image_bin_cannot_be_fetched() {
+fn should_send_partial_images_to_progressive_clients() {
+    let (wait_port, wait_chan) = stream();
 
-    let mock_resource_task = do mock_resource_task |response | {
-        response.send(resource_task::Payload(test_image_bin()));
-        // ERROR fetching image
-        response.send(resource_task::Done(result::Err(())));
+    let mock_resource_task = do mock_resource_task |_url, response, move wait_port| {
+        response.send(resource_task::Payload(~[1, 2]));
+        wait_port.recv();
+        response.send(resource_task::Payload(~[3, 4]));
+        response.send(resource_task::Done(result::Ok(())));
     };
 
-    let image_cache_task = ImageCacheTask(mock_resource_task);
+    // Decodes any prefix of the bytes, to an image telling how long it was
+    let decoder_factory = fn~() -> ~fn(&[u8]) -> Result<Image, DecodeError> {
+        fn~(data: &[u8]) -> Result<Image, DecodeError> {
+            Ok(Image(1, 1, 4, ~[data.len() as u8, 0, 0, 255]))
+        }
+    };
+
+    let image_cache_task = ImageCacheTask_(mock_resource_task, move decoder_factory,
+                                           default_options());
     let url = make_url(~"file", None);
 
-    let wait_for_prefetech = comm::Port();
-    let wait_for_prefetech_chan = wait_for_prefetech.chan();
+    let (response_port, response_chan) = stream();
+    image_cache_task.send(GetImageProgressive(copy url, move response_chan));
+    match response_port.recv() {
+      ImageReady(image) => assert arc::get(&image).data[0] == 2,
+      _ => fail!(~"expected the image decoded from the first payload")
+    }
+    wait_chan.send(());
 
-    image_cache_task.send(OnMsg(|msg| {
-        match *msg {
-          StorePrefetchedImageData(*) => wait_for_prefetech_chan.send(()),
-          _ => ()
+    let mut responses = ~[];
+    loop {
+        match response_port.try_recv() {
+            Some(move response) => responses.push(move response),
+            None => break
         }
-    }));
+    }
+    assert !responses.is_empty();
+    match responses[responses.len() - 1] {
+      ImageReady(ref image) => assert arc::get(image).data[0] == 4,
+      _ => fail!(~"expected the whole image")
+    }
 
-    image_cache_task.send(Prefetch(copy url));
-    image_cache_task.send(Decode(copy url));
+    image_cache_task.exit();
+    mock_resource_task.send(resource_task::Exit);
+}
 
-    // Wait until our mock resource task has sent the image to the image cache
-    wait_for_prefetech.recv();
+#[test]
+fn should_decode_partial_images_again_only_once_their_bytes_have_grown() {
+    let mock_resource_task = do mock_resource_task |_url, response| {
+        response.send(resource_task::Payload(vec::from_elem(10, 0u8)));
+        // Not enough more to decode again
+        response.send(resource_task::Payload(~[0]));
+        response.send(resource_task::Payload(vec::from_elem(9, 0u8)));
+        response.send(resource_task::Done(result::Ok(())));
+    };
 
-    let (response_chan, response_port) = stream();
-    image_cache_task.send(GetImage(copy url, move response_chan));
-    match response_port.recv() {
-      ImageFailed => (),
-      _ => fail
-    }
+    // Decodes any prefix of the bytes, to an image telling how long it was
+    let decoder_factory = fn~() -> ~fn(&[u8]) -> Result<Image, DecodeError> {
+        fn~(data: &[u8]) -> Result<Image, DecodeError> {
+            Ok(Image(1, 1, 4, ~[data.len() as u8, 0, 0, 255]))
+        }
+    };
 
-    // And ask again, we should get the same response
-    let (response_chan, response_port) = stream();
-    image_cache_task.send(GetImage(move url, move response_chan));
-    match response_port.recv() {
-      ImageFailed => (),
-      _ => fail
+    let image_cache_task = ImageCacheTask_(mock_resource_task, move decoder_factory,
+                                           default_options());
+    let url = make_url(~"file", None);
+
+    let (response_port, response_chan) = stream();
+    image_cache_task.send(GetImageProgressive(copy url, move response_chan));
+    let mut lens = ~[];
+    loop {
+        match response_port.try_recv() {
+            Some(ImageReady(ref image)) => lens.push(arc::get(image).data[0]),
+            Some(_) => fail!(~"expected only images"),
+            None => break
+        }
     }
+    // The partial images, then the whole one
+    assert lens == ~[10, 20, 20];
 
     image_cache_task.exit();
     mock_resource_task.send(resource_task::Exit);
 }
 
 #[test]
-fn should_return_not_ready_if_image_is_still_decoding() {
-
-    let (wait_to_decode_chan, wait_to_decode_port) = pipes::stream();
+fn should_not_fetch_the_target_of_a_redirect_again() {
+    let url_requested = comm::Port();
+    let url_requested_chan = url_requested.chan();
 
-    let mock_resource_task = do mock_resource_task |response| {
+    let mock_resource_task = do mock_resource_task |_url, response| {
+        url_requested_chan.send(());
+        response.send(resource_task::Redirected(make_url(~"http://example.com/target.png",
+                                                         None)));
         response.send(resource_task::Payload(test_image_bin()));
         response.send(resource_task::Done(result::Ok(())));
     };
 
-    let wait_to_decode_port_cell = Cell(move wait_to_decode_port);
-    let decoder_factory = fn~(move wait_to_decode_port_cell) -> ~fn(&[u8]) -> Option<Image> {
-        let wait_to_decode_port = wait_to_decode_port_cell.take();
-        fn~(data: &[u8], move wait_to_decode_port) -> Option<Image> {
-            // Don't decode until after the client requests the image
-            wait_to_decode_port.recv();
-            load_from_memory(data)
+    let image_cache_task = ImageCacheTask(mock_resource_task);
+    let url = make_url(~"http://example.com/image.png", None);
+    let target = make_url(~"http://example.com/target.png", None);
+
+    image_cache_task.send(Prefetch(copy url));
+    image_cache_task.send(Decode(copy url));
+    let (response_port, response_chan) = stream();
+    image_cache_task.send(WaitForImage(copy url, move response_chan));
+    let image = response_port.recv();
+    url_requested.recv();
+
+    image_cache_task.send(Prefetch(copy target));
+    image_cache_task.send(Decode(copy target));
+    let (response_port, response_chan) = stream();
+    image_cache_task.send(WaitForImage(copy target, move response_chan));
+    assert response_port.recv() == image;
+
+    image_cache_task.exit();
+    mock_resource_task.send(resource_task::Exit);
+    assert !url_requested.peek();
+}
+
+/*
+A resource task that serves the test image with an ETag, and answers loads
+conditional on that ETag with either NotModified or a different image with
+a new ETag
+*/
+#[cfg(test)]
+fn revalidating_resource_task(changed: bool) -> ResourceTask {
+    do spawn_listener |port: comm::Port<resource_task::ControlMsg>| {
+        loop {
+            match port.recv() {
+                resource_task::Load(_, response) |
+                resource_task::LoadWithTimeouts(_, _, response) => {
+                    response.send(resource_task::Done(result::Err(resource_task::LoadFailed)));
+                }
+                resource_task::LoadWithHeaders(_, headers, _, response) => {
+                    let conditional = do headers.any |header| {
+                        match *header {
+                            (ref name, ref value) => {
+                                *name == ~"If-None-Match" && *value == ~"\"v1\""
+                            }
+                        }
+                    };
+                    if !conditional {
+                        response.send(resource_task::Header(~"ETag", ~"\"v1\""));
+                        response.send(resource_task::Payload(test_image_bin()));
+                    } else if !changed {
+                        response.send(resource_task::NotModified);
+                    } else {
+                        response.send(resource_task::Header(~"ETag", ~"\"v2\""));
+                        response.send(resource_task::Payload(
+                            test_image_with_color(2, 2, (0, 0, 255, 255))));
+                    }
+                    response.send(resource_task::Done(result::Ok(())));
+                }
+                resource_task::SetLoadsPerOrigin(*) => (),
+                resource_task::Exit => break
+            }
         }
+    }
+}
+
+#[test]
+fn should_keep_unchanged_images_on_revalidation() {
+    let mock_resource_task = revalidating_resource_task(false);
+
+    // Counts the decodes started
+    let decodes = comm::Port();
+    let decodes_chan = decodes.chan();
+    let decoder_factory = fn~(move decodes_chan) -> ~fn(&[u8]) -> Result<Image, DecodeError> {
+        decodes_chan.send(());
+        fn~(data: &[u8]) -> Result<Image, DecodeError> { try_load_from_memory(data) }
     };
 
-    let image_cache_task = ImageCacheTask_(mock_resource_task, move decoder_factory);
-    let url = make_url(~"file", None);
+    let image_cache_task = ImageCacheTask_(mock_resource_task, move decoder_factory,
+                                           default_options());
+    let url = make_url(~"http://example.com/image.png", None);
 
-    let wait_for_prefetech = comm::Port();
-    let wait_for_prefetech_chan = wait_for_prefetech.chan();
+    image_cache_task.send(Prefetch(copy url));
+    image_cache_task.send(Decode(copy url));
+    let (response_port, response_chan) = stream();
+    image_cache_task.send(WaitForImage(copy url, move response_chan));
+    let image = response_port.recv();
 
+    let revalidated = comm::Port();
+    let revalidated_chan = revalidated.chan();
     image_cache_task.send(OnMsg(|msg| {
         match *msg {
-          StorePrefetchedImageData(*) => wait_for_prefetech_chan.send(()),
+          StoreRevalidation(*) => revalidated_chan.send(()),
           _ => ()
         }
     }));
 
-    image_cache_task.send(Prefetch(copy url));
-    image_cache_task.send(Decode(copy url));
-
-    // Wait until our mock resource task has sent the image to the image cache
-    wait_for_prefetech.recv();
-
-    // Make the request
-    let (response_chan, response_port) = stream();
-    image_cache_task.send(GetImage(move url, move response_chan));
+    image_cache_task.send(Revalidate(copy url));
+    revalidated.recv();
 
-    match response_port.recv() {
-      ImageNotReady => (),
-      _ => fail
-    }
+    let (response_port, response_chan) = stream();
+    image_cache_task.send(WaitForImage(copy url, move response_chan));
+    assert response_port.recv() == image;
 
-    // Now decode
-    wait_to_decode_chan.send(());
+    // The image wasn't decoded again
+    decodes.recv();
+    assert !decodes.peek();
 
     image_cache_task.exit();
     mock_resource_task.send(resource_task::Exit);
 }
 
 #[test]
-fn should_return_failed_if_image_decode_fails() {
-
-    let mock_resource_task = do mock_resource_task |response| {
-        // Bogus data
-        response.send(resource_task::Payload(~[]));
-        response.send(resource_task::Done(result::Ok(())));
+fn should_decode_changed_images_again_on_revalidation() {
+    let mock_resource_task = revalidating_resource_task(true);
+
+    // Counts the decodes started
+    let decodes = comm::Port();
+    let decodes_chan = decodes.chan();
+    let decoder_factory = fn~(move decodes_chan) -> ~fn(&[u8]) -> Result<Image, DecodeError> {
+        decodes_chan.send(());
+        fn~(data: &[u8]) -> Result<Image, DecodeError> { try_load_from_memory(data) }
     };
 
-    let image_cache_task = ImageCacheTask(mock_resource_task);
-    let url = make_url(~"file", None);
+    let image_cache_task = ImageCacheTask_(mock_resource_task, move decoder_factory,
+                                           default_options());
+    let url = make_url(~"http://example.com/image.png", None);
 
-    let wait_for_decode = comm::Port();
-    let wait_for_decode_chan = wait_for_decode.chan();
+    image_cache_task.send(Prefetch(copy url));
+    image_cache_task.send(Decode(copy url));
+    let (response_port, response_chan) = stream();
+    image_cache_task.send(WaitForImage(copy url, move response_chan));
+    let image = response_port.recv();
 
+    let revalidated = comm::Port();
+    let revalidated_chan = revalidated.chan();
     image_cache_task.send(OnMsg(|msg| {
         match *msg {
-          StoreImage(*) => wait_for_decode_chan.send(()),
+          StoreRevalidation(*) => revalidated_chan.send(()),
           _ => ()
         }
     }));
 
-    image_cache_task.send(Prefetch(copy url));
-    image_cache_task.send(Decode(copy url));
-
-    // Wait until our mock resource task has sent the image to the image cache
-    wait_for_decode.recv();
-
-    // Make the request
-    let (response_chan, response_port) = stream();
-    image_cache_task.send(GetImage(move url, move response_chan));
+    image_cache_task.send(Revalidate(copy url));
+    revalidated.recv();
 
+    let (response_port, response_chan) = stream();
+    image_cache_task.send(WaitForImage(copy url, move response_chan));
     match response_port.recv() {
-      ImageFailed => (),
+      ImageReady(ref changed) => {
+          assert ImageReady(changed.clone()) != image;
+          assert arc::get(changed).width == 2;
+      }
       _ => fail
     }
 
+    // The new image was decoded
+    decodes.recv();
+    decodes.recv();
+
     image_cache_task.exit();
     mock_resource_task.send(resource_task::Exit);
 }
 
 #[test]
-fn should_return_image_on_wait_if_image_is_already_loaded() {
-
-    let mock_resource_task = do mock_resource_task |response| {
+fn should_scale_images_to_fit_the_size_asked_for() {
+    let mock_resource_task = do mock_resource_task |_url, response| {
         response.send(resource_task::Payload(test_image_bin()));
         response.send(resource_task::Done(result::Ok(())));
     };
@@ -989,40 +5131,45 @@ fn should_return_image_on_wait_if_image_is_already_loaded() {
     let image_cache_task = ImageCacheTask(mock_resource_task);
     let url = make_url(~"file", None);
 
-    let wait_for_decode = comm::Port();
-    let wait_for_decode_chan = wait_for_decode.chan();
-
-    image_cache_task.send(OnMsg(|msg| {
-        match *msg {
-          StoreImage(*) => wait_for_decode_chan.send(()),
-          _ => ()
-        }
-    }));
-
-    image_cache_task.send(Prefetch(copy url));
-    image_cache_task.send(Decode(copy url));
+    // Scaled as it is decoded
+    let (response_port, response_chan) = stream();
+    image_cache_task.send(GetScaledImage(copy url, 16, 16, move response_chan));
+    let thumbnail = match response_port.recv() {
+      ImageReady(move image) => move image,
+      _ => fail
+    };
+    assert arc::get(&thumbnail).width <= 16 && arc::get(&thumbnail).height <= 16;
 
-    // Wait until our mock resource task has sent the image to the image cache
-    wait_for_decode.recv();
+    // The full image is still there
+    let (response_port, response_chan) = stream();
+    image_cache_task.send(WaitForImage(copy url, move response_chan));
+    match response_port.recv() {
+      ImageReady(ref image) => assert arc::get(image).width > 16,
+      _ => fail
+    }
 
-    let (response_chan, response_port) = stream();
-    image_cache_task.send(WaitForImage(move url, move response_chan));
+    // Scaled from the decoded image
+    let (response_port, response_chan) = stream();
+    image_cache_task.send(GetScaledImage(copy url, 32, 8, move response_chan));
     match response_port.recv() {
-      ImageReady(*) => (),
+      ImageReady(ref image) => {
+          assert arc::get(image).width <= 32 && arc::get(image).height <= 8;
+      }
       _ => fail
     }
 
+    // Kept for the next request of the same size
+    let (response_port, response_chan) = stream();
+    image_cache_task.send(GetScaledImage(copy url, 16, 16, move response_chan));
+    assert response_port.recv() == ImageReady(thumbnail);
+
     image_cache_task.exit();
     mock_resource_task.send(resource_task::Exit);
 }
 
 #[test]
-fn should_return_image_on_wait_if_image_is_not_yet_loaded() {
-
-    let (wait_chan, wait_port) = pipes::stream();
-
-    let mock_resource_task = do mock_resource_task |response, move wait_port| {
-        wait_port.recv();
+fn should_count_and_cap_the_scaled_copies_kept() {
+    let mock_resource_task = do mock_resource_task |_url, response| {
         response.send(resource_task::Payload(test_image_bin()));
         response.send(resource_task::Done(result::Ok(())));
     };
@@ -1032,14 +5179,42 @@ fn should_return_image_on_wait_if_image_is_not_yet_loaded() {
 
     image_cache_task.send(Prefetch(copy url));
     image_cache_task.send(Decode(copy url));
+    let (response_port, response_chan) = stream();
+    image_cache_task.send(WaitForImage(copy url, move response_chan));
+    let mut expected_bytes = match response_port.recv() {
+      ImageReady(ref image) => image_size_in_bytes(image),
+      _ => fail
+    };
 
-    let (response_chan, response_port) = stream();
-    image_cache_task.send(WaitForImage(move url, move response_chan));
+    // One more size than there is room for, so the first is dropped
+    let mut first = None;
+    for uint::range(1, MAX_SCALED_IMAGES + 2) |side| {
+        let (response_port, response_chan) = stream();
+        image_cache_task.send(GetScaledImage(copy url, side, side, move response_chan));
+        match response_port.recv() {
+          ImageReady(move image) => {
+              if side == 1 {
+                  first = Some(move image);
+              } else {
+                  expected_bytes += image_size_in_bytes(&image);
+              }
+          }
+          _ => fail
+        }
+    }
 
-    wait_chan.send(());
+    let (stats_port, stats_chan) = stream();
+    image_cache_task.send(GetStats(move stats_chan));
+    assert stats_port.recv().decoded_bytes == expected_bytes;
 
+    // Scaled again rather than kept
+    let (response_port, response_chan) = stream();
+    image_cache_task.send(GetScaledImage(copy url, 1, 1, move response_chan));
     match response_port.recv() {
-      ImageReady(*) => (),
+      ImageReady(ref image) => {
+          let first = option::unwrap(move first);
+          assert !ptr::ref_eq(arc::get(image), arc::get(&first));
+      }
       _ => fail
     }
 
@@ -1048,30 +5223,78 @@ fn should_return_image_on_wait_if_image_is_not_yet_loaded() {
 }
 
 #[test]
-fn should_return_image_failed_on_wait_if_image_fails_to_load() {
+fn should_start_waiting_fetches_in_order_of_priority() {
+    // Tells which URL each load is for, and holds it until released
+    let loaded = comm::Port();
+    let loaded_chan = loaded.chan();
+    let (release_port, release_chan) = stream();
+    let mock_resource_task = do mock_resource_task |url, response, move release_port| {
+        loaded_chan.send(copy *url);
+        release_port.recv();
+        response.send(resource_task::Payload(test_image_bin()));
+        response.send(resource_task::Done(result::Ok(())));
+    };
 
-    let (wait_chan, wait_port) = pipes::stream();
+    let image_cache_task = ImageCacheTask(mock_resource_task);
+    let first_url = make_url(~"http://example.com/first.png", None);
+    let low_url = make_url(~"http://example.com/low.png", None);
+    let same_url = make_url(~"http://example.com/same.png", None);
+    let high_url = make_url(~"http://example.com/high.png", None);
+
+    image_cache_task.send(SetMaxFetches(1));
+    image_cache_task.send(Prefetch(copy first_url));
+    assert loaded.recv() == first_url;
+
+    // At the limit, so these wait
+    image_cache_task.send(PrefetchWithPriority(copy low_url, 10));
+    image_cache_task.send(PrefetchWithPriority(copy same_url, 10));
+    image_cache_task.send(PrefetchWithPriority(copy high_url, 200));
+    let (sync_port, sync_chan) = stream();
+    image_cache_task.send(Sync(move sync_chan));
+    sync_port.recv();
+
+    release_chan.send(());
+    assert loaded.recv() == high_url;
+    release_chan.send(());
+    assert loaded.recv() == low_url;
+    release_chan.send(());
+    assert loaded.recv() == same_url;
+    release_chan.send(());
 
-    let mock_resource_task = do mock_resource_task |response, move wait_port| {
-        wait_port.recv();
-        response.send(resource_task::Payload(test_image_bin()));
-        response.send(resource_task::Done(result::Err(())));
+    image_cache_task.exit();
+    mock_resource_task.send(resource_task::Exit);
+}
+
+#[test]
+fn should_release_the_fetch_slot_of_a_failed_fetch() {
+    let mock_resource_task = do mock_resource_task |url, response| {
+        if url.path == ~"/broken.png" {
+            response.send(resource_task::Done(result::Err(LoadFailed)));
+        } else {
+            response.send(resource_task::Payload(test_image_bin()));
+            response.send(resource_task::Done(result::Ok(())));
+        }
     };
 
     let image_cache_task = ImageCacheTask(mock_resource_task);
-    let url = make_url(~"file", None);
+    image_cache_task.send(SetMaxFetches(1));
+
+    let broken_url = make_url(~"http://example.com/broken.png", None);
+    image_cache_task.send(Prefetch(copy broken_url));
+    image_cache_task.send(Decode(copy broken_url));
+    let (response_port, response_chan) = stream();
+    image_cache_task.send(WaitForImage(move broken_url, move response_chan));
+    assert response_port.recv() == ImageFailed(Some(NetworkFailure(LoadFailed)));
 
+    // The only slot is free again
+    let url = make_url(~"http://example.com/image.png", None);
     image_cache_task.send(Prefetch(copy url));
     image_cache_task.send(Decode(copy url));
-
-    let (response_chan, response_port) = stream();
+    let (response_port, response_chan) = stream();
     image_cache_task.send(WaitForImage(move url, move response_chan));
-
-    wait_chan.send(());
-
     match response_port.recv() {
-      ImageFailed => (),
-      _ => fail
+        ImageReady(*) => (),
+        _ => fail!(~"expected the image to load")
     }
 
     image_cache_task.exit();
@@ -1079,25 +5302,46 @@ fn should_return_image_failed_on_wait_if_image_fails_to_load() {
 }
 
 #[test]
-fn sync_cache_should_wait_for_images() {
-    let mock_resource_task = do mock_resource_task |response| {
+fn should_count_revalidations_against_the_fetch_limit() {
+    let (loaded_port, loaded_chan) = stream();
+    let (release_port, release_chan) = stream();
+    let mock_resource_task = do mock_resource_task |url, response, move loaded_chan,
+                                                     move release_port| {
+        loaded_chan.send(copy *url);
+        release_port.recv();
         response.send(resource_task::Payload(test_image_bin()));
         response.send(resource_task::Done(result::Ok(())));
     };
 
-    let image_cache_task = SyncImageCacheTask(mock_resource_task);
-    let url = make_url(~"file", None);
-
-    image_cache_task.send(Prefetch(copy url));
-    image_cache_task.send(Decode(copy url));
-
-    let (response_chan, response_port) = stream();
-    image_cache_task.send(GetImage(move url, move response_chan));
+    let image_cache_task = ImageCacheTask(mock_resource_task);
+    let decoded_url = make_url(~"http://example.com/decoded.png", None);
+    let other_url = make_url(~"http://example.com/other.png", None);
+    image_cache_task.send(SetMaxFetches(1));
+
+    image_cache_task.send(Prefetch(copy decoded_url));
+    image_cache_task.send(Decode(copy decoded_url));
+    assert loaded_port.recv() == decoded_url;
+    release_chan.send(());
+    let (response_port, response_chan) = stream();
+    image_cache_task.send(WaitForImage(copy decoded_url, move response_chan));
     match response_port.recv() {
-      ImageReady(_) => (),
-      _ => fail
+        ImageReady(*) => (),
+        _ => fail!(~"expected the image to load")
     }
 
+    // The other fetch takes the only slot, so the revalidation waits for it
+    image_cache_task.send(Prefetch(copy other_url));
+    assert loaded_port.recv() == other_url;
+    image_cache_task.send(Revalidate(copy decoded_url));
+    let (sync_port, sync_chan) = stream();
+    image_cache_task.send(Sync(move sync_chan));
+    sync_port.recv();
+    assert !loaded_port.peek();
+
+    release_chan.send(());
+    assert loaded_port.recv() == decoded_url;
+    release_chan.send(());
+
     image_cache_task.exit();
     mock_resource_task.send(resource_task::Exit);
 }
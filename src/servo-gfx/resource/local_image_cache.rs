@@ -8,7 +8,9 @@ use clone_arc = std::arc::clone;
 use std::net::url::Url;
 use pipes::{Port, Chan, stream};
 use resource::image_cache_task::{ImageCacheTask, ImageResponseMsg, Prefetch, Decode, GetImage};
-use resource::image_cache_task::{ WaitForImage, ImageReady, ImageNotReady, ImageFailed};
+use resource::image_cache_task::{DecodePriority, DecodeWithPriority, HighPriority, LowPriority};
+use resource::image_cache_task::{ WaitForImage, ImageReady, ImageReadyAnimated, ImageNotReady,
+                                  ImageFailed};
 use util::url::{UrlMap, url_map};
 
 pub fn LocalImageCache(image_cache_task: ImageCacheTask) -> LocalImageCache {
@@ -30,6 +32,7 @@ pub struct LocalImageCache {
 priv struct ImageState {
     mut prefetched: bool,
     mut decoded: bool,
+    mut decode_priority: Option<DecodePriority>,
     mut last_request_round: uint,
     mut last_response: ImageResponseMsg
 }
@@ -60,6 +63,26 @@ pub impl LocalImageCache {
         }
     }
 
+    /// Like decode, but resends the request if it raises the priority
+    pub fn decode_with_priority(url: &Url, priority: DecodePriority) {
+        let state = self.get_state(url);
+        let raise = match (state.decode_priority, priority) {
+            (None, _) | (Some(LowPriority), HighPriority) => true,
+            _ => false
+        };
+        if raise {
+            self.image_cache_task.send(DecodeWithPriority(copy *url, priority));
+            state.decode_priority = Some(priority);
+            state.decoded = true;
+        }
+    }
+
+    /// Whether the image has been prefetched. Images must be prefetched
+    /// before they are requested with get_image.
+    pub fn is_prefetched(url: &Url) -> bool {
+        self.get_state(url).prefetched
+    }
+
     // FIXME: Should return a Future
     pub fn get_image(url: &Url) -> Port<ImageResponseMsg> {
         let state = self.get_state(url);
@@ -78,6 +101,13 @@ pub impl LocalImageCache {
                     return move port;
                 }
             }
+            ImageReadyAnimated(ref frames) => {
+                unsafe {
+                    let (port, chan) = pipes::stream();
+                    chan.send(ImageReadyAnimated(clone_arc(frames)));
+                    return move port;
+                }
+            }
             ImageNotReady => {
                 if last_round == self.round_number {
                     let (port, chan) = pipes::stream();
@@ -88,9 +118,9 @@ pub impl LocalImageCache {
                     // remote cache this round
                 }
             }
-            ImageFailed => {
+            ImageFailed(reason) => {
                 let (port, chan) = pipes::stream();
-                chan.send(ImageFailed);
+                chan.send(ImageFailed(reason));
                 return move port;
             }
         }
@@ -122,8 +152,9 @@ pub impl LocalImageCache {
         // Put a copy of the response in the cache
         let response_copy = match response {
             ImageReady(ref image) => ImageReady(clone_arc(image)),
+            ImageReadyAnimated(ref frames) => ImageReadyAnimated(clone_arc(frames)),
             ImageNotReady => ImageNotReady,
-            ImageFailed => ImageFailed
+            ImageFailed(reason) => ImageFailed(reason)
         };
         state.last_response = move response_copy;
 
@@ -139,6 +170,7 @@ pub impl LocalImageCache {
                 let new_state = @ImageState {
                     prefetched: false,
                     decoded: false,
+                    decode_priority: None,
                     last_request_round: 0,
                     last_response: ImageNotReady
                 };
@@ -0,0 +1,148 @@
+/*!
+
+An `io::Reader` over the response to a resource task `Load`, for consumers
+that want to pull bytes incrementally instead of receiving `ProgressMsg`s
+
+*/
+
+use resource::resource_task::{ProgressMsg, Meta, Header, Redirected, PartialContent, Payload};
+use resource::resource_task::{Done, NotModified};
+
+use core::io::{Reader, SeekStyle};
+use core::pipes::Port;
+
+pub struct ResourceReader {
+    priv progress_port: Port<ProgressMsg>,
+    /// The payload currently being read from
+    priv mut buf: ~[u8],
+    priv mut pos: uint,
+    /// The number of bytes read so far
+    priv mut offset: uint,
+    priv mut done: bool,
+    priv mut failed: bool
+}
+
+pub fn ResourceReader(progress_port: Port<ProgressMsg>) -> ResourceReader {
+    ResourceReader {
+        progress_port: move progress_port,
+        buf: ~[],
+        pos: 0,
+        offset: 0,
+        done: false,
+        failed: false
+    }
+}
+
+impl ResourceReader {
+    /// Whether the load finished with an error. Reads stop at the error, as
+    /// if the resource had ended there.
+    fn failed(&self) -> bool {
+        self.failed
+    }
+
+    /// Makes sure there are unread bytes in the buffer, waiting for the next
+    /// payload if necessary. Returns false at the end of the resource.
+    priv fn fill_buf(&self) -> bool {
+        while self.pos == self.buf.len() {
+            if self.done {
+                return false;
+            }
+            match self.progress_port.recv() {
+                Meta(*) | Header(*) | Redirected(*) | NotModified => (),
+                // The resource task assembles ranges, so any that get here can't be read
+                PartialContent(*) => self.failed = true,
+                Payload(_) if self.failed => (),
+                Payload(move data) => {
+                    self.buf = move data;
+                    self.pos = 0;
+                }
+                Done(Ok(*)) => self.done = true,
+                Done(Err(*)) => {
+                    self.done = true;
+                    self.failed = true;
+                }
+            }
+        }
+        true
+    }
+}
+
+impl ResourceReader: Reader {
+    fn read(&self, bytes: &mut [u8], len: uint) -> uint {
+        let mut count = 0;
+        while count < len && self.fill_buf() {
+            let n = uint::min(len - count, self.buf.len() - self.pos);
+            for uint::range(0, n) |i| {
+                bytes[count + i] = self.buf[self.pos + i];
+            }
+            self.pos += n;
+            count += n;
+        }
+        self.offset += count;
+        count
+    }
+
+    fn read_byte(&self) -> int {
+        if !self.fill_buf() {
+            return -1;
+        }
+        let b = self.buf[self.pos];
+        self.pos += 1;
+        self.offset += 1;
+        b as int
+    }
+
+    fn eof(&self) -> bool {
+        !self.fill_buf()
+    }
+
+    fn seek(&self, _position: int, _style: SeekStyle) {
+        fail!(~"resource readers can't seek")
+    }
+
+    fn tell(&self) -> uint {
+        self.offset
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use resource::resource_task::{Payload, Done, LoadFailed};
+
+    use core::io::Reader;
+    use core::pipes::stream;
+
+    #[test]
+    fn should_read_multi_chunk_responses_byte_by_byte() {
+        let (port, chan) = stream();
+        chan.send(Payload(~[1, 2, 3]));
+        chan.send(Payload(~[]));
+        chan.send(Payload(~[4, 5]));
+        chan.send(Done(Ok(())));
+
+        let reader = ResourceReader(move port);
+        let mut bytes = ~[];
+        while !reader.eof() {
+            bytes.push(reader.read_byte() as u8);
+        }
+
+        assert bytes == ~[1, 2, 3, 4, 5];
+        assert reader.tell() == 5;
+        assert reader.read_byte() == -1;
+        assert !reader.failed();
+    }
+
+    #[test]
+    fn should_stop_reading_at_errors() {
+        let (port, chan) = stream();
+        chan.send(Payload(~[1, 2]));
+        chan.send(Done(Err(LoadFailed)));
+
+        let reader = ResourceReader(move port);
+        let mut buf = ~[0u8, 0, 0, 0];
+        assert reader.read(buf, 4) == 2;
+        assert reader.eof();
+        assert reader.failed();
+    }
+}
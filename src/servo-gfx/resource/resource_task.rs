@@ -4,26 +4,86 @@ A task that takes a URL and streams back the binary data
 
 */
 
-use pipes::{Chan, Port, SharedChan};
+use core::util::replace;
+use pipes::{Chan, Port, SharedChan, stream};
 use resource::util::spawn_listener;
 use std::cell::Cell;
+use std::oldmap::HashMap;
 use std::net::url;
 use std::net::url::{Url, to_str};
-use super::{file_loader, http_loader};
+use std::timer;
+use std::uv_global_loop;
+use super::{data_loader, file_loader, http_loader};
 
 pub enum ControlMsg {
     /// Request the data associated with a particular URL
     Load(Url, Chan<ProgressMsg>),
+    /// Like Load, but gives up on the load if the loader stalls for longer
+    /// than the timeouts allow
+    LoadWithTimeouts(Url, Timeouts, Chan<ProgressMsg>),
+    /// Like LoadWithTimeouts, also sending the given request headers, which
+    /// replace any of the resource task's headers of the same name
+    LoadWithHeaders(Url, ~[(~str, ~str)], Timeouts, Chan<ProgressMsg>),
+    /// Limit the number of loads from one origin that run at once. Loads
+    /// beyond the limit wait for an earlier one to finish.
+    SetLoadsPerOrigin(uint),
     Exit
 }
 
+/// How many loads from one origin run at once unless told otherwise, as in other browsers
+pub const DEFAULT_LOADS_PER_ORIGIN: uint = 6;
+
+/// The request headers sent with every load unless the task is created with others
+pub fn default_request_headers() -> ~[(~str, ~str)] {
+    ~[(~"Accept", ~"text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8"),
+      (~"Accept-Language", ~"en-US,en;q=0.5")]
+}
+
+/// How long a load may wait on its loader, in ms. None waits forever.
+pub struct Timeouts {
+    /// For the loader's first response, i.e. to connect
+    connect: Option<uint>,
+    /// Between each of the loader's later responses
+    read: Option<uint>
+}
+
+pub pure fn no_timeouts() -> Timeouts {
+    Timeouts { connect: None, read: None }
+}
+
+/// Why a load failed
+#[deriving_eq]
+pub enum NetworkError {
+    /// The loader couldn't produce the resource
+    LoadFailed,
+    /// The loader stalled for longer than the load's timeouts allow
+    Timeout
+}
+
 /// Messages sent in response to a `Load` message
 #[deriving_eq]
 pub enum ProgressMsg {
+    /// The MIME type of the resource, if known, sent before any Payload
+    Meta(~str),
+    /// A response header such as Cache-Control, by name and value, sent
+    /// before any Payload
+    Header(~str, ~str),
+    /// The URL the loader was redirected to, after following every
+    /// redirect, sent before any Payload
+    Redirected(Url),
+    /// The resource hasn't changed since the validators sent in the request's
+    /// If-None-Match or If-Modified-Since header, as with a 304 response. No
+    /// Payload follows.
+    NotModified,
     /// Binary data - there may be multiple of these
     Payload(~[u8]),
+    /// The bytes of a partial (206) response starting at the given offset of a
+    /// resource of the given total length. Loaders may send these instead of
+    /// Payloads; the resource task stitches them together, so clients only
+    /// ever see the assembled Payload.
+    PartialContent(uint, uint, ~[u8]),
     /// Indicates loading is complete, either successfully or not
-    Done(Result<(), ()>)
+    Done(Result<(), NetworkError>)
 }
 
 /// Handle to a resource task
@@ -35,26 +95,39 @@ Creates a task to load a specific resource
 The ResourceManager delegates loading to a different type of loader task for
 each URL scheme
 */
-type LoaderTaskFactory = ~fn() -> ~fn(url: Url, Chan<ProgressMsg>);
+type LoaderTaskFactory = ~fn() -> ~fn(url: Url, headers: ~[(~str, ~str)], Chan<ProgressMsg>);
 
-pub type LoaderTask = ~fn(url: Url, Chan<ProgressMsg>);
+/// Loads a URL, sending the given request headers if its scheme has any
+pub type LoaderTask = ~fn(url: Url, headers: ~[(~str, ~str)], Chan<ProgressMsg>);
 
-/// Create a ResourceTask with the default loaders
+/// Create a ResourceTask with the default loaders and request headers
 pub fn ResourceTask() -> ResourceTask {
+    ResourceTaskWithHeaders(default_request_headers())
+}
+
+/**
+Create a ResourceTask with the default loaders, sending `headers`, such as
+`Accept` and `Accept-Language` for content negotiation, with every load
+*/
+pub fn ResourceTaskWithHeaders(headers: ~[(~str, ~str)]) -> ResourceTask {
     let file_loader_factory: LoaderTaskFactory = file_loader::factory;
     let http_loader_factory: LoaderTaskFactory = http_loader::factory;
+    let data_loader_factory: LoaderTaskFactory = data_loader::factory;
     let loaders = ~[
         (~"file", file_loader_factory),
-        (~"http", http_loader_factory)
+        (~"http", http_loader_factory),
+        (~"data", data_loader_factory)
     ];
-    create_resource_task_with_loaders(move loaders)
+    create_resource_task_with_loaders(move loaders, move headers)
 }
 
-fn create_resource_task_with_loaders(loaders: ~[(~str, LoaderTaskFactory)]) -> ResourceTask {
+fn create_resource_task_with_loaders(loaders: ~[(~str, LoaderTaskFactory)],
+                                     headers: ~[(~str, ~str)]) -> ResourceTask {
 	let loaders_cell = Cell(loaders);
+    let headers_cell = Cell(move headers);
     let chan = do spawn_listener |from_client| {
         // TODO: change copy to move once we can move out of closures
-        ResourceManager(from_client, loaders_cell.take()).start()
+        ResourceManager(from_client, loaders_cell.take(), headers_cell.take()).start()
     };
 	SharedChan(chan)
 }
@@ -63,14 +136,41 @@ pub struct ResourceManager {
     from_client: Port<ControlMsg>,
     /// Per-scheme resource loaders
     loaders: ~[(~str, LoaderTaskFactory)],
+    /// Request headers sent with every load
+    headers: ~[(~str, ~str)],
+    /// The most loads from one origin to run at once
+    mut loads_per_origin: uint,
+    /// The number of loads running for each origin that has any
+    loads_in_flight: HashMap<~str, uint>,
+    /// Loads waiting for one from the same origin to finish, oldest first
+    mut queued_loads: ~[QueuedLoad],
+    /// Told the origin of each load that finishes
+    finished_port: Port<~str>,
+    finished_chan: SharedChan<~str>,
+}
+
+/// A load waiting for its origin to have a connection free
+struct QueuedLoad {
+    url: Url,
+    headers: ~[(~str, ~str)],
+    timeouts: Timeouts,
+    progress_chan: Chan<ProgressMsg>
 }
 
 
 pub fn ResourceManager(from_client: Port<ControlMsg>, 
-                       loaders: ~[(~str, LoaderTaskFactory)]) -> ResourceManager {
+                       loaders: ~[(~str, LoaderTaskFactory)],
+                       headers: ~[(~str, ~str)]) -> ResourceManager {
+    let (finished_port, finished_chan) = stream();
     ResourceManager {
         from_client : move from_client,
         loaders : move loaders,
+        headers : move headers,
+        loads_per_origin : DEFAULT_LOADS_PER_ORIGIN,
+        loads_in_flight : HashMap(),
+        queued_loads : ~[],
+        finished_port : move finished_port,
+        finished_chan : SharedChan(move finished_chan),
     }
 }
 
@@ -78,9 +178,29 @@ pub fn ResourceManager(from_client: Port<ControlMsg>,
 impl ResourceManager {
     fn start() {
         loop {
+            match pipes::select2i(&self.from_client, &self.finished_port) {
+                either::Left(()) => (),
+                either::Right(()) => {
+                    let origin = self.finished_port.recv();
+                    self.finish_load(move origin);
+                    loop;
+                }
+            }
+
             match self.from_client.recv() {
               Load(url, progress_chan) => {
-                self.load(copy url, progress_chan)
+                self.load(copy url, ~[], no_timeouts(), progress_chan)
+              }
+              LoadWithTimeouts(url, timeouts, progress_chan) => {
+                self.load(copy url, ~[], timeouts, progress_chan)
+              }
+              LoadWithHeaders(url, headers, timeouts, progress_chan) => {
+                self.load(copy url, headers, timeouts, progress_chan)
+              }
+              SetLoadsPerOrigin(limit) => {
+                assert limit > 0;
+                self.loads_per_origin = limit;
+                self.start_queued_loads();
               }
               Exit => {
                 break
@@ -89,16 +209,77 @@ impl ResourceManager {
         }
     }
 
-    fn load(url: Url, progress_chan: Chan<ProgressMsg>) {
+    // Starts a load, or queues it if its origin already has as many loads running as allowed
+    fn load(url: Url, headers: ~[(~str, ~str)], timeouts: Timeouts,
+            progress_chan: Chan<ProgressMsg>) {
+        let origin = origin_of(&url);
+        match origin {
+            Some(ref origin) if self.loads_in_flight.find(origin).get_or_default(0) >=
+                    self.loads_per_origin => {
+                debug!("resource_task: queuing load of %s", to_str(&url));
+                self.queued_loads.push(QueuedLoad {
+                    url: move url,
+                    headers: move headers,
+                    timeouts: timeouts,
+                    progress_chan: move progress_chan
+                });
+                return;
+            }
+            _ => ()
+        }
 
         match self.get_loader_factory(&url) {
             Some(loader_factory) => {
                 debug!("resource_task: loading url: %s", to_str(&url));
-                loader_factory(move url, progress_chan);
+                match origin {
+                    Some(ref origin) => {
+                        let running = self.loads_in_flight.find(origin).get_or_default(0);
+                        self.loads_in_flight.insert(copy *origin, running + 1);
+                    }
+                    None => ()
+                }
+
+                let (loader_port, loader_chan) = stream();
+                let loader_port = Cell(move loader_port);
+                let progress_chan = Cell(move progress_chan);
+                let origin = Cell(move origin);
+                let finished_chan = self.finished_chan.clone();
+                do task::spawn {
+                    assemble_partial_content(loader_port.take(), progress_chan.take(), timeouts);
+                    match origin.take() {
+                        // The resource task may have exited already
+                        Some(move origin) => { finished_chan.try_send(move origin); }
+                        None => ()
+                    }
+                }
+                loader_factory(move url, merge_headers(self.headers, headers), move loader_chan);
             }
             None => {
                 debug!("resource_task: no loader for scheme %s", url.scheme);
-                progress_chan.send(Done(Err(())));
+                progress_chan.send(Done(Err(LoadFailed)));
+            }
+        }
+    }
+
+    // Frees the connection of a finished load for the next load from its origin
+    fn finish_load(origin: ~str) {
+        match self.loads_in_flight.find(&origin) {
+            Some(1) => { self.loads_in_flight.remove(&origin); }
+            Some(running) => { self.loads_in_flight.insert(copy origin, running - 1); }
+            None => fail!(~"finished a load that wasn't running")
+        }
+        self.start_queued_loads();
+    }
+
+    // Starts the queued loads there are now connections for, keeping the rest queued in order
+    fn start_queued_loads() {
+        let queued = replace(&mut self.queued_loads, ~[]);
+        for vec::consume(move queued) |_, load| {
+            match move load {
+                QueuedLoad { url: move url, headers: move headers, timeouts: timeouts,
+                             progress_chan: move progress_chan } => {
+                    self.load(move url, move headers, timeouts, move progress_chan)
+                }
             }
         }
     }
@@ -117,6 +298,137 @@ impl ResourceManager {
     }
 }
 
+/// The scheme, host and port loads are limited by, if the URL has a host to connect to
+fn origin_of(url: &Url) -> Option<~str> {
+    if url.host.is_empty() {
+        return None;
+    }
+    Some(fmt!("%s://%s:%s", url.scheme, url.host, url.port.get_or_default(~"")))
+}
+
+/// The headers in `overrides`, followed by those in `headers` they don't replace
+fn merge_headers(headers: &[(~str, ~str)], overrides: &[(~str, ~str)]) -> ~[(~str, ~str)] {
+    let mut merged = vec::from_slice(overrides);
+    for headers.each |&(ref name, ref value)| {
+        let replaced = do overrides.any |&(ref override_name, _)| {
+            str::eq_slice(str::to_lower(*name), str::to_lower(*override_name))
+        };
+        if !replaced {
+            merged.push((copy *name, copy *value));
+        }
+    }
+    merged
+}
+
+/**
+Forwards a loader's progress to the client, stitching any PartialContent
+ranges into a single Payload. The load succeeds only if the ranges agree on
+the total length and cover every byte of the resource.
+
+If the loader stalls for longer than the timeouts allow, the load fails with
+a Timeout and the rest of the loader's progress is discarded.
+*/
+fn assemble_partial_content(from_loader: Port<ProgressMsg>, to_client: Chan<ProgressMsg>,
+                            timeouts: Timeouts) {
+    // The total the ranges claim, and the bytes that have arrived so far and which
+    // of them are real. The buffers grow as ranges arrive rather than being sized
+    // from the claimed total, which the server may have made up.
+    let mut assembled: Option<(uint, ~[u8], ~[bool])> = None;
+    let mut failed = false;
+    let mut timeout = timeouts.connect;
+
+    loop {
+        let msg = match recv_with_timeout(&from_loader, timeout) {
+            Some(move msg) => move msg,
+            None => {
+                debug!("resource_task: load timed out");
+                to_client.send(Done(Err(Timeout)));
+                discard_progress(move from_loader);
+                break;
+            }
+        };
+        timeout = timeouts.read;
+
+        match move msg {
+            PartialContent(offset, total, move data) => {
+                if assembled.is_none() {
+                    assembled = Some((total, ~[], ~[]));
+                }
+                match assembled {
+                    Some((assembled_total, ref mut buf, ref mut received)) => {
+                        if total != assembled_total || offset > total ||
+                                data.len() > total - offset {
+                            debug!("resource_task: partial content range doesn't fit");
+                            failed = true;
+                        } else if !failed {
+                            let end = offset + data.len();
+                            if buf.len() < end {
+                                buf.grow(end - buf.len(), &0u8);
+                                received.grow(end - received.len(), &false);
+                            }
+                            for data.eachi |i, b| {
+                                buf[offset + i] = *b;
+                                received[offset + i] = true;
+                            }
+                        }
+                    }
+                    None => fail!(~"no buffer for partial content")
+                }
+            }
+            Done(Ok(())) if assembled.is_some() => {
+                let (total, buf, received) = option::unwrap(move assembled);
+                if !failed && received.len() == total && received.all(|r| *r) {
+                    to_client.send(Payload(move buf));
+                    to_client.send(Done(Ok(())));
+                } else {
+                    debug!("resource_task: partial content doesn't cover the resource");
+                    to_client.send(Done(Err(LoadFailed)));
+                }
+                break;
+            }
+            Done(move result) => {
+                to_client.send(Done(move result));
+                break;
+            }
+            move msg => to_client.send(move msg)
+        }
+    }
+}
+
+/// Receives the loader's next message, or None if it takes longer than `timeout` ms
+fn recv_with_timeout(from_loader: &Port<ProgressMsg>, timeout: Option<uint>)
+                  -> Option<ProgressMsg> {
+    match timeout {
+        None => Some(from_loader.recv()),
+        Some(ms) => {
+            let (timer_port, timer_chan) = stream();
+            do task::spawn |move timer_chan| {
+                timer::sleep(uv_global_loop::get(), ms);
+                // The message may already have arrived and the port be gone
+                timer_chan.try_send(());
+            }
+            match pipes::select2i(&timer_port, from_loader) {
+                either::Left(()) => None,
+                either::Right(()) => Some(from_loader.recv())
+            }
+        }
+    }
+}
+
+/*
+Loaders can't be stopped once started, so after giving up on a load its
+progress is received until the end, keeping the loader from failing as it
+sends to a closed port.
+*/
+fn discard_progress(from_loader: Port<ProgressMsg>) {
+    loop {
+        match from_loader.recv() {
+            Done(*) => break,
+            _ => ()
+        }
+    }
+}
+
 #[test]
 fn test_exit() {
     let resource_task = ResourceTask();
@@ -127,9 +439,7 @@ fn test_exit() {
 #[allow(non_implicitly_copyable_typarams)]
 fn test_bad_scheme() {
     let resource_task = ResourceTask();
-    let progress = Port();
-    resource_task.send(Load(url::from_str(~"bogus://whatever").get(), progress.chan()));
-    match progress.recv() {
+    match request_response!(resource_task, Load, url::from_str(~"bogus://whatever").get()) {
       Done(result) => { assert result.is_err() }
       _ => fail
     }
@@ -140,15 +450,180 @@ fn test_bad_scheme() {
 #[allow(non_implicitly_copyable_typarams)]
 fn should_delegate_to_scheme_loader() {
     let payload = ~[1, 2, 3];
-    let loader_factory = fn~(_url: Url, progress_chan: Chan<ProgressMsg>, copy payload) {
+    let loader_factory = fn~(_url: Url, _headers: ~[(~str, ~str)],
+                             progress_chan: Chan<ProgressMsg>, copy payload) {
         progress_chan.send(Payload(copy payload));
         progress_chan.send(Done(Ok(())));
     };
     let loader_factories = ~[(~"snicklefritz", move loader_factory)];
-    let resource_task = create_resource_task_with_loaders(move loader_factories);
+    let resource_task = create_resource_task_with_loaders(move loader_factories, ~[]);
     let progress = Port();
     resource_task.send(Load(url::from_str(~"snicklefritz://heya").get(), progress.chan()));
     assert progress.recv() == Payload(move payload);
     assert progress.recv() == Done(Ok(()));
     resource_task.send(Exit);
 }
+
+#[test]
+#[allow(non_implicitly_copyable_typarams)]
+fn should_load_data_urls() {
+    let resource_task = ResourceTask();
+    let progress = Port();
+    resource_task.send(Load(url::from_str(~"data:text/plain;base64,SGVsbG8=").get(),
+                            progress.chan()));
+    assert progress.recv() == Meta(~"text/plain");
+    assert progress.recv() == Payload(str::to_bytes("Hello"));
+    assert progress.recv() == Done(Ok(()));
+    resource_task.send(Exit);
+}
+
+#[cfg(test)]
+fn partial_content_loader(ranges: ~[(uint, ~[u8])], total: uint) -> ResourceTask {
+    let loader_factory = fn~(_url: Url, _headers: ~[(~str, ~str)],
+                             progress_chan: Chan<ProgressMsg>, copy ranges) {
+        for ranges.each |range| {
+            match *range {
+                (offset, ref data) => progress_chan.send(PartialContent(offset, total, copy *data))
+            }
+        }
+        progress_chan.send(Done(Ok(())));
+    };
+    create_resource_task_with_loaders(~[(~"ranges", move loader_factory)], ~[])
+}
+
+#[test]
+#[allow(non_implicitly_copyable_typarams)]
+fn should_assemble_partial_content() {
+    let resource_task = partial_content_loader(~[(3, ~[4, 5]), (0, ~[1, 2, 3])], 5);
+    let progress = Port();
+    resource_task.send(Load(url::from_str(~"ranges://heya").get(), progress.chan()));
+    assert progress.recv() == Payload(~[1, 2, 3, 4, 5]);
+    assert progress.recv() == Done(Ok(()));
+    resource_task.send(Exit);
+}
+
+#[test]
+#[allow(non_implicitly_copyable_typarams)]
+fn should_fail_when_partial_content_has_gaps() {
+    let resource_task = partial_content_loader(~[(0, ~[1, 2]), (3, ~[4, 5])], 5);
+    let url = url::from_str(~"ranges://heya").get();
+    assert request_response!(resource_task, Load, url) == Done(Err(LoadFailed));
+    resource_task.send(Exit);
+}
+
+#[test]
+#[allow(non_implicitly_copyable_typarams)]
+fn should_fail_when_partial_content_claims_more_than_arrives() {
+    // Sizing the buffer from this total would exhaust memory
+    let resource_task = partial_content_loader(~[(0, ~[1, 2]), (uint::max_value, ~[3])],
+                                               uint::max_value);
+    let url = url::from_str(~"ranges://heya").get();
+    assert request_response!(resource_task, Load, url) == Done(Err(LoadFailed));
+    resource_task.send(Exit);
+}
+
+#[test]
+#[allow(non_implicitly_copyable_typarams)]
+fn should_time_out_stalled_loads() {
+    // Responds promptly, then stalls for far longer than the read timeout
+    let loader_factory = fn~(_url: Url, _headers: ~[(~str, ~str)],
+                             progress_chan: Chan<ProgressMsg>) {
+        do task::spawn |move progress_chan| {
+            progress_chan.send(Meta(~"text/plain"));
+            timer::sleep(uv_global_loop::get(), 2000);
+            progress_chan.send(Done(Ok(())));
+        }
+    };
+    let resource_task = create_resource_task_with_loaders(~[(~"stall", move loader_factory)], ~[]);
+    let progress = Port();
+    let start = std::time::precise_time_ns();
+    let timeouts = Timeouts { connect: Some(1000), read: Some(50) };
+    resource_task.send(LoadWithTimeouts(url::from_str(~"stall://heya").get(), timeouts,
+                                        progress.chan()));
+    assert progress.recv() == Meta(~"text/plain");
+    assert progress.recv() == Done(Err(Timeout));
+
+    // Not before the read timeout was up
+    let elapsed_ms = (std::time::precise_time_ns() - start) / 1000000;
+    assert elapsed_ms >= 50;
+    resource_task.send(Exit);
+}
+
+#[test]
+#[allow(non_implicitly_copyable_typarams)]
+fn should_send_configured_request_headers() {
+    // Echoes the request headers back as Meta messages
+    let loader_factory = fn~(_url: Url, headers: ~[(~str, ~str)],
+                             progress_chan: Chan<ProgressMsg>) {
+        for headers.each |&(ref name, ref value)| {
+            progress_chan.send(Meta(fmt!("%s: %s", *name, *value)));
+        }
+        progress_chan.send(Done(Ok(())));
+    };
+    let headers = ~[(~"Accept", ~"text/html"), (~"Accept-Language", ~"fr-CH, fr;q=0.9")];
+    let resource_task = create_resource_task_with_loaders(~[(~"echo", move loader_factory)],
+                                                          move headers);
+
+    let progress = Port();
+    resource_task.send(Load(url::from_str(~"echo://heya").get(), progress.chan()));
+    assert progress.recv() == Meta(~"Accept: text/html");
+    assert progress.recv() == Meta(~"Accept-Language: fr-CH, fr;q=0.9");
+    assert progress.recv() == Done(Ok(()));
+
+    // A load's own headers replace the configured ones of the same name
+    let progress = Port();
+    resource_task.send(LoadWithHeaders(url::from_str(~"echo://heya").get(),
+                                       ~[(~"accept", ~"image/png")], no_timeouts(),
+                                       progress.chan()));
+    assert progress.recv() == Meta(~"accept: image/png");
+    assert progress.recv() == Meta(~"Accept-Language: fr-CH, fr;q=0.9");
+    assert progress.recv() == Done(Ok(()));
+    resource_task.send(Exit);
+}
+
+#[test]
+#[allow(non_implicitly_copyable_typarams)]
+fn should_limit_concurrent_loads_per_origin() {
+    // Tells the monitor when each load starts and ends, holding it open for a while
+    let (monitor_port, monitor_chan) = stream();
+    let monitor_chan = SharedChan(move monitor_chan);
+    let loader_factory = fn~(_url: Url, _headers: ~[(~str, ~str)],
+                             progress_chan: Chan<ProgressMsg>, move monitor_chan) {
+        let monitor_chan = monitor_chan.clone();
+        do task::spawn |move progress_chan, move monitor_chan| {
+            monitor_chan.send(true);
+            timer::sleep(uv_global_loop::get(), 20);
+            monitor_chan.send(false);
+            progress_chan.send(Done(Ok(())));
+        }
+    };
+    let resource_task = create_resource_task_with_loaders(~[(~"count", move loader_factory)],
+                                                          ~[]);
+    resource_task.send(SetLoadsPerOrigin(2));
+
+    let mut ports = ~[];
+    for uint::range(0, 6) |i| {
+        let progress = Port();
+        resource_task.send(Load(url::from_str(fmt!("count://example.com/%u", i)).get(),
+                                progress.chan()));
+        ports.push(move progress);
+    }
+
+    let mut running = 0;
+    let mut most_running = 0;
+    for 12.times {
+        if monitor_port.recv() {
+            running += 1;
+            most_running = uint::max(most_running, running);
+        } else {
+            running -= 1;
+        }
+    }
+    assert most_running <= 2;
+
+    // Every load finished in the end
+    for ports.each |progress| {
+        assert progress.recv() == Done(Ok(()));
+    }
+    resource_task.send(Exit);
+}
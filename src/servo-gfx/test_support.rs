@@ -0,0 +1,50 @@
+/*!
+Helpers for tests of the tasks that answer requests on a chan. The servo
+crate's tests include this module by path, since neither macros nor test-only
+functions are exported from a crate.
+*/
+
+use resource::resource_task::{ControlMsg, Exit, Load, LoadWithHeaders, LoadWithTimeouts};
+use resource::resource_task::{ProgressMsg, ResourceTask, SetLoadsPerOrigin};
+use resource::util::spawn_listener;
+
+use core::pipes::{Chan, Port, SharedChan};
+use std::net::url::Url;
+
+/**
+Sends a request built from `$ctor`, its arguments, and a reply chan to
+`$task`, then waits for and returns the reply. Saves the stream boilerplate
+of request/response protocols such as the image cache's `GetImage`:
+
+    let response = request_response!(image_cache_task, GetImage, copy url);
+*/
+macro_rules! request_response(
+    ($task:expr, $ctor:path) => ({
+        let (request_response_port, request_response_chan) = pipes::stream();
+        $task.send($ctor(move request_response_chan));
+        request_response_port.recv()
+    });
+    ($task:expr, $ctor:path, $($arg:expr),+) => ({
+        let (request_response_port, request_response_chan) = pipes::stream();
+        $task.send($ctor($($arg),+, move request_response_chan));
+        request_response_port.recv()
+    })
+)
+
+/**
+A resource task that answers every load, whatever its scheme, by calling
+`on_load` with its URL and the chan to send its progress to.
+*/
+pub fn mock_resource_task(on_load: ~fn(url: &Url, response: Chan<ProgressMsg>))
+                       -> ResourceTask {
+    SharedChan(do spawn_listener |from_client: Port<ControlMsg>, move on_load| {
+        loop {
+            match from_client.recv() {
+                Load(url, response) | LoadWithTimeouts(url, _, response) |
+                LoadWithHeaders(url, _, _, response) => on_load(&url, response),
+                SetLoadsPerOrigin(*) => (),
+                Exit => break
+            }
+        }
+    })
+}
@@ -0,0 +1,388 @@
+/*!
+The Unicode Bidirectional Algorithm (UAX #9), which finds the order in which
+the parts of a line that mixes left-to-right and right-to-left text are
+displayed. Explicit embeddings and overrides, weak and neutral type
+resolution and reordering are implemented. Isolates are not, and the table of
+character types is an approximation that covers Latin, Hebrew and Arabic
+text.
+*/
+
+use util::range::Range;
+
+#[deriving_eq]
+pub enum Direction {
+    LeftToRight,
+    RightToLeft
+}
+
+/// The bidirectional character types of UAX #9
+#[deriving_eq]
+enum BidiClass {
+    L, R, AL,
+    EN, ES, ET, AN, CS, NSM, BN,
+    B, S, WS, ON,
+    LRE, LRO, RLE, RLO, PDF
+}
+
+/// Embeddings nested deeper than this are ignored
+const MAX_DEPTH: u8 = 61;
+
+/**
+Splits a line of text into runs of characters at the same embedding level,
+and returns their character ranges and directions in the order they are
+displayed from left to right. The characters of a right-to-left run are
+displayed in reverse.
+*/
+pub fn reorder_runs(text: &str, base_direction: Direction) -> ~[(Range, Direction)] {
+    let classes = str::chars(text).map(|c| bidi_class(*c));
+    let paragraph_level = match base_direction {
+        LeftToRight => 0,
+        RightToLeft => 1
+    };
+
+    let mut types = copy classes;
+    let mut levels = vec::from_elem(classes.len(), paragraph_level);
+    resolve_explicit_levels(classes, paragraph_level, &mut types, &mut levels);
+
+    // Embedding and override characters are ignored from here on
+    let kept = do vec::filter(vec::from_fn(classes.len(), |i| i)) |&i| { types[i] != BN };
+    let mut start = 0;
+    while start < kept.len() {
+        let level = levels[kept[start]];
+        let mut end = start;
+        while end < kept.len() && levels[kept[end]] == level {
+            end += 1;
+        }
+        let before = if start == 0 { paragraph_level } else { levels[kept[start - 1]] };
+        let after = if end == kept.len() { paragraph_level } else { levels[kept[end]] };
+        let sos = direction_class(uint::max(before as uint, level as uint) as u8);
+        let eos = direction_class(uint::max(after as uint, level as uint) as u8);
+        resolve_level_run(&mut types, &mut levels, vec::view(kept, start, end), level, sos, eos);
+        start = end;
+    }
+
+    // Ignored characters take the level of the character before them
+    for uint::range(0, classes.len()) |i| {
+        if types[i] == BN {
+            levels[i] = if i == 0 { paragraph_level } else { levels[i - 1] };
+        }
+    }
+    reset_whitespace_levels(classes, paragraph_level, &mut levels);
+    reorder_level_runs(levels)
+}
+
+// The type of a character, from the Unicode Character Database
+fn bidi_class(c: char) -> BidiClass {
+    match c as uint {
+        0x30 .. 0x39 | 0x06F0 .. 0x06F9 => EN,
+        0x2B | 0x2D => ES,
+        0x23 .. 0x25 | 0xA2 .. 0xA5 | 0xB0 | 0xB1 | 0x2030 .. 0x2034 | 0x20A0 .. 0x20CF => ET,
+        0x2C | 0x2E | 0x2F | 0x3A | 0xA0 | 0x060C => CS,
+        0x0A | 0x0D | 0x1C .. 0x1E | 0x85 | 0x2029 => B,
+        0x09 | 0x0B | 0x1F => S,
+        0x0C | 0x20 | 0x2000 .. 0x200A | 0x2028 => WS,
+        0x00 .. 0x08 | 0x0E .. 0x1B | 0x7F .. 0x84 | 0x86 .. 0x9F | 0xAD |
+        0x200B .. 0x200D | 0xFEFF => BN,
+        0x200E => L,
+        0x200F => R,
+        0x202A => LRE,
+        0x202B => RLE,
+        0x202C => PDF,
+        0x202D => LRO,
+        0x202E => RLO,
+        0x0300 .. 0x036F | 0x0591 .. 0x05BD | 0x05BF | 0x05C1 | 0x05C2 | 0x05C4 | 0x05C5 |
+        0x05C7 | 0x0610 .. 0x061A | 0x064B .. 0x065F | 0x0670 | 0x06D6 .. 0x06DC |
+        0x06DF .. 0x06E4 | 0x06E7 | 0x06E8 | 0x06EA .. 0x06ED => NSM,
+        0x0660 .. 0x0669 | 0x066B | 0x066C => AN,
+        0x0590 .. 0x05FF | 0x07C0 .. 0x085F | 0xFB1D .. 0xFB4F => R,
+        0x0600 .. 0x07BF | 0x08A0 .. 0x08FF | 0xFB50 .. 0xFDFF | 0xFE70 .. 0xFEFE => AL,
+        0x21 | 0x22 | 0x26 .. 0x2A | 0x3B .. 0x40 | 0x5B .. 0x60 | 0x7B .. 0x7E | 0xA1 |
+        0xA6 .. 0xA9 | 0xAB | 0xAC | 0xAE | 0xAF | 0xB4 | 0xB6 .. 0xB8 | 0xBB .. 0xBF |
+        0xD7 | 0xF7 | 0x2010 .. 0x2027 | 0x2035 .. 0x205E | 0x2190 .. 0x2BFF => ON,
+        _ => L
+    }
+}
+
+pure fn direction_class(level: u8) -> BidiClass {
+    if level % 2 == 0 { L } else { R }
+}
+
+// Rules X1-X9: the levels set by embeddings and overrides, and the types they force
+fn resolve_explicit_levels(classes: &[BidiClass], paragraph_level: u8,
+                           types: &mut ~[BidiClass], levels: &mut ~[u8]) {
+    // The level of each open embedding, and the type its override forces
+    let mut stack = ~[(paragraph_level, None)];
+    let mut overflow = 0u;
+    for classes.eachi |i, class| {
+        let (level, forced) = stack[stack.len() - 1];
+        levels[i] = level;
+        match *class {
+            RLE | LRE | RLO | LRO => {
+                let next = if *class == RLE || *class == RLO {
+                    (level + 1) | 1
+                } else {
+                    (level + 2) & !1u8
+                };
+                if next <= MAX_DEPTH && overflow == 0 {
+                    let next_forced = match *class {
+                        RLO => Some(R),
+                        LRO => Some(L),
+                        _ => None
+                    };
+                    stack.push((next, next_forced));
+                } else {
+                    overflow += 1;
+                }
+                types[i] = BN;
+            }
+            PDF => {
+                if overflow > 0 {
+                    overflow -= 1;
+                } else if stack.len() > 1 {
+                    stack.pop();
+                }
+                types[i] = BN;
+            }
+            B => levels[i] = paragraph_level,
+            BN => (),
+            _ => match forced {
+                Some(forced) => types[i] = forced,
+                None => ()
+            }
+        }
+    }
+}
+
+// Rules W1-W7, N1-N2 and I1-I2 for one run of characters at the same level
+fn resolve_level_run(types: &mut ~[BidiClass], levels: &mut ~[u8], run: &[uint], level: u8,
+                     sos: BidiClass, eos: BidiClass) {
+    let n = run.len();
+
+    // W1: marks take the type of what they mark
+    let mut previous = sos;
+    for run.each |&i| {
+        if types[i] == NSM {
+            types[i] = previous;
+        }
+        previous = types[i];
+    }
+
+    // W2: numbers in Arabic text are Arabic numbers. W3: Arabic letters are right-to-left.
+    let mut last_strong = sos;
+    for run.each |&i| {
+        match types[i] {
+            L | R => last_strong = types[i],
+            AL => {
+                last_strong = AL;
+                types[i] = R;
+            }
+            EN if last_strong == AL => types[i] = AN,
+            _ => ()
+        }
+    }
+
+    // W4: a single separator between two numbers of the same kind joins them
+    for uint::range(1, uint::max(n, 1) - 1) |k| {
+        let (before, after) = (types[run[k - 1]], types[run[k + 1]]);
+        match types[run[k]] {
+            ES if before == EN && after == EN => types[run[k]] = EN,
+            CS if before == after && (before == EN || before == AN) => types[run[k]] = before,
+            _ => ()
+        }
+    }
+
+    // W5: terminators next to a European number are part of it
+    let mut k = 0;
+    while k < n {
+        if types[run[k]] != ET {
+            k += 1;
+            loop;
+        }
+        let start = k;
+        while k < n && types[run[k]] == ET {
+            k += 1;
+        }
+        if (start > 0 && types[run[start - 1]] == EN) || (k < n && types[run[k]] == EN) {
+            for uint::range(start, k) |j| {
+                types[run[j]] = EN;
+            }
+        }
+    }
+
+    // W6: other separators and terminators are neutral. W7: European numbers
+    // in left-to-right text are left-to-right.
+    let mut last_strong = sos;
+    for run.each |&i| {
+        match types[i] {
+            ES | ET | CS => types[i] = ON,
+            L | R => last_strong = types[i],
+            EN if last_strong == L => types[i] = L,
+            _ => ()
+        }
+    }
+
+    // N1: neutrals between text of the same direction take that direction.
+    // N2: other neutrals take the direction of the embedding.
+    let mut k = 0;
+    while k < n {
+        if !is_neutral(types[run[k]]) {
+            k += 1;
+            loop;
+        }
+        let start = k;
+        while k < n && is_neutral(types[run[k]]) {
+            k += 1;
+        }
+        let before = if start == 0 { sos } else { strong_direction(types[run[start - 1]]) };
+        let after = if k == n { eos } else { strong_direction(types[run[k]]) };
+        let resolved = if before == after { before } else { direction_class(level) };
+        for uint::range(start, k) |j| {
+            types[run[j]] = resolved;
+        }
+    }
+
+    // I1-I2: raise the level of text against the embedding's direction
+    for run.each |&i| {
+        levels[i] += match (level % 2, types[i]) {
+            (0, R) => 1,
+            (0, AN) | (0, EN) => 2,
+            (1, L) | (1, EN) | (1, AN) => 1,
+            _ => 0
+        };
+    }
+}
+
+pure fn is_neutral(class: BidiClass) -> bool {
+    class == B || class == S || class == WS || class == ON
+}
+
+// The direction a resolved type counts as for rule N1, in which numbers are right-to-left
+pure fn strong_direction(class: BidiClass) -> BidiClass {
+    if class == L { L } else { R }
+}
+
+/*
+Rule L1: separators, and whitespace before them or at the end of the line,
+go back to the paragraph level.
+*/
+fn reset_whitespace_levels(classes: &[BidiClass], paragraph_level: u8, levels: &mut ~[u8]) {
+    let mut trailing = true;
+    let mut i = classes.len();
+    while i > 0 {
+        i -= 1;
+        match classes[i] {
+            S | B => {
+                levels[i] = paragraph_level;
+                trailing = true;
+            }
+            WS | BN | LRE | LRO | RLE | RLO | PDF => {
+                if trailing {
+                    levels[i] = paragraph_level;
+                }
+            }
+            _ => trailing = false
+        }
+    }
+}
+
+/*
+Rule L2: groups the characters into runs at the same level, then, from the
+highest level down to the lowest odd one, reverses every sequence of runs at
+that level or higher.
+*/
+fn reorder_level_runs(levels: &[u8]) -> ~[(Range, Direction)] {
+    let mut runs = ~[];
+    let mut start = 0;
+    while start < levels.len() {
+        let mut end = start;
+        while end < levels.len() && levels[end] == levels[start] {
+            end += 1;
+        }
+        runs.push((Range::new(start, end - start), levels[start]));
+        start = end;
+    }
+
+    let mut highest = 0;
+    let mut lowest_odd = MAX_DEPTH + 1;
+    for levels.each |&level| {
+        highest = uint::max(highest as uint, level as uint) as u8;
+        if level % 2 == 1 {
+            lowest_odd = uint::min(lowest_odd as uint, level as uint) as u8;
+        }
+    }
+    let run_level = |runs: &~[(Range, u8)], i: uint| {
+        let (_, level) = runs[i];
+        level
+    };
+
+    let mut level = highest;
+    while level >= lowest_odd && level > 0 {
+        let mut i = 0;
+        while i < runs.len() {
+            if run_level(&runs, i) < level {
+                i += 1;
+                loop;
+            }
+            let start = i;
+            while i < runs.len() && run_level(&runs, i) >= level {
+                i += 1;
+            }
+            let (mut a, mut b) = (start, i - 1);
+            while a < b {
+                runs[a] <-> runs[b];
+                a += 1;
+                b -= 1;
+            }
+        }
+        level -= 1;
+    }
+
+    do runs.map |&(range, level)| {
+        (range, if level % 2 == 0 { LeftToRight } else { RightToLeft })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use util::range::Range;
+
+    fn runs(text: &str, base_direction: Direction) -> ~[(uint, uint, Direction)] {
+        do reorder_runs(text, base_direction).map |&(range, direction)| {
+            (range.begin(), range.length(), direction)
+        }
+    }
+
+    #[test]
+    fn should_keep_logical_order_of_embedded_right_to_left_words() {
+        // "abc " then three Hebrew letters, then " def"
+        assert runs("abc אבג def", LeftToRight) ==
+            ~[(0, 4, LeftToRight), (4, 3, RightToLeft), (7, 4, LeftToRight)];
+        assert runs("abc", LeftToRight) == ~[(0, 3, LeftToRight)];
+        assert runs("", RightToLeft) == ~[];
+    }
+
+    #[test]
+    fn should_reverse_runs_of_right_to_left_paragraphs() {
+        // Hebrew, then "abc 12", then Hebrew. The number joins the English text, and
+        // the space after it takes the paragraph's direction.
+        assert runs("אבג abc 12 דה", RightToLeft) ==
+            ~[(10, 3, RightToLeft), (4, 6, LeftToRight), (0, 4, RightToLeft)];
+    }
+
+    #[test]
+    fn should_put_arabic_numbers_after_arabic_words() {
+        // "a ", an Arabic letter, " 12". Digits after Arabic are Arabic numbers, which
+        // read left-to-right but are displayed to the left of the word before them.
+        assert runs("a ج 12", LeftToRight) ==
+            ~[(0, 2, LeftToRight), (4, 2, LeftToRight), (2, 2, RightToLeft)];
+    }
+
+    #[test]
+    fn should_follow_explicit_overrides() {
+        // Latin letters forced right-to-left by RLO ... PDF. The controls themselves
+        // stay at the paragraph's level.
+        assert runs("\u202Eabc\u202C", LeftToRight) ==
+            ~[(0, 1, LeftToRight), (1, 3, RightToLeft), (4, 1, LeftToRight)];
+    }
+}
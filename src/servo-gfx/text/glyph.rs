@@ -635,6 +635,43 @@ pub impl GlyphStore {
         }
     }
 
+    // Returns the advance at each character boundary of the range, relative to
+    // its start: entry k is the total advance of the first k characters.
+    fn cumulative_advances_for_char_range(range: &const Range) -> ~[Au] {
+        let mut advances = vec::with_capacity(range.length() + 1);
+        let mut total = Au(0);
+        advances.push(total);
+        for range.eachi |i| {
+            for self.iter_glyphs_for_char_index(i) |_i, glyph| {
+                total += glyph.advance();
+            }
+            advances.push(total);
+        }
+        advances
+    }
+
+    // Returns the index of the character in the range whose glyphs span the
+    // horizontal offset `x`, measured from the start of the range. Offsets
+    // past the last glyph map to the end of the range.
+    fn char_index_for_x(range: &const Range, x: Au) -> uint {
+        let advances = self.cumulative_advances_for_char_range(range);
+        for uint::range(0, range.length()) |k| {
+            if x < advances[k + 1] {
+                return range.begin() + k;
+            }
+        }
+        range.end()
+    }
+
+    // Returns the horizontal offset, from the start of the range, of the
+    // leading edge of the character at index `i`. `i` may be the end of the
+    // range, in which case this is the advance of the whole range.
+    fn x_for_char_index(range: &const Range, i: uint) -> Au {
+        assert i >= range.begin() && i <= range.end();
+        let advances = self.cumulative_advances_for_char_range(range);
+        advances[i - range.begin()]
+    }
+
     // getter methods
     pure fn char_is_space(i: uint) -> bool {
         assert i < self.entry_buffer.len();
@@ -691,3 +728,45 @@ pub impl GlyphStore {
         self.entry_buffer[i] = entry.set_can_break_before(t);
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use geometry::Au;
+    use servo_gfx_util::range::Range;
+
+    // A store whose characters have advances of 10, 20, 30 and 40.
+    fn test_store() -> GlyphStore {
+        let mut store = GlyphStore::new(4);
+        for uint::range(0, 4) |i| {
+            let data = GlyphData(1, Au(10 * (i as i32 + 1)), None, false, true, true);
+            store.add_glyph_for_char_index(i, &data);
+        }
+        store
+    }
+
+    #[test]
+    fn should_round_trip_between_char_index_and_x() {
+        let store = test_store();
+        let range = Range::new(1, 3);
+
+        for range.eachi |i| {
+            let x = store.x_for_char_index(&const range, i);
+            assert store.char_index_for_x(&const range, x) == i;
+            // Any offset within the character's glyphs maps back to it
+            assert store.char_index_for_x(&const range, x + Au(5)) == i;
+        }
+        assert store.x_for_char_index(&const range, 2) == Au(20);
+    }
+
+    #[test]
+    fn should_clamp_char_index_for_x_to_range() {
+        let store = test_store();
+        let range = Range::new(1, 3);
+
+        assert store.char_index_for_x(&const range, Au(0)) == 1;
+        assert store.x_for_char_index(&const range, range.end()) == Au(90);
+        assert store.char_index_for_x(&const range, Au(90)) == range.end();
+        assert store.char_index_for_x(&const range, Au(1000)) == range.end();
+    }
+}
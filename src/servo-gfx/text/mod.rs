@@ -8,6 +8,7 @@ pub use text::shaper::Shaper;
 pub use text::text_run::TextRun;
 pub use text::text_run::SendableTextRun;
 
+pub mod bidi;
 pub mod glyph;
 pub mod text_run;
 pub mod util;
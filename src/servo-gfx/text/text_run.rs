@@ -103,6 +103,22 @@ pub impl TextRun {
         }
     }
 
+    /**
+    Splits the run before the character at `index` into two runs with the
+    same font. Each half is shaped on its own, so glyphs that would combine
+    across the split, as in ligatures and kerning, no longer do.
+    */
+    fn split_at(&self, index: uint) -> (@TextRun, @TextRun) {
+        if index > self.char_len() {
+            fail!(fmt!("split index %u is past the end of a run of %u chars",
+                       index, self.char_len()));
+        }
+        let byte_index = byte_index_for_char(self.text, index);
+        let before = str::slice(self.text, 0, byte_index);
+        let after = str::slice(self.text, byte_index, self.text.len());
+        (@TextRun::new(self.font, move before), @TextRun::new(self.font, move after))
+    }
+
     pure fn char_len() -> uint { self.glyphs.entry_buffer.len() }
     pure fn glyphs(&self) -> &self/GlyphStore { &self.glyphs }
 
@@ -119,6 +135,18 @@ pub impl TextRun {
         self.font.measure_text(self, range)
     }
 
+    /// Returns the index of the character at horizontal offset `x` from the
+    /// start of `range`, or the end of the range if `x` is past its last glyph.
+    fn index_for_x(&self, range: &const Range, x: Au) -> uint {
+        self.glyphs.char_index_for_x(range, x)
+    }
+
+    /// Returns the horizontal offset of the character at `index` from the
+    /// start of `range`. The inverse of `index_for_x`.
+    fn x_for_index(&self, range: &const Range, index: uint) -> Au {
+        self.glyphs.x_for_char_index(range, index)
+    }
+
     fn min_width_for_range(&self, range: &const Range) -> Au {
         let mut max_piece_width = Au(0);
         debug!("iterating outer range %?", range);
@@ -174,3 +202,69 @@ pub impl TextRun {
         }
     }
 }
+
+// The byte offset of the character at `char_index` in `text`
+fn byte_index_for_char(text: &str, char_index: uint) -> uint {
+    let mut byte_i = 0u;
+    for char_index.times {
+        byte_i = str::char_range_at(text, byte_i).next;
+    }
+    byte_i
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use font_context;
+    use font_context::FontContext;
+    use geometry::Au;
+    use servo_gfx_font::Font;
+    use servo_gfx_util::range::Range;
+
+    use azure::azure_hl::CairoBackend;
+
+    fn test_font(fctx: &FontContext) -> @Font {
+        result::unwrap(Font::new_from_buffer(fctx, font_context::test_font_bin(),
+                                             &font_context::dummy_style(), CairoBackend))
+    }
+
+    fn width(run: &TextRun) -> Au {
+        run.metrics_for_range(&const Range::new(0, run.char_len())).advance_width
+    }
+
+    #[test]
+    fn should_split_multibyte_text_at_char_boundaries() {
+        assert byte_index_for_char("h\u00e9llo", 0) == 0;
+        assert byte_index_for_char("h\u00e9llo", 2) == 3;
+        assert byte_index_for_char("h\u00e9llo", 5) == 6;
+
+        let fctx = FontContext::new(CairoBackend, false);
+        let font = test_font(&fctx);
+        let run = TextRun::new(font, ~"h\u00e9llo w\u00f6rld");
+        let (before, after) = run.split_at(2);
+        assert before.text == ~"h\u00e9" && before.char_len() == 2;
+        assert after.text == ~"llo w\u00f6rld" && after.char_len() == 9;
+
+        let (whole, empty) = run.split_at(run.char_len());
+        assert whole.text == run.text && empty.text == ~"";
+    }
+
+    #[test]
+    fn should_keep_the_run_width_when_split() {
+        let fctx = FontContext::new(CairoBackend, false);
+        let font = test_font(&fctx);
+        let run = TextRun::new(font, ~"hello world");
+        // Split at the space, where nothing is kerned
+        let (before, after) = run.split_at(5);
+        assert width(before) + width(after) == width(&run);
+        assert width(before) > Au(0) && width(after) > Au(0);
+    }
+
+    #[test]
+    #[should_fail]
+    fn should_fail_to_split_past_the_end() {
+        let fctx = FontContext::new(CairoBackend, false);
+        let font = test_font(&fctx);
+        TextRun::new(font, ~"abc").split_at(4);
+    }
+}
@@ -99,6 +99,68 @@ pub fn transform_text(text: &str, mode: CompressionMode) -> ~str {
     }
 }
 
+/// Stands in for invalid sequences in malformed text
+pub const REPLACEMENT_CHAR: char = '\uFFFD';
+
+/**
+Decodes UTF-8, replacing each invalid or truncated sequence with
+REPLACEMENT_CHAR. A sequence ends at the first byte that can't continue it,
+which is then decoded afresh.
+*/
+pub fn from_utf8_lossy(bytes: &[u8]) -> ~str {
+    let mut out = ~"";
+    let mut i = 0;
+    while i < bytes.len() {
+        let lead = bytes[i];
+        // The sequence length, and the smallest code point it may encode
+        let (len, min) = if lead < 0x80 {
+            (1, 0)
+        } else if lead & 0xE0 == 0xC0 {
+            (2, 0x80)
+        } else if lead & 0xF0 == 0xE0 {
+            (3, 0x800)
+        } else if lead & 0xF8 == 0xF0 {
+            (4, 0x10000)
+        } else {
+            // A stray continuation byte or an invalid lead byte
+            str::push_char(&mut out, REPLACEMENT_CHAR);
+            i += 1;
+            loop;
+        };
+
+        // The payload bits of the lead byte, by sequence length
+        let mask = [0x7F, 0x1F, 0x0F, 0x07][len - 1];
+        let mut code = (lead & mask) as uint;
+        let mut j = 1;
+        while j < len && i + j < bytes.len() && bytes[i + j] & 0xC0 == 0x80 {
+            code = code << 6 | (bytes[i + j] & 0x3F) as uint;
+            j += 1;
+        }
+
+        let is_surrogate = code >= 0xD800 && code <= 0xDFFF;
+        if j < len || code < min || code > 0x10FFFF || is_surrogate {
+            str::push_char(&mut out, REPLACEMENT_CHAR);
+        } else {
+            str::push_char(&mut out, code as char);
+        }
+        i += j;
+    }
+    out
+}
+
+/**
+Returns the text unchanged if it is valid UTF-8, and otherwise with its
+invalid sequences replaced. Text from outside Rust, such as the HTML parser,
+must be checked before it is indexed by char.
+*/
+pub fn to_valid_utf8(text: ~str) -> ~str {
+    if str::byte_slice(text, |bytes| str::is_utf8(bytes)) {
+        move text
+    } else {
+        str::byte_slice(text, |bytes| from_utf8_lossy(bytes))
+    }
+}
+
 pub fn float_to_fixed(before: int, f: float) -> i32 {
     (1i32 << before) * (f as i32)
 }
@@ -221,3 +283,22 @@ fn test_transform_compress_whitespace_newline() {
         assert transform_text(test_strs[i], mode) == oracle_strs[i];
     }
 }
+
+#[test]
+fn test_from_utf8_lossy_replaces_invalid_sequences() {
+    // 0xC3 starts a two byte sequence, but '(' can't continue it
+    let text = from_utf8_lossy([0x61, 0xC3, 0x28, 0x62, 0xC3, 0xA9]);
+    assert text == ~"a\uFFFD(b\u00E9";
+    assert str::char_len(text) == 5;
+    assert transform_text(text, CompressWhitespaceNewline) == text;
+
+    assert from_utf8_lossy([0x80, 0xE2, 0x82]) == ~"\uFFFD\uFFFD";
+    assert from_utf8_lossy([0xED, 0xA0, 0x80]) == ~"\uFFFD";
+    assert from_utf8_lossy(str::to_bytes("plain")) == ~"plain";
+}
+
+#[test]
+fn test_from_utf8_lossy_decodes_every_sequence_length() {
+    let text = from_utf8_lossy([0x24, 0xC2, 0xA2, 0xE2, 0x82, 0xAC, 0xF0, 0x90, 0x8D, 0x88]);
+    assert text == ~"$\u00A2\u20AC\U00010348";
+}
@@ -8,6 +8,7 @@ enum RangeRelation {
     EntirelyAfter
 }
 
+#[deriving_eq]
 pub struct Range {
     priv off: uint,
     priv len: uint
@@ -21,6 +22,28 @@ pub impl Range {
     static pub pure fn empty() -> Range {
         Range::new(0, 0)
     }
+
+    /// Merges overlapping and adjacent ranges, returning the smallest set of
+    /// ranges covering the same indices, sorted by their beginnings.
+    static pub pure fn coalesce(ranges: &[Range]) -> ~[Range] {
+        let sorted = do std::sort::merge_sort(ranges) |a, b| { a.begin() <= b.begin() };
+
+        let mut result: ~[Range] = ~[];
+        for sorted.each |range| {
+            let merged = match result.last_opt() {
+                Some(last) if range.begin() <= last.end() => {
+                    let end = uint::max(last.end(), range.end());
+                    Some(Range::new(last.begin(), end - last.begin()))
+                }
+                _ => None
+            };
+            match merged {
+                Some(merged) => result[result.len() - 1] = merged,
+                None => result.push(*range)
+            }
+        }
+        result
+    }
 }
 
 pub impl Range {
@@ -111,3 +134,20 @@ pub impl Range {
         debug!("repair_after_coalesced_range: new range: ---- %?", self);
     }
 }
+
+#[test]
+fn should_coalesce_adjacent_ranges() {
+    assert Range::coalesce([Range::new(0, 3), Range::new(3, 2)]) == ~[Range::new(0, 5)];
+}
+
+#[test]
+fn should_not_coalesce_separate_ranges() {
+    assert Range::coalesce([Range::new(4, 2), Range::new(0, 2)])
+        == ~[Range::new(0, 2), Range::new(4, 2)];
+}
+
+#[test]
+fn should_coalesce_overlapping_ranges() {
+    assert Range::coalesce([Range::new(0, 4), Range::new(2, 4)]) == ~[Range::new(0, 6)];
+    assert Range::coalesce([Range::new(0, 6), Range::new(2, 1)]) == ~[Range::new(0, 6)];
+}
@@ -105,3 +105,82 @@ pub fn url_map<T: Copy>() -> UrlMap<T> {
 
     HashMap::<Url, T>()
 }
+
+pub trait UrlMapMethods<T: Copy> {
+    /// Returns the value for `url`, first inserting `default` if there is
+    /// none, in a single lookup.
+    fn find_or_insert(url: Url, default: T) -> T;
+    /// Sets the value for `url`, returning the value it replaced, in a
+    /// single lookup.
+    fn replace(url: Url, value: T) -> Option<T>;
+}
+
+impl<T: Copy> UrlMap<T>: UrlMapMethods<T> {
+    fn find_or_insert(url: Url, default: T) -> T {
+        let mut found = None;
+        do self.update_with_key(move url, copy default) |_, old, _| {
+            found = Some(copy old);
+            old
+        };
+        match move found {
+            Some(move value) => move value,
+            None => move default
+        }
+    }
+
+    fn replace(url: Url, value: T) -> Option<T> {
+        let mut replaced = None;
+        do self.update_with_key(move url, value) |_, old, new| {
+            replaced = Some(old);
+            new
+        };
+        move replaced
+    }
+}
+
+mod url_map_tests {
+
+    #[test]
+    fn should_insert_on_miss() {
+        let map: UrlMap<int> = url_map();
+        let url = make_url(~"file", None);
+        assert map.find_or_insert(copy url, 1) == 1;
+        assert map.find(&url) == Some(1);
+    }
+
+    #[test]
+    fn should_not_insert_on_hit() {
+        let map: UrlMap<int> = url_map();
+        let url = make_url(~"file", None);
+        map.insert(copy url, 1);
+        assert map.find_or_insert(copy url, 2) == 1;
+        assert map.find(&url) == Some(1);
+    }
+
+    #[test]
+    fn should_return_the_stored_value_over_many_lookups() {
+        let map: UrlMap<@mut ~[int]> = url_map();
+        let url = make_url(~"file", None);
+        let first = map.find_or_insert(copy url, @mut ~[]);
+
+        for uint::range(0, 100) |i| {
+            let values = map.find_or_insert(copy url, @mut ~[]);
+            assert ptr::ref_eq(values, first);
+            values.push(i as int);
+        }
+
+        assert map.size() == 1;
+        assert *map.get(&url) == vec::from_fn(100, |i| i as int);
+    }
+
+    #[test]
+    fn should_replace_and_return_the_previous_value() {
+        let map: UrlMap<int> = url_map();
+        let url = make_url(~"file", None);
+        assert map.replace(copy url, 1).is_none();
+        assert map.find(&url) == Some(1);
+        assert map.replace(copy url, 2) == Some(1);
+        assert map.find(&url) == Some(2);
+        assert map.size() == 1;
+    }
+}
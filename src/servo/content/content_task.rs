@@ -3,14 +3,18 @@ The content task is the main task that runs JavaScript and spawns layout
 tasks.
 */
 
+use content::embedder::{EmbedderCallbacks, EmbedderFactory};
 use dom::bindings::utils::rust_box;
 use dom::document::Document;
 use dom::node::{Node, NodeScope, define_bindings};
 use dom::event::{Event, ResizeEvent, ReflowEvent};
+use dom::mutation::{AttributeChanged, ChildAdded, ChildRemoved, MutationRecord};
 use dom::window::Window;
+use html::charset::{charset_from_mime_type, transcode_to_utf8};
 use layout::layout_task;
-use layout::layout_task::{AddStylesheet, BuildData, BuildMsg, Damage, LayoutTask};
-use layout::layout_task::{MatchSelectorsDamage, NoDamage, ReflowDamage};
+use layout::layout_task::{AddStylesheet, AppendNodesMsg, BuildData, BuildMsg, Damage};
+use layout::layout_task::{FinishMsg, LayoutTask};
+use layout::layout_task::{MatchSelectorsDamage, NoDamage, ReflowDamage, RestyleDamage};
 use util::task::spawn_listener;
 
 use core::pipes::{Port, Chan, SharedChan, select2};
@@ -21,13 +25,16 @@ use core::ptr::null;
 use core::util::replace;
 use geom::size::Size2D;
 use gfx::resource::image_cache_task::ImageCacheTask;
+use gfx::resource::resource_task::{Done, Header, Load, Meta, PartialContent, Payload};
+use gfx::resource::resource_task::{NotModified, Redirected};
 use gfx::resource::resource_task::ResourceTask;
 use gfx::util::url::make_url;
 use js::JSVAL_NULL;
 use js::global::{global_class, debug_fns};
 use js::glue::bindgen::RUST_JSVAL_TO_OBJECT;
 use js::jsapi::{JSContext, JSVal};
-use js::jsapi::bindgen::{JS_CallFunctionValue, JS_GetContextPrivate};
+use js::jsapi::bindgen::{JS_CallFunctionValue, JS_ClearPendingException, JS_GetContextPrivate,
+                         JS_ReportPendingException};
 use js::rust::{Compartment, Cx};
 use jsrt = js::rust::rt;
 use newcss::stylesheet::Stylesheet;
@@ -40,7 +47,13 @@ use html;
 
 pub enum ControlMsg {
     ParseMsg(Url),
-    ExecuteMsg(Url),
+    /// Loads the document at a URL, converting it to UTF-8 per its charset, and shows it,
+    /// or shows an error page if it can't be loaded
+    NavigateMsg(Url),
+    /// Runs the script at a URL. If the flag is set the script is sandboxed: it runs in a
+    /// compartment of its own that has the debug functions but no DOM, so it can reach neither
+    /// the document nor the network.
+    ExecuteMsg(Url, bool),
     Timer(~dom::window::TimerData),
     ExitMsg
 }
@@ -55,7 +68,8 @@ pub fn ContentTask(layout_task: LayoutTask,
                    dom_event_port: Port<Event>,
                    dom_event_chan: SharedChan<Event>,
                    resource_task: ResourceTask,
-                   img_cache_task: ImageCacheTask)
+                   img_cache_task: ImageCacheTask,
+                   embedder_factory: EmbedderFactory)
                 -> ContentTask {
     let (control_port, control_chan) = pipes::stream();
 
@@ -64,15 +78,18 @@ pub fn ContentTask(layout_task: LayoutTask,
     let control_port = Cell(control_port);
     let dom_event_port = Cell(dom_event_port);
     let dom_event_chan = Cell(dom_event_chan);
+    let embedder_factory = Cell(move embedder_factory);
 
     do task().sched_mode(SingleThreaded).spawn {
+        let embedder = (embedder_factory.take())();
         let content = Content(layout_task.clone(),
                               control_port.take(),
                               control_chan_copy.clone(),
                               resource_task.clone(),
                               img_cache_task.clone(),
                               dom_event_port.take(),
-                              dom_event_chan.take());
+                              dom_event_chan.take(),
+                              embedder);
         content.start();
     }
 
@@ -90,6 +107,8 @@ pub struct Content {
     event_chan: pipes::SharedChan<Event>,
 
     scope: NodeScope,
+    // Records of the mutations made through `scope` since the last relayout
+    mutation_port: pipes::Port<MutationRecord>,
     jsrt: jsrt,
     cx: @Cx,
 
@@ -97,9 +116,13 @@ pub struct Content {
     mut window:   Option<@Window>,
     mut doc_url: Option<Url>,
     mut window_size: Size2D<uint>,
+    // Device pixels per px. The platform doesn't report this yet.
+    mut display_density: float,
 
     resource_task: ResourceTask,
 
+    embedder: @EmbedderCallbacks,
+
     compartment: Option<@mut Compartment>,
 
     // What parts of layout are dirty.
@@ -112,7 +135,8 @@ pub fn Content(layout_task: LayoutTask,
                resource_task: ResourceTask,
                img_cache_task: ImageCacheTask,
                event_port: pipes::Port<Event>,
-               event_chan: pipes::SharedChan<Event>)
+               event_chan: pipes::SharedChan<Event>,
+               embedder: @EmbedderCallbacks)
             -> @Content {
     let jsrt = jsrt();
     let cx = jsrt.cx();
@@ -125,6 +149,10 @@ pub fn Content(layout_task: LayoutTask,
           Err(()) => None
     };
 
+    let scope = NodeScope();
+    let (mutation_port, mutation_chan) = pipes::stream();
+    scope.observe_mutations(move mutation_chan);
+
     let content = @Content {
         layout_task : move layout_task,
         layout_join_port : None,
@@ -134,7 +162,8 @@ pub fn Content(layout_task: LayoutTask,
         event_port : move event_port,
         event_chan : move event_chan,
 
-        scope : NodeScope(),
+        scope : scope,
+        mutation_port : move mutation_port,
         jsrt : jsrt,
         cx : cx,
 
@@ -142,8 +171,10 @@ pub fn Content(layout_task: LayoutTask,
         window      : None,
         doc_url     : None,
         window_size : Size2D(800u, 600u),
+        display_density : 1.0,
 
         resource_task : resource_task,
+        embedder : embedder,
         compartment : compartment,
 
         damage : MatchSelectorsDamage,
@@ -154,6 +185,60 @@ pub fn Content(layout_task: LayoutTask,
     content
 }
 
+// The page shown in place of a document that couldn't be loaded
+fn error_page(url: &Url) -> ~str {
+    let url = str::replace(str::replace(url_to_str(url), "&", "&amp;"), "<", "&lt;");
+    fmt!("<html><head><title>Problem loading page</title></head>\
+          <body><p>Servo couldn't load %s.</p></body></html>", url)
+}
+
+/// Tells the embedder the title of a freshly loaded document, then that it
+/// has finished loading.
+pub fn notify_document_loaded(embedder: @EmbedderCallbacks, document: &Document, url: &Url) {
+    do document.title().iter |title| {
+        embedder.on_title_changed(*title);
+    }
+    embedder.on_load_complete(url);
+}
+
+/**
+The damage done to layout by the mutations recorded on `port` so far.
+Attribute changes mark the element's style dirty, so only dirty subtrees
+need restyling; adding or removing children can change which selectors
+match the siblings, so everything is matched again.
+*/
+pub fn mutation_damage(port: &pipes::Port<MutationRecord>) -> Damage {
+    let mut damage = NoDamage;
+    while port.peek() {
+        match port.recv().details {
+            AttributeChanged(*) => damage.add(RestyleDamage),
+            ChildAdded(*) | ChildRemoved(*) => damage.add(MatchSelectorsDamage)
+        }
+    }
+    damage
+}
+
+/// Returns the document bound in `compartment`, binding one for `root` if there isn't one yet
+fn bind_document(bound: @mut Option<@Document>, compartment: @mut Compartment, root: Node,
+                 scope: NodeScope, window: @Window) -> @Document {
+    match *bound {
+        Some(document) => document,
+        None => {
+            let document = @Document(root, scope);
+            define_bindings(compartment, document, window);
+            *bound = Some(document);
+            document
+        }
+    }
+}
+
+/// A new compartment holding only the standard and debug globals, for sandboxed scripts
+fn sandbox_compartment(cx: @Cx) -> @mut Compartment {
+    let compartment = result::unwrap(cx.new_compartment(global_class));
+    compartment.define_functions(debug_fns);
+    compartment
+}
+
 pub fn task_from_context(cx: *JSContext) -> *Content {
     unsafe {
         cast::reinterpret_cast(&JS_GetContextPrivate(cx))
@@ -179,52 +264,16 @@ impl Content {
         match move control_msg {
           ParseMsg(move url) => {
             debug!("content: Received url `%s` to parse", url_to_str(&url));
+            self.run_unload_handlers();
+            self.parse_document(move url, None);
+            return true;
+          }
 
-            // Note: we can parse the next document in parallel
-            // with any previous documents.
-
-            let result = html::hubbub_html_parser::parse_html(self.scope,
-                                                              copy url,
-                                                              self.resource_task.clone(),
-                                                              self.image_cache_task.clone());
-
-            let root = result.root;
-
-              // Send stylesheets over to layout
-              // FIXME: Need these should be streamed to layout as they are parsed
-              // and do not need to stop here in the content task
-              loop {
-                  match result.style_port.recv() {
-                      Some(move sheet) => {
-                          self.layout_task.send(AddStylesheet(move sheet));
-                      }
-                      None => break
-                  }
-              }
-
-            let js_scripts = result.js_port.recv();
-            debug!("js_scripts: %?", js_scripts);
-
-            let document = Document(root, self.scope);
-            let window   = Window(self.control_chan.clone());
-
-            self.damage.add(MatchSelectorsDamage);
-            self.relayout(&document, &url);
-
-            self.document = Some(@move document);
-            self.window   = Some(@move window);
-            self.doc_url = Some(move url);
-
-            let compartment = option::expect(self.compartment, ~"TODO error checking");
-            compartment.define_functions(debug_fns);
-            define_bindings(compartment,
-                            option::get(self.document),
-                            option::get(self.window));
-
-            do vec::consume(move js_scripts) |_i, bytes| {
-                self.cx.evaluate_script(compartment.global_obj, move bytes, ~"???", 1u);
-            }
-
+          NavigateMsg(move url) => {
+            debug!("content: Received url `%s` to navigate to", url_to_str(&url));
+            let data = self.fetch_document(&url);
+            self.run_unload_handlers();
+            self.parse_document(move url, Some(move data));
             return true;
           }
 
@@ -244,7 +293,7 @@ impl Content {
           }
 
 
-          ExecuteMsg(url) => {
+          ExecuteMsg(url, sandboxed) => {
             debug!("content: Received url `%s` to execute", url_to_str(&url));
 
             match read_whole_file(&Path(url.path)) {
@@ -252,8 +301,13 @@ impl Content {
                 println(fmt!("Error opening %s: %s", url_to_str(&url), msg));
               }
               Ok(move bytes) => {
-                let compartment = option::expect(self.compartment, ~"TODO error checking");
-                compartment.define_functions(debug_fns);
+                let compartment = if sandboxed {
+                    sandbox_compartment(self.cx)
+                } else {
+                    let compartment = option::expect(self.compartment, ~"TODO error checking");
+                    compartment.define_functions(debug_fns);
+                    compartment
+                };
                 self.cx.evaluate_script(compartment.global_obj, move bytes, copy url.path, 1u);
               }
             }
@@ -261,12 +315,140 @@ impl Content {
           }
 
           ExitMsg => {
+            self.run_unload_handlers();
             self.layout_task.send(layout_task::ExitMsg);
             return false;
           }
         }
     }
 
+    /**
+       Parses, styles and lays out the document at `url`, running its scripts. The document
+       is loaded by the parser unless its UTF-8 `data` is given.
+    */
+    fn parse_document(url: Url, data: Option<~[u8]>) {
+        // Note: we can parse the next document in parallel
+        // with any previous documents.
+
+        let compartment = option::expect(self.compartment, ~"TODO error checking");
+        compartment.define_functions(debug_fns);
+        let window = @Window(self.control_chan.clone(), self.embedder);
+
+        // Inline scripts run as they are parsed, so the document is bound to
+        // script by the first of them.
+        let script_document: @mut Option<@Document> = @mut None;
+        let (cx, scope) = (self.cx, self.scope);
+        let run_script: @fn(Node, ~[u8]) -> ~str = |root, script| {
+            let document = bind_document(script_document, compartment, root, scope, window);
+            document.begin_script_writes();
+            cx.evaluate_script(compartment.global_obj, move script, ~"inline script", 1u);
+            document.end_script_writes()
+        };
+
+        // Lay out the document as the parser appends nodes to it, so the first
+        // paint need not wait for the whole page to arrive.
+        let result = do html::hubbub_html_parser::parse_html_(self.scope,
+                                                              copy url,
+                                                              move data,
+                                                              self.resource_task.clone(),
+                                                              self.image_cache_task.clone(),
+                                                              self.window_size,
+                                                              self.display_density,
+                                                              run_script)
+                |partial_root| {
+            self.damage.add(MatchSelectorsDamage);
+            self.relayout_with(partial_root, &url, |data| AppendNodesMsg(data));
+        };
+
+        let root = result.root;
+
+          // Send stylesheets over to layout
+          // FIXME: Need these should be streamed to layout as they are parsed
+          // and do not need to stop here in the content task
+          loop {
+              match result.style_port.recv() {
+                  Some(move sheet) => {
+                      self.layout_task.send(AddStylesheet(move sheet));
+                  }
+                  None => break
+              }
+          }
+
+        let js_scripts = result.js_port.recv();
+        debug!("js_scripts: %?", js_scripts);
+
+        let document = bind_document(script_document, compartment, root, self.scope,
+                                     window);
+
+        self.damage.add(MatchSelectorsDamage);
+        self.relayout_with(document.root, &url, |data| FinishMsg(data));
+
+        self.document = Some(document);
+        self.window   = Some(window);
+        self.doc_url = Some(move url);
+
+        do vec::consume(move js_scripts) |_i, bytes| {
+            self.cx.evaluate_script(compartment.global_obj, move bytes, ~"???", 1u);
+        }
+
+        notify_document_loaded(self.embedder, self.document.get(),
+                               self.doc_url.get_ref());
+    }
+
+    /**
+       Fetches the document at `url` and converts it to UTF-8 according to the charset of its
+       MIME type. A document that can't be loaded is replaced with an error page.
+    */
+    fn fetch_document(url: &Url) -> ~[u8] {
+        let (input_port, input_chan) = pipes::stream();
+        self.resource_task.send(Load(copy *url, move input_chan));
+        let mut charset = None;
+        let mut data = ~[];
+        let mut unassembled = false;
+        loop {
+            match input_port.recv() {
+                Meta(ref mime_type) => charset = charset_from_mime_type(*mime_type),
+                Header(*) | Redirected(*) | NotModified => (),
+                PartialContent(*) => unassembled = true,
+                Payload(move bytes) => data.push_all_move(move bytes),
+                Done(Ok(())) if unassembled => {
+                    debug!("content: unassembled partial content for `%s`", url_to_str(url));
+                    return str::to_bytes(error_page(url));
+                }
+                Done(Ok(())) => break,
+                Done(Err(error)) => {
+                    debug!("content: failed to load `%s`: %?", url_to_str(url), error);
+                    return str::to_bytes(error_page(url));
+                }
+            }
+        }
+        match charset {
+            Some(ref charset) => transcode_to_utf8(move data, *charset),
+            None => move data
+        }
+    }
+
+    /**
+       Calls the window's unload handlers, before its document is replaced or anything is
+       torn down. Exceptions they throw are reported and cleared, so every handler gets to run.
+    */
+    fn run_unload_handlers() {
+        let window = match self.window {
+            Some(window) => window,
+            None => return
+        };
+        let compartment = option::expect(self.compartment, ~"TODO error checking");
+        for window.unload_handlers().each |handler| {
+            let rval = JSVAL_NULL;
+            if JS_CallFunctionValue(self.cx.ptr, compartment.global_obj.ptr, *handler,
+                                    0, null(), ptr::to_unsafe_ptr(&rval)) == 0 {
+                debug!("content: unload handler threw");
+                JS_ReportPendingException(self.cx.ptr);
+                JS_ClearPendingException(self.cx.ptr);
+            }
+        }
+    }
+
     /**
        Sends a ping to layout and waits for the response (i.e., it has finished any
        pending layout request messages).
@@ -299,11 +481,20 @@ impl Content {
        new layout computation to finish.
     */
     fn relayout(document: &Document, doc_url: &Url) {
+        self.relayout_with(document.root, doc_url, |data| BuildMsg(data));
+    }
+
+    /**
+       Like `relayout`, but lays out the tree rooted at `root` and lets the caller choose the
+       message used to send the build request to layout.
+    */
+    fn relayout_with(root: Node, doc_url: &Url, to_msg: &fn(BuildData) -> layout_task::Msg) {
         debug!("content: performing relayout");
 
         // Now, join the layout so that they will see the latest
         // changes we have made.
         self.join_layout();
+        self.damage.add(mutation_damage(&self.mutation_port));
 
         // Layout will let us know when it's done
         let (join_port, join_chan) = pipes::stream();
@@ -312,7 +503,7 @@ impl Content {
         // Send new document and relevant styles to layout
 
         let data = BuildData {
-            node: document.root,
+            node: root,
             url: copy *doc_url,
             dom_event_chan: self.event_chan.clone(),
             window_size: self.window_size,
@@ -320,7 +511,7 @@ impl Content {
             damage: replace(&mut self.damage, NoDamage),
         };
 
-        self.layout_task.send(BuildMsg(move data));
+        self.layout_task.send(to_msg(move data));
 
         // Indicate that reader was forked so any further
         // changes will be isolated.
@@ -377,3 +568,348 @@ impl Content {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use content::embedder::{EmbedderCallbacks, EmbedderFactory};
+    use dom::console::ConsoleLevel;
+    use dom::event::Event;
+    use dom::bindings;
+    use dom::element::{ElementData, HTMLDivElement};
+    use dom::node::{Element, Node, NodeScope, NodeScopeExtensions, NodeTree, Text};
+    use layout::layout_task::{AddStylesheet, AppendNodesMsg, BuildMsg, FinishMsg, Msg};
+    use layout::layout_task::{MatchSelectorsDamage, NoDamage, QueryMsg, RestyleDamage};
+    use layout::layout_task;
+    use util::task::spawn_listener;
+
+    use core::pipes::{Chan, Port, SharedChan, stream};
+    use gfx::resource::image_cache_task::{Exit, ImageCacheTask};
+    use gfx::resource::resource_task::{Done, LoadFailed, Meta, Payload, ResourceTask};
+    use test_support::mock_resource_task;
+    use gfx::resource::resource_task;
+    use gfx::util::url::make_url;
+    use std::cell::Cell;
+    use std::net::url::Url;
+
+    struct AlertEmbedder {
+        alerts: Chan<~str>
+    }
+
+    impl AlertEmbedder : EmbedderCallbacks {
+        fn on_console(&self, _level: ConsoleLevel, _message: &str) { }
+        fn on_alert(&self, message: &str) {
+            self.alerts.send(str::from_slice(message));
+        }
+        fn on_title_changed(&self, _title: &str) { }
+        fn on_load_complete(&self, _url: &Url) { }
+    }
+
+    fn page_resource_task(html: ~str) -> ResourceTask {
+        do mock_resource_task |_url, response, move html| {
+            response.send(Payload(str::to_bytes(html)));
+            response.send(Done(Ok(())));
+        }
+    }
+
+    // Lets content carry on after each layout request, and reports when it is told to exit
+    fn mock_layout_task(exit_chan: Chan<()>) -> LayoutTask {
+        SharedChan(do spawn_listener |port: Port<Msg>, move exit_chan| {
+            loop {
+                match port.recv() {
+                    BuildMsg(data) | AppendNodesMsg(data) | FinishMsg(data) => {
+                        data.content_join_chan.send(());
+                    }
+                    AddStylesheet(*) | QueryMsg(*) => (),
+                    layout_task::ExitMsg => {
+                        exit_chan.send(());
+                        break;
+                    }
+                }
+            }
+        })
+    }
+
+    // Loads `html` in a content task, then exits it and returns what was alerted
+    fn alerts_from_page(html: ~str) -> ~[~str] {
+        alerts_from_loads(move html, 1)
+    }
+
+    // Loads `html` in a content task `loads` times, then exits it and returns what was alerted
+    fn alerts_from_loads(html: ~str, loads: uint) -> ~[~str] {
+        let resource_task = page_resource_task(move html);
+        let image_cache_task = ImageCacheTask(resource_task.clone());
+
+        let (exit_port, exit_chan) = stream();
+        let (alert_port, alert_chan) = stream();
+        let alert_chan = Cell(move alert_chan);
+        let embedder_factory: EmbedderFactory = fn~(move alert_chan) -> @EmbedderCallbacks {
+            @AlertEmbedder { alerts: alert_chan.take() } as @EmbedderCallbacks
+        };
+        let (event_port, event_chan) = stream::<Event>();
+        let content_task = ContentTask(mock_layout_task(move exit_chan),
+                                       move event_port,
+                                       SharedChan(move event_chan),
+                                       resource_task.clone(),
+                                       image_cache_task.clone(),
+                                       move embedder_factory);
+
+        for loads.times {
+            content_task.send(ParseMsg(make_url(~"test.html", None)));
+        }
+        content_task.send(ExitMsg);
+        exit_port.recv();
+
+        // The embedder's chan closes when the content task finishes
+        let mut alerts = ~[];
+        loop {
+            match alert_port.try_recv() {
+                Some(move alert) => alerts.push(move alert),
+                None => break
+            }
+        }
+
+        request_response!(image_cache_task, Exit);
+        resource_task.send(resource_task::Exit);
+        move alerts
+    }
+
+    #[test]
+    fn should_restyle_only_dirty_nodes_after_attribute_changes() {
+        let scope = NodeScope();
+        let (mutation_port, mutation_chan) = stream();
+        scope.observe_mutations(move mutation_chan);
+        let parent = scope.new_node(Element(ElementData(~"div", ~HTMLDivElement)));
+        let child = scope.new_node(Element(ElementData(~"div", ~HTMLDivElement)));
+
+        assert mutation_damage(&mutation_port) == NoDamage;
+
+        scope.set_attr(&child, "class", ~"changed");
+        assert mutation_damage(&mutation_port) == RestyleDamage;
+
+        scope.set_attr(&child, "style", ~"color: red");
+        scope.add_child(parent, child);
+        assert mutation_damage(&mutation_port) == MatchSelectorsDamage;
+    }
+
+    #[test]
+    fn should_run_unload_handlers_on_exit() {
+        let alerts = alerts_from_page(~"<html><body><script>
+            window.onunload = function () { window.alert('onunload'); };
+            window.addEventListener('unload', function () { throw 'oops'; });
+            window.addEventListener('unload', function () { window.alert('listener'); });
+        </script></body></html>");
+
+        // The handler that throws doesn't stop the one after it
+        assert alerts == ~[~"onunload", ~"listener"];
+    }
+
+    #[test]
+    fn should_run_unload_handlers_before_replacing_the_document() {
+        let alerts = alerts_from_loads(~"<html><body><script>
+            window.alert('loaded');
+            window.onunload = function () { window.alert('unloaded'); };
+        </script></body></html>", 2);
+
+        assert alerts == ~[~"loaded", ~"unloaded", ~"loaded", ~"unloaded"];
+    }
+
+    #[test]
+    fn should_throw_type_errors_for_missing_arguments() {
+        let alerts = alerts_from_page(~"<html><body><script>
+            try {
+                document.getElementsByTagName();
+            } catch (e) {
+                window.alert(e instanceof TypeError);
+                window.alert(e.message);
+            }
+        </script></body></html>");
+
+        assert alerts == ~[~"true",
+                           ~"getElementsByTagName requires 1 argument, but only 0 were passed"];
+    }
+
+    #[test]
+    fn should_get_elements_by_id() {
+        let alerts = alerts_from_page(~"<html><body><div id='outer'><span id='inner'></span>\
+                                        </div><script>
+            window.alert(document.getElementById('inner').nodeName);
+            window.alert(document.getElementById('outer').firstChild.nodeName);
+            window.alert(document.getElementById('missing') === null);
+        </script></body></html>");
+
+        assert alerts == ~[~"SPAN", ~"SPAN", ~"true"];
+    }
+
+    #[test]
+    fn should_set_the_title_from_script() {
+        let alerts = alerts_from_page(~"<html><head><title>Old</title></head><body><script>
+            window.alert(document.title);
+            document.title = 'New';
+            window.alert(document.title);
+            window.alert(document.getElementsByTagName('title').length);
+        </script></body></html>");
+        assert alerts == ~[~"Old", ~"New", ~"1"];
+
+        let alerts = alerts_from_page(~"<html><head></head><body><script>
+            window.alert(document.title === '');
+            document.title = 'Added';
+            window.alert(document.title);
+        </script></body></html>");
+        assert alerts == ~[~"true", ~"Added"];
+    }
+
+    #[test]
+    fn should_link_nodes_to_their_parents_and_document() {
+        let alerts = alerts_from_page(~"<html><body><div><span></span></div><script>
+            var root = document.documentElement;
+            var span = document.getElementsByTagName('span').item(0);
+            window.alert(span.parentNode.nodeName);
+            window.alert(span.parentElement.parentElement.nodeName);
+            window.alert(root.parentNode === document);
+            window.alert(root.parentElement === null);
+            window.alert(span.ownerDocument === document);
+        </script></body></html>");
+
+        assert alerts == ~[~"DIV", ~"BODY", ~"true", ~"true", ~"true"];
+    }
+
+    #[test]
+    fn should_parse_markup_written_by_inline_scripts() {
+        let alerts = alerts_from_page(~"<html><body><script>
+            document.write('<p id=\"written\">x</p>');
+        </script><div></div><script>
+            var p = document.getElementById('written');
+            window.alert(p.nodeName);
+            window.alert(p.parentNode.nodeName);
+            window.alert(p.nextSibling.nodeName);
+            window.alert(p.firstChild.nodeType);
+        </script></body></html>");
+
+        // The written paragraph is parsed just after the script that wrote it
+        assert alerts == ~[~"P", ~"BODY", ~"DIV", ~"3"];
+    }
+
+    #[test]
+    fn should_give_detached_nodes_no_parent() {
+        let alerts = alerts_from_page(~"<html><body><div><span></span></div><script>
+            var div = document.getElementsByTagName('div').item(0).cloneNode(true);
+            window.alert(div.parentNode === null);
+            window.alert(div.parentElement === null);
+            window.alert(div.firstChild.parentNode.nodeName);
+            window.alert(div.ownerDocument === document);
+        </script></body></html>");
+
+        assert alerts == ~[~"true", ~"true", ~"DIV", ~"true"];
+    }
+
+    #[test]
+    fn should_hide_the_document_from_sandboxed_scripts() {
+        let rt = jsrt();
+        let cx = rt.cx();
+        let check = ~"if (typeof document !== 'undefined') throw 'saw the document';";
+
+        let sandbox = sandbox_compartment(cx);
+        assert cx.evaluate_script(sandbox.global_obj, str::to_bytes(check), ~"sandboxed",
+                                  1u).is_ok();
+
+        // A compartment the DOM is bound in, as for unsandboxed scripts, sees the document
+        let compartment = result::unwrap(cx.new_compartment(global_class));
+        let scope = NodeScope();
+        let root = scope.new_node(Element(ElementData(~"html", ~HTMLDivElement)));
+        bindings::document::init(compartment, @Document(root, scope));
+        assert cx.evaluate_script(compartment.global_obj, str::to_bytes(check), ~"unsandboxed",
+                                  1u).is_err();
+    }
+
+    // Serves `page.html` as ISO-8859-1 encoded HTML, and fails to load anything else
+    fn mock_site_resource_task(page: ~[u8]) -> ResourceTask {
+        do mock_resource_task |url, response, move page| {
+            if str::ends_with(url.path, "page.html") {
+                response.send(Meta(~"text/html; charset=ISO-8859-1"));
+                response.send(Payload(copy page));
+                response.send(Done(Ok(())));
+            } else {
+                response.send(Done(Err(LoadFailed)));
+            }
+        }
+    }
+
+    // The tag names and text of a tree, read from layout's side
+    fn describe_tree(node: &Node) -> ~str {
+        let mut s = do node.read |n| {
+            match n.kind {
+                ~Element(ref element) => ~"<" + element.tag_name + ~">",
+                ~Text(ref text) => copy *text,
+                _ => ~""
+            }
+        };
+        for NodeTree.each_child(node) |child| {
+            s += describe_tree(child);
+        }
+        s
+    }
+
+    // Reports the tree and URL of the finished document, then exits
+    fn mock_reporting_layout_task(report_chan: Chan<(~str, Url)>) -> LayoutTask {
+        SharedChan(do spawn_listener |port: Port<Msg>, move report_chan| {
+            loop {
+                match port.recv() {
+                    FinishMsg(data) => {
+                        report_chan.send((describe_tree(&data.node), copy data.url));
+                        data.content_join_chan.send(());
+                    }
+                    BuildMsg(data) | AppendNodesMsg(data) => data.content_join_chan.send(()),
+                    AddStylesheet(*) | QueryMsg(*) => (),
+                    layout_task::ExitMsg => break
+                }
+            }
+        })
+    }
+
+    // Navigates a content task to `url` and returns the tree and URL layout was given
+    fn navigate(url: Url) -> (~str, Url) {
+        // "<p>Café</p>" in ISO-8859-1
+        let page = str::to_bytes("<html><body><p>Caf") + ~[0xE9u8] +
+            str::to_bytes("</p></body></html>");
+        let resource_task = mock_site_resource_task(move page);
+        let image_cache_task = ImageCacheTask(resource_task.clone());
+
+        let (report_port, report_chan) = stream();
+        let embedder_factory: EmbedderFactory = fn~() -> @EmbedderCallbacks {
+            let (_alert_port, alert_chan) = stream();
+            @AlertEmbedder { alerts: move alert_chan } as @EmbedderCallbacks
+        };
+        let (event_port, event_chan) = stream::<Event>();
+        let content_task = ContentTask(mock_reporting_layout_task(move report_chan),
+                                       move event_port,
+                                       SharedChan(move event_chan),
+                                       resource_task.clone(),
+                                       image_cache_task.clone(),
+                                       move embedder_factory);
+
+        content_task.send(NavigateMsg(move url));
+        let report = report_port.recv();
+        content_task.send(ExitMsg);
+
+        request_response!(image_cache_task, Exit);
+        resource_task.send(resource_task::Exit);
+        move report
+    }
+
+    #[test]
+    fn should_load_and_lay_out_the_document_navigated_to() {
+        let url = make_url(~"http://example.com/page.html", None);
+        let (tree, layout_url) = navigate(copy url);
+        assert str::contains(tree, "<body><p>Café");
+        assert layout_url == url;
+    }
+
+    #[test]
+    fn should_show_an_error_page_for_documents_that_fail_to_load() {
+        let url = make_url(~"http://example.com/missing.html", None);
+        let (tree, layout_url) = navigate(copy url);
+        assert str::contains(tree, "<p>Servo couldn't load http://example.com/missing.html.");
+        assert layout_url == url;
+    }
+}
@@ -0,0 +1,114 @@
+/*!
+Hooks through which the content task reports script-visible events to the
+host application. A command line browser prints them, a GUI shows dialogs
+and updates its title bar, and a test harness records them.
+*/
+
+use dom::console::{ConsoleLevel, ErrorLevel, LogLevel, WarnLevel};
+
+use std::net::url::{Url, to_str};
+
+pub trait EmbedderCallbacks {
+    /// A message was logged with `console.log`, `warn` or `error`
+    fn on_console(&self, level: ConsoleLevel, message: &str);
+    /// Script called `window.alert`
+    fn on_alert(&self, message: &str);
+    /// The document's title became known or changed
+    fn on_title_changed(&self, title: &str);
+    /// The document at `url` finished parsing and running its scripts
+    fn on_load_complete(&self, url: &Url);
+}
+
+/// Creates the embedder inside the content task, since managed callbacks
+/// can't be sent between tasks
+pub type EmbedderFactory = ~fn() -> @EmbedderCallbacks;
+
+/// The default embedder, which prints everything to stdout
+pub struct PrintingEmbedder;
+
+impl PrintingEmbedder : EmbedderCallbacks {
+    fn on_console(&self, level: ConsoleLevel, message: &str) {
+        let prefix = match level {
+            LogLevel => ~"LOG",
+            WarnLevel => ~"WARN",
+            ErrorLevel => ~"ERROR"
+        };
+        io::println(fmt!("%s: %s", prefix, message));
+    }
+
+    fn on_alert(&self, message: &str) {
+        io::println(fmt!("ALERT: %s", message));
+    }
+
+    fn on_title_changed(&self, title: &str) {
+        io::println(fmt!("TITLE: %s", title));
+    }
+
+    fn on_load_complete(&self, url: &Url) {
+        debug!("embedder: finished loading %s", to_str(url));
+    }
+}
+
+pub fn default_embedder_factory() -> @EmbedderCallbacks {
+    @PrintingEmbedder as @EmbedderCallbacks
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use content::content_task::{ControlMsg, notify_document_loaded};
+    use dom::console::{Console, WarnLevel};
+    use dom::document::Document;
+    use dom::element::{ElementData, HTMLHtmlElement, HTMLTitleElement};
+    use dom::node::{Element, NodeScope, NodeScopeExtensions, Text};
+    use dom::window::Window;
+
+    use core::dvec::DVec;
+    use core::pipes::{SharedChan, stream};
+    use std::net::url;
+
+    struct RecordingEmbedder {
+        events: DVec<~str>
+    }
+
+    impl RecordingEmbedder : EmbedderCallbacks {
+        fn on_console(&self, level: ConsoleLevel, message: &str) {
+            self.events.push(fmt!("console %? %s", level == WarnLevel, message));
+        }
+        fn on_alert(&self, message: &str) {
+            self.events.push(fmt!("alert %s", message));
+        }
+        fn on_title_changed(&self, title: &str) {
+            self.events.push(fmt!("title %s", title));
+        }
+        fn on_load_complete(&self, url: &Url) {
+            self.events.push(fmt!("load %s", url.path));
+        }
+    }
+
+    #[test]
+    fn should_report_events_to_the_embedder() {
+        let recorder = @RecordingEmbedder { events: DVec() };
+        let embedder = recorder as @EmbedderCallbacks;
+
+        let console = Console(10, embedder);
+        console.warn(~"careful");
+
+        let (_control_port, control_chan) = stream::<ControlMsg>();
+        let window = Window(SharedChan(move control_chan), embedder);
+        window.alert(~"hello");
+
+        let scope = NodeScope();
+        let html = scope.new_node(Element(ElementData(~"html", ~HTMLHtmlElement)));
+        let title = scope.new_node(Element(ElementData(~"title", ~HTMLTitleElement)));
+        scope.add_child(html, title);
+        scope.add_child(title, scope.new_node(Text(~"A page")));
+        let document = Document(html, scope);
+        notify_document_loaded(embedder, &document, &url::from_str(~"http://example.com/test.html").get());
+
+        assert recorder.events.get() == ~[~"console true careful",
+                                          ~"alert hello",
+                                          ~"title A page",
+                                          ~"load /test.html"];
+    }
+}
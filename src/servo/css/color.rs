@@ -0,0 +1,237 @@
+// Parsing of CSS color values.
+
+use css::declarations::rewrite_declarations;
+
+use newcss::color::{Color, rgb, rgba};
+
+// The properties whose values are colors
+const COLOR_PROPERTIES: &[&static/str] = &[
+    "color", "background-color", "border-color", "border-top-color", "border-right-color",
+    "border-bottom-color", "border-left-color", "outline-color"
+];
+
+/**
+Parses a CSS color: `#rgb`, `#rrggbb`, `rgb()`, `rgba()` or a named color.
+Returns None if the value isn't a valid color, in which case the declaration
+it came from should be ignored.
+*/
+pub fn parse_color(value: &str) -> Option<Color> {
+    let value = str::to_lower(str::trim(value));
+    if str::starts_with(value, "#") {
+        parse_hex_color(str::slice(value, 1, value.len()))
+    } else if str::starts_with(value, "rgba(") && str::ends_with(value, ")") {
+        parse_color_function(str::slice(value, 5, value.len() - 1), true)
+    } else if str::starts_with(value, "rgb(") && str::ends_with(value, ")") {
+        parse_color_function(str::slice(value, 4, value.len() - 1), false)
+    } else {
+        named_color(value)
+    }
+}
+
+// The digits of a hex color, without the `#`. `abc` is shorthand for `aabbcc`.
+fn parse_hex_color(digits: &str) -> Option<Color> {
+    // Lengths are in bytes, so anything but ASCII digits would be sliced apart
+    if !str::all(digits, |c| char::is_digit_radix(c, 16)) {
+        return None;
+    }
+    let digits = match digits.len() {
+        3 => {
+            let mut expanded = ~"";
+            for str::each_char(digits) |c| {
+                str::push_char(&mut expanded, c);
+                str::push_char(&mut expanded, c);
+            }
+            move expanded
+        }
+        6 => str::from_slice(digits),
+        _ => return None
+    };
+
+    let mut channels = ~[];
+    for uint::range(0, 3) |i| {
+        match uint::from_str_radix(str::slice(digits, i * 2, i * 2 + 2), 16) {
+            Some(channel) => channels.push(channel as u8),
+            None => return None
+        }
+    }
+    Some(rgb(channels[0], channels[1], channels[2]))
+}
+
+// The arguments of `rgb()` or `rgba()`, which are all integers or all percentages
+fn parse_color_function(args: &str, has_alpha: bool) -> Option<Color> {
+    let args = str::split_char(args, ',').map(|arg| str::trim(*arg));
+    let expected_args = if has_alpha { 4 } else { 3 };
+    if args.len() != expected_args {
+        return None;
+    }
+
+    let percentages = str::ends_with(args[0], "%");
+    let mut channels = ~[];
+    for uint::range(0, 3) |i| {
+        if str::ends_with(args[i], "%") != percentages {
+            return None;
+        }
+        let channel = if percentages {
+            match float::from_str(str::slice(args[i], 0, args[i].len() - 1)) {
+                Some(percent) => float::round(clamp(percent, 0.0, 100.0) * 255.0 / 100.0),
+                None => return None
+            }
+        } else {
+            match int::from_str(args[i]) {
+                Some(channel) => clamp(channel as float, 0.0, 255.0),
+                None => return None
+            }
+        };
+        channels.push(channel as u8);
+    }
+
+    let alpha = if has_alpha {
+        match float::from_str(args[3]) {
+            Some(alpha) => clamp(alpha, 0.0, 1.0),
+            None => return None
+        }
+    } else {
+        1.0
+    };
+    Some(rgba(channels[0], channels[1], channels[2], alpha))
+}
+
+/**
+Rewrites the color declarations of a sheet into `rgb()`, `rgba()` or
+`transparent`, which libcss parses alike whatever form the author used.
+Declarations with malformed hex or functional colors are dropped; other
+values, such as `inherit` or `currentColor`, are left to libcss.
+*/
+pub fn resolve_colors(css: &str) -> ~str {
+    rewrite_declarations(css, |_prelude, declaration| resolve_declaration(declaration))
+}
+
+// The rewritten color declaration, or an empty one if its color is malformed
+fn resolve_declaration(declaration: &str) -> Option<~str> {
+    let colon = match str::find_char(declaration, ':') {
+        Some(colon) => colon,
+        None => return None
+    };
+    let name = str::to_lower(str::trim(str::slice(declaration, 0, colon)));
+    if !vec::any(COLOR_PROPERTIES, |property| str::eq_slice(*property, name)) {
+        return None;
+    }
+    let mut value = str::trim(str::slice(declaration, colon + 1, declaration.len()));
+    let important = str::ends_with(str::to_lower(value), "!important");
+    if important {
+        value = str::trim(str::slice(value, 0, value.len() - 10));
+    }
+
+    let priority = if important { ~" !important" } else { ~"" };
+    match parse_color(value) {
+        Some(color) if color.alpha == 0.0 => Some(fmt!(" %s: transparent%s", name, priority)),
+        Some(color) if color.alpha == 1.0 => {
+            Some(fmt!(" %s: rgb(%u, %u, %u)%s", name, color.red as uint, color.green as uint,
+                      color.blue as uint, priority))
+        }
+        Some(color) => {
+            Some(fmt!(" %s: rgba(%u, %u, %u, %?)%s", name, color.red as uint,
+                      color.green as uint, color.blue as uint, color.alpha, priority))
+        }
+        None => {
+            let lower = str::to_lower(value);
+            if str::starts_with(lower, "#") || str::starts_with(lower, "rgb") {
+                debug!("color: dropping the malformed color %s", value);
+                Some(~"")
+            } else {
+                None
+            }
+        }
+    }
+}
+
+pure fn clamp(value: float, min: float, max: float) -> float {
+    float::fmax(min, float::fmin(value, max))
+}
+
+// The basic CSS color keywords, and `transparent`
+fn named_color(name: &str) -> Option<Color> {
+    if name == "transparent" {
+        return Some(rgba(0, 0, 0, 0.0));
+    }
+    let colors: [(&static/str, u8, u8, u8) * 18] = [
+        ("black", 0, 0, 0), ("silver", 192, 192, 192), ("gray", 128, 128, 128),
+        ("grey", 128, 128, 128), ("white", 255, 255, 255), ("maroon", 128, 0, 0),
+        ("red", 255, 0, 0), ("purple", 128, 0, 128), ("fuchsia", 255, 0, 255),
+        ("green", 0, 128, 0), ("lime", 0, 255, 0), ("olive", 128, 128, 0),
+        ("yellow", 255, 255, 0), ("navy", 0, 0, 128), ("blue", 0, 0, 255),
+        ("teal", 0, 128, 128), ("aqua", 0, 255, 255), ("orange", 255, 165, 0)
+    ];
+    for colors.each |&(color_name, r, g, b)| {
+        if name == color_name {
+            return Some(rgb(r, g, b));
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use css::declarations::rewrite_declarations;
+
+use newcss::color::{Color, rgb, rgba};
+
+    fn channels(color: Option<Color>) -> Option<(u8, u8, u8, float)> {
+        color.map(|c| (c.red, c.green, c.blue, c.alpha))
+    }
+
+    fn expect(value: &str, r: u8, g: u8, b: u8, a: float) {
+        assert channels(parse_color(value)) == Some((r, g, b, a));
+    }
+
+    #[test]
+    fn should_parse_hex_colors() {
+        expect("#ff8000", 255, 128, 0, 1.0);
+        expect("#FF8000", 255, 128, 0, 1.0);
+        // Each digit of the shorthand is doubled
+        expect("#abc", 0xaa, 0xbb, 0xcc, 1.0);
+        assert channels(parse_color("#abc")) == channels(parse_color("#aabbcc"));
+    }
+
+    #[test]
+    fn should_parse_color_functions() {
+        expect("rgb(255, 0, 128)", 255, 0, 128, 1.0);
+        expect("rgb(100%, 0%, 50%)", 255, 0, 128, 1.0);
+        expect("rgb(300, -20, 0)", 255, 0, 0, 1.0);
+        expect(" rgba(0, 0, 255, 0.5) ", 0, 0, 255, 0.5);
+    }
+
+    #[test]
+    fn should_parse_named_colors() {
+        expect("red", 255, 0, 0, 1.0);
+        expect("Navy", 0, 0, 128, 1.0);
+        assert channels(parse_color("transparent")) == channels(Some(rgba(0, 0, 0, 0.0)));
+        assert channels(parse_color("white")) == channels(Some(rgb(255, 255, 255)));
+    }
+
+    #[test]
+    fn should_reject_invalid_colors() {
+        assert parse_color("#abcd").is_none();
+        assert parse_color("#ggg").is_none();
+        assert parse_color("rgb(1, 2)").is_none();
+        assert parse_color("rgb(10%, 2, 3)").is_none();
+        assert parse_color("rgba(1, 2, 3)").is_none();
+        assert parse_color("reddish").is_none();
+        // Six bytes, but not six hex digits, and the second pair splits the 'é'
+        assert parse_color("#a\u00e9bcd").is_none();
+    }
+
+    #[test]
+    fn should_rewrite_color_declarations_for_libcss() {
+        assert resolve_colors("a { color: #F80; margin: 0 }") ==
+            ~"a { color: rgb(255, 136, 0); margin: 0 }";
+        assert resolve_colors("a { background-color: Transparent !important }") ==
+            ~"a { background-color: transparent !important}";
+        assert resolve_colors("a { color: rgb(100%, 0%, 0%); color: inherit }") ==
+            ~"a { color: rgb(255, 0, 0); color: inherit }";
+        // Malformed colors are dropped, so an earlier declaration stands
+        assert resolve_colors("a { color: red; color: #ff00 }") == ~"a { color: rgb(255, 0, 0);}";
+    }
+}
@@ -0,0 +1,87 @@
+/*!
+Rewriting of the declarations of a sheet, for the passes that turn values
+libcss doesn't understand into ones it does before the sheet is parsed.
+*/
+
+/**
+Rewrites each declaration of the rules in a sheet with `f`, which is given the
+prelude of the innermost rule the declaration is in, usually its selectors,
+and the declaration. Declarations `f` returns None for, and all text outside
+rules, are left as they are. `;`, `{` and `}` in quoted strings don't end a
+declaration.
+*/
+pub fn rewrite_declarations(css: &str, f: fn(prelude: &str, declaration: &str) -> Option<~str>)
+                         -> ~str {
+    let mut result = ~"";
+    let mut preludes = ~[];
+    let mut quote = None;
+    let mut start = 0;
+    let mut i = 0;
+    while i < css.len() {
+        // Bytes of multibyte characters are never quotes or delimiters
+        let c = css[i] as char;
+        match quote {
+            Some(q) => {
+                if c == q {
+                    quote = None;
+                }
+            }
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if c == ';' || c == '{' || c == '}' => {
+                let segment = str::slice(css, start, i);
+                if c == '{' {
+                    // Text ending in `{` is a selector or at-rule prelude, not a declaration
+                    preludes.push(copy segment);
+                    result += segment;
+                } else if preludes.is_empty() {
+                    result += segment;
+                } else {
+                    result += f(preludes.last(), segment).get_or_default(move segment);
+                }
+                if c == '}' && !preludes.is_empty() {
+                    preludes.pop();
+                }
+                str::push_char(&mut result, c);
+                start = i + 1;
+            }
+            None => ()
+        }
+        i += 1;
+    }
+    result + str::slice(css, start, css.len())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn shout(_prelude: &str, declaration: &str) -> Option<~str> {
+        Some(str::to_upper(declaration))
+    }
+
+    #[test]
+    fn should_rewrite_only_declarations() {
+        let css = "@charset \"x\"; a, b { color: red; margin: 0 } c {}";
+        assert rewrite_declarations(css, shout) ==
+            ~"@charset \"x\"; a, b { COLOR: RED; MARGIN: 0 } c {}";
+    }
+
+    #[test]
+    fn should_not_split_quoted_strings() {
+        assert rewrite_declarations("a { content: \"a;b}\"; quotes: '{' }", shout) ==
+            ~"a { CONTENT: \"A;B}\"; QUOTES: '{' }";
+    }
+
+    #[test]
+    fn should_give_the_innermost_prelude() {
+        let css = "@media screen { html { a: b } p { c: d } } div { e: f }";
+        let rewritten = do rewrite_declarations(css) |prelude, declaration| {
+            if str::contains(declaration, ":") {
+                Some(fmt!("%s/%s", str::trim(prelude), str::trim(declaration)))
+            } else {
+                None
+            }
+        };
+        assert rewritten == ~"@media screen { html {html/a: b} p {p/c: d} } div {div/e: f}";
+    }
+}
@@ -0,0 +1,178 @@
+/*!
+Resolution of the CSS-wide keywords `initial` and `unset`. libcss computes
+styles, but only understands `inherit`, so declarations using the other two
+are rewritten into values it understands before a sheet is parsed.
+*/
+
+use css::declarations::rewrite_declarations;
+
+/**
+The properties whose initial values are known, whether each is inherited,
+and its initial value. Declarations of other properties are left alone.
+*/
+const PROPERTIES: &[(&static/str, bool, &static/str)] = &[
+    ("color", true, "black"),
+    ("font-family", true, "serif"),
+    ("font-size", true, "medium"),
+    ("font-style", true, "normal"),
+    ("font-weight", true, "normal"),
+    ("line-height", true, "normal"),
+    ("list-style-type", true, "disc"),
+    ("text-align", true, "left"),
+    ("visibility", true, "visible"),
+    ("white-space", true, "normal"),
+    ("background-color", false, "transparent"),
+    ("border-bottom-style", false, "none"),
+    ("border-bottom-width", false, "medium"),
+    ("border-left-style", false, "none"),
+    ("border-left-width", false, "medium"),
+    ("border-right-style", false, "none"),
+    ("border-right-width", false, "medium"),
+    ("border-top-style", false, "none"),
+    ("border-top-width", false, "medium"),
+    ("bottom", false, "auto"),
+    ("clear", false, "none"),
+    ("content", false, "normal"),
+    ("display", false, "inline"),
+    ("float", false, "none"),
+    ("height", false, "auto"),
+    ("left", false, "auto"),
+    ("margin", false, "0"),
+    ("margin-bottom", false, "0"),
+    ("margin-left", false, "0"),
+    ("margin-right", false, "0"),
+    ("margin-top", false, "0"),
+    ("max-height", false, "none"),
+    ("max-width", false, "none"),
+    ("min-height", false, "0"),
+    ("min-width", false, "0"),
+    ("overflow", false, "visible"),
+    ("padding", false, "0"),
+    ("padding-bottom", false, "0"),
+    ("padding-left", false, "0"),
+    ("padding-right", false, "0"),
+    ("padding-top", false, "0"),
+    ("position", false, "static"),
+    ("right", false, "auto"),
+    ("text-decoration", false, "none"),
+    ("top", false, "auto"),
+    ("vertical-align", false, "baseline"),
+    ("width", false, "auto"),
+    ("z-index", false, "auto"),
+];
+
+/**
+Rewrites the declarations of a sheet whose value is `initial` into the
+property's initial value, and those whose value is `unset` into `inherit` for
+inherited properties or the initial value for the rest.
+*/
+pub fn resolve_wide_keywords(css: &str) -> ~str {
+    rewrite_declarations(css, |_prelude, declaration| resolve_declaration(declaration))
+}
+
+// The rewritten declaration, if it uses one of the keywords
+fn resolve_declaration(declaration: &str) -> Option<~str> {
+    let colon = match str::find_char(declaration, ':') {
+        Some(colon) => colon,
+        None => return None
+    };
+    let name = str::to_lower(str::trim(str::slice(declaration, 0, colon)));
+    let mut value = str::to_lower(str::trim(str::slice(declaration, colon + 1,
+                                                         declaration.len())));
+    let important = str::ends_with(value, "!important");
+    if important {
+        value = str::trim(str::slice(value, 0, value.len() - 10));
+    }
+    if value != ~"initial" && value != ~"unset" {
+        return None;
+    }
+
+    let resolved = match property(name) {
+        Some((inherited, initial)) => {
+            if value == ~"unset" && inherited { ~"inherit" } else { initial.to_str() }
+        }
+        None => {
+            debug!("keywords: no initial value known for %s", name);
+            return None;
+        }
+    };
+    let priority = if important { ~" !important" } else { ~"" };
+    Some(fmt!(" %s: %s%s", name, resolved, priority))
+}
+
+// Whether a property is inherited, and its initial value
+fn property(name: &str) -> Option<(bool, &static/str)> {
+    for PROPERTIES.each |&(property_name, inherited, initial)| {
+        if name == property_name {
+            return Some((inherited, initial));
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use css::matching::MatchMethods;
+    use css::node_style::StyledNode;
+    use css::select::{new_css_select_ctx, test_stylesheet};
+    use dom::element::{ElementData, HTMLDivElement, HTMLSpanElement};
+    use dom::node::{Element, Node, NodeScope, NodeScopeExtensions};
+    use layout::aux::LayoutAuxMethods;
+
+    use core::dvec::DVec;
+    use newcss::types::OriginAuthor;
+    use newcss::values::CSSDisplayInline;
+
+    // Styles a div holding a span with the given sheet, and returns the span
+    fn styled_child(css: &str) -> Node {
+        let sheet = test_stylesheet(resolve_wide_keywords(css));
+
+        let scope = NodeScope();
+        let parent = scope.new_node(Element(ElementData(~"div", ~HTMLDivElement)));
+        let child = scope.new_node(Element(ElementData(~"span", ~HTMLSpanElement)));
+        scope.add_child(parent, child);
+        let refs = DVec();
+        parent.initialize_style_for_subtree(&refs);
+
+        let mut select_ctx = new_css_select_ctx();
+        select_ctx.append_sheet(move sheet, OriginAuthor);
+        parent.restyle_subtree(&select_ctx);
+        child
+    }
+
+    fn rgb(node: &Node) -> (u8, u8, u8) {
+        let color = node.style().color();
+        (color.red, color.green, color.blue)
+    }
+
+    #[test]
+    fn should_rewrite_keyword_declarations() {
+        assert resolve_wide_keywords("a:hover { color: unset; display: UNSET }") ==
+            ~"a:hover { color: inherit; display: inline}";
+        assert resolve_wide_keywords("p { margin: initial !important }") ==
+            ~"p { margin: 0 !important}";
+        // Unknown properties and other values are left alone
+        assert resolve_wide_keywords("p { unknown: initial; color: red }") ==
+            ~"p { unknown: initial; color: red }";
+    }
+
+    #[test]
+    fn should_inherit_unset_color() {
+        let child = styled_child("div { color: rgb(0, 0, 255) } span { color: red }\n\
+                                  span { color: unset }");
+        assert rgb(&child) == (0, 0, 255);
+    }
+
+    #[test]
+    fn should_reset_initial_color_to_black() {
+        let child = styled_child("div { color: rgb(0, 0, 255) } span { color: initial }");
+        assert rgb(&child) == (0, 0, 0);
+    }
+
+    #[test]
+    fn should_use_initial_display_for_unset() {
+        let child = styled_child("span { display: block } span { display: unset }");
+        assert child.style().display(false) == CSSDisplayInline;
+    }
+}
@@ -2,15 +2,18 @@
 
 use css::node_util::NodeUtil;
 use css::select_handler::NodeSelectHandler;
-use dom::node::{Node, NodeTree};
+use dom::node::{Element, Node, NodeTree};
 use layout::context::LayoutContext;
 use newcss::complete::CompleteSelectResults;
-use newcss::select::{SelectCtx, SelectResults};
+use newcss::select::{PseudoAfter, PseudoBefore, PseudoElement, SelectCtx, SelectResults};
+use newcss::values::{CSSContent, CSSContentAttr, CSSContentItems, CSSContentNone};
+use newcss::values::{CSSContentNormal, CSSContentString};
 
 use std::arc::{ARC, get, clone};
 
 pub trait MatchMethods {
-    fn restyle_subtree(select_ctx: &SelectCtx);
+    fn restyle_subtree(select_ctx: &SelectCtx) -> uint;
+    fn restyle(select_ctx: &SelectCtx) -> uint;
 }
 
 impl Node : MatchMethods {
@@ -20,8 +23,11 @@ impl Node : MatchMethods {
      * This is, importantly, the function that updates the layout data for
      * the node (the reader-auxiliary box in the COW model) with the
      * computed style.
+     *
+     * Returns the number of elements whose styles were recomputed.
      */
-    fn restyle_subtree(select_ctx: &SelectCtx) {
+    fn restyle_subtree(select_ctx: &SelectCtx) -> uint {
+        let mut restyled = 0;
 
         // Only elements have styles
         if self.is_element() {
@@ -32,14 +38,35 @@ impl Node : MatchMethods {
             // Combine this node's results with its parent's to resolve all inherited values
             let complete_results = compose_results(&self, move incomplete_results);
             self.set_css_select_results(move complete_results);
+
+            let before = select_generated_content(&self, select_ctx, &select_handler, PseudoBefore);
+            let after = select_generated_content(&self, select_ctx, &select_handler, PseudoAfter);
+            self.set_generated_content(move before, move after);
+            restyled += 1;
+        }
+
+        for NodeTree.each_child(&self) |kid| {
+            restyled += kid.restyle_subtree(select_ctx);
+        }
+        restyled
+    }
+
+    /**
+     * Recomputes styles only for the subtrees rooted at dirty elements, since
+     * a change to an element's style may be inherited by its descendants.
+     *
+     * Returns the number of elements whose styles were recomputed.
+     */
+    fn restyle(select_ctx: &SelectCtx) -> uint {
+        if self.is_element() && self.needs_restyle() {
+            return self.restyle_subtree(select_ctx);
         }
 
-        let mut i = 0u;
-        
+        let mut restyled = 0;
         for NodeTree.each_child(&self) |kid| {
-            i = i + 1u;
-            kid.restyle_subtree(select_ctx); 
+            restyled += kid.restyle(select_ctx);
         }
+        restyled
     }
 }
 
@@ -53,6 +80,57 @@ fn compose_results(node: &Node, results: SelectResults) -> CompleteSelectResults
     }    
 }
 
+/**
+Selects the style of one of the node's pseudo-elements and returns the
+text its `content` property generates, or None if it generates no box.
+*/
+fn select_generated_content(node: &Node, select_ctx: &SelectCtx,
+                            select_handler: &NodeSelectHandler,
+                            pseudo: PseudoElement) -> Option<~str> {
+    match select_ctx.select_pseudo_element_style(node, select_handler, pseudo) {
+        None => None,
+        Some(move results) => {
+            // Pseudo-elements inherit from their originating element
+            let complete_results = CompleteSelectResults::new_from_parent(
+                node.get_css_select_results(), move results);
+            let content = complete_results.computed_style().content();
+            do node.read |n| {
+                match n.kind {
+                    ~Element(ref element) => {
+                        resolve_content(&content, |name| element.get_attr(name))
+                    }
+                    _ => None
+                }
+            }
+        }
+    }
+}
+
+/**
+Concatenates the items of a `content` value into the text of the generated
+box. Only strings and `attr()` are supported so far; a missing attribute
+contributes the empty string.
+*/
+pub fn resolve_content(content: &CSSContent,
+                       get_attr: &fn(&str) -> Option<~str>) -> Option<~str> {
+    match *content {
+        CSSContentNone | CSSContentNormal => None,
+        CSSContentItems(ref items) => {
+            let mut text = ~"";
+            for items.each |item| {
+                match *item {
+                    CSSContentString(ref s) => text += *s,
+                    CSSContentAttr(ref name) => match get_attr(*name) {
+                        Some(value) => text += value,
+                        None => {}
+                    }
+                }
+            }
+            Some(move text)
+        }
+    }
+}
+
 fn find_parent_element_node(node: &Node) -> Option<Node> {
     use util::tree::parent;
 
@@ -67,3 +145,101 @@ fn find_parent_element_node(node: &Node) -> Option<Node> {
         None => None
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use css::node_style::StyledNode;
+    use css::select::{new_css_select_ctx, new_ua_select_ctx, test_stylesheet};
+    use dom::element::{Attr, ElementData, ElementKind, HTMLDivElement, HTMLSpanElement};
+    use dom::node::{Element, NodeScope, NodeScopeExtensions};
+    use layout::aux::LayoutAuxMethods;
+
+    use core::dvec::DVec;
+    use newcss::types::OriginAuthor;
+
+    fn new_element(scope: &NodeScope, tag_name: ~str, kind: ~ElementKind, class: ~str) -> Node {
+        let data = ElementData(move tag_name, move kind);
+        data.attrs.push(~Attr(~"class", move class));
+        scope.new_node(Element(move data))
+    }
+
+    #[test]
+    fn should_only_restyle_dirty_subtrees() {
+        let scope = NodeScope();
+        let root = new_element(&scope, ~"div", ~HTMLDivElement, ~"root");
+        let mutated = new_element(&scope, ~"div", ~HTMLDivElement, ~"before");
+        let mutated_child = new_element(&scope, ~"span", ~HTMLSpanElement, ~"child");
+        let unrelated = new_element(&scope, ~"div", ~HTMLDivElement, ~"unrelated");
+        scope.add_child(root, mutated);
+        scope.add_child(mutated, mutated_child);
+        scope.add_child(root, unrelated);
+
+        let refs = DVec();
+        root.initialize_style_for_subtree(&refs);
+        let select_ctx = new_css_select_ctx();
+
+        // Nothing has been styled yet
+        assert root.restyle(&select_ctx) == 4;
+        assert root.restyle(&select_ctx) == 0;
+
+        do scope.write(&mutated) |nd| {
+            match nd.kind {
+                ~Element(ref ed) => ed.set_attr(~"class", ~"after"),
+                _ => fail!(~"not an element")
+            }
+        }
+        scope.mark_style_dirty(&mutated);
+
+        // The mutated element and its child, but not its sibling
+        assert root.restyle(&select_ctx) == 2;
+        assert root.restyle(&select_ctx) == 0;
+    }
+
+    #[test]
+    fn should_put_important_declarations_above_specificity() {
+        let scope = NodeScope();
+        let root = new_element(&scope, ~"div", ~HTMLDivElement, ~"root");
+        let child = new_element(&scope, ~"span", ~HTMLSpanElement, ~"child");
+        scope.add_child(root, child);
+        let refs = DVec();
+        root.initialize_style_for_subtree(&refs);
+
+        let mut select_ctx = new_css_select_ctx();
+        select_ctx.append_sheet(test_stylesheet("span { color: rgb(0, 0, 255) !important }\n\
+                                                 div.root > span.child { color: red }\n\
+                                                 div.root { color: red }\n\
+                                                 div { color: rgb(0, 128, 0) !important }"),
+                                OriginAuthor);
+        root.restyle_subtree(&select_ctx);
+
+        let color = child.style().color();
+        assert (color.red, color.green, color.blue) == (0, 0, 255);
+        let color = root.style().color();
+        assert (color.red, color.green, color.blue) == (0, 128, 0);
+    }
+
+    #[test]
+    fn should_put_important_ua_declarations_above_important_author_ones() {
+        let scope = NodeScope();
+        let root = new_element(&scope, ~"div", ~HTMLDivElement, ~"root");
+        let child = new_element(&scope, ~"span", ~HTMLSpanElement, ~"child");
+        scope.add_child(root, child);
+        let refs = DVec();
+        root.initialize_style_for_subtree(&refs);
+
+        let ua_style = ~"span { color: rgb(0, 0, 255) !important }\n\
+                         div { color: red; display: block }";
+        let mut select_ctx = new_ua_select_ctx([("ua", move ua_style)]);
+        select_ctx.append_sheet(test_stylesheet("div.root > span.child { color: red !important }\n\
+                                                 div { color: rgb(0, 128, 0) !important }"),
+                                OriginAuthor);
+        root.restyle_subtree(&select_ctx);
+
+        // UA !important beats author !important, which beats UA normal
+        let color = child.style().color();
+        assert (color.red, color.green, color.blue) == (0, 0, 255);
+        let color = root.style().color();
+        assert (color.red, color.green, color.blue) == (0, 128, 0);
+    }
+}
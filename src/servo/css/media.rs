@@ -0,0 +1,205 @@
+// Evaluation of media queries, such as those of `@media` rules and `matchMedia`.
+
+use geom::size::Size2D;
+
+/// The number of px in an `em` when a media query is evaluated
+const MEDIA_EM_PX: float = 16.0;
+
+/// The result of evaluating a media query, as returned by `window.matchMedia`
+pub struct MediaQueryList {
+    /// The query as it was given
+    media: ~str,
+    matches: bool,
+}
+
+pub fn MediaQueryList(media: ~str, viewport: Size2D<uint>) -> MediaQueryList {
+    let matches = evaluate_media_query_list(media, viewport);
+    MediaQueryList {
+        media: move media,
+        matches: matches,
+    }
+}
+
+/**
+Whether a comma-separated list of media queries matches a screen showing a
+viewport of `viewport` px. An empty list matches everything. A query that
+can't be parsed matches nothing, even when negated with `not`.
+*/
+pub fn evaluate_media_query_list(queries: &str, viewport: Size2D<uint>) -> bool {
+    if str::trim(queries).is_empty() {
+        return true;
+    }
+    for str::split_char(queries, ',').each |query| {
+        match evaluate_media_query(*query, viewport) {
+            Some(true) => return true,
+            Some(false) | None => ()
+        }
+    }
+    false
+}
+
+// Evaluates a single query, or returns None if it can't be parsed
+fn evaluate_media_query(query: &str, viewport: Size2D<uint>) -> Option<bool> {
+    let query = str::to_lower(str::trim(query));
+    let mut words = split_query(query);
+    if words.is_empty() {
+        return None;
+    }
+
+    let negated = words[0] == ~"not";
+    if negated || words[0] == ~"only" {
+        vec::shift(&mut words);
+    }
+
+    let mut matches = true;
+    let mut expect_condition = false;
+    for words.eachi |i, word| {
+        if expect_condition && *word != ~"and" && !str::starts_with(*word, "(") {
+            return None;
+        }
+        if *word == ~"and" {
+            if expect_condition || i == 0 || i == words.len() - 1 {
+                return None;
+            }
+            expect_condition = true;
+        } else if str::starts_with(*word, "(") {
+            match evaluate_feature(*word, viewport) {
+                Some(result) => matches = matches && result,
+                None => return None
+            }
+            expect_condition = false;
+        } else if i == 0 {
+            matches = match *word {
+                ~"all" | ~"screen" => true,
+                ~"print" | ~"speech" => false,
+                _ => return None
+            };
+        } else {
+            return None;
+        }
+    }
+
+    Some(if negated { !matches } else { matches })
+}
+
+// Splits a query into words and parenthesized features
+fn split_query(query: &str) -> ~[~str] {
+    let mut words = ~[];
+    let mut current = ~"";
+    let mut in_parens = false;
+    for str::each_char(query) |c| {
+        if in_parens {
+            str::push_char(&mut current, c);
+            if c == ')' {
+                words.push(move current);
+                current = ~"";
+                in_parens = false;
+            }
+        } else if c == '(' {
+            if !current.is_empty() {
+                words.push(move current);
+                current = ~"";
+            }
+            str::push_char(&mut current, c);
+            in_parens = true;
+        } else if char::is_whitespace(c) {
+            if !current.is_empty() {
+                words.push(move current);
+                current = ~"";
+            }
+        } else {
+            str::push_char(&mut current, c);
+        }
+    }
+    if !current.is_empty() {
+        words.push(move current);
+    }
+    words
+}
+
+// Evaluates a parenthesized feature such as `(min-width: 600px)`
+fn evaluate_feature(feature: &str, viewport: Size2D<uint>) -> Option<bool> {
+    if !str::ends_with(feature, ")") {
+        return None;
+    }
+    let inner = str::slice(feature, 1, feature.len() - 1);
+    let parts = str::split_char(inner, ':');
+    if parts.len() != 2 {
+        return None;
+    }
+    let name = str::trim(parts[0]);
+    let value = str::trim(parts[1]);
+
+    if name == ~"orientation" {
+        let portrait = viewport.height >= viewport.width;
+        return match value {
+            ~"portrait" => Some(portrait),
+            ~"landscape" => Some(!portrait),
+            _ => None
+        };
+    }
+
+    let length = match parse_length(value) {
+        Some(length) => length,
+        None => return None
+    };
+    let width = viewport.width as float;
+    let height = viewport.height as float;
+    match name {
+        ~"width" => Some(width == length),
+        ~"min-width" => Some(width >= length),
+        ~"max-width" => Some(width <= length),
+        ~"height" => Some(height == length),
+        ~"min-height" => Some(height >= length),
+        ~"max-height" => Some(height <= length),
+        _ => None
+    }
+}
+
+// Parses a length in px or em into px. Zero needs no unit.
+fn parse_length(value: &str) -> Option<float> {
+    let (number, scale) = if str::ends_with(value, "px") {
+        (str::slice(value, 0, value.len() - 2), 1.0)
+    } else if str::ends_with(value, "em") {
+        (str::slice(value, 0, value.len() - 2), MEDIA_EM_PX)
+    } else if value == "0" {
+        return Some(0.0);
+    } else {
+        return None;
+    };
+    float::from_str(number).map(|n| *n * scale)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use geom::size::Size2D;
+
+    #[test]
+    fn should_flip_min_width_query_with_viewport_size() {
+        let query = ~"(min-width: 600px)";
+        let wide = MediaQueryList(copy query, Size2D(800u, 600u));
+        let narrow = MediaQueryList(copy query, Size2D(400u, 600u));
+
+        assert wide.media == query && narrow.media == query;
+        assert wide.matches;
+        assert !narrow.matches;
+    }
+
+    #[test]
+    fn should_evaluate_media_types_and_conditions() {
+        let viewport = Size2D(800u, 600u);
+        assert evaluate_media_query_list("", viewport);
+        assert evaluate_media_query_list("screen and (max-width: 50em)", viewport);
+        assert !evaluate_media_query_list("print", viewport);
+        assert evaluate_media_query_list("print, (orientation: landscape)", viewport);
+        assert evaluate_media_query_list("not print", viewport);
+        assert !evaluate_media_query_list("screen and (min-height: 601px)", viewport);
+
+        // Unparseable queries don't match, negated or not
+        assert !evaluate_media_query_list("(min-width: wide)", viewport);
+        assert !evaluate_media_query_list("not (min-width: wide)", viewport);
+        assert !evaluate_media_query_list("screen and", viewport);
+    }
+}
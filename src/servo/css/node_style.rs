@@ -7,6 +7,7 @@ use newcss::complete::CompleteStyle;
 /// Node mixin providing `style` method that returns a `NodeStyle`
 pub trait StyledNode {
     fn style(&self) -> CompleteStyle/&self;
+    fn generated_content(&self) -> (Option<~str>, Option<~str>);
 }
 
 impl Node: StyledNode {
@@ -15,4 +16,9 @@ impl Node: StyledNode {
         let results = self.get_css_select_results();
         results.computed_style()
     }
+
+    /// The text of the node's ::before and ::after boxes, if it generates any
+    fn generated_content(&self) -> (Option<~str>, Option<~str>) {
+        self.get_generated_content()
+    }
 }
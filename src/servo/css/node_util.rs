@@ -5,6 +5,9 @@ use std::cell::Cell;
 pub trait NodeUtil {
     fn get_css_select_results() -> &self/CompleteSelectResults;
     fn set_css_select_results(decl : CompleteSelectResults);
+    fn needs_restyle() -> bool;
+    fn get_generated_content() -> (Option<~str>, Option<~str>);
+    fn set_generated_content(before: Option<~str>, after: Option<~str>);
 }
 
 impl NodeUtil for Node {
@@ -32,8 +35,46 @@ impl NodeUtil for Node {
     */
     fn set_css_select_results(decl : CompleteSelectResults) {
         let decl = Cell(move decl);
+        let epoch = self.read(|n| n.style_epoch);
         do self.aux |data| {
-            data.style = Some(decl.take())
+            data.style = Some(decl.take());
+            data.style_epoch = Some(epoch);
+        }
+    }
+
+    /**
+    Whether the node has not been styled since it was last marked dirty.
+    Nodes that have never been styled need restyling too.
+    */
+    fn needs_restyle() -> bool {
+        if !self.has_aux() {
+            return true;
+        }
+        let epoch = self.read(|n| n.style_epoch);
+        do self.aux |data| {
+            data.style.is_none() || data.style_epoch != Some(epoch)
+        }
+    }
+
+    /**
+    Returns the text generated for the node's ::before and ::after
+    pseudo-elements, as resolved during selector matching.
+    */
+    fn get_generated_content() -> (Option<~str>, Option<~str>) {
+        if !self.has_aux() {
+            return (None, None);
+        }
+        do self.aux |data| {
+            (copy data.before_content, copy data.after_content)
+        }
+    }
+
+    fn set_generated_content(before: Option<~str>, after: Option<~str>) {
+        let before = Cell(move before);
+        let after = Cell(move after);
+        do self.aux |data| {
+            data.before_content = before.take();
+            data.after_content = after.take();
         }
     }
 }
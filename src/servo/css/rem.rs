@@ -0,0 +1,296 @@
+/*!
+Resolution of `rem` lengths, which are relative to the font size of the root
+element however deeply they are nested. libcss doesn't know the unit, so
+lengths in it are rewritten into px before a sheet is parsed, against the root
+font size styling last computed. Layout parses sheets with rem lengths again
+once styling finds the root font size has changed.
+*/
+
+use css::declarations::rewrite_declarations;
+use css::matching::MatchMethods;
+use css::node_style::StyledNode;
+use css::select::new_author_select_ctx;
+use dom::node::Node;
+
+use newcss::select::SelectCtx;
+use newcss::units::{Pt, Px};
+use newcss::values::CSSFontSizeLength;
+use std::net::url::Url;
+
+/// The px in the root font size before styling computes it, which is `medium`
+pub const INITIAL_ROOT_FONT_SIZE_PX: float = 16.0;
+
+/**
+Called once the tree under `root` has been styled with the author sheets,
+their rem lengths resolved against `*root_size` px. If styling computed
+another root font size, the sheets are parsed again against it, the tree is
+styled again, and the new selection context is returned, unless no sheet has
+rem lengths. Rems in the root's own font size are relative to the initial
+size, so styling again doesn't change the root font size.
+*/
+pub fn restyle_for_root_font_size(root: &Node, sheets: &[(Url, ~str)],
+                                  root_size: &mut float) -> Option<SelectCtx> {
+    if !root.is_element() {
+        return None;
+    }
+    let computed_size = root_font_size_px(root);
+    if computed_size == *root_size {
+        return None;
+    }
+    *root_size = computed_size;
+    if !sheets.any(|&(_, ref source)| has_rem_units(*source)) {
+        return None;
+    }
+
+    debug!("rem: styling again for a root font size of %fpx", computed_size);
+    let select_ctx = new_author_select_ctx(sheets, computed_size);
+    root.restyle_subtree(&select_ctx);
+    Some(move select_ctx)
+}
+
+/// The root element's computed font size in px, or the initial size if it isn't in px or pt
+pub fn root_font_size_px(root: &Node) -> float {
+    match root.style().font_size() {
+        CSSFontSizeLength(Px(px)) => px,
+        CSSFontSizeLength(Pt(pt)) => pt * 4.0 / 3.0,
+        _ => INITIAL_ROOT_FONT_SIZE_PX
+    }
+}
+
+/**
+Rewrites the `rem` lengths in the declarations of a sheet into px, for a root
+font size of `root_size` px. In the `font-size` of rules for `html` or `:root`,
+which sets the root font size, rem lengths are relative to the initial font
+size instead.
+*/
+pub fn resolve_rem_units(css: &str, root_size: float) -> ~str {
+    do rewrite_declarations(css) |prelude, declaration| {
+        if is_root_rule(prelude) && declaration_name(declaration) == Some(~"font-size") {
+            Some(resolve_lengths(declaration, INITIAL_ROOT_FONT_SIZE_PX))
+        } else {
+            Some(resolve_lengths(declaration, root_size))
+        }
+    }
+}
+
+/// Whether a sheet has any `rem` lengths to resolve
+pub fn has_rem_units(css: &str) -> bool {
+    // Every rem length is rewritten, and nothing else is
+    resolve_rem_units(css, 0.0) != str::from_slice(css)
+}
+
+// Rewrites the rem lengths of a declaration into px, for a root font size of `root_size` px
+fn resolve_lengths(css: &str, root_size: float) -> ~str {
+    let mut result = ~"";
+    let mut quote = None;
+    let mut i = 0;
+    while i < css.len() {
+        let c = css[i] as char;
+        match quote {
+            Some(q) => {
+                if c == q {
+                    quote = None;
+                }
+            }
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if starts_number(css, i) => {
+                let end = number_end(css, i);
+                if is_rem_unit(css, end) {
+                    match float::from_str(str::slice(css, i, end)) {
+                        Some(rems) => {
+                            result += fmt!("%fpx", rems * root_size);
+                            i = end + 3;
+                            loop;
+                        }
+                        None => ()
+                    }
+                }
+                result += str::slice(css, i, end);
+                i = end;
+                loop;
+            }
+            None => ()
+        }
+        // Copy whole characters, so as not to split multibyte ones
+        let next = str::char_range_at(css, i).next;
+        result += str::slice(css, i, next);
+        i = next;
+    }
+    result
+}
+
+// Whether a rule with the given prelude is for the root element
+fn is_root_rule(prelude: &str) -> bool {
+    do str::split_char(prelude, ',').any |selector| {
+        let selector = str::to_lower(str::trim(*selector));
+        selector == ~"html" || selector == ~":root"
+    }
+}
+
+// The lowercased property name of a declaration
+fn declaration_name(declaration: &str) -> Option<~str> {
+    str::find_char(declaration, ':').map(|&colon| {
+        str::to_lower(str::trim(str::slice(declaration, 0, colon)))
+    })
+}
+
+pure fn is_name_char(c: char) -> bool {
+    char::is_alphanumeric(c) || c == '_' || c == '-' || c == '#'
+}
+
+// Whether a byte is part of a name. Any byte of a multibyte character is.
+pure fn is_name_byte(b: u8) -> bool {
+    b >= 0x80 || is_name_char(b as char)
+}
+
+// Whether a number starts at `i`, rather than the middle of a name or another number
+fn starts_number(css: &str, i: uint) -> bool {
+    let c = css[i] as char;
+    if !char::is_digit(c) && c != '.' {
+        return false;
+    }
+    if c == '.' && (i + 1 >= css.len() || !char::is_digit(css[i + 1] as char)) {
+        return false;
+    }
+    // A `-` before the number is a sign, unless it ends a name
+    let mut before = i;
+    if before > 0 && css[before - 1] as char == '-' {
+        before -= 1;
+    }
+    before == 0 || !is_name_byte(css[before - 1]) && css[before - 1] as char != '.'
+}
+
+// The index just past the digits and decimal point of the number starting at `i`
+fn number_end(css: &str, i: uint) -> uint {
+    let mut end = i;
+    while end < css.len() && (char::is_digit(css[end] as char) || css[end] as char == '.') {
+        end += 1;
+    }
+    end
+}
+
+// Whether the unit after a number, starting at `i`, is `rem`
+fn is_rem_unit(css: &str, i: uint) -> bool {
+    // Compared byte by byte, since slicing could split a multibyte character
+    let unit = [('r', 'R'), ('e', 'E'), ('m', 'M')];
+    if i + 3 > css.len() {
+        return false;
+    }
+    for unit.eachi |j, &(lower, upper)| {
+        let c = css[i + j] as char;
+        if c != lower && c != upper {
+            return false;
+        }
+    }
+    i + 3 == css.len() || !is_name_byte(css[i + 3]) && css[i + 3] as char != '.'
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use css::matching::MatchMethods;
+    use css::node_style::StyledNode;
+    use css::select::new_author_select_ctx;
+    use dom::element::{ElementData, HTMLDivElement, HTMLHtmlElement, HTMLSpanElement};
+    use dom::node::{Element, Node, NodeScope, NodeScopeExtensions};
+    use layout::aux::LayoutAuxMethods;
+
+    use core::dvec::DVec;
+    use newcss::units::Px;
+    use newcss::values::{CSSFontSizeLength, CSSWidthLength};
+    use std::net::url;
+
+    /*
+    Styles an html element holding three nested divs, the innermost holding a
+    span, with the given sheet, as layout does. Returns the divs and the span.
+    */
+    fn styled_nesting(css: &str) -> (~[Node], Node) {
+        let scope = NodeScope();
+        let root = scope.new_node(Element(ElementData(~"html", ~HTMLHtmlElement)));
+        let mut divs = ~[];
+        let mut parent = root;
+        for 3.times {
+            let div = scope.new_node(Element(ElementData(~"div", ~HTMLDivElement)));
+            scope.add_child(parent, div);
+            divs.push(div);
+            parent = div;
+        }
+        let span = scope.new_node(Element(ElementData(~"span", ~HTMLSpanElement)));
+        scope.add_child(parent, span);
+        let refs = DVec();
+        root.initialize_style_for_subtree(&refs);
+
+        let sheets = ~[(url::from_str(~"http://test").get(), str::from_slice(css))];
+        let mut root_size = INITIAL_ROOT_FONT_SIZE_PX;
+        root.restyle_subtree(&new_author_select_ctx(sheets, root_size));
+        restyle_for_root_font_size(&root, sheets, &mut root_size);
+        (divs, span)
+    }
+
+    fn font_size_px(node: &Node) -> float {
+        match node.style().font_size() {
+            CSSFontSizeLength(Px(px)) => px,
+            _ => fail!(~"font size not in px")
+        }
+    }
+
+    fn width_px(node: &Node) -> float {
+        match node.style().width() {
+            CSSWidthLength(Px(px)) => px,
+            _ => fail!(~"width not in px")
+        }
+    }
+
+    #[test]
+    fn should_resolve_nested_rems_against_the_root() {
+        let (divs, span) = styled_nesting("div { font-size: 1.5rem } span { width: 10rem }");
+        for divs.each |div| {
+            assert font_size_px(div) == 24.0;
+        }
+        assert width_px(&span) == 160.0;
+    }
+
+    #[test]
+    fn should_follow_the_root_font_size() {
+        let (divs, span) = styled_nesting("html { font-size: 20px }\n\
+                                           div { font-size: 1.5rem } span { width: 10rem }");
+        for divs.each |div| {
+            assert font_size_px(div) == 30.0;
+        }
+        assert width_px(&span) == 200.0;
+
+        let (divs, span) = styled_nesting(":root { font-size: 50% }\n\
+                                           div { font-size: 1.5rem } span { width: 10rem }");
+        for divs.each |div| {
+            assert font_size_px(div) == 12.0;
+        }
+        assert width_px(&span) == 80.0;
+    }
+
+    #[test]
+    fn should_resolve_rems_in_the_root_font_size_against_the_initial_size() {
+        let (divs, span) = styled_nesting("html { font-size: 2rem }\n\
+                                           div { font-size: 1.5rem } span { width: 10rem }");
+        for divs.each |div| {
+            assert font_size_px(div) == 48.0;
+        }
+        assert width_px(&span) == 320.0;
+    }
+
+    #[test]
+    fn should_leave_other_units_and_names_alone() {
+        let css = "#rem1, .x2rem { margin: 1em; background: url(\"3rem.png\") }";
+        assert resolve_rem_units(css, 20.0) == str::from_slice(css);
+        assert !has_rem_units(css);
+    }
+
+    #[test]
+    fn should_not_split_characters_around_numbers() {
+        // "3ré" is a name, and the rem after "é" isn't a unit
+        let css = "p { width: 2r\u00E9m; content: \"\u00E91rem\" } .\u00E91rem { width: 1rem }";
+        let resolved = resolve_rem_units(css, 20.0);
+        assert str::starts_with(resolved, "p { width: 2r\u00E9m; content: \"\u00E91rem\" } \
+                                           .\u00E91rem { width: 20");
+        assert str::ends_with(resolved, "px }");
+    }
+}
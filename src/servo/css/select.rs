@@ -1,26 +1,113 @@
+use html::cssparse::parse_stylesheet;
+
 use std::net::url::Url;
 use url_from_str = std::net::url::from_str;
 use std::cell::Cell;
 use newcss::stylesheet::Stylesheet;
 use newcss::select::SelectCtx;
-use newcss::types::OriginUA;
+use newcss::types::{OriginAuthor, OriginUA, OriginUser};
 use newcss::util::DataStream;
 
+/// A selection context holding the user agent sheets
 pub fn new_css_select_ctx() -> SelectCtx {
+    new_ua_select_ctx([("html4_style", html4_default_style_str()),
+                       ("servo_style", servo_default_style_str())])
+}
+
+/**
+A selection context holding the given user agent sheets, named by their host.
+Declarations cascade as UA < author < author `!important` < UA `!important`.
+libcss ranks important UA declarations with the normal ones, but ranks user
+`!important` above author `!important`; since there are no user sheets, the
+important UA declarations are split out into a sheet of user origin.
+*/
+pub fn new_ua_select_ctx(sheets: &[(&str, ~str)]) -> SelectCtx {
     let mut ctx = SelectCtx::new();
-    ctx.append_sheet(html4_default_style(), OriginUA);
-    ctx.append_sheet(servo_default_style(), OriginUA);
-    return move ctx;
+    for sheets.each |&(name, ref style)| {
+        let (normal, important) = split_important(*style);
+        ctx.append_sheet(Stylesheet::new(default_url(name), style_stream(normal)), OriginUA);
+        if !important.is_empty() {
+            ctx.append_sheet(Stylesheet::new(default_url(name), style_stream(important)),
+                             OriginUser);
+        }
+    }
+    move ctx
 }
 
-fn html4_default_style() -> Stylesheet {
-    Stylesheet::new(default_url("html4_style"),
-                    style_stream(html4_default_style_str()))
+/**
+A selection context holding the user agent sheets and the given author sheets,
+parsed with their `rem` lengths resolved for a root font size of `root_size` px
+*/
+pub fn new_author_select_ctx(sheets: &[(Url, ~str)], root_size: float) -> SelectCtx {
+    let mut ctx = new_css_select_ctx();
+    for sheets.each |&(ref url, ref source)| {
+        ctx.append_sheet(parse_stylesheet(copy *url, *source, root_size), OriginAuthor);
+    }
+    move ctx
 }
 
-fn servo_default_style() -> Stylesheet {
-    Stylesheet::new(default_url("servo_style"),
-                    style_stream(servo_default_style_str()))
+/**
+Splits a sheet into a sheet of its normal declarations and a sheet of its
+`!important` ones, keeping each rule's selectors and enclosing at-rules
+*/
+pub fn split_important(css: &str) -> (~str, ~str) {
+    let mut normal = ~"";
+    let mut important = ~"";
+    let mut i = 0;
+    while i < css.len() {
+        let open = match str::find_char_from(css, '{', i) {
+            Some(open) => open,
+            None => {
+                // Trailing whitespace or comments
+                normal += str::slice(css, i, css.len());
+                break;
+            }
+        };
+        let prelude = str::slice(css, i, open);
+        if str::starts_with(str::trim_left(prelude), "@") {
+            // A block at-rule such as @media, whose rules are split in turn
+            let mut depth = 1;
+            let mut close = open + 1;
+            while close < css.len() && depth > 0 {
+                match css[close] as char {
+                    '{' => depth += 1,
+                    '}' => depth -= 1,
+                    _ => ()
+                }
+                close += 1;
+            }
+            let inner_end = if depth == 0 { close - 1 } else { close };
+            let (inner_normal, inner_important) = split_important(str::slice(css, open + 1,
+                                                                             inner_end));
+            normal += fmt!("%s{%s}", prelude, inner_normal);
+            if !str::is_whitespace(inner_important) {
+                important += fmt!("%s {%s}\n", str::trim(prelude), inner_important);
+            }
+            i = close;
+            loop;
+        }
+
+        let close = match str::find_char_from(css, '}', open) {
+            Some(close) => close,
+            None => css.len()
+        };
+        let mut normal_decls = ~[];
+        let mut important_decls = ~[];
+        for str::split_char(str::slice(css, open + 1, close), ';').each |decl| {
+            if str::contains(str::to_lower(*decl), "!important") {
+                important_decls.push(str::trim(*decl));
+            } else {
+                normal_decls.push(copy *decl);
+            }
+        }
+        normal += fmt!("%s{%s}", prelude, str::connect(normal_decls, ";"));
+        if !important_decls.is_empty() {
+            important += fmt!("%s { %s }\n", str::trim(prelude),
+                              str::connect(important_decls, "; "));
+        }
+        i = uint::min(close + 1, css.len());
+    }
+    (move normal, move important)
 }
 
 fn default_url(name: &str) -> Url {
@@ -37,6 +124,12 @@ fn style_stream(style: &str) -> DataStream {
     return d;
 }
 
+/// A sheet holding `css`, for tests that style nodes
+#[cfg(test)]
+pub fn test_stylesheet(css: &str) -> Stylesheet {
+    Stylesheet::new(default_url("test"), style_stream(css))
+}
+
 fn html4_default_style_str() -> ~str {
 ~"
 html, address,
@@ -123,4 +216,18 @@ ul, ol, dl    { page-break-before: avoid }
 fn servo_default_style_str() -> ~str {
     // libcss want's this to default to 2px..
     ~"* { border-width: 0px; }"
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn should_split_important_declarations_out_of_sheets() {
+        let (normal, important) = split_important("a { color: red; margin: 0 !IMPORTANT }\n\
+                                                   @media print { b { color: blue !important } }");
+        assert normal == ~"a { color: red}\n@media print { b {} }";
+        assert important == ~"a { margin: 0 !IMPORTANT }\n\
+                              @media print {b { color: blue !important }\n}\n";
+    }
+}
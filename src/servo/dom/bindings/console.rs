@@ -0,0 +1,121 @@
+// DOM bindings for the console object.
+
+use dom::bindings::utils::{rust_box, squirrel_away};
+use dom::console::Console;
+use super::utils;
+
+use core::libc::c_uint;
+use core::ptr::null;
+use js::crust::{JS_PropertyStub, JS_StrictPropertyStub};
+use js::global::jsval_to_rust_str;
+use js::glue::bindgen::*;
+use js::jsapi::bindgen::{JS_DefineFunctions, JS_GetReservedSlot, JS_SetReservedSlot};
+use js::jsapi::bindgen::JS_ValueToString;
+use js::jsapi::{JSContext, JSVal, JSObject, JSBool, JSFreeOp, JSFunctionSpec};
+use js::jsapi::{JSNativeWrapper};
+use js::rust::Compartment;
+use js::{JS_ARGV, JSPROP_ENUMERATE, JSVAL_NULL};
+use js::{JS_THIS_OBJECT, JS_SET_RVAL};
+
+// Joins the string values of all arguments with spaces, as console methods do.
+unsafe fn args_to_str(cx: *JSContext, argc: c_uint, argv: *JSVal) -> ~str {
+    let mut parts = ~[];
+    let mut i = 0;
+    while i < argc as uint {
+        let jsstr = JS_ValueToString(cx, *ptr::offset(argv, i));
+        parts.push(jsval_to_rust_str(cx, jsstr));
+        i += 1;
+    }
+    str::connect(parts, ~" ")
+}
+
+extern fn log(cx: *JSContext, argc: c_uint, vp: *JSVal) -> JSBool {
+    unsafe {
+        let s = args_to_str(cx, argc, JS_ARGV(cx, vp));
+        (*unwrap(JS_THIS_OBJECT(cx, vp))).payload.log(s);
+        JS_SET_RVAL(cx, vp, JSVAL_NULL);
+        return 1;
+    }
+}
+
+extern fn warn(cx: *JSContext, argc: c_uint, vp: *JSVal) -> JSBool {
+    unsafe {
+        let s = args_to_str(cx, argc, JS_ARGV(cx, vp));
+        (*unwrap(JS_THIS_OBJECT(cx, vp))).payload.warn(s);
+        JS_SET_RVAL(cx, vp, JSVAL_NULL);
+        return 1;
+    }
+}
+
+extern fn error(cx: *JSContext, argc: c_uint, vp: *JSVal) -> JSBool {
+    unsafe {
+        let s = args_to_str(cx, argc, JS_ARGV(cx, vp));
+        (*unwrap(JS_THIS_OBJECT(cx, vp))).payload.error(s);
+        JS_SET_RVAL(cx, vp, JSVAL_NULL);
+        return 1;
+    }
+}
+
+unsafe fn unwrap(obj: *JSObject) -> *rust_box<Console> {
+    let val = JS_GetReservedSlot(obj, 0);
+    cast::reinterpret_cast(&RUST_JSVAL_TO_PRIVATE(val))
+}
+
+extern fn finalize(_fop: *JSFreeOp, obj: *JSObject) {
+    debug!("console finalize!");
+    unsafe {
+        let val = JS_GetReservedSlot(obj, 0);
+        let _: @Console = cast::reinterpret_cast(&RUST_JSVAL_TO_PRIVATE(val));
+    }
+}
+
+pub fn init(compartment: @mut Compartment, console: @Console) {
+    let proto = utils::define_empty_prototype(~"Console", None, compartment);
+    compartment.register_class(utils::instance_jsclass(~"ConsoleInstance", finalize));
+
+    let obj = result::unwrap(
+                 compartment.new_object_with_proto(~"ConsoleInstance",
+                                                   ~"Console", null()));
+
+    let methods = [
+        JSFunctionSpec {
+            name: compartment.add_name(~"log"),
+            call: JSNativeWrapper { op: log, info: null() },
+            nargs: 1,
+            flags: 0,
+            selfHostedName: null()
+        },
+        JSFunctionSpec {
+            name: compartment.add_name(~"warn"),
+            call: JSNativeWrapper { op: warn, info: null() },
+            nargs: 1,
+            flags: 0,
+            selfHostedName: null()
+        },
+        JSFunctionSpec {
+            name: compartment.add_name(~"error"),
+            call: JSNativeWrapper { op: error, info: null() },
+            nargs: 1,
+            flags: 0,
+            selfHostedName: null()
+        },
+        JSFunctionSpec {
+            name: null(),
+            call: JSNativeWrapper { op: null(), info: null() },
+            nargs: 0,
+            flags: 0,
+            selfHostedName: null()
+        }
+    ];
+
+    unsafe {
+        JS_DefineFunctions(compartment.cx.ptr, proto.ptr, &methods[0]);
+
+        let raw_ptr: *libc::c_void = cast::reinterpret_cast(&squirrel_away(console));
+        JS_SetReservedSlot(obj.ptr, 0, RUST_PRIVATE_TO_JSVAL(raw_ptr));
+    }
+
+    compartment.define_property(~"console", RUST_OBJECT_TO_JSVAL(obj.ptr),
+                                JS_PropertyStub, JS_StrictPropertyStub,
+                                JSPROP_ENUMERATE);
+}
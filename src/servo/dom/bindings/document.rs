@@ -4,14 +4,22 @@ use js::{JS_ARGV, JSCLASS_HAS_RESERVED_SLOTS, JSPROP_ENUMERATE, JSPROP_SHARED,
 use js::jsapi::{JSContext, JSVal, JSObject, JSBool, jsid, JSClass, JSFreeOp};
 use js::jsapi::bindgen::{JS_ValueToString, JS_GetStringCharsZAndLength, JS_ReportError,
                             JS_GetReservedSlot, JS_SetReservedSlot, JS_NewStringCopyN,
-                            JS_DefineFunctions, JS_DefineProperty, JS_DefineProperties};
+                            JS_DefineFunctions, JS_DefineProperty, JS_DefineProperties,
+                            JS_GetProperty};
 use js::glue::bindgen::*;
+use js::jsval::INT_TO_JSVAL;
 use js::glue::{PROPERTY_STUB, STRICT_PROPERTY_STUB};
 use js::crust::{JS_PropertyStub, JS_StrictPropertyStub, JS_EnumerateStub, JS_ConvertStub, JS_ResolveStub};
 use ptr::null;
 use libc::c_uint;
-use dom::bindings::utils::{DOMString, domstring_to_jsval, rust_box, squirrel_away, str};
+use dom::bindings::utils::{DOMString, check_argc, domstring_to_jsval, get_string_arg, jsval_to_str};
+use dom::bindings::utils::{rust_box, squirrel_away};
+use dom::bindings::utils::{str};
 use dom::bindings::node::create;
+use dom::bindings::htmlcollection;
+use dom::bindings::nodelist;
+use dom::selector::parse_selector;
+use js::jsapi::{JSFunctionSpec, JSNativeWrapper};
 
 use dom::document::Document;
 use dom::bindings::node;
@@ -23,37 +31,6 @@ enum DOMException {
 
 enum Element = int;
 
-/*extern fn getElementById(cx: *JSContext, argc: c_uint, vp: *jsval) -> JSBool {
-    //XXX check if actually document object
-    if argc != 1 {
-        //XXX throw proper DOM exception
-        str::as_c_str("Not enough arguments", |s| {
-            JS_ReportError(cx, s);
-        });
-        return 0;
-    }
-    let id;
-    unsafe {
-        id = JS_ARGV(cx, vp)[0];
-    }
-    alt jsval_to_str(cx, id) {
-      ok(s) {
-        unsafe {
-            let doc: *Document = cast::reinterpret_cast(JS_GetContextPrivate(cx));
-            let elem = (*doc).getElementById(s);
-        }
-        //XXX wrap result
-        return 1;
-      }
-      err(_) {
-        str::as_c_str("???", |s| {
-            JS_ReportError(cx, s);
-        });
-        return 0;
-      }
-    }
-}*/
-
 /*extern fn getDocumentURI(cx: *JSContext, _argc: c_uint, vp: *jsval) -> JSBool {
     unsafe {
         let uri = (*unwrap(JS_THIS_OBJECT(cx, vp))).payload.getDocumentURI();
@@ -77,12 +54,212 @@ extern fn getDocumentElement(cx: *JSContext, _argc: c_uint, vp: *mut JSVal) -> J
     }
 }
 
-unsafe fn unwrap(obj: *JSObject) -> *rust_box<Document> {
+extern fn getNodeType(cx: *JSContext, _argc: c_uint, vp: *mut JSVal) -> JSBool {
+    unsafe {
+        let obj = JS_THIS_OBJECT(cx, cast::reinterpret_cast(&vp));
+        if obj.is_null() {
+            return 0;
+        }
+
+        *vp = INT_TO_JSVAL((*unwrap(obj)).payload.node_type());
+        return 1;
+    }
+}
+
+extern fn getNodeName(cx: *JSContext, _argc: c_uint, vp: *mut JSVal) -> JSBool {
+    unsafe {
+        let obj = JS_THIS_OBJECT(cx, cast::reinterpret_cast(&vp));
+        if obj.is_null() {
+            return 0;
+        }
+
+        let name = str((*unwrap(obj)).payload.node_name());
+        *vp = domstring_to_jsval(cx, &name);
+        return 1;
+    }
+}
+
+extern fn getTitle(cx: *JSContext, _argc: c_uint, vp: *mut JSVal) -> JSBool {
+    unsafe {
+        let obj = JS_THIS_OBJECT(cx, cast::reinterpret_cast(&vp));
+        if obj.is_null() {
+            return 0;
+        }
+
+        let title = str((*unwrap(obj)).payload.title().get_or_default(~""));
+        *vp = domstring_to_jsval(cx, &title);
+        return 1;
+    }
+}
+
+extern fn setTitle(cx: *JSContext, _argc: c_uint, vp: *mut JSVal) -> JSBool {
+    unsafe {
+        let obj = JS_THIS_OBJECT(cx, cast::reinterpret_cast(&vp));
+        if obj.is_null() {
+            return 0;
+        }
+
+        match jsval_to_str(cx, *ptr::offset(JS_ARGV(cx, cast::reinterpret_cast(&vp)), 0)) {
+            Ok(title) => (*unwrap(obj)).payload.set_title(title),
+            Err(()) => return 0
+        }
+        return 1;
+    }
+}
+
+#[allow(non_implicitly_copyable_typarams)]
+extern fn querySelectorAll(cx: *JSContext, argc: c_uint, vp: *JSVal) -> JSBool {
+    unsafe {
+        if !check_argc(cx, argc, 1, "querySelectorAll") {
+            return 0;
+        }
+
+        let obj = JS_THIS_OBJECT(cx, vp);
+        if obj.is_null() {
+            return 0;
+        }
+
+        let selector = match jsval_to_str(cx, *ptr::offset(JS_ARGV(cx, vp), 0)) {
+            Ok(s) => parse_selector(s),
+            Err(()) => None
+        };
+
+        match selector {
+            Some(ref selector) => {
+                let box = unwrap(obj);
+                let nodes = (*box).payload.query_selector_all(selector);
+                let list = nodelist::create(cx, move nodes, (*box).payload.scope);
+                JS_SET_RVAL(cx, vp, RUST_OBJECT_TO_JSVAL(list.ptr));
+                return 1;
+            }
+            None => {
+                //XXX throw a proper SYNTAX_ERR DOM exception
+                str::as_c_str("Invalid or unsupported selector", |s| {
+                    JS_ReportError(cx, s);
+                });
+                return 0;
+            }
+        }
+    }
+}
+
+#[allow(non_implicitly_copyable_typarams)]
+extern fn getElementById(cx: *JSContext, argc: c_uint, vp: *JSVal) -> JSBool {
+    unsafe {
+        if !check_argc(cx, argc, 1, "getElementById") {
+            return 0;
+        }
+
+        let obj = JS_THIS_OBJECT(cx, vp);
+        if obj.is_null() {
+            return 0;
+        }
+
+        match jsval_to_str(cx, *ptr::offset(JS_ARGV(cx, vp), 0)) {
+            Ok(id) => {
+                let box = unwrap(obj);
+                let element = match (*box).payload.get_element_by_id(id) {
+                    Some(node) => RUST_OBJECT_TO_JSVAL(node::create(cx, node,
+                                                                    (*box).payload.scope).ptr),
+                    None => JSVAL_NULL
+                };
+                JS_SET_RVAL(cx, vp, element);
+                return 1;
+            }
+            Err(()) => return 0
+        }
+    }
+}
+
+#[allow(non_implicitly_copyable_typarams)]
+extern fn getElementsByName(cx: *JSContext, argc: c_uint, vp: *JSVal) -> JSBool {
+    unsafe {
+        if !check_argc(cx, argc, 1, "getElementsByName") {
+            return 0;
+        }
+
+        let obj = JS_THIS_OBJECT(cx, vp);
+        if obj.is_null() {
+            return 0;
+        }
+
+        match get_string_arg(cx, argc, JS_ARGV(cx, vp), 0) {
+            Ok(name) => {
+                let box = unwrap(obj);
+                let nodes = (*box).payload.get_elements_by_name(name);
+                let list = nodelist::create(cx, move nodes, (*box).payload.scope);
+                JS_SET_RVAL(cx, vp, RUST_OBJECT_TO_JSVAL(list.ptr));
+                return 1;
+            }
+            Err(()) => return 0
+        }
+    }
+}
+
+#[allow(non_implicitly_copyable_typarams)]
+extern fn getElementsByTagName(cx: *JSContext, argc: c_uint, vp: *JSVal) -> JSBool {
+    unsafe {
+        if !check_argc(cx, argc, 1, "getElementsByTagName") {
+            return 0;
+        }
+
+        let obj = JS_THIS_OBJECT(cx, vp);
+        if obj.is_null() {
+            return 0;
+        }
+
+        match get_string_arg(cx, argc, JS_ARGV(cx, vp), 0) {
+            Ok(name) => {
+                let box = unwrap(obj);
+                let elements = (*box).payload.get_elements_by_tag_name(name);
+                let collection = htmlcollection::create(cx, move elements, (*box).payload.scope);
+                JS_SET_RVAL(cx, vp, RUST_OBJECT_TO_JSVAL(collection.ptr));
+                return 1;
+            }
+            Err(()) => return 0
+        }
+    }
+}
+
+extern fn write(cx: *JSContext, argc: c_uint, vp: *JSVal) -> JSBool {
+    unsafe {
+        let obj = JS_THIS_OBJECT(cx, vp);
+        if obj.is_null() {
+            return 0;
+        }
+
+        let box = unwrap(obj);
+        for uint::range(0, argc as uint) |i| {
+            match jsval_to_str(cx, *ptr::offset(JS_ARGV(cx, vp), i)) {
+                Ok(markup) => (*box).payload.write(markup),
+                Err(()) => return 0
+            }
+        }
+        JS_SET_RVAL(cx, vp, JSVAL_NULL);
+        return 1;
+    }
+}
+
+pub unsafe fn unwrap(obj: *JSObject) -> *rust_box<Document> {
     //TODO: some kind of check if this is a Document object
     let val = JS_GetReservedSlot(obj, 0);
     cast::reinterpret_cast(&RUST_JSVAL_TO_PRIVATE(val))
 }
 
+/// The `document` object of the compartment `cx` runs script in, if one has been bound
+pub unsafe fn global_document(cx: *JSContext) -> Option<*JSObject> {
+    let compartment = utils::get_compartment(cx);
+    let document = JSVAL_NULL;
+    let found = str::as_c_str("document", |name| {
+        JS_GetProperty(cx, compartment.global_obj.ptr, name, ptr::to_unsafe_ptr(&document))
+    });
+    if found == 0 || !RUST_JSVAL_IS_OBJECT(document) ||
+            RUST_JSVAL_TO_OBJECT(document).is_null() {
+        return None;
+    }
+    Some(RUST_JSVAL_TO_OBJECT(document))
+}
+
 extern fn finalize(_fop: *JSFreeOp, obj: *JSObject) {
     debug!("document finalize!");
     unsafe {
@@ -99,12 +276,76 @@ pub fn init(compartment: @mut Compartment, doc: @Document) {
          tinyid: 0,
          flags: (JSPROP_SHARED | JSPROP_ENUMERATE | JSPROP_NATIVE_ACCESSORS) as u8,
          getter: {op: getDocumentElement, info: null()},
-         setter: {op: null(), info: null()}}];
+         setter: {op: null(), info: null()}},
+
+        {name: compartment.add_name(~"nodeType"),
+         tinyid: 0,
+         flags: (JSPROP_SHARED | JSPROP_ENUMERATE | JSPROP_NATIVE_ACCESSORS) as u8,
+         getter: {op: getNodeType, info: null()},
+         setter: {op: null(), info: null()}},
+
+        {name: compartment.add_name(~"nodeName"),
+         tinyid: 0,
+         flags: (JSPROP_SHARED | JSPROP_ENUMERATE | JSPROP_NATIVE_ACCESSORS) as u8,
+         getter: {op: getNodeName, info: null()},
+         setter: {op: null(), info: null()}},
+
+        {name: compartment.add_name(~"title"),
+         tinyid: 0,
+         flags: (JSPROP_SHARED | JSPROP_ENUMERATE | JSPROP_NATIVE_ACCESSORS) as u8,
+         getter: {op: getTitle, info: null()},
+         setter: {op: setTitle, info: null()}}];
     vec::push(&mut compartment.global_props, attrs);
     vec::as_imm_buf(*attrs, |specs, _len| {
         assert JS_DefineProperties(compartment.cx.ptr, obj.ptr, specs) == 1;
     });
 
+    let methods = [
+        JSFunctionSpec {
+            name: compartment.add_name(~"querySelectorAll"),
+            call: JSNativeWrapper { op: querySelectorAll, info: null() },
+            nargs: 1,
+            flags: 0,
+            selfHostedName: null()
+        },
+        JSFunctionSpec {
+            name: compartment.add_name(~"getElementById"),
+            call: JSNativeWrapper { op: getElementById, info: null() },
+            nargs: 1,
+            flags: 0,
+            selfHostedName: null()
+        },
+        JSFunctionSpec {
+            name: compartment.add_name(~"getElementsByName"),
+            call: JSNativeWrapper { op: getElementsByName, info: null() },
+            nargs: 1,
+            flags: 0,
+            selfHostedName: null()
+        },
+        JSFunctionSpec {
+            name: compartment.add_name(~"getElementsByTagName"),
+            call: JSNativeWrapper { op: getElementsByTagName, info: null() },
+            nargs: 1,
+            flags: 0,
+            selfHostedName: null()
+        },
+        JSFunctionSpec {
+            name: compartment.add_name(~"write"),
+            call: JSNativeWrapper { op: write, info: null() },
+            nargs: 1,
+            flags: 0,
+            selfHostedName: null()
+        },
+        JSFunctionSpec {
+            name: null(),
+            call: JSNativeWrapper { op: null(), info: null() },
+            nargs: 0,
+            flags: 0,
+            selfHostedName: null()
+        }
+    ];
+    JS_DefineFunctions(compartment.cx.ptr, obj.ptr, &methods[0]);
+
     compartment.register_class(utils::instance_jsclass(~"DocumentInstance", finalize));
 
     let instance : jsobj = result::unwrap(
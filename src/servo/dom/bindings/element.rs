@@ -2,6 +2,7 @@ use js::rust::{Compartment, jsobj};
 use js::{JS_ARGV, JSCLASS_HAS_RESERVED_SLOTS, JSPROP_ENUMERATE, JSPROP_SHARED, JSVAL_NULL,
             JS_THIS_OBJECT, JS_SET_RVAL, JSPROP_NATIVE_ACCESSORS};
 use js::jsapi::{JSContext, JSVal, JSObject, JSBool, jsid, JSClass, JSFreeOp, JSPropertySpec};
+use js::jsapi::{JSFunctionSpec, JSNativeWrapper};
 use js::jsapi::bindgen::{JS_ValueToString, JS_GetStringCharsZAndLength, JS_ReportError,
                             JS_GetReservedSlot, JS_SetReservedSlot, JS_NewStringCopyN,
                             JS_DefineFunctions, JS_DefineProperty};
@@ -16,6 +17,8 @@ use dom::element::*;
 use dom::bindings::node::NodeBundle;
 use dom::bindings::utils::{rust_box, squirrel_away_unique, get_compartment, domstring_to_jsval};
 use dom::bindings::utils::{str};
+use geom::size::Size2D;
+use gfx::geometry::Au;
 use libc::c_uint;
 use ptr::null;
 use dom::bindings::node::unwrap;
@@ -36,12 +39,50 @@ pub fn init(compartment: @mut Compartment) {
          tinyid: 0,
          flags: (JSPROP_ENUMERATE | JSPROP_SHARED | JSPROP_NATIVE_ACCESSORS) as u8,
          getter: {op: getTagName, info: null()},
+         setter: {op: null(), info: null()}},
+        {name: compartment.add_name(~"offsetWidth"),
+         tinyid: 1,
+         flags: (JSPROP_ENUMERATE | JSPROP_SHARED | JSPROP_NATIVE_ACCESSORS) as u8,
+         getter: {op: getOffsetWidth, info: null()},
+         setter: {op: null(), info: null()}},
+        {name: compartment.add_name(~"offsetHeight"),
+         tinyid: 2,
+         flags: (JSPROP_ENUMERATE | JSPROP_SHARED | JSPROP_NATIVE_ACCESSORS) as u8,
+         getter: {op: getOffsetHeight, info: null()},
+         setter: {op: null(), info: null()}},
+        {name: compartment.add_name(~"scrollWidth"),
+         tinyid: 3,
+         flags: (JSPROP_ENUMERATE | JSPROP_SHARED | JSPROP_NATIVE_ACCESSORS) as u8,
+         getter: {op: getScrollWidth, info: null()},
+         setter: {op: null(), info: null()}},
+        {name: compartment.add_name(~"scrollHeight"),
+         tinyid: 4,
+         flags: (JSPROP_ENUMERATE | JSPROP_SHARED | JSPROP_NATIVE_ACCESSORS) as u8,
+         getter: {op: getScrollHeight, info: null()},
          setter: {op: null(), info: null()}}];
     vec::push(&mut compartment.global_props, attrs);
     vec::as_imm_buf(*attrs, |specs, _len| {
         JS_DefineProperties(compartment.cx.ptr, obj.ptr, specs);
     });
 
+    let methods = [
+        JSFunctionSpec {
+            name: compartment.add_name(~"getBoundingClientRect"),
+            call: JSNativeWrapper { op: getBoundingClientRect, info: null() },
+            nargs: 0,
+            flags: 0,
+            selfHostedName: null()
+        },
+        JSFunctionSpec {
+            name: null(),
+            call: JSNativeWrapper { op: null(), info: null() },
+            nargs: 0,
+            flags: 0,
+            selfHostedName: null()
+        }
+    ];
+    JS_DefineFunctions(compartment.cx.ptr, obj.ptr, &methods[0]);
+
     compartment.register_class(utils::instance_jsclass(~"GenericElementInstance",
                                                        finalize));
 
@@ -82,8 +123,8 @@ extern fn HTMLImageElement_getWidth(cx: *JSContext, _argc: c_uint, vp: *mut JSVa
                         &~HTMLImageElement(*) => {
                             let content = task_from_context(cx);
                             match (*content).query_layout(layout_task::ContentBox(node)) {
-                                Ok(rect) => rect.width,
-                                Err(()) => 0,
+                                Ok(layout_task::ContentSize(size)) => size.width,
+                                Ok(_) | Err(()) => 0,
                             }
                             // TODO: if nothing is being rendered(?), return zero dimensions
                         }
@@ -109,20 +150,114 @@ extern fn HTMLImageElement_setWidth(cx: *JSContext, _argc: c_uint, vp: *mut JSVa
         }
 
         let bundle = unwrap(obj);
-        do (*bundle).payload.scope.write(&(*bundle).payload.node) |nd| {
+        do (*bundle).payload.scope.read(&(*bundle).payload.node) |nd| {
             match nd.kind {
                 ~Element(ref ed) => {
                     match ed.kind {
-                        ~HTMLImageElement(*) => {
-                            let arg = ptr::offset(JS_ARGV(cx, cast::reinterpret_cast(&vp)), 0);
-                            ed.set_attr(~"width", int::str(RUST_JSVAL_TO_INT(*arg) as int))
-                        }
+                        ~HTMLImageElement(*) => (),
                         _ => fail!(~"why is this not an image element?")
                     }
                 }
                 _ => fail!(~"why is this not an element?")
             }
         };
+        let arg = ptr::offset(JS_ARGV(cx, cast::reinterpret_cast(&vp)), 0);
+        (*bundle).payload.scope.set_attr(&(*bundle).payload.node, "width",
+                                         int::str(RUST_JSVAL_TO_INT(*arg) as int));
+        return 1;
+    }
+}
+
+/**
+Answers a layout query about the element and stores one dimension of the
+resulting size in px. Querying layout reflows first if the document has
+changed since it was last laid out. Elements without boxes measure zero.
+*/
+unsafe fn get_layout_size(cx: *JSContext, vp: *mut JSVal,
+                          query: fn(Node) -> layout_task::LayoutQuery,
+                          dimension: fn(&Size2D<int>) -> int) -> JSBool {
+    let obj = JS_THIS_OBJECT(cx, cast::reinterpret_cast(&vp));
+    if obj.is_null() {
+        return 0;
+    }
+
+    let bundle = unwrap(obj);
+    let content = task_from_context(cx);
+    let value = match (*content).query_layout(query((*bundle).payload.node)) {
+        Ok(layout_task::ContentSize(ref size)) => dimension(size),
+        Ok(_) | Err(()) => 0
+    };
+    *vp = RUST_INT_TO_JSVAL((value & (i32::max_value as int)) as libc::c_int);
+    return 1;
+}
+
+extern fn getOffsetWidth(cx: *JSContext, _argc: c_uint, vp: *mut JSVal) -> JSBool {
+    unsafe {
+        get_layout_size(cx, vp, |node| layout_task::OffsetSize(node), |size| size.width)
+    }
+}
+
+extern fn getOffsetHeight(cx: *JSContext, _argc: c_uint, vp: *mut JSVal) -> JSBool {
+    unsafe {
+        get_layout_size(cx, vp, |node| layout_task::OffsetSize(node), |size| size.height)
+    }
+}
+
+extern fn getScrollWidth(cx: *JSContext, _argc: c_uint, vp: *mut JSVal) -> JSBool {
+    unsafe {
+        get_layout_size(cx, vp, |node| layout_task::ScrollSize(node), |size| size.width)
+    }
+}
+
+extern fn getScrollHeight(cx: *JSContext, _argc: c_uint, vp: *mut JSVal) -> JSBool {
+    unsafe {
+        get_layout_size(cx, vp, |node| layout_task::ScrollSize(node), |size| size.height)
+    }
+}
+
+/**
+Returns a plain object with the position and size in px of the union of the
+element's border boxes, relative to the top left of the document. Querying
+layout reflows first if the document has changed. Elements without boxes get
+an empty rect at the origin.
+*/
+extern fn getBoundingClientRect(cx: *JSContext, _argc: c_uint, vp: *JSVal) -> JSBool {
+    unsafe {
+        let obj = JS_THIS_OBJECT(cx, vp);
+        if obj.is_null() {
+            return 0;
+        }
+
+        let bundle = unwrap(obj);
+        let content = task_from_context(cx);
+        let query = layout_task::BoundingClientRect((*bundle).payload.node);
+        let rect = match (*content).query_layout(query) {
+            Ok(layout_task::ContentRect(rect)) => rect,
+            Ok(_) | Err(()) => Au::zero_rect()
+        };
+
+        let rect_obj = JS_NewObject(cx, null(), null(), null());
+        if rect_obj.is_null() {
+            return 0;
+        }
+        let left = rect.origin.x.to_px();
+        let top = rect.origin.y.to_px();
+        let width = rect.size.width.to_px();
+        let height = rect.size.height.to_px();
+        let values = [("x", left), ("y", top), ("width", width), ("height", height),
+                      ("top", top), ("left", left), ("right", left + width),
+                      ("bottom", top + height)];
+        for values.each |&(name, value)| {
+            let defined = str::as_c_str(name, |name| {
+                JS_DefineProperty(cx, rect_obj, name,
+                                  RUST_INT_TO_JSVAL(value as libc::c_int),
+                                  null(), null(), JSPROP_ENUMERATE as c_uint)
+            });
+            if defined == 0 {
+                return 0;
+            }
+        }
+        JS_SET_RVAL(cx, vp, RUST_OBJECT_TO_JSVAL(rect_obj));
         return 1;
     }
 }
@@ -139,8 +274,8 @@ extern fn getTagName(cx: *JSContext, _argc: c_uint, vp: *mut JSVal)
         let bundle = unwrap(obj);
         do (*bundle).payload.scope.write(&(*bundle).payload.node) |nd| {
             match nd.kind {
-              ~Element(ref ed) => {
-                let s = str(copy ed.tag_name);
+              ~Element(*) => {
+                let s = str(nd.kind.node_name());
                 *vp = domstring_to_jsval(cx, &s);
               }
               _ => {
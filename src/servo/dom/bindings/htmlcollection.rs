@@ -0,0 +1,135 @@
+// DOM bindings for HTMLCollection objects, such as those returned by getElementsByTagName.
+
+use dom::bindings::node;
+use dom::bindings::nodelist::NodeList;
+use dom::bindings::nodelist;
+use dom::bindings::utils::{check_argc, get_string_arg};
+use dom::element::ElementData;
+use dom::node::{Element, Node, NodeScope};
+
+use core::libc::c_uint;
+use core::ptr::null;
+use js::glue::bindgen::*;
+use js::jsapi::{JSContext, JSVal, JSBool, JSFunctionSpec, JSNativeWrapper};
+use js::rust::{Compartment, jsobj};
+use js::{JS_ARGV, JSVAL_NULL, JS_THIS_OBJECT, JS_SET_RVAL};
+
+/**
+The first element of `elements` whose `id` is `key`, or failing that the first
+whose `name` is. The empty string names nothing.
+*/
+fn named_item(elements: &NodeList, key: &str) -> Option<Node> {
+    if key.is_empty() {
+        return None;
+    }
+    match find_by_attr(elements, "id", key) {
+        Some(element) => Some(element),
+        None => find_by_attr(elements, "name", key)
+    }
+}
+
+fn find_by_attr(elements: &NodeList, attr: &str, key: &str) -> Option<Node> {
+    for elements.nodes.each |element| {
+        let is_match = do elements.scope.write(element) |nd| {
+            match nd.kind {
+                ~Element(ref data) => has_attr_value(data, attr, key),
+                _ => false
+            }
+        };
+        if is_match {
+            return Some(*element);
+        }
+    }
+    None
+}
+
+fn has_attr_value(element: &ElementData, attr: &str, key: &str) -> bool {
+    do element.with_attr(attr) |value| {
+        match value {
+            Some(value) => str::eq_slice(value, key),
+            None => false
+        }
+    }
+}
+
+// An HTMLCollection is a node list of elements that can also be looked up by id or name
+pub fn init(compartment: @mut Compartment) {
+    let methods = [
+        JSFunctionSpec {
+            name: compartment.add_name(~"namedItem"),
+            call: JSNativeWrapper { op: namedItem, info: null() },
+            nargs: 1,
+            flags: 0,
+            selfHostedName: null()
+        }
+    ];
+    nodelist::define_list(compartment, ~"HTMLCollection", methods);
+}
+
+pub fn create(cx: *JSContext, elements: ~[Node], scope: NodeScope) -> jsobj {
+    nodelist::create_list(cx, ~"HTMLCollection", elements, scope)
+}
+
+#[allow(non_implicitly_copyable_typarams)]
+extern fn namedItem(cx: *JSContext, argc: c_uint, vp: *JSVal) -> JSBool {
+    unsafe {
+        if !check_argc(cx, argc, 1, "namedItem") {
+            return 0;
+        }
+
+        let obj = JS_THIS_OBJECT(cx, vp);
+        if obj.is_null() {
+            return 0;
+        }
+
+        match get_string_arg(cx, argc, JS_ARGV(cx, vp), 0) {
+            Ok(key) => {
+                let collection = nodelist::unwrap(obj);
+                match named_item(&(*collection).payload, key) {
+                    Some(element) => {
+                        let element_obj = node::create(cx, element,
+                                                       (*collection).payload.scope);
+                        JS_SET_RVAL(cx, vp, RUST_OBJECT_TO_JSVAL(element_obj.ptr));
+                    }
+                    None => JS_SET_RVAL(cx, vp, JSVAL_NULL)
+                }
+                return 1;
+            }
+            Err(()) => return 0
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use dom::bindings::nodelist::NodeList;
+    use dom::element::{Attr, ElementData, HTMLDivElement, HTMLInputElement};
+    use dom::node::{Element, Node, NodeScope, NodeScopeExtensions};
+
+    fn new_element(scope: &NodeScope, attrs: &[(&str, &str)]) -> Node {
+        let data = ElementData(~"input", ~HTMLInputElement);
+        for attrs.each |&(name, value)| {
+            data.attrs.push(~Attr(str::from_slice(name), str::from_slice(value)));
+        }
+        scope.new_node(Element(move data))
+    }
+
+    #[test]
+    fn should_find_named_items_by_id_then_name() {
+        let scope = NodeScope();
+        let named = new_element(&scope, [("name", "x")]);
+        let identified = new_element(&scope, [("id", "x")]);
+        let other = new_element(&scope, [("id", "z"), ("name", "z")]);
+        let plain = scope.new_node(Element(ElementData(~"div", ~HTMLDivElement)));
+
+        let collection = NodeList {
+            nodes: ~[named, identified, other, plain],
+            scope: scope
+        };
+        assert named_item(&collection, "x") == Some(identified);
+        assert named_item(&collection, "z") == Some(other);
+        assert named_item(&collection, "y") == None;
+        assert named_item(&collection, "") == None;
+    }
+}
@@ -0,0 +1,89 @@
+// DOM bindings for the MediaQueryList objects returned by window.matchMedia.
+
+use css::media::MediaQueryList;
+use dom::bindings::utils::{rust_box, squirrel_away_unique, domstring_to_jsval, str};
+use super::utils;
+
+use core::libc::c_uint;
+use core::ptr::null;
+use js::glue::bindgen::*;
+use js::jsapi::bindgen::{JS_DefineProperties, JS_GetReservedSlot, JS_SetReservedSlot};
+use js::jsapi::{JSContext, JSVal, JSObject, JSBool, JSFreeOp};
+use js::rust::{Compartment, jsobj};
+use js::{JSPROP_ENUMERATE, JSPROP_SHARED, JSPROP_NATIVE_ACCESSORS, JSVAL_TRUE, JSVAL_FALSE};
+use js::JS_THIS_OBJECT;
+
+pub fn init(compartment: @mut Compartment) {
+    let obj = utils::define_empty_prototype(~"MediaQueryList", None, compartment);
+
+    let attrs = @~[
+        {name: compartment.add_name(~"matches"),
+         tinyid: 0,
+         flags: (JSPROP_SHARED | JSPROP_ENUMERATE | JSPROP_NATIVE_ACCESSORS) as u8,
+         getter: {op: getMatches, info: null()},
+         setter: {op: null(), info: null()}},
+        {name: compartment.add_name(~"media"),
+         tinyid: 0,
+         flags: (JSPROP_SHARED | JSPROP_ENUMERATE | JSPROP_NATIVE_ACCESSORS) as u8,
+         getter: {op: getMedia, info: null()},
+         setter: {op: null(), info: null()}}];
+    vec::push(&mut compartment.global_props, attrs);
+    vec::as_imm_buf(*attrs, |specs, _len| {
+        JS_DefineProperties(compartment.cx.ptr, obj.ptr, specs);
+    });
+
+    compartment.register_class(utils::instance_jsclass(~"MediaQueryListInstance", finalize));
+}
+
+pub fn create(cx: *JSContext, list: MediaQueryList) -> jsobj {
+    let compartment = utils::get_compartment(cx);
+    let obj = result::unwrap(
+        compartment.new_object_with_proto(~"MediaQueryListInstance", ~"MediaQueryList",
+                                          compartment.global_obj.ptr));
+
+    unsafe {
+        let raw_ptr: *libc::c_void =
+            cast::reinterpret_cast(&squirrel_away_unique(~(move list)));
+        JS_SetReservedSlot(obj.ptr, 0, RUST_PRIVATE_TO_JSVAL(raw_ptr));
+    }
+    return obj;
+}
+
+unsafe fn unwrap(obj: *JSObject) -> *rust_box<MediaQueryList> {
+    let val = JS_GetReservedSlot(obj, 0);
+    cast::reinterpret_cast(&RUST_JSVAL_TO_PRIVATE(val))
+}
+
+extern fn finalize(_fop: *JSFreeOp, obj: *JSObject) {
+    debug!("mediaquerylist finalize!");
+    unsafe {
+        let val = JS_GetReservedSlot(obj, 0);
+        let _list: ~MediaQueryList = cast::reinterpret_cast(&RUST_JSVAL_TO_PRIVATE(val));
+    }
+}
+
+extern fn getMatches(cx: *JSContext, _argc: c_uint, vp: *mut JSVal) -> JSBool {
+    unsafe {
+        let obj = JS_THIS_OBJECT(cx, cast::reinterpret_cast(&vp));
+        if obj.is_null() {
+            return 0;
+        }
+
+        let list = unwrap(obj);
+        *vp = if (*list).payload.matches { JSVAL_TRUE } else { JSVAL_FALSE };
+    }
+    return 1;
+}
+
+extern fn getMedia(cx: *JSContext, _argc: c_uint, vp: *mut JSVal) -> JSBool {
+    unsafe {
+        let obj = JS_THIS_OBJECT(cx, cast::reinterpret_cast(&vp));
+        if obj.is_null() {
+            return 0;
+        }
+
+        let list = unwrap(obj);
+        *vp = domstring_to_jsval(cx, &str(copy (*list).payload.media));
+    }
+    return 1;
+}
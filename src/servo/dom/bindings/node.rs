@@ -2,6 +2,7 @@ use js::rust::{Compartment, jsobj};
 use js::{JS_ARGV, JSCLASS_HAS_RESERVED_SLOTS, JSPROP_ENUMERATE, JSPROP_SHARED, JSVAL_NULL,
             JS_THIS_OBJECT, JS_SET_RVAL, JSPROP_NATIVE_ACCESSORS};
 use js::jsapi::{JSContext, JSVal, JSObject, JSBool, jsid, JSClass, JSFreeOp, JSPropertySpec};
+use js::jsapi::{JSFunctionSpec, JSNativeWrapper};
 use js::jsapi::bindgen::{JS_ValueToString, JS_GetStringCharsZAndLength, JS_ReportError,
                             JS_GetReservedSlot, JS_SetReservedSlot, JS_NewStringCopyN,
                             JS_DefineFunctions, JS_DefineProperty, JS_GetContextPrivate};
@@ -9,9 +10,10 @@ use js::jsval::{INT_TO_JSVAL, JSVAL_TO_PRIVATE};
 use js::jsapi::bindgen::*;
 use js::glue::bindgen::*;
 
-use dom::node::{Node, NodeScope, Text, Doctype, Comment, Element};
+use dom::node::{ELEMENT_NODE, Node, NodeScope, Text, Doctype, Comment, Element};
 use dom::bindings::utils::{rust_box, squirrel_away_unique, get_compartment, domstring_to_jsval};
-use dom::bindings::utils::{str};
+use dom::bindings::utils::{DOMString, str};
+use dom::bindings::document;
 use libc::c_uint;
 use ptr::null;
 use super::utils;
@@ -34,15 +36,57 @@ pub fn init(compartment: @mut Compartment) {
          getter: {op: getNextSibling, info: null()},
          setter: {op: null(), info: null()}},
 
+        {name: compartment.add_name(~"parentNode"),
+         tinyid: 0,
+         flags: (JSPROP_SHARED | JSPROP_ENUMERATE | JSPROP_NATIVE_ACCESSORS) as u8,
+         getter: {op: getParentNode, info: null()},
+         setter: {op: null(), info: null()}},
+
+        {name: compartment.add_name(~"parentElement"),
+         tinyid: 0,
+         flags: (JSPROP_SHARED | JSPROP_ENUMERATE | JSPROP_NATIVE_ACCESSORS) as u8,
+         getter: {op: getParentElement, info: null()},
+         setter: {op: null(), info: null()}},
+
+        {name: compartment.add_name(~"ownerDocument"),
+         tinyid: 0,
+         flags: (JSPROP_SHARED | JSPROP_ENUMERATE | JSPROP_NATIVE_ACCESSORS) as u8,
+         getter: {op: getOwnerDocument, info: null()},
+         setter: {op: null(), info: null()}},
+
         {name: compartment.add_name(~"nodeType"),
          tinyid: 0,
          flags: (JSPROP_SHARED | JSPROP_ENUMERATE | JSPROP_NATIVE_ACCESSORS) as u8,
          getter: {op: getNodeType, info: null()},
+         setter: {op: null(), info: null()}},
+
+        {name: compartment.add_name(~"nodeName"),
+         tinyid: 0,
+         flags: (JSPROP_SHARED | JSPROP_ENUMERATE | JSPROP_NATIVE_ACCESSORS) as u8,
+         getter: {op: getNodeName, info: null()},
          setter: {op: null(), info: null()}}];
     vec::push(&mut compartment.global_props, attrs);
     vec::as_imm_buf(*attrs, |specs, _len| {
         JS_DefineProperties(compartment.cx.ptr, obj.ptr, specs);
     });
+
+    let methods = [
+        JSFunctionSpec {
+            name: compartment.add_name(~"cloneNode"),
+            call: JSNativeWrapper { op: cloneNode, info: null() },
+            nargs: 1,
+            flags: 0,
+            selfHostedName: null()
+        },
+        JSFunctionSpec {
+            name: null(),
+            call: JSNativeWrapper { op: null(), info: null() },
+            nargs: 0,
+            flags: 0,
+            selfHostedName: null()
+        }
+    ];
+    JS_DefineFunctions(compartment.cx.ptr, obj.ptr, &methods[0]);
 }
 
 #[allow(non_implicitly_copyable_typarams)]
@@ -122,15 +166,108 @@ extern fn getNextSibling(cx: *JSContext, _argc: c_uint, vp: *mut JSVal) -> JSBoo
     return 1;
 }
 
+/// `parentNode`: the parent element, the document for its root element, or null if detached
+#[allow(non_implicitly_copyable_typarams)]
+extern fn getParentNode(cx: *JSContext, _argc: c_uint, vp: *mut JSVal) -> JSBool {
+    unsafe {
+        let obj = JS_THIS_OBJECT(cx, cast::reinterpret_cast(&vp));
+        if obj.is_null() {
+            return 0;
+        }
+
+        let bundle = unwrap(obj);
+        let node = (*bundle).payload.node;
+        match (*bundle).payload.scope.get_parent(&node) {
+            Some(parent) => {
+                *vp = RUST_OBJECT_TO_JSVAL(create(cx, parent, (*bundle).payload.scope).ptr);
+            }
+            None => {
+                *vp = match document::global_document(cx) {
+                    Some(doc) if (*document::unwrap(doc)).payload.root == node => {
+                        RUST_OBJECT_TO_JSVAL(doc)
+                    }
+                    _ => JSVAL_NULL
+                };
+            }
+        }
+    }
+    return 1;
+}
+
+/// `parentElement`: like `parentNode`, but null rather than the document
+#[allow(non_implicitly_copyable_typarams)]
+extern fn getParentElement(cx: *JSContext, _argc: c_uint, vp: *mut JSVal) -> JSBool {
+    unsafe {
+        let obj = JS_THIS_OBJECT(cx, cast::reinterpret_cast(&vp));
+        if obj.is_null() {
+            return 0;
+        }
+
+        let bundle = unwrap(obj);
+        let scope = (*bundle).payload.scope;
+        let parent = scope.get_parent(&(*bundle).payload.node);
+        *vp = match parent {
+            Some(parent) if scope.read(&parent, |nd| nd.kind.node_type()) == ELEMENT_NODE => {
+                RUST_OBJECT_TO_JSVAL(create(cx, parent, scope).ptr)
+            }
+            _ => JSVAL_NULL
+        };
+    }
+    return 1;
+}
+
+/// `ownerDocument`: the document every node of the compartment was made by, detached or not
+extern fn getOwnerDocument(cx: *JSContext, _argc: c_uint, vp: *mut JSVal) -> JSBool {
+    unsafe {
+        let obj = JS_THIS_OBJECT(cx, cast::reinterpret_cast(&vp));
+        if obj.is_null() {
+            return 0;
+        }
+
+        *vp = match document::global_document(cx) {
+            Some(doc) => RUST_OBJECT_TO_JSVAL(doc),
+            None => JSVAL_NULL
+        };
+    }
+    return 1;
+}
+
+/// `cloneNode(deep)`. The clone is detached and gets a fresh wrapper; event
+/// listeners are not copied.
+#[allow(non_implicitly_copyable_typarams)]
+extern fn cloneNode(cx: *JSContext, argc: c_uint, vp: *JSVal) -> JSBool {
+    unsafe {
+        let obj = JS_THIS_OBJECT(cx, vp);
+        if obj.is_null() {
+            return 0;
+        }
+
+        let deep: JSBool = 0;
+        if argc > 0 {
+            let arg = *ptr::offset(JS_ARGV(cx, vp), 0);
+            if JS_ValueToBoolean(cx, arg, ptr::to_unsafe_ptr(&deep)) == 0 {
+                return 0;
+            }
+        }
+
+        let bundle = unwrap(obj);
+        let scope = (*bundle).payload.scope;
+        let clone = scope.clone_node(&(*bundle).payload.node, deep != 0);
+        JS_SET_RVAL(cx, vp, RUST_OBJECT_TO_JSVAL(create(cx, clone, scope).ptr));
+    }
+    return 1;
+}
+
 impl NodeBundle {
     fn getNodeType() -> i32 {
         do self.node.read |nd| {
-            match nd.kind {
-              ~Element(*) => 1,
-              ~Text(*)    => 3,
-              ~Comment(*) => 8,
-              ~Doctype(*) => 10
-            }
+            nd.kind.node_type()
+        }
+    }
+
+    fn getNodeName() -> DOMString {
+        do self.node.read |nd| {
+            str(nd.kind.node_name())
         }
     }
 }
@@ -148,3 +285,17 @@ extern fn getNodeType(cx: *JSContext, _argc: c_uint, vp: *mut JSVal) -> JSBool {
     }
     return 1;
 }
+
+extern fn getNodeName(cx: *JSContext, _argc: c_uint, vp: *mut JSVal) -> JSBool {
+    unsafe {
+        let obj = JS_THIS_OBJECT(cx, cast::reinterpret_cast(&vp));
+        if obj.is_null() {
+            return 0;
+        }
+
+        let bundle = unwrap(obj);
+        let nodeName = (*bundle).payload.getNodeName();
+        *vp = domstring_to_jsval(cx, &nodeName);
+    }
+    return 1;
+}
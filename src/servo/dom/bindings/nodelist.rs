@@ -0,0 +1,141 @@
+// DOM bindings for static NodeList objects, such as those returned by querySelectorAll, and
+// for the other kinds of node list built on them.
+
+use dom::bindings::node;
+use dom::bindings::utils::{check_argc, rust_box, squirrel_away_unique};
+use dom::node::{Node, NodeScope};
+use super::utils;
+
+use core::libc::c_uint;
+use core::ptr::null;
+use js::glue::bindgen::*;
+use js::jsapi::bindgen::{JS_DefineFunctions, JS_DefineProperties, JS_GetReservedSlot};
+use js::jsapi::bindgen::{JS_SetReservedSlot};
+use js::jsapi::{JSContext, JSVal, JSObject, JSBool, JSFreeOp, JSFunctionSpec};
+use js::jsapi::{JSNativeWrapper};
+use js::jsval::INT_TO_JSVAL;
+use js::rust::{Compartment, jsobj};
+use js::{JS_ARGV, JSPROP_ENUMERATE, JSPROP_SHARED, JSPROP_NATIVE_ACCESSORS, JSVAL_NULL};
+use js::{JS_THIS_OBJECT, JS_SET_RVAL};
+
+/// A snapshot of a list of nodes. Later changes to the document are not reflected.
+pub struct NodeList {
+    nodes: ~[Node],
+    scope: NodeScope,
+}
+
+pub fn init(compartment: @mut Compartment) {
+    define_list(compartment, ~"NodeList", []);
+}
+
+/**
+Defines the prototype `name` for a kind of node list, with a `length` and an
+`item` that index into the list and any `extra_methods` as well. Its instances
+are created with `create_list`.
+*/
+pub fn define_list(compartment: @mut Compartment, name: ~str, extra_methods: &[JSFunctionSpec]) {
+    let obj = utils::define_empty_prototype(copy name, None, compartment);
+
+    let attrs = @~[
+        {name: compartment.add_name(~"length"),
+         tinyid: 0,
+         flags: (JSPROP_SHARED | JSPROP_ENUMERATE | JSPROP_NATIVE_ACCESSORS) as u8,
+         getter: {op: getLength, info: null()},
+         setter: {op: null(), info: null()}}];
+    vec::push(&mut compartment.global_props, attrs);
+    vec::as_imm_buf(*attrs, |specs, _len| {
+        JS_DefineProperties(compartment.cx.ptr, obj.ptr, specs);
+    });
+
+    let mut methods = ~[
+        JSFunctionSpec {
+            name: compartment.add_name(~"item"),
+            call: JSNativeWrapper { op: item, info: null() },
+            nargs: 1,
+            flags: 0,
+            selfHostedName: null()
+        }
+    ];
+    methods.push_all(extra_methods);
+    methods.push(JSFunctionSpec {
+        name: null(),
+        call: JSNativeWrapper { op: null(), info: null() },
+        nargs: 0,
+        flags: 0,
+        selfHostedName: null()
+    });
+    JS_DefineFunctions(compartment.cx.ptr, obj.ptr, &methods[0]);
+
+    compartment.register_class(utils::instance_jsclass(name + ~"Instance", finalize));
+}
+
+pub fn create(cx: *JSContext, nodes: ~[Node], scope: NodeScope) -> jsobj {
+    create_list(cx, ~"NodeList", nodes, scope)
+}
+
+/// Wraps `nodes` in an instance of the list prototype `name`, as defined by `define_list`
+pub fn create_list(cx: *JSContext, name: ~str, nodes: ~[Node], scope: NodeScope) -> jsobj {
+    let compartment = utils::get_compartment(cx);
+    let obj = result::unwrap(
+        compartment.new_object_with_proto(name + ~"Instance", copy name,
+                                          compartment.global_obj.ptr));
+
+    unsafe {
+        let list = ~NodeList { nodes: move nodes, scope: scope };
+        let raw_ptr: *libc::c_void = cast::reinterpret_cast(&squirrel_away_unique(move list));
+        JS_SetReservedSlot(obj.ptr, 0, RUST_PRIVATE_TO_JSVAL(raw_ptr));
+    }
+    return obj;
+}
+
+pub unsafe fn unwrap(obj: *JSObject) -> *rust_box<NodeList> {
+    let val = JS_GetReservedSlot(obj, 0);
+    cast::reinterpret_cast(&RUST_JSVAL_TO_PRIVATE(val))
+}
+
+extern fn finalize(_fop: *JSFreeOp, obj: *JSObject) {
+    debug!("nodelist finalize!");
+    unsafe {
+        let val = JS_GetReservedSlot(obj, 0);
+        let _list: ~NodeList = cast::reinterpret_cast(&RUST_JSVAL_TO_PRIVATE(val));
+    }
+}
+
+extern fn getLength(cx: *JSContext, _argc: c_uint, vp: *mut JSVal) -> JSBool {
+    unsafe {
+        let obj = JS_THIS_OBJECT(cx, cast::reinterpret_cast(&vp));
+        if obj.is_null() {
+            return 0;
+        }
+
+        let list = unwrap(obj);
+        *vp = INT_TO_JSVAL((*list).payload.nodes.len() as i32);
+    }
+    return 1;
+}
+
+#[allow(non_implicitly_copyable_typarams)]
+extern fn item(cx: *JSContext, argc: c_uint, vp: *JSVal) -> JSBool {
+    unsafe {
+        if !check_argc(cx, argc, 1, "item") {
+            return 0;
+        }
+
+        let obj = JS_THIS_OBJECT(cx, vp);
+        if obj.is_null() {
+            return 0;
+        }
+
+        let list = unwrap(obj);
+        let index = RUST_JSVAL_TO_INT(*ptr::offset(JS_ARGV(cx, vp), 0)) as int;
+
+        if index >= 0 && (index as uint) < (*list).payload.nodes.len() {
+            let node = (*list).payload.nodes[index as uint];
+            let node_obj = node::create(cx, node, (*list).payload.scope);
+            JS_SET_RVAL(cx, vp, RUST_OBJECT_TO_JSVAL(node_obj.ptr));
+        } else {
+            JS_SET_RVAL(cx, vp, JSVAL_NULL);
+        }
+    }
+    return 1;
+}
@@ -6,10 +6,12 @@ use js::jsapi::{JSContext, JSVal, JSObject, JSBool, jsid, JSClass, JSFreeOp};
 use js::jsapi::bindgen::{JS_ValueToString, JS_GetStringCharsZAndLength, JS_ReportError,
                             JS_GetReservedSlot, JS_SetReservedSlot, JS_NewStringCopyN,
                             JS_DefineFunctions, JS_DefineProperty, JS_GetContextPrivate,
-                            JS_GetClass, JS_GetPrototype};
+                            JS_GetClass, JS_GetPrototype, JS_GetProperty, JS_New,
+                            JS_SetPendingException};
 use js::glue::{PROPERTY_STUB, STRICT_PROPERTY_STUB, ENUMERATE_STUB, CONVERT_STUB,
                   RESOLVE_STUB};
 use js::glue::bindgen::*;
+use core::libc::c_uint;
 use ptr::null;
 use content::content_task::{Content, task_from_context};
 
@@ -56,6 +58,53 @@ pub fn jsval_to_str(cx: *JSContext, v: JSVal) -> Result<~str, ()> {
     }
 }
 
+/**
+Converts argument `i` of a native to a string the way JS would. An argument
+that wasn't passed is `undefined`, which becomes "undefined". Err if the
+conversion threw.
+*/
+pub unsafe fn get_string_arg(cx: *JSContext, argc: c_uint, argv: *JSVal, i: uint)
+                          -> Result<~str, ()> {
+    if i >= argc as uint {
+        return Ok(~"undefined");
+    }
+    jsval_to_str(cx, *ptr::offset(argv, i))
+}
+
+/**
+Whether a native was passed at least the `required` arguments it needs. If
+not, a TypeError naming `name` is thrown and the native should return 0.
+*/
+pub unsafe fn check_argc(cx: *JSContext, argc: c_uint, required: uint, name: &str) -> bool {
+    if argc as uint >= required {
+        return true;
+    }
+    throw_type_error(cx, fmt!("%s requires %u argument%s, but only %u %s passed", name,
+                              required, if required == 1 { "" } else { "s" },
+                              argc as uint, if argc == 1 { "was" } else { "were" }));
+    false
+}
+
+/// Makes a TypeError with `message` the pending exception
+pub unsafe fn throw_type_error(cx: *JSContext, message: &str) {
+    let compartment = get_compartment(cx);
+    let constructor = JSVAL_NULL;
+    let found = str::as_c_str("TypeError", |name| {
+        JS_GetProperty(cx, compartment.global_obj.ptr, name, ptr::to_unsafe_ptr(&constructor))
+    });
+    if found == 0 || !RUST_JSVAL_IS_OBJECT(constructor) {
+        // Without the constructor, throw a plain error
+        str::as_c_str(message, |s| JS_ReportError(cx, s));
+        return;
+    }
+
+    let arg = domstring_to_jsval(cx, &str(str::from_slice(message)));
+    let error = JS_New(cx, RUST_JSVAL_TO_OBJECT(constructor), 1, ptr::to_unsafe_ptr(&arg));
+    if error.is_not_null() {
+        JS_SetPendingException(cx, RUST_OBJECT_TO_JSVAL(error));
+    }
+}
+
 pub unsafe fn domstring_to_jsval(cx: *JSContext, string: &DOMString) -> JSVal {
     match string {
       &null_string => {
@@ -179,3 +228,25 @@ pub fn define_empty_prototype(name: ~str, proto: Option<~str>, compartment: @mut
     compartment.stash_global_proto(move name, obj);
     return obj;
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use js::global::global_class;
+    use js::jsval::INT_TO_JSVAL;
+    use js::rust::rt;
+
+    #[test]
+    fn should_coerce_arguments_to_strings() {
+        let rt = rt();
+        let cx = rt.cx();
+        let _compartment = result::unwrap(cx.new_compartment(global_class));
+
+        let args = [INT_TO_JSVAL(42)];
+        unsafe {
+            assert get_string_arg(cx.ptr, 1, &args[0], 0) == Ok(~"42");
+            assert get_string_arg(cx.ptr, 1, &args[0], 1) == Ok(~"undefined");
+        }
+    }
+}
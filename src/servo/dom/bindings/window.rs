@@ -1,7 +1,10 @@
 // DOM bindings for the Window object.
 
+use content::content_task::task_from_context;
+use css::media::MediaQueryList;
+use dom::bindings::mediaquerylist;
 use dom::bindings::node::create;
-use dom::bindings::utils::{rust_box, squirrel_away, jsval_to_str};
+use dom::bindings::utils::{check_argc, rust_box, squirrel_away, jsval_to_str};
 use dom::node::Node;
 use dom::window::{Window, TimerMessage_Fire};
 use super::utils;
@@ -22,16 +25,21 @@ use js::jsapi::{JSContext, JSVal, JSObject, JSBool, jsid, JSClass, JSFreeOp, JSF
 use js::jsapi::{JSNativeWrapper};
 use js::rust::Compartment;
 use js::{JS_ARGV, JSCLASS_HAS_RESERVED_SLOTS, JSPROP_ENUMERATE, JSPROP_SHARED, JSVAL_NULL};
-use js::{JS_THIS_OBJECT, JS_SET_RVAL};
+use js::{JSPROP_NATIVE_ACCESSORS, JS_THIS_OBJECT, JS_SET_RVAL};
 
 extern fn alert(cx: *JSContext, argc: c_uint, vp: *JSVal) -> JSBool {
   unsafe {
     let argv = JS_ARGV(cx, vp);
-    assert (argc == 1);
-    // Abstract this pattern and use it in debug, too?
-    let jsstr = JS_ValueToString(cx, *ptr::offset(argv, 0));
-    
-    (*unwrap(JS_THIS_OBJECT(cx, vp))).payload.alert(jsval_to_rust_str(cx, jsstr));
+    // The message is optional
+    let message = if argc > 0 {
+        // Abstract this pattern and use it in debug, too?
+        let jsstr = JS_ValueToString(cx, *ptr::offset(argv, 0));
+        jsval_to_rust_str(cx, jsstr)
+    } else {
+        ~""
+    };
+
+    (*unwrap(JS_THIS_OBJECT(cx, vp))).payload.alert(message);
 
     JS_SET_RVAL(cx, vp, JSVAL_NULL);
   }
@@ -40,8 +48,10 @@ extern fn alert(cx: *JSContext, argc: c_uint, vp: *JSVal) -> JSBool {
 
 extern fn setTimeout(cx: *JSContext, argc: c_uint, vp: *JSVal) -> JSBool {
     unsafe {
+        if !check_argc(cx, argc, 2, "setTimeout") {
+            return 0;
+        }
         let argv = JS_ARGV(cx, vp);
-        assert (argc >= 2);
 
         //TODO: don't crash when passed a non-integer value for the timeout
 
@@ -62,6 +72,85 @@ extern fn close(cx: *JSContext, _argc: c_uint, vp: *JSVal) -> JSBool {
     }
 }
 
+extern fn matchMedia(cx: *JSContext, argc: c_uint, vp: *JSVal) -> JSBool {
+    unsafe {
+        if !check_argc(cx, argc, 1, "matchMedia") {
+            return 0;
+        }
+        let argv = JS_ARGV(cx, vp);
+
+        let query = match jsval_to_str(cx, *ptr::offset(argv, 0)) {
+            Ok(move s) => move s,
+            Err(()) => return 0
+        };
+
+        // Evaluated once, against the viewport as it is now
+        let content = task_from_context(cx);
+        let list = MediaQueryList(move query, (*content).window_size);
+        let obj = mediaquerylist::create(cx, move list);
+        JS_SET_RVAL(cx, vp, RUST_OBJECT_TO_JSVAL(obj.ptr));
+        return 1;
+    }
+}
+
+extern fn addEventListener(cx: *JSContext, argc: c_uint, vp: *JSVal) -> JSBool {
+    unsafe {
+        if !check_argc(cx, argc, 2, "addEventListener") {
+            return 0;
+        }
+        let argv = JS_ARGV(cx, vp);
+
+        let event_type = match jsval_to_str(cx, *ptr::offset(argv, 0)) {
+            Ok(move s) => move s,
+            Err(()) => return 0
+        };
+
+        //TODO: other event types
+        if event_type == ~"unload" {
+            let obj = JS_THIS_OBJECT(cx, vp);
+            if obj.is_null() {
+                return 0;
+            }
+            (*unwrap(obj)).payload.add_unload_listener(*ptr::offset(argv, 1));
+        }
+
+        JS_SET_RVAL(cx, vp, JSVAL_NULL);
+        return 1;
+    }
+}
+
+extern fn getOnunload(cx: *JSContext, _argc: c_uint, vp: *mut JSVal) -> JSBool {
+    unsafe {
+        let obj = JS_THIS_OBJECT(cx, cast::reinterpret_cast(&vp));
+        if obj.is_null() {
+            return 0;
+        }
+
+        *vp = match (*unwrap(obj)).payload.onunload {
+            Some(handler) => handler,
+            None => JSVAL_NULL
+        };
+        return 1;
+    }
+}
+
+extern fn setOnunload(cx: *JSContext, _argc: c_uint, vp: *mut JSVal) -> JSBool {
+    unsafe {
+        let obj = JS_THIS_OBJECT(cx, cast::reinterpret_cast(&vp));
+        if obj.is_null() {
+            return 0;
+        }
+
+        let handler = *ptr::offset(JS_ARGV(cx, cast::reinterpret_cast(&vp)), 0);
+        (*unwrap(obj)).payload.onunload = if handler == JSVAL_NULL {
+            None
+        } else {
+            Some(handler)
+        };
+        return 1;
+    }
+}
+
 unsafe fn unwrap(obj: *JSObject) -> *rust_box<Window> {
     let val = JS_GetReservedSlot(obj, 0);
     cast::reinterpret_cast(&RUST_JSVAL_TO_PRIVATE(val))
@@ -83,6 +172,17 @@ pub fn init(compartment: @mut Compartment, win: @Window) {
                  compartment.new_object_with_proto(~"WindowInstance",
                                                    ~"Window", null()));
 
+    let attrs = @~[
+        {name: compartment.add_name(~"onunload"),
+         tinyid: 0,
+         flags: (JSPROP_SHARED | JSPROP_ENUMERATE | JSPROP_NATIVE_ACCESSORS) as u8,
+         getter: {op: getOnunload, info: null()},
+         setter: {op: setOnunload, info: null()}}];
+    vec::push(&mut compartment.global_props, attrs);
+    vec::as_imm_buf(*attrs, |specs, _len| {
+        JS_DefineProperties(compartment.cx.ptr, proto.ptr, specs);
+    });
+
     /* Define methods on a window */
     let methods = [
         JSFunctionSpec {
@@ -106,6 +206,20 @@ pub fn init(compartment: @mut Compartment, win: @Window) {
             flags: 0,
             selfHostedName: null()
         },
+        JSFunctionSpec {
+            name: compartment.add_name(~"addEventListener"),
+            call: JSNativeWrapper { op: addEventListener, info: null() },
+            nargs: 2,
+            flags: 0,
+            selfHostedName: null()
+        },
+        JSFunctionSpec {
+            name: compartment.add_name(~"matchMedia"),
+            call: JSNativeWrapper { op: matchMedia, info: null() },
+            nargs: 1,
+            flags: 0,
+            selfHostedName: null()
+        },
         JSFunctionSpec {
             name: null(),
             call: JSNativeWrapper { op: null(), info: null() },
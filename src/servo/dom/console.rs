@@ -0,0 +1,91 @@
+/*!
+The `console` object. Messages are passed to the embedder and also retained
+in a ring buffer of the most recent entries, so tests and tools can inspect
+them.
+*/
+
+use content::embedder::EmbedderCallbacks;
+
+use core::dvec::DVec;
+
+pub const DEFAULT_CAPACITY: uint = 100;
+
+#[deriving_eq]
+pub enum ConsoleLevel {
+    LogLevel,
+    WarnLevel,
+    ErrorLevel
+}
+
+pub struct ConsoleEntry {
+    level: ConsoleLevel,
+    text: ~str
+}
+
+pub struct Console {
+    capacity: uint,
+    priv embedder: @EmbedderCallbacks,
+    priv entries: DVec<ConsoleEntry>,
+    // Index of the oldest entry once the buffer has wrapped around
+    priv mut head: uint
+}
+
+pub fn Console(capacity: uint, embedder: @EmbedderCallbacks) -> Console {
+    assert capacity > 0;
+    Console {
+        capacity: capacity,
+        embedder: embedder,
+        entries: DVec(),
+        head: 0
+    }
+}
+
+#[allow(non_implicitly_copyable_typarams)]
+impl Console {
+    fn log(s: &str)   { self.add_entry(LogLevel, s) }
+    fn warn(s: &str)  { self.add_entry(WarnLevel, s) }
+    fn error(s: &str) { self.add_entry(ErrorLevel, s) }
+
+    fn add_entry(level: ConsoleLevel, s: &str) {
+        self.embedder.on_console(level, s);
+
+        let entry = ConsoleEntry { level: level, text: s.to_str() };
+        if self.entries.len() < self.capacity {
+            self.entries.push(move entry);
+        } else {
+            self.entries.set_elt(self.head, move entry);
+            self.head = (self.head + 1) % self.capacity;
+        }
+    }
+
+    /// Returns the retained messages, oldest first
+    fn recent_messages() -> ~[ConsoleEntry] {
+        let len = self.entries.len();
+        do vec::from_fn(len) |i| {
+            let entry = self.entries.get_elt((self.head + i) % len);
+            ConsoleEntry { level: entry.level, text: copy entry.text }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use content::embedder::{EmbedderCallbacks, PrintingEmbedder};
+
+    #[test]
+    fn should_retain_only_most_recent_messages() {
+        let console = Console(3, @PrintingEmbedder as @EmbedderCallbacks);
+        console.log(~"one");
+        console.warn(~"two");
+        console.log(~"three");
+        console.error(~"four");
+        console.log(~"five");
+
+        let messages = console.recent_messages();
+        assert messages.len() == 3;
+        assert messages[0].level == LogLevel && messages[0].text == ~"three";
+        assert messages[1].level == ErrorLevel && messages[1].text == ~"four";
+        assert messages[2].level == LogLevel && messages[2].text == ~"five";
+    }
+}
@@ -1,15 +1,294 @@
 use newcss::stylesheet::Stylesheet;
-use dom::node::{NodeScope, Node};
+use dom::element::{ElementData, HTMLTitleElement};
+use dom::node::{DOCUMENT_NODE, Element, NodeScope, NodeScopeExtensions, Node, Text};
+use dom::selector::{Selector, parse_selector};
 use std::arc::ARC;
 
+use core::util::replace;
+
 pub struct Document {
     root: Node,
     scope: NodeScope,
+    // Markup passed to `write` by the parser-blocking script being run, if any
+    priv mut write_buffer: Option<~str>,
 }
 
 pub fn Document(root: Node, scope: NodeScope) -> Document {
     Document {
         root : root,
         scope : scope,
+        write_buffer : None,
+    }
+}
+
+impl Document {
+    pure fn node_type(&self) -> i32 { DOCUMENT_NODE }
+    pure fn node_name(&self) -> ~str { ~"#document" }
+
+    /// Returns every element below the root that matches `selector`, in document order.
+    fn query_selector_all(&self, selector: &Selector) -> ~[Node] {
+        let mut matches = ~[];
+        self.collect_matches(self.root, |element| selector.matches(element), &mut matches);
+        move matches
+    }
+
+    /// Returns every element below the root whose `name` attribute is `name`, in document order.
+    fn get_elements_by_name(&self, name: &str) -> ~[Node] {
+        let has_name: fn(&ElementData) -> bool = |element| {
+            do element.with_attr("name") |value| {
+                match value {
+                    Some(value) => str::eq_slice(value, name),
+                    None => false
+                }
+            }
+        };
+        let mut matches = ~[];
+        self.collect_matches(self.root, has_name, &mut matches);
+        move matches
+    }
+
+    /// Returns the first element in document order whose `id` attribute is `id`, if any
+    fn get_element_by_id(&self, id: &str) -> Option<Node> {
+        let has_id: fn(&ElementData) -> bool = |element| {
+            do element.with_attr("id") |value| {
+                match value {
+                    Some(value) => str::eq_slice(value, id),
+                    None => false
+                }
+            }
+        };
+        self.find_match(self.root, has_id)
+    }
+
+    /**
+    Returns every element below the root with the tag name `name`, ignoring
+    case, in document order. `*` matches every element.
+    */
+    fn get_elements_by_tag_name(&self, name: &str) -> ~[Node] {
+        let name = str::to_lower(name);
+        let has_tag_name: fn(&ElementData) -> bool = |element| {
+            name == ~"*" || str::to_lower(element.tag_name) == name
+        };
+        let mut matches = ~[];
+        self.collect_matches(self.root, has_tag_name, &mut matches);
+        move matches
+    }
+
+    /// Starts collecting the markup written by a script the parser is running
+    fn begin_script_writes(&self) {
+        self.write_buffer = Some(~"");
+    }
+
+    /// Returns the markup written since `begin_script_writes`, for the parser to parse next
+    fn end_script_writes(&self) -> ~str {
+        replace(&mut self.write_buffer, None).get_or_default(~"")
+    }
+
+    /**
+    Writes markup into the document at the point the parser has reached.
+    Only scripts run by the parser can write: once the document has been
+    parsed, writes are ignored rather than replacing the document.
+    */
+    fn write(&self, markup: &str) {
+        match self.write_buffer {
+            Some(ref mut buffer) => *buffer += markup,
+            None => debug!("document: ignoring write outside of a parser-blocking script")
+        }
+    }
+
+    /// The text of the first `<title>` element, if there is one
+    fn title(&self) -> Option<~str> {
+        let selector = parse_selector("title").get();
+        let titles = self.query_selector_all(&selector);
+        if titles.is_empty() {
+            return None;
+        }
+
+        let mut title = ~"";
+        let mut child = self.scope.write(&titles[0], |nd| nd.tree.first_child);
+        loop {
+            match child {
+                None => break,
+                Some(c) => {
+                    do self.scope.write(&c) |nd| {
+                        match nd.kind {
+                            ~Text(ref text) => title += *text,
+                            _ => ()
+                        }
+                    }
+                    child = self.scope.write(&c, |nd| nd.tree.next_sibling);
+                }
+            }
+        }
+        Some(move title)
+    }
+
+    /**
+    Replaces the text of the first `<title>` element with `title`. Without a
+    `<title>`, one is added to the first `<head>`; without a `<head>` either,
+    the document is left as it is.
+    */
+    fn set_title(&self, title: &str) {
+        let titles = self.get_elements_by_tag_name("title");
+        let element = if !titles.is_empty() {
+            titles[0]
+        } else {
+            let heads = self.get_elements_by_tag_name("head");
+            if heads.is_empty() {
+                debug!("document: not setting the title of a document without a head");
+                return;
+            }
+            let element = self.scope.new_node(Element(ElementData(~"title",
+                                                                  ~HTMLTitleElement)));
+            self.scope.add_child(heads[0], element);
+            element
+        };
+
+        let mut children = ~[];
+        for self.scope.each_child(&element) |child| {
+            children.push(*child);
+        }
+        for children.each |child| {
+            self.scope.remove_child(element, *child);
+        }
+        if !title.is_empty() {
+            self.scope.add_child(element, self.scope.new_node(Text(title.to_str())));
+        }
+    }
+
+    // The first element at or below `node` that matches, in document order
+    priv fn find_match(&self, node: Node, predicate: fn(&ElementData) -> bool) -> Option<Node> {
+        let is_match = do self.scope.write(&node) |nd| {
+            match nd.kind {
+                ~Element(ref element) => predicate(element),
+                _ => false
+            }
+        };
+        if is_match {
+            return Some(node);
+        }
+
+        let mut child = self.scope.write(&node, |nd| nd.tree.first_child);
+        loop {
+            match child {
+                None => return None,
+                Some(c) => {
+                    match self.find_match(c, predicate) {
+                        Some(found) => return Some(found),
+                        None => ()
+                    }
+                    child = self.scope.write(&c, |nd| nd.tree.next_sibling);
+                }
+            }
+        }
+    }
+
+    priv fn collect_matches(&self, node: Node, predicate: fn(&ElementData) -> bool,
+                            matches: &mut ~[Node]) {
+        let is_match = do self.scope.write(&node) |nd| {
+            match nd.kind {
+                ~Element(ref element) => predicate(element),
+                _ => false
+            }
+        };
+        if is_match {
+            matches.push(node);
+        }
+
+        let mut child = self.scope.write(&node, |nd| nd.tree.first_child);
+        loop {
+            match child {
+                None => break,
+                Some(c) => {
+                    self.collect_matches(c, predicate, matches);
+                    child = self.scope.write(&c, |nd| nd.tree.next_sibling);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use dom::element::{Attr, ElementData, HTMLDivElement, HTMLHeadElement, HTMLInputElement};
+    use dom::node::{Element, Node, NodeScope, NodeScopeExtensions};
+
+    fn new_input(scope: &NodeScope, name: ~str) -> Node {
+        let data = ElementData(~"input", ~HTMLInputElement);
+        data.attrs.push(~Attr(~"name", move name));
+        scope.new_node(Element(move data))
+    }
+
+    #[test]
+    fn should_get_elements_by_name_in_document_order() {
+        let scope = NodeScope();
+        let root = scope.new_node(Element(ElementData(~"html", ~HTMLDivElement)));
+        let form = scope.new_node(Element(ElementData(~"div", ~HTMLDivElement)));
+        let first = new_input(&scope, ~"email");
+        let other = new_input(&scope, ~"password");
+        let second = new_input(&scope, ~"email");
+        scope.add_child(root, form);
+        scope.add_child(form, first);
+        scope.add_child(form, other);
+        scope.add_child(root, second);
+
+        let document = Document(root, scope);
+        assert document.get_elements_by_name("email") == ~[first, second];
+        assert document.get_elements_by_name("password") == ~[other];
+        assert document.get_elements_by_name("missing").is_empty();
+    }
+
+    #[test]
+    fn should_get_elements_by_tag_name_ignoring_case() {
+        let scope = NodeScope();
+        let root = scope.new_node(Element(ElementData(~"html", ~HTMLDivElement)));
+        let div = scope.new_node(Element(ElementData(~"div", ~HTMLDivElement)));
+        let input = new_input(&scope, ~"email");
+        scope.add_child(root, div);
+        scope.add_child(div, input);
+
+        let document = Document(root, scope);
+        assert document.get_elements_by_tag_name("DIV") == ~[div];
+        assert document.get_elements_by_tag_name("*") == ~[root, div, input];
+        assert document.get_elements_by_tag_name("42").is_empty();
+    }
+
+    #[test]
+    fn should_get_the_first_element_with_an_id() {
+        let with_id = |tag_name: ~str, id: ~str| {
+            let data = ElementData(move tag_name, ~HTMLDivElement);
+            data.attrs.push(~Attr(~"id", move id));
+            Element(move data)
+        };
+        let scope = NodeScope();
+        let root = scope.new_node(Element(ElementData(~"html", ~HTMLDivElement)));
+        let div = scope.new_node(with_id(~"div", ~"main"));
+        let inner = scope.new_node(with_id(~"div", ~"inner"));
+        let duplicate = scope.new_node(with_id(~"div", ~"main"));
+        scope.add_child(root, div);
+        scope.add_child(div, inner);
+        scope.add_child(root, duplicate);
+
+        let document = Document(root, scope);
+        assert document.get_element_by_id("main") == Some(div);
+        assert document.get_element_by_id("inner") == Some(inner);
+        assert document.get_element_by_id("MAIN") == None;
+    }
+
+    #[test]
+    fn should_set_the_title_adding_one_to_the_head() {
+        let scope = NodeScope();
+        let root = scope.new_node(Element(ElementData(~"html", ~HTMLDivElement)));
+        let head = scope.new_node(Element(ElementData(~"head", ~HTMLHeadElement)));
+        scope.add_child(root, head);
+
+        let document = Document(root, scope);
+        assert document.title() == None;
+        document.set_title("First");
+        assert document.title() == Some(~"First");
+        document.set_title("Second");
+        assert document.title() == Some(~"Second");
+        assert document.get_elements_by_tag_name("title").len() == 1;
     }
 }
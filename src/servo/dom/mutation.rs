@@ -0,0 +1,71 @@
+/*!
+Records of changes to the DOM tree, sent to the observers of a `NodeScope`
+as each change happens. Layout and tests use them to follow mutations.
+*/
+
+use dom::node::Node;
+
+#[deriving_eq]
+pub enum MutationDetails {
+    ChildAdded(Node),
+    ChildRemoved(Node),
+    /// The name of the attribute that was set
+    AttributeChanged(~str)
+}
+
+#[deriving_eq]
+pub struct MutationRecord {
+    /// The node whose children or attributes changed
+    target: Node,
+    details: MutationDetails
+}
+
+impl MutationRecord {
+    /// The DOM `MutationRecord.type` of this change
+    pure fn record_type(&self) -> ~str {
+        match self.details {
+            ChildAdded(*) | ChildRemoved(*) => ~"childList",
+            AttributeChanged(*) => ~"attributes"
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use dom::element::{ElementData, HTMLDivElement};
+    use dom::node::{Element, NodeScope, NodeScopeExtensions};
+
+    use core::pipes;
+
+    #[test]
+    fn should_send_records_for_appends_and_attribute_changes() {
+        let scope = NodeScope();
+        let parent = scope.new_node(Element(ElementData(~"div", ~HTMLDivElement)));
+        let child = scope.new_node(Element(ElementData(~"div", ~HTMLDivElement)));
+
+        let (port, chan) = pipes::stream();
+        scope.observe_mutations(move chan);
+
+        scope.add_child(parent, child);
+        scope.set_attr(&child, "id", ~"inner");
+
+        let append = port.recv();
+        assert append.record_type() == ~"childList";
+        assert append == MutationRecord { target: parent, details: ChildAdded(child) };
+
+        let set_attr = port.recv();
+        assert set_attr.record_type() == ~"attributes";
+        assert set_attr == MutationRecord { target: child,
+                                            details: AttributeChanged(~"id") };
+        assert !port.peek();
+
+        let id = do child.read |nd| {
+            match nd.kind {
+                ~Element(ref ed) => ed.get_attr("id"),
+                _ => fail!(~"not an element")
+            }
+        };
+        assert id == Some(~"inner");
+    }
+}
@@ -3,6 +3,7 @@ use newcss::complete::CompleteSelectResults;
 use dom::bindings;
 use dom::document::Document;
 use dom::element::{Attr, ElementData};
+use dom::mutation::{AttributeChanged, ChildAdded, ChildRemoved, MutationRecord};
 use dom::window::Window;
 use geom::size::Size2D;
 use js::crust::*;
@@ -12,6 +13,8 @@ use js::rust::Compartment;
 use js::{JSPROP_ENUMERATE, JSPROP_SHARED};
 use layout::debug::DebugMethods;
 use layout::flow::FlowContext;
+use core::pipes::Chan;
+use core::task::local_data::{local_data_get, local_data_set};
 use ptr::null;
 use std::arc::ARC;
 use util::tree;
@@ -20,6 +23,9 @@ use super::cow;
 pub enum NodeData = {
     tree: tree::Tree<Node>,
     kind: ~NodeKind,
+    // Bumped by mutations that may change the node's style. Layout compares
+    // it against the epoch it last styled the node at to find dirty nodes.
+    mut style_epoch: uint,
 };
 
 /* The tree holding Nodes (read-only) */
@@ -96,6 +102,35 @@ pub enum NodeKind {
     Text(~str)
 }
 
+// Values of the DOM `nodeType` attribute
+pub const ELEMENT_NODE: i32 = 1;
+pub const TEXT_NODE: i32 = 3;
+pub const COMMENT_NODE: i32 = 8;
+pub const DOCUMENT_NODE: i32 = 9;
+pub const DOCUMENT_TYPE_NODE: i32 = 10;
+
+impl NodeKind {
+    pure fn node_type(&self) -> i32 {
+        match *self {
+            Element(*) => ELEMENT_NODE,
+            Text(*)    => TEXT_NODE,
+            Comment(*) => COMMENT_NODE,
+            Doctype(*) => DOCUMENT_TYPE_NODE
+        }
+    }
+
+    /// The DOM `nodeName`. For elements this is the tag name, uppercased as
+    /// for HTML documents.
+    pure fn node_name(&self) -> ~str {
+        match *self {
+            Element(ref ed) => str::to_upper(ed.tag_name),
+            Text(*)         => ~"#text",
+            Comment(*)      => ~"#comment",
+            Doctype(ref d)  => copy d.name
+        }
+    }
+}
+
 pub struct DoctypeData {
     name: ~str,
     public_id: Option<~str>,
@@ -117,9 +152,13 @@ pub fn DoctypeData(name: ~str, public_id: Option<~str>,
 
 pub fn define_bindings(compartment: @mut Compartment, doc: @Document, win: @Window) {
     bindings::window::init(compartment, win);
+    bindings::console::init(compartment, win.console);
     bindings::document::init(compartment, doc);
     bindings::node::init(compartment);
     bindings::element::init(compartment);
+    bindings::htmlcollection::init(compartment);
+    bindings::nodelist::init(compartment);
+    bindings::mediaquerylist::init(compartment);
 }
 
 
@@ -130,7 +169,12 @@ pub fn define_bindings(compartment: @mut Compartment, doc: @Document, win: @Wind
    Note that there may be multiple boxes per DOM node. */
 enum LayoutData = {
     mut style: Option<CompleteSelectResults>,
-    mut flow:  Option<@FlowContext>
+    mut flow:  Option<@FlowContext>,
+    // The node's style_epoch when its style was last computed
+    mut style_epoch: Option<uint>,
+    // Text generated by the `content` property of ::before/::after, if any
+    mut before_content: Option<~str>,
+    mut after_content: Option<~str>
 };
 
 pub type Node = cow::Handle<NodeData, LayoutData>;
@@ -148,7 +192,7 @@ pub trait NodeScopeExtensions {
 #[allow(non_implicitly_copyable_typarams)]
 impl NodeScope : NodeScopeExtensions {
     fn new_node(k: NodeKind) -> Node {
-        self.handle(&NodeData({tree: tree::empty(), kind: ~move k}))
+        self.handle(&NodeData({tree: tree::empty(), kind: ~move k, mut style_epoch: 0}))
     }
 }
 
@@ -169,9 +213,102 @@ impl NodeScope : tree::ReadMethods<Node> {
     }
 }
 
+// The observers of the mutations made through the NodeScopes of this task. Scopes
+// are only written by the task that made them, so the task's observers are theirs.
+fn mutation_observers_key(_v: @@mut ~[Chan<MutationRecord>]) {}
+
+fn mutation_observers() -> @mut ~[Chan<MutationRecord>] {
+    unsafe {
+        match local_data_get(mutation_observers_key) {
+            Some(observers) => *observers,
+            None => {
+                let observers = @mut ~[];
+                local_data_set(mutation_observers_key, @observers);
+                observers
+            }
+        }
+    }
+}
+
+// Sends a record to every observer that is still listening
+fn notify_observers(record: &MutationRecord) {
+    for mutation_observers().each |chan| {
+        chan.try_send(copy *record);
+    }
+}
+
 impl NodeScope {
     fn add_child(node: Node, child: Node) {
-        tree::add_child(&self, node, child)
+        tree::add_child(&self, node, child);
+        notify_observers(&MutationRecord { target: node, details: ChildAdded(child) });
+    }
+
+    fn remove_child(node: Node, child: Node) {
+        tree::remove_child(&self, node, child);
+        notify_observers(&MutationRecord { target: node, details: ChildRemoved(child) });
+    }
+
+    /// Sets an attribute of an element, adding it if the element has none by
+    /// that name, and marks the element's style dirty
+    fn set_attr(node: &Node, name: &str, value: ~str) {
+        do self.write(node) |nd| {
+            match nd.kind {
+                ~Element(ref ed) if ed.get_attr(name).is_some() => ed.set_attr(name, copy value),
+                ~Element(ref ed) => ed.attrs.push(~Attr(name.to_str(), copy value)),
+                _ => fail!(~"setting an attribute of a non-element")
+            }
+        }
+        self.mark_style_dirty(node);
+        notify_observers(&MutationRecord { target: *node,
+                                           details: AttributeChanged(name.to_str()) });
+    }
+
+    /**
+    Copies a node, and its attributes if it's an element. A deep clone also
+    copies its descendants; a shallow one has no children. The copy has no
+    parent, and no mutation records are sent for building it.
+    */
+    fn clone_node(node: &Node, deep: bool) -> Node {
+        let kind = do self.read(node) |nd| {
+            match *nd.kind {
+                Element(ref ed) => {
+                    let copied = ElementData(copy ed.tag_name, ~copy *ed.kind);
+                    for ed.attrs.each |attr| {
+                        copied.attrs.push(~Attr(copy attr.name, copy attr.value));
+                    }
+                    Element(move copied)
+                }
+                Text(ref text) => Text(copy *text),
+                Comment(ref text) => Comment(copy *text),
+                Doctype(ref doctype) => Doctype(copy *doctype)
+            }
+        };
+
+        let clone = self.new_node(move kind);
+        if deep {
+            for self.each_child(node) |child| {
+                tree::add_child(&self, clone, self.clone_node(child, true));
+            }
+        }
+        clone
+    }
+
+    /**
+    Subscribes `chan` to a record of every later structural or attribute
+    change made through this scope, or any other scope of the same task.
+    Observers that hang up are skipped.
+    */
+    fn observe_mutations(chan: Chan<MutationRecord>) {
+        mutation_observers().push(move chan);
+    }
+
+    /**
+    Marks the style of a node out of date, e.g. after one of its attributes
+    changed. The next incremental restyle recomputes the styles of the node
+    and its descendants.
+    */
+    fn mark_style_dirty(node: &Node) {
+        self.write(node, |n| n.style_epoch += 1)
     }
 }
 
@@ -183,3 +320,80 @@ impl NodeScope : tree::WriteMethods<Node> {
         self.write(node, |n| f(&n.tree))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use dom::document::Document;
+    use dom::element::{ElementData, HTMLDivElement, HTMLSpanElement};
+
+    #[test]
+    fn should_report_standard_node_types_and_names() {
+        let scope = NodeScope();
+        let div = scope.new_node(Element(ElementData(~"div", ~HTMLDivElement)));
+        let text = scope.new_node(Text(~"hello"));
+        let comment = scope.new_node(Comment(~"note"));
+
+        assert div.read(|nd| nd.kind.node_type()) == ELEMENT_NODE;
+        assert div.read(|nd| nd.kind.node_name()) == ~"DIV";
+        assert text.read(|nd| nd.kind.node_type()) == TEXT_NODE;
+        assert text.read(|nd| nd.kind.node_name()) == ~"#text";
+        assert comment.read(|nd| nd.kind.node_type()) == COMMENT_NODE;
+        assert comment.read(|nd| nd.kind.node_name()) == ~"#comment";
+
+        let document = Document(div, scope);
+        assert document.node_type() == DOCUMENT_NODE;
+        assert document.node_name() == ~"#document";
+    }
+
+    #[test]
+    fn should_clone_nodes_deeply_or_shallowly() {
+        let scope = NodeScope();
+        let parent = scope.new_node(Element(ElementData(~"div", ~HTMLDivElement)));
+        let root = scope.new_node(Element(ElementData(~"div", ~HTMLDivElement)));
+        let child = scope.new_node(Element(ElementData(~"span", ~HTMLSpanElement)));
+        let text = scope.new_node(Text(~"hello"));
+        scope.add_child(parent, root);
+        scope.add_child(root, child);
+        scope.add_child(child, text);
+        scope.set_attr(&root, "id", ~"root");
+        scope.set_attr(&child, "class", ~"inner");
+
+        let get_attr = |node: &Node, name: &str| {
+            do scope.read(node) |nd| {
+                match nd.kind {
+                    ~Element(ref ed) => ed.get_attr(name),
+                    _ => fail!(~"not an element")
+                }
+            }
+        };
+        let children = |node: &Node| {
+            let mut kids = ~[];
+            for scope.each_child(node) |kid| { kids.push(*kid); }
+            move kids
+        };
+
+        let deep = scope.clone_node(&root, true);
+        assert deep != root;
+        assert scope.get_parent(&deep).is_none();
+        assert get_attr(&deep, "id") == Some(~"root");
+        let deep_kids = children(&deep);
+        assert deep_kids.len() == 1 && deep_kids[0] != child;
+        assert deep_kids[0].read(|nd| nd.kind.node_name()) == ~"SPAN";
+        assert get_attr(&deep_kids[0], "class") == Some(~"inner");
+        let deep_text = children(&deep_kids[0]);
+        assert deep_text.len() == 1 && deep_text[0] != text;
+        assert deep_text[0].read(|nd| match nd.kind { ~Text(ref s) => copy *s, _ => ~"" })
+            == ~"hello";
+
+        // Changing the clone leaves the original alone
+        scope.set_attr(&deep, "id", ~"copy");
+        assert get_attr(&root, "id") == Some(~"root");
+
+        let shallow = scope.clone_node(&root, false);
+        assert scope.get_parent(&shallow).is_none();
+        assert get_attr(&shallow, "id") == Some(~"root");
+        assert children(&shallow).is_empty();
+        assert children(&root) == ~[child];
+    }
+}
@@ -0,0 +1,146 @@
+/*!
+A matcher for the simple selectors accepted by `querySelectorAll`: a type
+selector, `#id` and `.class` selectors, and compounds of them such as
+`div#main.note`. Combinators are not supported.
+*/
+
+use dom::element::ElementData;
+
+pub struct Selector {
+    tag_name: Option<~str>,
+    id: Option<~str>,
+    classes: ~[~str],
+}
+
+/// Parses a compound selector, returning None if it is empty or uses unsupported syntax.
+pub fn parse_selector(selector: &str) -> Option<Selector> {
+    let selector = str::trim(selector);
+    if selector.is_empty() {
+        return None;
+    }
+
+    let mut tag_name = None;
+    let mut id = None;
+    let mut classes = ~[];
+
+    // Walk by char, so that non-ASCII names aren't split mid-character
+    let mut i = 0;
+    while i < selector.len() {
+        let prefix = str::char_range_at(selector, i);
+        let start = if prefix.ch == '#' || prefix.ch == '.' { prefix.next } else { i };
+
+        let mut end = start;
+        while end < selector.len() {
+            let next = str::char_range_at(selector, end);
+            if !is_ident_char(next.ch) {
+                break;
+            }
+            end = next.next;
+        }
+
+        let name = str::slice(selector, start, end);
+        match prefix.ch {
+            '#' if !name.is_empty() && id.is_none() => id = Some(move name),
+            '.' if !name.is_empty() => classes.push(move name),
+            '*' if i == 0 => end = prefix.next,
+            _ if i == 0 && !name.is_empty() => tag_name = Some(str::to_lower(name)),
+            _ => return None
+        }
+
+        i = end;
+    }
+
+    Some(Selector { tag_name: move tag_name, id: move id, classes: move classes })
+}
+
+pure fn is_ident_char(c: char) -> bool {
+    char::is_alphanumeric(c) || c == '-' || c == '_'
+}
+
+impl Selector {
+    fn matches(&self, element: &ElementData) -> bool {
+        match self.tag_name {
+            Some(ref tag_name) if *tag_name != str::to_lower(element.tag_name) => return false,
+            _ => ()
+        }
+
+        match self.id {
+            Some(ref id) => {
+                let id_matches = do element.with_attr("id") |value| {
+                    match value {
+                        Some(value) => str::eq_slice(value, *id),
+                        None => false
+                    }
+                };
+                if !id_matches {
+                    return false;
+                }
+            }
+            None => ()
+        }
+
+        if self.classes.is_empty() {
+            return true;
+        }
+
+        do element.with_attr("class") |value| {
+            match value {
+                None => false,
+                Some(value) => {
+                    let element_classes = str::words(value);
+                    do vec::all(self.classes) |class| { element_classes.contains(class) }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use dom::element::{Attr, ElementData, HTMLDivElement};
+
+    fn div(id: ~str, class: ~str) -> ElementData {
+        let element = ElementData(~"div", ~HTMLDivElement);
+        element.attrs.push(~Attr(~"id", move id));
+        element.attrs.push(~Attr(~"class", move class));
+        element
+    }
+
+    #[test]
+    fn should_parse_compound_selectors() {
+        let selector = parse_selector("DIV#main.note.wide").get();
+        assert selector.tag_name == Some(~"div");
+        assert selector.id == Some(~"main");
+        assert selector.classes == ~[~"note", ~"wide"];
+
+        let selector = parse_selector("p.caf\u00e9").get();
+        assert selector.classes == ~[~"caf\u00e9"];
+    }
+
+    #[test]
+    fn should_reject_unsupported_selectors() {
+        assert parse_selector("").is_none();
+        assert parse_selector("div p").is_none();
+        assert parse_selector("div > p").is_none();
+        assert parse_selector("#").is_none();
+        assert parse_selector(".caf\u00e9 p").is_none();
+        assert parse_selector("\u2603").is_none();
+    }
+
+    #[test]
+    fn should_match_elements() {
+        let element = div(~"main", ~"note wide");
+        assert parse_selector("div").get().matches(&element);
+        assert parse_selector("*").get().matches(&element);
+        assert parse_selector("#main").get().matches(&element);
+        assert parse_selector(".wide.note").get().matches(&element);
+        assert parse_selector("div#main.note").get().matches(&element);
+        assert !parse_selector("p").get().matches(&element);
+        assert !parse_selector("#other").get().matches(&element);
+        assert !parse_selector(".note.narrow").get().matches(&element);
+
+        let element = div(~"\u00fcber", ~"caf\u00e9");
+        assert parse_selector("#\u00fcber.caf\u00e9").get().matches(&element);
+    }
+}
@@ -1,5 +1,8 @@
 use core::pipes::{Port, Chan};
 use content::content_task::{ControlMsg, Timer, ExitMsg};
+use content::embedder::EmbedderCallbacks;
+use dom::console;
+use dom::console::Console;
 use js::jsapi::JSVal;
 use dvec::DVec;
 use util::task::spawn_listener;
@@ -14,6 +17,12 @@ pub enum TimerControlMsg {
 
 pub struct Window {
     timer_chan: Chan<TimerControlMsg>,
+    console: @Console,
+    embedder: @EmbedderCallbacks,
+    /// The handler assigned to `window.onunload`
+    mut onunload: Option<JSVal>,
+    /// Handlers added with `addEventListener("unload", ...)`
+    unload_listeners: DVec<JSVal>,
 
     drop {
         self.timer_chan.send(TimerMessage_Close);
@@ -49,8 +58,7 @@ pub fn TimerData(argc: libc::c_uint, argv: *JSVal) -> TimerData {
 #[allow(non_implicitly_copyable_typarams)]
 impl Window {
     fn alert(s: &str) {
-        // Right now, just print to the console
-        io::println(fmt!("ALERT: %s", s));
+        self.embedder.on_alert(s);
     }
 
     fn close() {
@@ -67,11 +75,29 @@ impl Window {
                             &self.timer_chan,
                             TimerMessage_Fire(~TimerData(argc, argv)));
     }
+
+    fn add_unload_listener(&self, listener: JSVal) {
+        self.unload_listeners.push(listener);
+    }
+
+    /// The handlers to call when the document is torn down, in the order to call them
+    fn unload_handlers(&self) -> ~[JSVal] {
+        let mut handlers = ~[];
+        for self.onunload.each |handler| {
+            handlers.push(*handler);
+        }
+        handlers + self.unload_listeners.get()
+    }
 }
 
-pub fn Window(content_chan: pipes::SharedChan<ControlMsg>) -> Window {
+pub fn Window(content_chan: pipes::SharedChan<ControlMsg>,
+              embedder: @EmbedderCallbacks) -> Window {
         
     Window {
+        console: @Console(console::DEFAULT_CAPACITY, embedder),
+        embedder: embedder,
+        onunload: None,
+        unload_listeners: DVec(),
         timer_chan: do spawn_listener |timer_port: Port<TimerControlMsg>,
                                        move content_chan| {
             loop {
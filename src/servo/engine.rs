@@ -1,5 +1,6 @@
-use content::content_task::{ContentTask, ExecuteMsg, ParseMsg, ExitMsg};
+use content::content_task::{ContentTask, ExecuteMsg, NavigateMsg, ExitMsg};
 use content::content_task;
+use content::embedder::default_embedder_factory;
 use dom::event::Event;
 use layout::layout_task;
 use layout::layout_task::LayoutTask;
@@ -51,7 +52,8 @@ pub fn Engine<C:Compositor + Owned + Clone>(compositor: C,
                                        dom_event_port.take(),
                                        dom_event_chan.take(),
                                        resource_task.clone(),
-                                       image_cache_task.clone());
+                                       image_cache_task.clone(),
+                                       default_embedder_factory);
 
         Engine {
             request_port: request,
@@ -76,9 +78,9 @@ impl<C:Compositor + Owned + Clone> Engine<C> {
         match move request {
           LoadURLMsg(move url) => {
             if url.path.ends_with(".js") {
-                self.content_task.send(ExecuteMsg(move url))
+                self.content_task.send(ExecuteMsg(move url, false))
             } else {
-                self.content_task.send(ParseMsg(move url))
+                self.content_task.send(NavigateMsg(move url))
             }
             return true;
           }
@@ -0,0 +1,72 @@
+/*!
+Conversion of fetched documents into UTF-8, which is all the HTML parser
+reads, according to the charset their MIME type declares.
+*/
+
+/// The charset parameter of a MIME type such as `text/html; charset=ISO-8859-1`, lowercased
+pub fn charset_from_mime_type(mime_type: &str) -> Option<~str> {
+    for str::split_char(mime_type, ';').eachi |i, param| {
+        if i == 0 {
+            loop;
+        }
+        let param = str::trim(*param);
+        match str::find_char(param, '=') {
+            Some(eq) if str::to_lower(str::trim(str::slice(param, 0, eq))) == ~"charset" => {
+                let value = str::trim(str::slice(param, eq + 1, param.len()));
+                let value = if value.len() >= 2 && str::starts_with(value, "\"") &&
+                        str::ends_with(value, "\"") {
+                    str::slice(value, 1, value.len() - 1)
+                } else {
+                    move value
+                };
+                return Some(str::to_lower(value));
+            }
+            _ => ()
+        }
+    }
+    None
+}
+
+/**
+Converts a document in `charset` into UTF-8. Single-byte Latin charsets are
+mapped byte for byte onto the first 256 code points, which is exact for
+ISO-8859-1 and close enough for windows-1252. Anything else is assumed to be
+UTF-8 already; the parser replaces any bytes that aren't.
+*/
+pub fn transcode_to_utf8(data: ~[u8], charset: &str) -> ~[u8] {
+    match str::from_slice(charset) {
+        ~"iso-8859-1" | ~"latin1" | ~"l1" | ~"windows-1252" | ~"cp1252" | ~"us-ascii" |
+        ~"ascii" => {
+            let mut text = ~"";
+            for data.each |byte| {
+                str::push_char(&mut text, *byte as char);
+            }
+            str::to_bytes(text)
+        }
+        ~"utf-8" | ~"utf8" => move data,
+        _ => {
+            debug!("charset: treating %s as UTF-8", charset);
+            move data
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn should_find_the_charset_of_a_mime_type() {
+        assert charset_from_mime_type("text/html; charset=ISO-8859-1") == Some(~"iso-8859-1");
+        assert charset_from_mime_type("text/html;CHARSET=\"utf-8\"") == Some(~"utf-8");
+        assert charset_from_mime_type("text/html") == None;
+    }
+
+    #[test]
+    fn should_transcode_latin1_to_utf8() {
+        // "café" in ISO-8859-1
+        let latin1 = ~[0x63u8, 0x61, 0x66, 0xE9];
+        assert transcode_to_utf8(latin1, "iso-8859-1") == str::to_bytes("café");
+        assert transcode_to_utf8(str::to_bytes("café"), "utf-8") == str::to_bytes("café");
+    }
+}
@@ -2,11 +2,17 @@
 Some little helpers for hooking up the HTML parser with the CSS parser
 */
 
-use resource::resource_task::{ResourceTask, ProgressMsg, Load, Payload, Done};
+use css::color::resolve_colors;
+use css::keywords::resolve_wide_keywords;
+use css::rem::{INITIAL_ROOT_FONT_SIZE_PX, resolve_rem_units};
+use resource::resource_task::{ResourceTask, Load, Meta, Header, Payload, Done};
+use resource::resource_task::{NotModified, PartialContent, Redirected};
 
-use core::pipes::{Port, Chan};
+use core::pipes::Port;
 use core::pipes;
 use core::str;
+use gfx::text::util::from_utf8_lossy;
+use gfx::util::url::make_url;
 use newcss::stylesheet::Stylesheet;
 use newcss::util::DataStream;
 use std::cell::Cell;
@@ -19,9 +25,21 @@ pub enum StylesheetProvenance {
     InlineProvenance(Url, ~str),
 }
 
+/// A parsed author sheet, with the text it was parsed from
+pub struct AuthorSheet {
+    sheet: Stylesheet,
+    url: Url,
+    /// The text with its imports and keywords resolved but not its `rem`
+    /// lengths, to parse again should the root font size change
+    source: ~str
+}
+
+/// How deeply `@import`s may nest
+pub const MAX_IMPORT_DEPTH: uint = 8;
+
 pub fn spawn_css_parser(provenance: StylesheetProvenance,
                         resource_task: ResourceTask)
-                     -> Port<Stylesheet> {
+                     -> Port<AuthorSheet> {
     let (result_port, result_chan) = pipes::stream();
 
     let provenance_cell = Cell(move provenance);
@@ -33,34 +51,195 @@ pub fn spawn_css_parser(provenance: StylesheetProvenance,
             }
         };
 
-        let sheet = Stylesheet::new(url, data_stream(provenance_cell.take(),
-                                                     resource_task.clone()));
-        result_chan.send(sheet);
+        let source = sheet_source(provenance_cell.take(), resource_task.clone());
+        let sheet = parse_stylesheet(copy url, source, INITIAL_ROOT_FONT_SIZE_PX);
+        result_chan.send(AuthorSheet { sheet: move sheet, url: move url, source: move source });
     }
 
     return result_port;
 }
 
-fn data_stream(provenance: StylesheetProvenance, resource_task: ResourceTask) -> DataStream {
-    match move provenance {
+/*
+The sheet is read in full before it is parsed, so that its `@import`s can be
+replaced with the text of the sheets they import, the `initial` and `unset`
+keywords, which libcss doesn't know, with values it does, and colors with
+the forms it parses.
+*/
+fn sheet_source(provenance: StylesheetProvenance, resource_task: ResourceTask) -> ~str {
+    let css = match move provenance {
         UrlProvenance(move url) => {
-            let (input_port, input_chan) = pipes::stream();
-            resource_task.send(Load(move url, input_chan));
-            resource_port_to_data_stream(input_port)
+            let css = load_css(copy url, resource_task.clone()).get_or_default(~"");
+            inline_imports(move css, &url, resource_task, [copy url])
         }
-        InlineProvenance(_, move data) => {
-            data_to_data_stream(move data)
+        InlineProvenance(move url, move data) => {
+            inline_imports(move data, &url, resource_task, [])
         }
-    }
+    };
+    resolve_colors(resolve_wide_keywords(css))
 }
 
-fn resource_port_to_data_stream(input_port: Port<ProgressMsg>) -> DataStream {
-    return || {
+/// Parses the text of a sheet, resolving its `rem` lengths for a root font size of `root_size` px
+pub fn parse_stylesheet(url: Url, source: &str, root_size: float) -> Stylesheet {
+    Stylesheet::new(move url, data_to_data_stream(resolve_rem_units(source, root_size)))
+}
+
+/*
+Reads the whole of a sheet, or None if it can't be loaded. Sheets are taken to
+be UTF-8, with anything that isn't replaced by U+FFFD.
+*/
+fn load_css(url: Url, resource_task: ResourceTask) -> Option<~str> {
+    let (input_port, input_chan) = pipes::stream();
+    resource_task.send(Load(move url, input_chan));
+    let mut data = ~[];
+    let mut unassembled = false;
+    loop {
         match input_port.recv() {
-            Payload(move data) => Some(move data),
-            Done(*) => None
+            Meta(*) | Header(*) | Redirected(*) | NotModified => (),
+            PartialContent(*) => unassembled = true,
+            Payload(move bytes) => data.push_all_move(move bytes),
+            Done(Ok(())) if unassembled => return None,
+            Done(Ok(())) => return Some(from_utf8_lossy(data)),
+            Done(Err(*)) => return None
+        }
+    }
+}
+
+/**
+Replaces the `@import` rules at the top of a sheet with the sheets they
+import, fetched relative to `url`, so that imported rules come before the
+sheet's own in the cascade. `importers` are the URLs of the sheets that
+imported this one, outermost first; a sheet that imports one of them, or that
+would nest imports more than MAX_IMPORT_DEPTH deep, has the import dropped.
+
+TODO: media lists on `@import` are ignored.
+*/
+pub fn inline_imports(css: ~str, url: &Url, resource_task: ResourceTask,
+                      importers: &[Url]) -> ~str {
+    let (hrefs, rules) = split_imports(css);
+    let mut result = ~"";
+    for hrefs.each |href| {
+        if importers.len() >= MAX_IMPORT_DEPTH {
+            debug!("cssparse: not importing %s, imports are nested too deeply", *href);
+            loop;
+        }
+        let import_url = make_url(copy *href, Some(copy *url));
+        if import_url == *url || importers.contains(&import_url) {
+            debug!("cssparse: not importing %s, which would import itself", *href);
+            loop;
+        }
+        match load_css(copy import_url, resource_task.clone()) {
+            Some(move imported) => {
+                let importers = vec::append_one(importers.to_vec(), copy *url);
+                result += inline_imports(move imported, &import_url, resource_task.clone(),
+                                         importers);
+                result += ~"\n";
+            }
+            None => debug!("cssparse: failed to import %s", *href)
         }
     }
+    result + rules
+}
+
+/**
+Splits a sheet into the URLs of the `@import` rules it starts with, which
+may only be preceded by a `@charset` rule, and the rest of its text.
+*/
+pub fn split_imports(css: &str) -> (~[~str], ~str) {
+    let mut hrefs = ~[];
+    let mut i = skip_space(css, 0);
+    if starts_with_at(css, i, "@charset") {
+        match str::find_char_from(css, ';', i) {
+            Some(end) => i = skip_space(css, end + 1),
+            None => return (hrefs, css.to_str())
+        }
+    }
+
+    while starts_with_at(css, i, "@import") {
+        match parse_import(css, i + 7) {
+            Some((move href, end)) => {
+                hrefs.push(move href);
+                i = skip_space(css, end);
+            }
+            // Leave a malformed import to the CSS parser, which ignores it
+            None => break
+        }
+    }
+    (hrefs, str::slice(css, i, css.len()))
+}
+
+// Parses the rest of an import rule from `i`, returning its URL and the index after it
+fn parse_import(css: &str, i: uint) -> Option<(~str, uint)> {
+    let mut i = skip_space(css, i);
+    let href = if starts_with_at(css, i, "url(") {
+        i = skip_space(css, i + 4);
+        let (href, end) = if starts_with_at(css, i, "\"") || starts_with_at(css, i, "'") {
+            match parse_string(css, i) {
+                Some(move string) => move string,
+                None => return None
+            }
+        } else {
+            match str::find_char_from(css, ')', i) {
+                Some(end) => (str::trim(str::slice(css, i, end)), end),
+                None => return None
+            }
+        };
+        i = skip_space(css, end);
+        if !starts_with_at(css, i, ")") {
+            return None;
+        }
+        i += 1;
+        href
+    } else {
+        match parse_string(css, i) {
+            Some((move href, end)) => {
+                i = end;
+                href
+            }
+            None => return None
+        }
+    };
+
+    match str::find_char_from(css, ';', i) {
+        Some(end) => Some((href, end + 1)),
+        None => None
+    }
+}
+
+// Parses a quoted string at `i`, returning its contents and the index after it
+fn parse_string(css: &str, i: uint) -> Option<(~str, uint)> {
+    if i >= css.len() {
+        return None;
+    }
+    let quote = css[i] as char;
+    if quote != '"' && quote != '\'' {
+        return None;
+    }
+    match str::find_char_from(css, quote, i + 1) {
+        Some(end) => Some((str::slice(css, i + 1, end), end + 1)),
+        None => None
+    }
+}
+
+// Skips whitespace and comments from `i`
+fn skip_space(css: &str, i: uint) -> uint {
+    let mut i = i;
+    loop {
+        if i < css.len() && char::is_whitespace(css[i] as char) {
+            i += 1;
+        } else if starts_with_at(css, i, "/*") {
+            match str::find_str_from(css, "*/", i + 2) {
+                Some(end) => i = end + 2,
+                None => return css.len()
+            }
+        } else {
+            return i;
+        }
+    }
+}
+
+pure fn starts_with_at(css: &str, i: uint, prefix: &str) -> bool {
+    i + prefix.len() <= css.len() &&
+        str::eq_slice(str::view(css, i, i + prefix.len()), prefix)
 }
 
 fn data_to_data_stream(data: ~str) -> DataStream {
@@ -75,3 +254,77 @@ fn data_to_data_stream(data: ~str) -> DataStream {
     }
 }
 
+#[cfg(test)]
+mod test {
+    use super::*;
+    use resource::resource_task::{Done, LoadFailed, Payload, ResourceTask};
+    use resource::resource_task;
+    use test_support::mock_resource_task;
+
+    use gfx::util::url::make_url;
+
+    // Serves the sheets named in `sheets` by the last segment of their URL path
+    fn sheet_resource_task(sheets: ~[(~str, ~str)]) -> ResourceTask {
+        do mock_resource_task |url, response, move sheets| {
+            let name = str::split_char(url.path, '/').last();
+            match sheets.find(|&(ref sheet_name, _)| *sheet_name == name) {
+                Some((_, move css)) => {
+                    response.send(Payload(str::to_bytes(css)));
+                    response.send(Done(Ok(())));
+                }
+                None => response.send(Done(Err(LoadFailed)))
+            }
+        }
+    }
+
+    #[test]
+    fn should_split_leading_imports() {
+        let (hrefs, rules) = split_imports("@charset \"utf-8\"; /* imports */\n\
+                                            @import url(a.css); @import url( 'b.css' );\n\
+                                            @import \"c.css\" screen; p { color: red }");
+        assert hrefs == ~[~"a.css", ~"b.css", ~"c.css"];
+        assert rules == ~"p { color: red }";
+    }
+
+    #[test]
+    fn should_put_imported_rules_before_host_rules() {
+        let resource_task = sheet_resource_task(~[
+            (~"host.css", ~"@import url(imported.css);\np { color: red }"),
+            (~"imported.css", ~"div { color: blue }")
+        ]);
+        let url = make_url(~"http://example.com/host.css", None);
+        let css = inline_imports(~"@import url(imported.css);\np { color: red }", &url,
+                                 resource_task.clone(), []);
+        assert css == ~"div { color: blue }\np { color: red }";
+        resource_task.send(resource_task::Exit);
+    }
+
+    #[test]
+    fn should_stop_import_cycles() {
+        let resource_task = sheet_resource_task(~[
+            (~"a.css", ~"@import \"b.css\"; a { color: red }"),
+            (~"b.css", ~"@import \"a.css\"; b { color: blue }")
+        ]);
+        let url = make_url(~"http://example.com/a.css", None);
+        let css = inline_imports(~"@import \"b.css\"; a { color: red }", &url,
+                                 resource_task.clone(), []);
+        // b.css's import of a.css is dropped, so each sheet's rules appear once
+        assert css == ~"b { color: blue }\na { color: red }";
+        resource_task.send(resource_task::Exit);
+    }
+
+    #[test]
+    fn should_decode_sheets_that_arent_utf8() {
+        let resource_task = do mock_resource_task |_url, response| {
+            // "p { content: \"\xE9\" }" in Latin-1
+            let mut css = str::to_bytes("p { content: \"");
+            css.push_all([0xE9u8]);
+            css.push_all(str::to_bytes("\" }"));
+            response.send(Payload(move css));
+            response.send(Done(Ok(())));
+        };
+        let url = make_url(~"http://example.com/latin1.css", None);
+        assert load_css(url, resource_task.clone()) == Some(~"p { content: \"\uFFFD\" }");
+        resource_task.send(resource_task::Exit);
+    }
+}
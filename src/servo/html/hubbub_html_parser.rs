@@ -1,3 +1,4 @@
+use gfx::text::util::to_valid_utf8;
 use gfx::util::url::make_url;
 use au = gfx::geometry;
 use content::content_task::ContentTask;
@@ -8,14 +9,18 @@ use dom::node::{Comment, Doctype, DoctypeData, Element, Node, NodeScope, NodeSco
 use dom::node::{Text};
 use resource::image_cache_task::ImageCacheTask;
 use resource::image_cache_task;
-use resource::resource_task::{Done, Load, Payload, ResourceTask};
+use resource::resource_task::{Done, Header, Load, Meta, NotModified, PartialContent, Payload};
+use resource::resource_task::Redirected;
+use resource::resource_task::ResourceTask;
 use util::task::{spawn_listener, spawn_conversation};
 
 use core::pipes::{Chan, Port, SharedChan};
-use html::cssparse::{InlineProvenance, StylesheetProvenance, UrlProvenance, spawn_css_parser};
+use geom::size::Size2D;
+use html::cssparse::{AuthorSheet, InlineProvenance, StylesheetProvenance, UrlProvenance};
+use html::cssparse::spawn_css_parser;
+use html::srcset::parse_srcset;
 use hubbub::hubbub::Attribute;
 use hubbub::hubbub;
-use newcss::stylesheet::Stylesheet;
 use std::net::url::Url;
 use std::net::url;
 
@@ -33,7 +38,7 @@ enum JSMessage {
 
 struct HtmlParserResult {
     root: Node,
-    style_port: Port<Option<Stylesheet>>,
+    style_port: Port<Option<AuthorSheet>>,
     js_port: Port<JSResult>,
 }
 
@@ -52,7 +57,7 @@ spawned, collates them, and sends them to the given result channel.
 * `from_parent` - A port on which to receive new links.
 
 */
-fn css_link_listener(to_parent: Chan<Option<Stylesheet>>,
+fn css_link_listener(to_parent: Chan<Option<AuthorSheet>>,
                      from_parent: Port<CSSMessage>,
                      resource_task: ResourceTask) {
     let mut result_vec = ~[];
@@ -92,11 +97,19 @@ fn js_script_listener(to_parent: Chan<~[~[u8]]>,
                     resource_task.send(Load(copy url, input_chan));
 
                     let mut buf = ~[];
+                    let mut unassembled = false;
                     loop {
                         match input_port.recv() {
+                            Meta(*) | Header(*) | Redirected(*) | NotModified => (),
+                            PartialContent(*) => unassembled = true,
                             Payload(move data) => {
                                 buf += data;
                             }
+                            Done(Ok(*)) if unassembled => {
+                                error!("error loading script %s", url.to_str());
+                                result_chan.send(~[]);
+                                break;
+                            }
                             Done(Ok(*)) => {
                                 result_chan.send(move buf);
                                 break;
@@ -162,16 +175,51 @@ fn build_element_kind(tag: &str) -> ~ElementKind {
     else { ~UnknownElement }
 }
 
-#[allow(non_implicitly_copyable_typarams)]
+/**
+Parses the HTML document at `url`, building its node tree in `scope`.
+
+`run_script` is called with the root node and the text of each inline script as soon as the
+script is parsed. It returns any markup the script passed to `document.write`, which is
+parsed next, as if it had followed the script in the document. Scripts with a `src` are
+loaded and returned in the result instead.
+
+`on_nodes_appended` is called with the root node after each chunk of input that added nodes
+to the tree, so that callers can lay out and paint the partial document.
+
+`window_size` and `display_density`, in device pixels per px, pick which of the images an
+`<img srcset>` offers is loaded.
+*/
 pub fn parse_html(scope: NodeScope,
                   url: Url,
                   resource_task: ResourceTask,
-                  image_cache_task: ImageCacheTask) -> HtmlParserResult {
+                  image_cache_task: ImageCacheTask,
+                  window_size: Size2D<uint>,
+                  display_density: float,
+                  run_script: @fn(Node, ~[u8]) -> ~str,
+                  on_nodes_appended: &fn(Node)) -> HtmlParserResult {
+    parse_html_(scope, url, None, resource_task, image_cache_task, window_size, display_density,
+                run_script, on_nodes_appended)
+}
+
+/**
+Like `parse_html`, but parses `data` as the document at `url` if it is given, rather than
+loading it. The data must be UTF-8.
+*/
+#[allow(non_implicitly_copyable_typarams)]
+pub fn parse_html_(scope: NodeScope,
+                   url: Url,
+                   data: Option<~[u8]>,
+                   resource_task: ResourceTask,
+                   image_cache_task: ImageCacheTask,
+                   window_size: Size2D<uint>,
+                   display_density: float,
+                   run_script: @fn(Node, ~[u8]) -> ~str,
+                   on_nodes_appended: &fn(Node)) -> HtmlParserResult {
     // Spawn a CSS parser to receive links to CSS style sheets.
     let resource_task2 = resource_task.clone();
-    let (css_port, css_chan): (Port<Option<Stylesheet>>, Chan<CSSMessage>) =
+    let (css_port, css_chan): (Port<Option<AuthorSheet>>, Chan<CSSMessage>) =
             do spawn_conversation |css_port: Port<CSSMessage>,
-                                   css_chan: Chan<Option<Stylesheet>>| {
+                                   css_chan: Chan<Option<AuthorSheet>>| {
         css_link_listener(css_chan, css_port, resource_task2.clone());
     };
     let css_chan = SharedChan(css_chan);
@@ -191,10 +239,16 @@ pub fn parse_html(scope: NodeScope,
         // Build the root node.
         let root = scope.new_node(Element(ElementData(~"html", ~HTMLDivElement)));
         debug!("created new node");
-        let parser = hubbub::Parser("UTF-8", false);
+        let parser = @hubbub::Parser("UTF-8", false);
         debug!("created parser");
         parser.set_document_node(cast::transmute(cow::unwrap(root)));
         parser.enable_scripting(true);
+        // Lets scripts write into the input while the parser is running them. The parser
+        // holds the tree handler that holds this, so it is emptied once parsing is done.
+        let writable_parser: @mut Option<@hubbub::Parser> = @mut Some(parser);
+
+        // Set whenever the tree handler appends a node, so we know when to report progress.
+        let nodes_appended = @mut false;
 
         // Performs various actions necessary after appending has taken place. Currently, this consists
         // of processing inline stylesheets, but in the future it might perform prefetching, etc.
@@ -273,12 +327,54 @@ pub fn parse_html(scope: NodeScope,
                         }
                     },
                     ~HTMLImageElement(ref d) => {
-                        do elem.get_attr(~"src").iter |img_url_str| {
-                            let img_url = make_url(copy *img_url_str, Some(copy *url));
-                            d.image = Some(copy img_url);
-                            // inform the image cache to load this, but don't store a handle.
+                        let src = elem.get_attr(~"src").map(|src| make_url(copy *src,
+                                                                           Some(copy *url)));
+                        let mut candidates = match elem.get_attr(~"srcset") {
+                            Some(srcset) => {
+                                parse_srcset(srcset, elem.get_attr(~"sizes"), url, window_size)
+                            }
+                            None => ~[]
+                        };
+                        // `src` is the 1x candidate unless srcset has one
+                        match src {
+                            Some(ref src) if !candidates.is_empty() &&
+                                    !candidates.any(|c| c.density == 1.0) => {
+                                candidates.push(image_cache_task::ImageCandidate {
+                                    url: copy *src,
+                                    density: 1.0
+                                });
+                            }
+                            _ => ()
+                        }
+
+                        let img_url = match image_cache_task::select_candidate(candidates,
+                                                                           display_density) {
+                            Some(candidate) => Some(copy candidate.url),
+                            None => copy src
+                        };
+                        do img_url.iter |img_url| {
+                            d.image = Some(copy *img_url);
+                            // Lazy images are left for layout to load once they near the
+                            // viewport; the rest start loading now.
                             // TODO (Issue #84): don't prefetch if we are within a <noscript> tag.
-                            image_cache_task.send(image_cache_task::Prefetch(move img_url));
+                            let lazy = match elem.get_attr(~"loading") {
+                                Some(ref loading) => str::to_lower(*loading) == ~"lazy",
+                                None => false
+                            };
+                            if !lazy {
+                                if candidates.is_empty() {
+                                    image_cache_task.send(image_cache_task::Prefetch(
+                                        copy *img_url));
+                                } else {
+                                    // Registered under the URL the element is known by
+                                    let key = match src {
+                                        Some(ref src) => copy *src,
+                                        None => copy candidates[0].url
+                                    };
+                                    image_cache_task.send(image_cache_task::PrefetchSrcset(
+                                        key, copy candidates, display_density));
+                                }
+                            }
                         }
                     }
                     //TODO (Issue #86): handle inline styles ('style' attr)
@@ -289,7 +385,8 @@ pub fn parse_html(scope: NodeScope,
             },
             create_text: |data: ~str| {
                 debug!("create text");
-                let new_node = scope.new_node(Text(move data));
+                // hubbub hands over bytes as found in the document, which may be malformed
+                let new_node = scope.new_node(Text(to_valid_utf8(move data)));
                 unsafe { cast::transmute(cow::unwrap(new_node)) }
             },
             ref_node: |_node| {},
@@ -301,6 +398,7 @@ pub fn parse_html(scope: NodeScope,
                     let c: Node = cow::wrap(cast::transmute(child));
                     scope.add_child(p, c);
                     append_hook(p, c);
+                    *nodes_appended = true;
                 }
                 child
             },
@@ -347,44 +445,76 @@ pub fn parse_html(scope: NodeScope,
                 debug!("encoding change");
             },
             complete_script: |script| {
-                // A little function for holding this lint attr
+                // A little function for holding this lint attr. Returns the text of
+                // inline scripts, which are run right away.
                 #[allow(non_implicitly_copyable_typarams)]
                 fn complete_script(scope: &NodeScope,
                                    script: hubbub::NodeDataPtr,
                                    url: &Url,
-                                   js_chan: SharedChan<JSMessage>) {
+                                   js_chan: SharedChan<JSMessage>) -> Option<~str> {
                     unsafe {
-                        do scope.read(&cow::wrap(cast::transmute(script))) |node_contents| {
+                        let script: Node = cow::wrap(cast::transmute(script));
+                        let src = do scope.read(&script) |node_contents| {
                             match *node_contents.kind {
                                 Element(ref element) if element.tag_name == ~"script" => {
-                                    match element.get_attr(~"src") {
-                                        Some(move src) => {
-                                            debug!("found script: %s", src);
-                                            let new_url = make_url(move src, Some(copy *url));
-                                            js_chan.send(JSTaskNewFile(move new_url));
-                                        }
-                                        None => {}
-                                    }
+                                    Some(element.get_attr(~"src"))
                                 }
-                                _ => {}
+                                _ => None
+                            }
+                        };
+                        match move src {
+                            Some(Some(move src)) => {
+                                debug!("found script: %s", src);
+                                let new_url = make_url(move src, Some(copy *url));
+                                js_chan.send(JSTaskNewFile(move new_url));
+                                None
                             }
+                            Some(None) => Some(text_content(scope, script)),
+                            None => None
                         }
                     }
                 }
-                complete_script(scope, script, url, js_chan2.clone());
+                match complete_script(scope, script, url, js_chan2.clone()) {
+                    Some(move text) => {
+                        debug!("running inline script");
+                        let markup = run_script(root, str::to_bytes(text));
+                        match *writable_parser {
+                            Some(parser) if !markup.is_empty() => {
+                                parser.insert_chunk(str::to_bytes(markup));
+                            }
+                            _ => ()
+                        }
+                    }
+                    None => ()
+                }
                 debug!("complete script");
             }
         });
         debug!("set tree handler");
 
         let (input_port, input_chan) = pipes::stream();
-        resource_task.send(Load(copy *url, input_chan));
+        match move data {
+            Some(move data) => {
+                input_chan.send(Payload(move data));
+                input_chan.send(Done(Ok(())));
+            }
+            None => resource_task.send(Load(copy *url, move input_chan))
+        }
         debug!("loaded page");
+        let mut unassembled = false;
         loop {
             match input_port.recv() {
+                Meta(*) | Header(*) | Redirected(*) | NotModified => (),
+                // What arrived up to here is all there is of the page
+                PartialContent(*) => unassembled = true,
+                Payload(_) if unassembled => (),
                 Payload(data) => {
                     debug!("received data");
                     parser.parse_chunk(data);
+                    if *nodes_appended {
+                        *nodes_appended = false;
+                        on_nodes_appended(root);
+                    }
                 }
                 Done(*) => {
                     break;
@@ -392,6 +522,8 @@ pub fn parse_html(scope: NodeScope,
             }
         }
 
+        *writable_parser = None;
+
         css_chan.send(CSSTaskExit);
         js_chan.send(JSTaskExit);
 
@@ -399,3 +531,199 @@ pub fn parse_html(scope: NodeScope,
     }
 }
 
+// The concatenated text of the children of `node`
+fn text_content(scope: &NodeScope, node: Node) -> ~str {
+    let mut text = ~"";
+    for scope.each_child(&node) |child| {
+        do scope.read(child) |child_contents| {
+            match *child_contents.kind {
+                Text(ref data) => text += *data,
+                _ => ()
+            }
+        }
+    }
+    move text
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use dom::event::Event;
+    use dom::node::{Comment, Doctype, Element, Node, NodeScope, Text};
+    use layout::layout_task::{AppendNodesMsg, BuildData, FinishMsg, Msg, ReflowDamage};
+    use resource::image_cache_task::{Exit, GetSrcset, ImageCacheTask, ImageCandidate, ListUrls};
+    use resource::image_cache_task::Sync;
+    use resource::resource_task::{Done, Payload, ResourceTask};
+    use resource::resource_task;
+    use test_support::mock_resource_task;
+
+    use core::pipes::{Chan, SharedChan, stream};
+    use geom::size::Size2D;
+    use gfx::util::url::make_url;
+    use std::net::url::Url;
+
+    fn chunked_resource_task(chunks: ~[~[u8]]) -> ResourceTask {
+        do mock_resource_task |_url, response, move chunks| {
+            for chunks.each |chunk| {
+                response.send(Payload(copy *chunk));
+            }
+            response.send(Done(Ok(())));
+        }
+    }
+
+    fn no_scripts(_root: Node, _script: ~[u8]) -> ~str { ~"" }
+
+    fn build_data(node: Node) -> BuildData {
+        let (_event_port, event_chan) = stream::<Event>();
+        let (_join_port, join_chan) = stream();
+        BuildData {
+            node: node,
+            url: make_url(~"test.html", None),
+            dom_event_chan: SharedChan(move event_chan),
+            window_size: Size2D(800u, 600u),
+            content_join_chan: move join_chan,
+            damage: ReflowDamage,
+        }
+    }
+
+    fn describe_tree(scope: &NodeScope, node: Node) -> ~str {
+        let mut s = do scope.read(&node) |data| {
+            match *data.kind {
+                Element(ref element) => copy element.tag_name,
+                Text(ref text) => ~"\"" + *text + ~"\"",
+                Comment(*) => ~"#comment",
+                Doctype(*) => ~"#doctype"
+            }
+        };
+        s += ~"(";
+        let mut child = scope.read(&node, |data| data.tree.first_child);
+        loop {
+            match child {
+                None => break,
+                Some(c) => {
+                    s += describe_tree(scope, c);
+                    child = scope.read(&c, |data| data.tree.next_sibling);
+                }
+            }
+        }
+        s + ~")"
+    }
+
+    fn shut_down(resource_task: &ResourceTask, image_cache_task: &ImageCacheTask) {
+        let (exit_port, exit_chan) = stream();
+        image_cache_task.send(Exit(move exit_chan));
+        exit_port.recv();
+        resource_task.send(resource_task::Exit);
+    }
+
+    // Parses `html` as http://example.com/test.html, then hands `f` the image cache once it
+    // has handled everything the parser sent it
+    fn with_parsed_images(html: &str, f: fn(&ImageCacheTask, &Url)) {
+        let resource_task = chunked_resource_task(~[str::to_bytes(html)]);
+        let image_cache_task = ImageCacheTask(resource_task.clone());
+        let url = make_url(~"http://example.com/test.html", None);
+
+        do parse_html(NodeScope(), copy url, resource_task.clone(), image_cache_task.clone(),
+                      Size2D(800u, 600u), 1.0, no_scripts) |_root| { };
+
+        let (sync_port, sync_chan) = stream();
+        image_cache_task.send(Sync(move sync_chan));
+        sync_port.recv();
+
+        f(&image_cache_task, &url);
+        shut_down(&resource_task, &image_cache_task);
+    }
+
+    fn parse_chunks(chunks: ~[~str], layout_chan: &Chan<Msg>) -> ~str {
+        parse_byte_chunks(chunks.map(|chunk| str::to_bytes(*chunk)), layout_chan)
+    }
+
+    fn parse_byte_chunks(chunks: ~[~[u8]], layout_chan: &Chan<Msg>) -> ~str {
+        let resource_task = chunked_resource_task(move chunks);
+        let image_cache_task = ImageCacheTask(resource_task.clone());
+        let scope = NodeScope();
+        let url = make_url(~"test.html", None);
+
+        let result = do parse_html(scope, move url, resource_task.clone(),
+                                   image_cache_task.clone(), Size2D(800u, 600u), 1.0,
+                                   no_scripts) |root| {
+            layout_chan.send(AppendNodesMsg(build_data(root)));
+        };
+        layout_chan.send(FinishMsg(build_data(result.root)));
+
+        shut_down(&resource_task, &image_cache_task);
+
+        describe_tree(&scope, result.root)
+    }
+
+    #[test]
+    fn should_send_appended_nodes_to_layout_before_finishing() {
+        let (layout_port, layout_chan) = stream();
+        let progressive = parse_chunks(~[~"<html><body><p>one</p>",
+                                         ~"<p>two</p></body></html>"], &layout_chan);
+
+        let mut appends = 0;
+        loop {
+            match layout_port.recv() {
+                AppendNodesMsg(_) => appends += 1,
+                FinishMsg(_) => break,
+                _ => fail!(~"unexpected layout message")
+            }
+        }
+        assert appends == 2;
+        assert !layout_port.peek();
+
+        let (_oneshot_port, oneshot_chan) = stream();
+        let oneshot = parse_chunks(~[~"<html><body><p>one</p><p>two</p></body></html>"],
+                                   &oneshot_chan);
+        assert progressive == oneshot;
+    }
+
+    #[test]
+    fn should_replace_invalid_utf8_in_text() {
+        let (_layout_port, layout_chan) = stream();
+        let mut html = str::to_bytes("<html><body><p>a");
+        // 0xC3 starts a two byte sequence, but 'b' can't continue it
+        html.push(0xC3);
+        html.push_all(str::to_bytes("b</p></body></html>"));
+
+        let tree = parse_byte_chunks(~[move html], &layout_chan);
+        assert str::contains(tree, "\"a\uFFFDb\"");
+    }
+
+    #[test]
+    fn should_only_prefetch_eager_images_while_parsing() {
+        do with_parsed_images(
+            "<html><body><img src=\"lazy.png\" loading=\"lazy\">\
+             <img src=\"shouty.png\" loading=\"LAZY\">\
+             <img src=\"eager.png\" loading=\"eager\"><img src=\"default.png\">\
+             </body></html>") |image_cache_task, url| {
+            let (list_port, list_chan) = stream();
+            image_cache_task.send(ListUrls(move list_chan));
+            let urls = list_port.recv().map(|&(ref url, _)| copy *url);
+            assert urls.len() == 2;
+            assert urls.contains(&make_url(~"eager.png", Some(copy *url)));
+            assert urls.contains(&make_url(~"default.png", Some(copy *url)));
+        }
+    }
+
+    #[test]
+    fn should_register_srcset_candidates_with_the_cache() {
+        do with_parsed_images(
+            "<html><body><img src=\"small.png\" srcset=\"small.png 1x, images/big.png 2x\">\
+             </body></html>") |image_cache_task, _url| {
+            let small = make_url(~"http://example.com/small.png", None);
+            let big = make_url(~"http://example.com/images/big.png", None);
+            let (srcset_port, srcset_chan) = stream();
+            image_cache_task.send(GetSrcset(copy small, move srcset_chan));
+            assert srcset_port.recv() == ~[ImageCandidate { url: copy small, density: 1.0 },
+                                           ImageCandidate { url: copy big, density: 2.0 }];
+
+            // Only the candidate for a 1x display is fetched
+            let (list_port, list_chan) = stream();
+            image_cache_task.send(ListUrls(move list_chan));
+            let urls = list_port.recv().map(|&(ref url, _)| copy *url);
+            assert urls == ~[small];
+        }
+    }
+}
@@ -0,0 +1,146 @@
+/*!
+Parsing of the `srcset` and `sizes` attributes of `<img>`, which offer
+alternative images for displays of different pixel densities and for
+different rendered widths.
+*/
+
+use css::media::evaluate_media_query_list;
+
+use geom::size::Size2D;
+use gfx::resource::image_cache_task::ImageCandidate;
+use gfx::util::url::make_url;
+use std::net::url::Url;
+
+/// The px in an `em` in `sizes` lengths
+const SIZES_EM_PX: float = 16.0;
+
+/**
+Parses `srcset` into candidates with URLs resolved against `base`. Width
+descriptors (`480w`) are turned into densities by dividing by the image's
+rendered width, which `sizes` gives for the viewport. A candidate without a
+descriptor is 1x. Candidates with malformed descriptors are skipped.
+*/
+pub fn parse_srcset(srcset: &str, sizes: Option<~str>, base: &Url,
+                    viewport: Size2D<uint>) -> ~[ImageCandidate] {
+    let source_width = match sizes {
+        Some(ref sizes) => parse_sizes(*sizes, viewport),
+        None => viewport.width as float
+    };
+
+    let mut candidates = ~[];
+    for str::split_char(srcset, ',').each |candidate| {
+        let words = str::words(*candidate);
+        if words.is_empty() || words.len() > 2 {
+            loop;
+        }
+
+        let density = if words.len() == 1 {
+            Some(1.0)
+        } else {
+            parse_descriptor(words[1], source_width)
+        };
+        match density {
+            Some(density) => {
+                candidates.push(ImageCandidate {
+                    url: make_url(copy words[0], Some(copy *base)),
+                    density: density
+                });
+            }
+            None => debug!("skipping srcset candidate with a malformed descriptor: %s",
+                           *candidate)
+        }
+    }
+    candidates
+}
+
+// The density a descriptor such as `2x` or `480w` gives, if it's well formed
+fn parse_descriptor(descriptor: &str, source_width: float) -> Option<float> {
+    // Split by char, since a malformed unit needn't be a single byte
+    let chars = str::chars(descriptor);
+    if chars.len() < 2 {
+        return None;
+    }
+    let number = str::from_chars(vec::slice(chars, 0, chars.len() - 1));
+    match chars.last() {
+        'x' => match float::from_str(number) {
+            Some(density) if density > 0.0 => Some(density),
+            _ => None
+        },
+        'w' => match uint::from_str(number) {
+            Some(width) if width > 0 && source_width > 0.0 => {
+                Some((width as float) / source_width)
+            }
+            _ => None
+        },
+        _ => None
+    }
+}
+
+/**
+The width in px that `sizes` gives an image: the length of the first entry
+whose media condition matches `viewport`, or of the last entry, which has no
+condition. Anything unparseable is skipped, and the default is the viewport
+width.
+*/
+pub fn parse_sizes(sizes: &str, viewport: Size2D<uint>) -> float {
+    for str::split_char(sizes, ',').each |entry| {
+        let entry = str::trim(*entry);
+        let (condition, length) = match str::rfind_char(entry, ' ') {
+            Some(i) => (str::slice(entry, 0, i), str::slice(entry, i + 1, entry.len())),
+            None => (~"", copy entry)
+        };
+        if !evaluate_media_query_list(condition, viewport) {
+            loop;
+        }
+        match parse_size_length(length, viewport) {
+            Some(width) => return width,
+            None => ()
+        }
+    }
+    viewport.width as float
+}
+
+// A length in px, em or vw
+fn parse_size_length(length: &str, viewport: Size2D<uint>) -> Option<float> {
+    let units = [("px", 1.0), ("em", SIZES_EM_PX), ("vw", (viewport.width as float) / 100.0)];
+    for units.each |&(unit, scale)| {
+        if str::ends_with(length, unit) {
+            return float::from_str(str::slice(length, 0, length.len() - unit.len()))
+                .map(|n| *n * scale);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use geom::size::Size2D;
+    use gfx::resource::image_cache_task::ImageCandidate;
+    use gfx::util::url::make_url;
+
+    #[test]
+    fn should_parse_density_candidates_and_skip_malformed_ones() {
+        let base = make_url(~"http://example.com/page.html", None);
+        let candidates = parse_srcset("a.png, b.png 2x, c.png 2y, d.png 1x 2x, e.png 2\xe9",
+                                      None, &base, Size2D(800u, 600u));
+        assert candidates == ~[
+            ImageCandidate { url: make_url(~"http://example.com/a.png", None), density: 1.0 },
+            ImageCandidate { url: make_url(~"http://example.com/b.png", None), density: 2.0 }
+        ];
+    }
+
+    #[test]
+    fn should_turn_widths_into_densities_with_sizes() {
+        let base = make_url(~"http://example.com/page.html", None);
+        let viewport = Size2D(800u, 600u);
+        assert parse_sizes("(max-width: 500px) 100vw, 400px", viewport) == 400.0;
+        assert parse_sizes("(min-width: 500px) 50vw, 400px", viewport) == 400.0;
+        assert parse_sizes("nonsense", viewport) == 800.0;
+
+        let candidates = parse_srcset("small.png 400w, large.png 800w", Some(~"400px"), &base,
+                                      viewport);
+        assert candidates.map(|c| c.density) == ~[1.0, 2.0];
+    }
+}
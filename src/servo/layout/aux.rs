@@ -19,7 +19,10 @@ impl Node : LayoutAuxMethods {
             false => {
                 let data = @LayoutData({
                     mut style : None,
-                    mut flow  : None
+                    mut flow  : None,
+                    mut style_epoch : None,
+                    mut before_content : None,
+                    mut after_content  : None
                 });
                 self.set_aux(data); Some(data)
             },
@@ -3,6 +3,7 @@
 use layout::box::{RenderBox};
 use layout::context::LayoutContext;
 use layout::display_list_builder::{DisplayListBuilder, FlowDisplayListBuilderMethods};
+use layout::flex::FlexLayout;
 use layout::flow::{FlowContext, FlowTree, InlineBlockFlow, BlockFlow, RootFlow};
 use layout::inline::InlineLayout;
 use newcss::values::*;
@@ -13,7 +14,7 @@ use core::mutable::Mut;
 use geom::point::Point2D;
 use geom::rect::Rect;
 use geom::size::Size2D;
-use gfx::display_list::DisplayList;
+use gfx::display_list::{DisplayItem, DisplayList};
 use gfx::geometry::Au;
 
 pub struct BlockFlowData {
@@ -116,9 +117,26 @@ impl FlowContext : BlockLayout {
         /* Let the box consume some width. It will return the amount remaining
            for its children. */
         do self.with_block_box |box| {
+            let containing_width = remaining_width;
             box.d().position.size.width = remaining_width;
             let (left_used, right_used) = box.get_used_width();
             remaining_width -= left_used.add(&right_used);
+
+            // An explicit 'width' takes precedence over the containing block's
+            match box.specified_content_width(containing_width) {
+                Some(width) => {
+                    box.d().position.size.width = width;
+                    remaining_width = width;
+                }
+                None => ()
+            }
+        }
+
+        if self.is_flex_container() {
+            let mut justify = CSSJustifyContentFlexStart;
+            do self.with_block_box |box| { justify = box.justify_content(); }
+            self.assign_widths_flex(justify, left_used, remaining_width);
+            return;
         }
 
         for FlowTree.each_child(self) |child_ctx| {
@@ -133,9 +151,15 @@ impl FlowContext : BlockLayout {
 
         let mut cur_y = Au(0);
 
-        for FlowTree.each_child(self) |child_ctx| {
-            child_ctx.d().position.origin.y = cur_y;
-            cur_y += child_ctx.d().position.size.height;
+        if self.is_flex_container() {
+            let mut align = CSSAlignItemsStretch;
+            do self.with_block_box |box| { align = box.align_items(); }
+            cur_y = self.assign_height_flex(align);
+        } else {
+            for FlowTree.each_child(self) |child_ctx| {
+                child_ctx.d().position.origin.y = cur_y;
+                cur_y += child_ctx.d().position.size.height;
+            }
         }
 
         self.d().position.size.height = cur_y;
@@ -154,17 +178,105 @@ impl FlowContext : BlockLayout {
                                 offset: &Point2D<Au>, list: &Mut<DisplayList>) {
 
         assert self.starts_block_flow();
-        
-        // add box that starts block context
+
+        let mut opacity = 1.0;
         do self.with_block_box |box| {
-            box.build_display_list(builder, dirty, offset, list)
+            opacity = box.opacity();
+        }
+        if opacity <= 0.0 {
+            return;
+        }
+        if opacity < 1.0 {
+            // Translucent blocks are painted as a group, so that their
+            // contents don't show through each other
+            let group = Mut(DisplayList::new());
+            build_block_contents(self, builder, dirty, offset, &group);
+            let bounds = Rect(*offset, self.d().position.size);
+            do list.borrow_mut |list| {
+                list.append_item(~DisplayItem::new_OpacityGroup(&bounds, opacity,
+                                                                ~group.unwrap()));
+            }
+            return;
         }
 
-        // TODO: handle any out-of-flow elements
+        build_block_contents(self, builder, dirty, offset, list);
+    }
+}
+
+// Adds the block's box and its child flows to the display list
+fn build_block_contents(flow: @FlowContext, builder: &DisplayListBuilder, dirty: &Rect<Au>,
+                        offset: &Point2D<Au>, list: &Mut<DisplayList>) {
+    // add box that starts block context
+    do flow.with_block_box |box| {
+        box.build_display_list(builder, dirty, offset, list)
+    }
 
-        // go deeper into the flow tree
-        for FlowTree.each_child(self) |child| {
-            self.build_display_list_for_child(builder, child, dirty, offset, list)
+    // TODO: handle any out-of-flow elements
+
+    // A flow that establishes a stacking context paints the contexts
+    // established by its descendants, down to the next contexts, in order of
+    // stack level and then of the document: those below level 0 beneath its
+    // in-flow descendants, the rest above them. The flows in between leave
+    // them out.
+    let contexts = if establishes_stacking_context(flow) {
+        let mut contexts = ~[];
+        collect_stacking_contexts(flow, offset, &mut contexts);
+        do std::sort::merge_sort(contexts) |&(a, _, _), &(b, _, _)| { a <= b }
+    } else {
+        ~[]
+    };
+
+    for contexts.each |&(level, child, parent_offset)| {
+        if level < 0 {
+            flow.build_display_list_for_child(builder, child, dirty, &parent_offset, list)
         }
     }
+
+    // go deeper into the flow tree
+    for FlowTree.each_child(flow) |child| {
+        if stack_level(child).is_none() {
+            flow.build_display_list_for_child(builder, child, dirty, offset, list)
+        }
+    }
+
+    for contexts.each |&(level, child, parent_offset)| {
+        if level >= 0 {
+            flow.build_display_list_for_child(builder, child, dirty, &parent_offset, list)
+        }
+    }
+}
+
+// Whether a flow paints the stacking contexts below it. Flows painted on their own, such
+// as the root, are treated as establishing one.
+fn establishes_stacking_context(flow: @FlowContext) -> bool {
+    stack_level(flow).is_some() || tree::get_parent(&FlowTree, &flow).is_none()
+}
+
+/**
+Adds the descendants of `flow`, at `offset`, that establish stacking contexts,
+without looking inside them. Each comes with its stack level and the offset of
+its parent.
+*/
+fn collect_stacking_contexts(flow: @FlowContext, offset: &Point2D<Au>,
+                             contexts: &mut ~[(int, @FlowContext, Point2D<Au>)]) {
+    for FlowTree.each_child(flow) |child| {
+        match stack_level(child) {
+            Some(level) => contexts.push((level, child, *offset)),
+            None => {
+                let child_offset = offset.add(&child.d().position.origin);
+                collect_stacking_contexts(child, &child_offset, contexts)
+            }
+        }
+    }
+}
+
+// The stack level of the stacking context a flow establishes, if any
+fn stack_level(flow: @FlowContext) -> Option<int> {
+    match *flow {
+        BlockFlow(*) => match flow.block().box {
+            Some(box) => box.stack_level(),
+            None => None
+        },
+        _ => None
+    }
 }
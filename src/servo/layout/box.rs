@@ -13,10 +13,24 @@ use newcss::color::{Color, rgba, rgb};
 use newcss::complete::CompleteStyle;
 use newcss::units::{BoxSizing, Cursive, Em, Fantasy, Length, Monospace, Pt, Px, SansSerif, Serif};
 use newcss::values::{CSSBackgroundColorColor, CSSBackgroundColorTransparent, CSSBorderColor};
-use newcss::values::{CSSBorderWidthLength, CSSBorderWidthMedium, CSSDisplay};
+use newcss::values::{CSSBorderWidthLength, CSSBorderWidthMedium, CSSDirection, CSSDisplay};
 use newcss::values::{CSSFontFamilyFamilyName, CSSFontFamilyGenericFamily, CSSPositionAbsolute};
+use newcss::values::{CSSPosition, CSSPositionStatic, CSSZIndexAuto, CSSZIndexInteger};
 use newcss::values::{CSSFontSizeLength, CSSFontStyleItalic, CSSFontStyleNormal};
 use newcss::values::{CSSFontStyleOblique, CSSTextAlign, Specified};
+use newcss::values::{CSSFontWeight100, CSSFontWeight200, CSSFontWeight300, CSSFontWeight400};
+use newcss::values::{CSSFontWeight500, CSSFontWeight600, CSSFontWeight700, CSSFontWeight800};
+use newcss::values::{CSSFontWeight900, CSSFontWeightBold, CSSFontWeightBolder};
+use newcss::values::{CSSFontWeightLighter, CSSFontWeightNormal};
+use newcss::values::{CSSLineHeightLength, CSSLineHeightNormal, CSSLineHeightNumber};
+use newcss::values::{CSSLineHeightPercentage, CSSWhiteSpace};
+use newcss::values::{CSSAlignItems, CSSJustifyContent};
+use newcss::values::{CSSVisibility, CSSVisibilityCollapse, CSSVisibilityHidden};
+use newcss::values::{CSSVisibilityVisible};
+use newcss::values::{CSSBoxSizing, CSSBoxSizingBorderBox, CSSBoxSizingContentBox};
+use newcss::values::{CSSBorderWidth, CSSPadding, CSSPaddingLength, CSSPaddingPercentage};
+use newcss::values::{CSSWidthAuto, CSSWidthLength, CSSWidthPercentage};
+use newcss::values::{CSSHeightAuto, CSSHeightLength, CSSHeightPercentage};
 use util::tree::ReadMethods;
 
 use core::dvec::DVec;
@@ -27,7 +41,10 @@ use core::task::spawn;
 use core::to_str::ToStr;
 use geom::{Point2D, Rect, Size2D};
 use gfx::display_list::{DisplayItem, DisplayList};
-use gfx::font::{FontStyle, FontWeight300};
+use gfx::font::{CSSFontWeight, FontStyle, FontWeight100, FontWeight200, FontWeight300};
+use gfx::font::{FontWeight400, FontWeight500, FontWeight600, FontWeight700, FontWeight800};
+use gfx::font::{FontWeight900};
+use gfx::font_context::FontDefaults;
 use gfx::geometry::Au;
 use gfx::image::base::Image;
 use gfx::image::holder::ImageHolder;
@@ -309,14 +326,8 @@ impl RenderBox  {
        Coordinates are relative to the owning flow. */
     pure fn content_box() -> Rect<Au> {
         match &self {
-            &ImageBox(_, ref i) => {
-                let size = i.size();
-                Rect {
-                    origin: copy self.d().position.origin,
-                    size:   Size2D(Au::from_px(size.width),
-                                   Au::from_px(size.height))
-                }
-            },
+            // Layout sizes images from their intrinsic size and 'width' and 'height'
+            &ImageBox(*) => copy self.d().position,
             &GenericBox(*) => {
                 copy self.d().position
                 /* FIXME: The following hits an ICE for whatever reason
@@ -386,7 +397,12 @@ impl RenderBox  {
     */
     fn build_display_list(@self, _builder: &DisplayListBuilder, dirty: &Rect<Au>,
                           offset: &Point2D<Au>, list: &Mut<DisplayList>) {
+        self.add_display_items(dirty, offset, list)
+    }
 
+    /// Appends the items painting this box within `dirty` to `list`, in paint order
+    fn add_display_items(@self, dirty: &Rect<Au>, offset: &Point2D<Au>,
+                         list: &Mut<DisplayList>) {
         let box_bounds = self.d().position;
 
         let abs_box_bounds = box_bounds.translate(offset);
@@ -400,6 +416,11 @@ impl RenderBox  {
             return;
         }
 
+        // Invisible boxes still take up space, but paint nothing
+        if !is_painted(self.visibility()) {
+            return;
+        }
+
         self.add_bgcolor_to_list(list, &abs_box_bounds); 
 
         match self {
@@ -526,6 +547,12 @@ impl RenderBox  {
 
     // Converts this node's ComputedStyle to a font style used in the graphics code.
     fn font_style(@self) -> FontStyle {
+        self.font_style_with_defaults(&FontDefaults::new())
+    }
+
+    /// The font style of the box, taking the family and size from `defaults`
+    /// when its style doesn't give them
+    fn font_style_with_defaults(@self, defaults: &FontDefaults) -> FontStyle {
         do self.with_style_of_nearest_element |my_style| {
             let font_families = do my_style.font_family().map |family| {
                 match *family {
@@ -537,14 +564,18 @@ impl RenderBox  {
                     CSSFontFamilyGenericFamily(Monospace)   => ~"monospace",
                 }
             };
-            let font_families = str::connect(font_families, ~", ");
+            let font_families = if font_families.is_empty() {
+                copy defaults.family
+            } else {
+                str::connect(font_families, ~", ")
+            };
             debug!("(font style) font families: `%s`", font_families);
 
             let font_size = match my_style.font_size() {
                 CSSFontSizeLength(Px(l)) |
                 CSSFontSizeLength(Pt(l)) => l,
                 CSSFontSizeLength(Em(l)) => l,
-                _ => 16f
+                _ => defaults.size
             };
             debug!("(font style) font size: `%f`", font_size);
 
@@ -555,9 +586,22 @@ impl RenderBox  {
                 CSSFontStyleOblique => { italic = false; oblique = true;  }
             }
 
+            // TODO: bolder and lighter should be relative to the parent's weight
+            let weight: CSSFontWeight = match my_style.font_weight() {
+                CSSFontWeight100 | CSSFontWeightLighter => FontWeight100,
+                CSSFontWeight200    => FontWeight200,
+                CSSFontWeight300    => FontWeight300,
+                CSSFontWeight400 | CSSFontWeightNormal => FontWeight400,
+                CSSFontWeight500    => FontWeight500,
+                CSSFontWeight600    => FontWeight600,
+                CSSFontWeight700 | CSSFontWeightBold | CSSFontWeightBolder => FontWeight700,
+                CSSFontWeight800    => FontWeight800,
+                CSSFontWeight900    => FontWeight900,
+            };
+
             FontStyle {
                 pt_size: font_size,
-                weight: FontWeight300,
+                weight: weight,
                 italic: italic,
                 oblique: oblique,
                 families: move font_families,
@@ -565,12 +609,163 @@ impl RenderBox  {
         }
     }
 
+    // Computes the used 'line-height' of this box from the nearest element's style. Replaced
+    // content is as tall as its content box.
+    fn line_height(@self) -> Au {
+        match self {
+            @TextBox(_, data) => {
+                let metrics = &data.run.font.metrics;
+                do self.with_style_of_nearest_element |my_style| {
+                    match my_style.line_height() {
+                        CSSLineHeightNormal => metrics.ascent + metrics.descent + metrics.leading,
+                        CSSLineHeightNumber(n) => metrics.em_size.scale_by(n),
+                        CSSLineHeightPercentage(p) => metrics.em_size.scale_by(p / 100f),
+                        CSSLineHeightLength(Px(l)) => Au::from_frac_px(l),
+                        CSSLineHeightLength(Pt(l)) => Au::from_pt(l),
+                        CSSLineHeightLength(Em(l)) => metrics.em_size.scale_by(l),
+                    }
+                }
+            },
+            _ => self.d().position.size.height
+        }
+    }
+
     // Converts this node's ComputedStyle to a text alignment used in the inline layout code.
     fn text_align(@self) -> CSSTextAlign {
         do self.with_style_of_nearest_element |my_style| {
             my_style.text_align()
         }
     }
+
+    // The 'direction' property, which is the base direction of the lines of text the box is in
+    fn direction(@self) -> CSSDirection {
+        do self.with_style_of_nearest_element |my_style| {
+            my_style.direction()
+        }
+    }
+
+    // Returns the 'white-space' property, which controls line breaking in the inline layout code.
+    fn white_space(@self) -> CSSWhiteSpace {
+        do self.with_style_of_nearest_element |my_style| {
+            my_style.white_space()
+        }
+    }
+
+    fn visibility(@self) -> CSSVisibility {
+        do self.with_style_of_nearest_element |my_style| {
+            my_style.visibility()
+        }
+    }
+
+    fn display(@self) -> CSSDisplay {
+        do self.with_style_of_nearest_element |my_style| {
+            my_style.display(false)
+        }
+    }
+
+    // The 'justify-content' and 'align-items' properties of a flex container.
+    fn justify_content(@self) -> CSSJustifyContent {
+        do self.with_style_of_nearest_element |my_style| {
+            my_style.justify_content()
+        }
+    }
+
+    fn align_items(@self) -> CSSAlignItems {
+        do self.with_style_of_nearest_element |my_style| {
+            my_style.align_items()
+        }
+    }
+
+    // The 'opacity' property, from 0 (transparent) to 1 (opaque)
+    fn opacity(@self) -> float {
+        do self.with_style_of_nearest_element |my_style| {
+            float::fmax(0.0, float::fmin(my_style.opacity(), 1.0))
+        }
+    }
+
+    fn css_position(@self) -> CSSPosition {
+        do self.with_style_of_nearest_element |my_style| {
+            my_style.position()
+        }
+    }
+
+    /**
+    The stack level of the stacking context this box establishes, or None if
+    it doesn't establish one. Positioned boxes with an integer z-index do, at
+    that level; translucent boxes do at level 0.
+    */
+    fn stack_level(@self) -> Option<int> {
+        let z_index = do self.with_style_of_nearest_element |my_style| {
+            my_style.z_index()
+        };
+        match (self.css_position(), z_index) {
+            (CSSPositionStatic, _) | (_, CSSZIndexAuto) => {
+                if self.opacity() < 1.0 { Some(0) } else { None }
+            }
+            (_, CSSZIndexInteger(level)) => Some(level)
+        }
+    }
+
+    fn box_sizing(@self) -> CSSBoxSizing {
+        do self.with_style_of_nearest_element |my_style| {
+            my_style.box_sizing()
+        }
+    }
+
+    /* The sum of the left and right padding and border widths. Percentage
+       padding resolves against the width of the containing block. */
+    fn horizontal_padding_and_border(@self, containing_width: Au) -> Au {
+        let em = Au::from_pt(self.font_style().pt_size);
+        do self.with_style_of_nearest_element |my_style| {
+            padding_to_au(my_style.padding_left(), containing_width, em) +
+                padding_to_au(my_style.padding_right(), containing_width, em) +
+                border_width_to_au(my_style.border_left_width(), em) +
+                border_width_to_au(my_style.border_right_width(), em)
+        }
+    }
+
+    /* The width of the content box given by the 'width' property, or None for
+       'width: auto'. A percentage resolves against the containing block
+       before padding and border are taken off for 'box-sizing: border-box'. */
+    fn specified_content_width(@self, containing_width: Au) -> Option<Au> {
+        let em = Au::from_pt(self.font_style().pt_size);
+        let width = do self.with_style_of_nearest_element |my_style| {
+            match my_style.width() {
+                CSSWidthAuto => None,
+                CSSWidthLength(l) => Some(length_to_au(l, em)),
+                CSSWidthPercentage(p) => Some(containing_width.scale_by(p / 100f))
+            }
+        };
+        do width.map |width| {
+            content_width(self.box_sizing(), *width,
+                          self.horizontal_padding_and_border(containing_width))
+        }
+    }
+
+    /* The height of the content box given by the 'height' property, or None for
+       'height: auto'. TODO: percentages need the height of the containing
+       block, so are treated as 'auto' for now. */
+    fn specified_content_height(@self) -> Option<Au> {
+        let em = Au::from_pt(self.font_style().pt_size);
+        do self.with_style_of_nearest_element |my_style| {
+            match my_style.height() {
+                CSSHeightAuto | CSSHeightPercentage(*) => None,
+                CSSHeightLength(l) => Some(length_to_au(l, em))
+            }
+        }
+    }
+
+    /* The size of an image box's content: its intrinsic size, adjusted by
+       'width' and 'height'. Images that haven't loaded have no size yet. */
+    fn image_size(@self, containing_width: Au) -> Size2D<Au> {
+        let intrinsic = match self {
+            @ImageBox(_, ref i) => i.get_size().get_or_default(Size2D(0, 0)),
+            _ => fail!(~"image_size of a box that isn't an image")
+        };
+        replaced_size(Size2D(Au::from_px(intrinsic.width), Au::from_px(intrinsic.height)),
+                      self.specified_content_width(containing_width),
+                      self.specified_content_height())
+    }
 }
 
 impl RenderBox : BoxedDebugMethods {
@@ -616,6 +811,82 @@ impl RenderBox {
     }
 }
 
+/**
+Returns the value of 'visibility' that layout acts on. `collapse` only differs
+from `hidden` on table rows and columns, where it removes the whole track;
+until table layout exists it is treated as `hidden` everywhere.
+*/
+pub pure fn used_visibility(visibility: CSSVisibility) -> CSSVisibility {
+    match visibility {
+        // TODO: remove collapsed table rows and columns during table layout
+        CSSVisibilityCollapse => CSSVisibilityHidden,
+        v => v
+    }
+}
+
+pub pure fn is_painted(visibility: CSSVisibility) -> bool {
+    match used_visibility(visibility) {
+        CSSVisibilityVisible => true,
+        _ => false
+    }
+}
+
+/// Converts the specified value of 'width' to the width of the content box.
+pub pure fn content_width(box_sizing: CSSBoxSizing, width: Au, padding_and_border: Au) -> Au {
+    match box_sizing {
+        CSSBoxSizingContentBox => width,
+        CSSBoxSizingBorderBox => Au::max(Au(0), width - padding_and_border)
+    }
+}
+
+/**
+Returns the size of replaced content such as an image, given its intrinsic
+size and the specified width and height, None being 'auto'. With one
+dimension specified, the other keeps the intrinsic aspect ratio; with both,
+the content is stretched to fit.
+*/
+pub pure fn replaced_size(intrinsic: Size2D<Au>, width: Option<Au>,
+                          height: Option<Au>) -> Size2D<Au> {
+    match (width, height) {
+        (Some(width), Some(height)) => Size2D(width, height),
+        (Some(width), None) if intrinsic.width != Au(0) => {
+            Size2D(width, width.scale_by((*intrinsic.height as float) /
+                                         (*intrinsic.width as float)))
+        }
+        (None, Some(height)) if intrinsic.height != Au(0) => {
+            Size2D(height.scale_by((*intrinsic.width as float) /
+                                   (*intrinsic.height as float)), height)
+        }
+        // Without a ratio to keep, the unspecified dimension is intrinsic
+        (Some(width), None) => Size2D(width, intrinsic.height),
+        (None, Some(height)) => Size2D(intrinsic.width, height),
+        (None, None) => intrinsic
+    }
+}
+
+pure fn length_to_au(length: Length, em: Au) -> Au {
+    match length {
+        Px(l) => Au::from_frac_px(l),
+        Pt(l) => Au::from_pt(l),
+        Em(l) => em.scale_by(l)
+    }
+}
+
+pure fn padding_to_au(padding: CSSPadding, containing_width: Au, em: Au) -> Au {
+    match padding {
+        CSSPaddingLength(l) => length_to_au(l, em),
+        CSSPaddingPercentage(p) => containing_width.scale_by(p / 100f)
+    }
+}
+
+pure fn border_width_to_au(width: CSSBorderWidth, em: Au) -> Au {
+    match width {
+        CSSBorderWidthLength(l) => length_to_au(l, em),
+        // TODO: thin, medium and thick only matter once borders are styled
+        _ => Au(0)
+    }
+}
+
 // FIXME: This belongs somewhere else
 trait ToGfxColor {
     fn to_gfx_color(&self) -> gfx::color::Color;
@@ -629,3 +900,78 @@ impl Color: ToGfxColor {
                          self.alpha)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use css::matching::MatchMethods;
+    use css::select::test_stylesheet;
+    use dom::element::{ElementData, HTMLDivElement};
+    use dom::node::{Element, NodeScope, NodeScopeExtensions};
+    use layout::aux::LayoutAuxMethods;
+    use layout::block::BlockFlowData;
+    use layout::flow::{BlockFlow, FlowData};
+
+    use core::dvec::DVec;
+    use newcss::select::SelectCtx;
+    use newcss::types::OriginAuthor;
+
+    fn styled_box(css: &str) -> @RenderBox {
+        let scope = NodeScope();
+        let div = scope.new_node(Element(ElementData(~"div", ~HTMLDivElement)));
+        let refs = DVec();
+        div.initialize_style_for_subtree(&refs);
+
+        let mut select_ctx = SelectCtx::new();
+        select_ctx.append_sheet(test_stylesheet(css), OriginAuthor);
+        div.restyle_subtree(&select_ctx);
+
+        let flow = @BlockFlow(FlowData(0), BlockFlowData());
+        @GenericBox(RenderBoxData(div, flow, 0))
+    }
+
+    #[test]
+    fn should_treat_visibility_collapse_as_hidden() {
+        let box = styled_box("div { visibility: collapse }");
+
+        assert box.visibility() == CSSVisibilityCollapse;
+        assert used_visibility(box.visibility()) == CSSVisibilityHidden;
+        assert !is_painted(box.visibility());
+        assert is_painted(CSSVisibilityVisible);
+    }
+
+    #[test]
+    fn should_subtract_padding_from_border_box_widths() {
+        let containing_width = Au::from_px(500);
+
+        let border_box = styled_box("div { width: 100px; padding: 10px; box-sizing: border-box }");
+        assert border_box.specified_content_width(containing_width) == Some(Au::from_px(80));
+
+        let content_box = styled_box("div { width: 100px; padding: 10px; box-sizing: content-box }");
+        assert content_box.specified_content_width(containing_width) == Some(Au::from_px(100));
+
+        // Percentages resolve before padding is subtracted
+        let percentage = styled_box("div { width: 20%; padding: 10px; box-sizing: border-box }");
+        assert percentage.specified_content_width(containing_width) == Some(Au::from_px(80));
+    }
+
+    #[test]
+    fn should_keep_the_intrinsic_ratio_with_one_dimension_specified() {
+        let intrinsic = Size2D(Au::from_px(200), Au::from_px(100));
+
+        assert replaced_size(intrinsic, Some(Au::from_px(50)), None) ==
+            Size2D(Au::from_px(50), Au::from_px(25));
+        assert replaced_size(intrinsic, None, Some(Au::from_px(50))) ==
+            Size2D(Au::from_px(100), Au::from_px(50));
+    }
+
+    #[test]
+    fn should_use_intrinsic_size_when_both_dimensions_are_auto() {
+        let intrinsic = Size2D(Au::from_px(200), Au::from_px(100));
+
+        assert replaced_size(intrinsic, None, None) == intrinsic;
+        // Specifying both stretches the image
+        assert replaced_size(intrinsic, Some(Au::from_px(50)), Some(Au::from_px(50))) ==
+            Size2D(Au::from_px(50), Au::from_px(50));
+    }
+}
@@ -1,5 +1,6 @@
 /** Creates CSS boxes from a DOM. */
 
+use css::node_style::StyledNode;
 use dom;
 use dom::element::*;
 use dom::node::{Comment, Doctype, Element, Text, Node, LayoutData};
@@ -156,6 +157,20 @@ impl BoxGenerator {
         }
     }
 
+    /// Appends a text box for ::before/::after content generated by `node`.
+    pub fn push_generated_content(builder: &LayoutTreeBuilder, node: Node, text: ~str) {
+        debug!("BoxGenerator[f%d]: pushing generated content for node: %s",
+               self.flow.d().id, node.debug_str());
+
+        match self.flow {
+            @InlineFlow(*) => {
+                let new_box = builder.make_unscanned_text_box(node, self.flow, move text);
+                self.flow.inline().boxes.push(new_box);
+            },
+            _ => { warn!("push_generated_content() not implemented for flow f%d", self.flow.d().id) }
+        }
+    }
+
     pub fn pop_node(ctx: &LayoutContext, _builder: &LayoutTreeBuilder, node: Node) {
         debug!("BoxGenerator[f%d]: popping node: %s", self.flow.d().id, node.debug_str());
 
@@ -238,6 +253,15 @@ impl BuilderContext {
         self.inline_collector = None;
     }
 
+    // generated content is inline, so it goes into an inline collector
+    // when the node itself established a block.
+    priv fn get_generated_content_collector(builder: &LayoutTreeBuilder) -> @BoxGenerator {
+        match self.default_collector.flow {
+            @InlineFlow(*) => self.default_collector,
+            _ => self.get_inline_collector(builder).default_collector
+        }
+    }
+
     // returns a context for the current node, or None if the document subtree rooted
     // by the node should not generate a layout tree. For example, nodes with style 'display:none'
     // should just not generate any flows or boxes.
@@ -292,11 +316,16 @@ impl LayoutTreeBuilder {
         this_ctx.default_collector.push_node(layout_ctx, &self, cur_node);
         debug!("point b: %s", cur_node.debug_str());
 
+        // ::before content precedes the node's children, and ::after content follows them.
+        let (before, after) = cur_node.generated_content();
+        self.construct_generated_content(&this_ctx, cur_node, move before);
+
         // recurse on child nodes.
         for tree::each_child(&NodeTree, &cur_node) |child_node| {
             self.construct_recursively(layout_ctx, *child_node, &this_ctx);
         }
 
+        self.construct_generated_content(&this_ctx, cur_node, move after);
         this_ctx.default_collector.pop_node(layout_ctx, &self, cur_node);
         self.simplify_children_of_flow(layout_ctx, &this_ctx);
 
@@ -312,6 +341,16 @@ impl LayoutTreeBuilder {
         }
     }
 
+    fn construct_generated_content(ctx: &BuilderContext, node: Node, content: Option<~str>) {
+        match move content {
+            Some(move text) => {
+                let collector = ctx.get_generated_content_collector(&self);
+                collector.push_generated_content(&self, node, move text);
+            }
+            None => {}
+        }
+    }
+
     // Fixup any irregularities such as:
     //
     // * split inlines (CSS 2.1 Section 9.2.1.1)
@@ -447,12 +486,16 @@ impl LayoutTreeBuilder {
     fn make_text_box(_layout_ctx: &LayoutContext, node: Node, ctx: @FlowContext) -> @RenderBox {
         do node.read |n| {
             match n.kind {
-                ~Text(ref string) => @UnscannedTextBox(RenderBoxData(node, ctx, self.next_box_id()), copy *string),
+                ~Text(ref string) => self.make_unscanned_text_box(node, ctx, copy *string),
                 _ => fail!(~"WAT error: why couldn't we make a text box?")
             }
         }
     }
 
+    fn make_unscanned_text_box(node: Node, ctx: @FlowContext, text: ~str) -> @RenderBox {
+        @UnscannedTextBox(RenderBoxData(node, ctx, self.next_box_id()), move text)
+    }
+
     fn decide_box_type(node: Node, display: CSSDisplay) -> RenderBoxType {
         do node.read |n| {
             match n.kind {
@@ -475,3 +518,98 @@ impl LayoutTreeBuilder {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use css::matching::MatchMethods;
+    use css::select::{new_css_select_ctx, test_stylesheet};
+    use dom::node::{Node, NodeScope};
+    use html::hubbub_html_parser::parse_html_;
+    use layout::aux::LayoutAuxMethods;
+    use layout::box::UnscannedTextBox;
+    use layout::context::LayoutContext;
+    use layout::flow::FlowTree;
+
+    use azure::azure_hl::CairoBackend;
+    use core::dvec::DVec;
+    use geom::rect::Rect;
+    use geom::size::Size2D;
+    use gfx::font_context::FontContext;
+    use gfx::geometry::Au;
+    use gfx::resource::image_cache_task::{ImageCacheTask, ImageCacheTaskClient};
+    use gfx::resource::local_image_cache::LocalImageCache;
+    use gfx::resource::resource_task;
+    use gfx::resource::resource_task::ResourceTask;
+    use gfx::util::url::make_url;
+    use newcss::types::OriginAuthor;
+    use std::net::url;
+
+    fn no_scripts(_root: Node, _script: ~[u8]) -> ~str { ~"" }
+
+    // The text of the unscanned text boxes in `flow` and the flows below it, in tree order
+    fn text_of_boxes(flow: @FlowContext) -> ~[~str] {
+        let mut text = ~[];
+        match flow {
+            @InlineFlow(*) => {
+                for flow.inline().boxes.each |box| {
+                    match *box {
+                        @UnscannedTextBox(_, ref s) => text.push(copy *s),
+                        _ => ()
+                    }
+                }
+            }
+            _ => ()
+        }
+        for FlowTree.each_child(flow) |child| {
+            text.push_all_move(text_of_boxes(child));
+        }
+        move text
+    }
+
+    // Parses and styles `html`, then builds its flow tree and returns the text of its boxes
+    fn text_of_boxes_for_page(html: &str, css: &str) -> ~[~str] {
+        let resource_task = ResourceTask();
+        let image_cache_task = ImageCacheTask(resource_task.clone());
+        let parsed = do parse_html_(NodeScope(), make_url(~"http://test/", None),
+                                    Some(str::to_bytes(html)), resource_task.clone(),
+                                    image_cache_task.clone(), Size2D(800u, 600u), 1.0,
+                                    no_scripts) |_root| { };
+        let root = parsed.root;
+
+        let refs = DVec();
+        root.initialize_style_for_subtree(&refs);
+        let mut select_ctx = new_css_select_ctx();
+        select_ctx.append_sheet(test_stylesheet(css), OriginAuthor);
+        root.restyle_subtree(&select_ctx);
+
+        let ctx = LayoutContext {
+            font_ctx: @FontContext::new(CairoBackend, false),
+            image_cache: @LocalImageCache(image_cache_task.clone()),
+            doc_url: url::from_str(~"http://test").get(),
+            screen_size: Rect(Au::zero_point(), Size2D(Au::from_px(800), Au::from_px(600)))
+        };
+        let builder = LayoutTreeBuilder::new();
+        let flow = result::unwrap(builder.construct_trees(&ctx, root));
+        let text = text_of_boxes(flow);
+
+        image_cache_task.exit();
+        resource_task.send(resource_task::Exit);
+        move text
+    }
+
+    #[test]
+    fn should_generate_before_content_ahead_of_children() {
+        let text = text_of_boxes_for_page("<html><body><span>content</span></body></html>",
+                                          "span::before { content: \"X\" }");
+        assert text == ~[~"X", ~"content"];
+    }
+
+    #[test]
+    fn should_generate_after_content_from_attributes() {
+        let text = text_of_boxes_for_page(
+            "<html><body><span title=\"Y\">content</span><div>block</div></body></html>",
+            "span::after { content: \"(\" attr(title) \")\" } div::before { content: \"X\" }");
+        assert text == ~[~"content", ~"(Y)", ~"X", ~"block"];
+    }
+}
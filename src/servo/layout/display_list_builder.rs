@@ -71,3 +71,203 @@ impl FlowDisplayListBuilderMethods for FlowContext {
         }
     }
 }
+
+/**
+Builds the display list painting a laid-out box, in paint order and in the
+coordinates of its flow. Nothing is culled, so tests can compare the whole
+of a box's paint output without a surface to draw it on.
+*/
+pub fn build_display_list(root: @RenderBox) -> DisplayList {
+    let list = Mut(DisplayList::new());
+    let bounds = root.d().position;
+    root.add_display_items(&bounds, &Au::zero_point(), &list);
+    list.unwrap()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use css::matching::MatchMethods;
+    use css::select::test_stylesheet;
+    use dom::element::{ElementData, HTMLDivElement, HTMLParagraphElement, HTMLSectionElement};
+    use dom::node::{Element, Node, NodeScope, NodeScopeExtensions, Text};
+    use layout::aux::LayoutAuxMethods;
+    use layout::block::BlockFlowData;
+    use layout::box::{GenericBox, RenderBoxData};
+    use layout::context::LayoutContext;
+    use layout::flow::{BlockFlow, FlowContext, FlowData, FlowTree};
+    use layout::text::adapt_textbox_with_range;
+
+    use azure::azure_hl::CairoBackend;
+    use core::dvec::DVec;
+    use core::mutable::Mut;
+    use geom::point::Point2D;
+    use geom::rect::Rect;
+    use geom::size::Size2D;
+    use gfx::color::Color;
+    use gfx::display_list;
+    use gfx::display_list::DisplayList;
+    use gfx::geometry::Au;
+    use gfx::font::Font;
+    use gfx::font_context::{FontContext, dummy_style, test_font_bin};
+    use gfx::resource::image_cache_task::{ImageCacheTask, ImageCacheTaskClient};
+    use gfx::resource::local_image_cache::LocalImageCache;
+    use gfx::resource::resource_task;
+    use gfx::resource::resource_task::ResourceTask;
+    use gfx::text::text_run::TextRun;
+    use gfx::util::range::Range;
+    use newcss::select::SelectCtx;
+    use newcss::types::OriginAuthor;
+    use std::net::url;
+
+    fn rgb_of(color: Color) -> (float, float, float) {
+        (color.r as float, color.g as float, color.b as float)
+    }
+
+    #[test]
+    fn should_list_background_before_text() {
+        let scope = NodeScope();
+        let div = scope.new_node(Element(ElementData(~"div", ~HTMLDivElement)));
+        let text = scope.new_node(Text(~"hello"));
+        scope.add_child(div, text);
+        let refs = DVec();
+        div.initialize_style_for_subtree(&refs);
+        let mut select_ctx = SelectCtx::new();
+        select_ctx.append_sheet(test_stylesheet("div { background-color: rgb(255, 0, 0); \
+                                                  color: rgb(0, 0, 255) }"),
+                                OriginAuthor);
+        div.restyle_subtree(&select_ctx);
+
+        let fctx = FontContext::new(CairoBackend, false);
+        let font = result::unwrap(Font::new_from_buffer(&fctx, test_font_bin(), &dummy_style(),
+                                                        CairoBackend));
+        let run = @TextRun::new(font, ~"hello");
+        let flow = @BlockFlow(FlowData(0), BlockFlowData());
+        let box = adapt_textbox_with_range(&RenderBoxData(text, flow, 0), run,
+                                           &Range::new(0, 5));
+
+        let list = build_display_list(box);
+        assert list.list.len() == 2;
+        match *list.list[0] {
+            display_list::SolidColor(ref d, color) => {
+                assert d.bounds == box.d().position;
+                assert rgb_of(color) == (1.0, 0.0, 0.0);
+            }
+            _ => fail!(~"expected the background first")
+        }
+        match *list.list[1] {
+            display_list::Text(ref d, ref run, range, color) => {
+                assert d.bounds == box.d().position;
+                assert run.text == ~"hello";
+                assert range.begin() == 0 && range.length() == 5;
+                assert rgb_of(color) == (0.0, 0.0, 1.0);
+            }
+            _ => fail!(~"expected the text after its background")
+        }
+    }
+
+    // A block flow at `position`, whose box fills it
+    fn block_flow(node: Node, id: int, position: Rect<Au>) -> @FlowContext {
+        let flow = @BlockFlow(FlowData(id), BlockFlowData());
+        flow.d().node = Some(node);
+        flow.d().position = position;
+
+        let box = @GenericBox(RenderBoxData(node, flow, id));
+        box.d().position = Rect(Au::zero_point(), position.size);
+        flow.block().box = Some(box);
+        flow
+    }
+
+    fn px_rect(x: int, y: int, w: int, h: int) -> Rect<Au> {
+        Rect(Point2D(Au::from_px(x), Au::from_px(y)), Size2D(Au::from_px(w), Au::from_px(h)))
+    }
+
+    // The backgrounds painted for a flow tree, in paint order
+    fn paint_backgrounds(root: @FlowContext) -> ~[(Rect<Au>, (float, float, float))] {
+        let resources = ResourceTask();
+        let image_cache_task = ImageCacheTask(resources.clone());
+        let ctx = LayoutContext {
+            font_ctx: @FontContext::new(CairoBackend, false),
+            image_cache: @LocalImageCache(image_cache_task.clone()),
+            doc_url: url::from_str(~"http://test").get(),
+            screen_size: px_rect(0, 0, 100, 100)
+        };
+        let builder = DisplayListBuilder { ctx: &ctx };
+        let list = Mut(DisplayList::new());
+        root.build_display_list(&builder, &px_rect(0, 0, 100, 100), &list);
+
+        let mut backgrounds = ~[];
+        for list.unwrap().list.each |item| {
+            match **item {
+                display_list::SolidColor(ref d, color) => {
+                    backgrounds.push((d.bounds, rgb_of(color)))
+                }
+                _ => ()
+            }
+        }
+
+        image_cache_task.exit();
+        resources.send(resource_task::Exit);
+        backgrounds
+    }
+
+    fn style_subtree(root: Node, css: &str) {
+        let refs = DVec();
+        root.initialize_style_for_subtree(&refs);
+        let mut select_ctx = SelectCtx::new();
+        select_ctx.append_sheet(test_stylesheet(css), OriginAuthor);
+        root.restyle_subtree(&select_ctx);
+    }
+
+    #[test]
+    fn should_paint_higher_z_index_on_top() {
+        let scope = NodeScope();
+        let section = scope.new_node(Element(ElementData(~"section", ~HTMLSectionElement)));
+        let div = scope.new_node(Element(ElementData(~"div", ~HTMLDivElement)));
+        let p = scope.new_node(Element(ElementData(~"p", ~HTMLParagraphElement)));
+        scope.add_child(section, div);
+        scope.add_child(section, p);
+        style_subtree(section, "div { position: absolute; z-index: 2; \
+                                      background-color: rgb(255, 0, 0) } \
+                                p { position: absolute; z-index: 1; \
+                                    background-color: rgb(0, 0, 255) }");
+
+        // The paragraph comes later in the document and overlaps the div
+        let section_flow = block_flow(section, 0, px_rect(0, 0, 100, 100));
+        let div_flow = block_flow(div, 1, px_rect(10, 10, 50, 50));
+        let p_flow = block_flow(p, 2, px_rect(30, 30, 50, 50));
+        FlowTree.add_child(section_flow, div_flow);
+        FlowTree.add_child(section_flow, p_flow);
+
+        assert paint_backgrounds(section_flow) == ~[(px_rect(30, 30, 50, 50), (0.0, 0.0, 1.0)),
+                                                    (px_rect(10, 10, 50, 50), (1.0, 0.0, 0.0))];
+    }
+
+    #[test]
+    fn should_order_nested_stacking_contexts_within_their_ancestor_context() {
+        let scope = NodeScope();
+        let section = scope.new_node(Element(ElementData(~"section", ~HTMLSectionElement)));
+        let wrapper = scope.new_node(Element(ElementData(~"div", ~HTMLDivElement)));
+        let div = scope.new_node(Element(ElementData(~"div", ~HTMLDivElement)));
+        let p = scope.new_node(Element(ElementData(~"p", ~HTMLParagraphElement)));
+        scope.add_child(section, wrapper);
+        scope.add_child(wrapper, div);
+        scope.add_child(section, p);
+        style_subtree(section, "div div { position: absolute; z-index: 2; \
+                                          background-color: rgb(255, 0, 0) } \
+                                p { position: absolute; z-index: 1; \
+                                    background-color: rgb(0, 0, 255) }");
+
+        // The div is inside a block that isn't positioned, and still paints over the paragraph
+        let section_flow = block_flow(section, 0, px_rect(0, 0, 100, 100));
+        let wrapper_flow = block_flow(wrapper, 1, px_rect(0, 5, 100, 60));
+        let div_flow = block_flow(div, 2, px_rect(10, 5, 50, 50));
+        let p_flow = block_flow(p, 3, px_rect(30, 30, 50, 50));
+        FlowTree.add_child(section_flow, wrapper_flow);
+        FlowTree.add_child(wrapper_flow, div_flow);
+        FlowTree.add_child(section_flow, p_flow);
+
+        assert paint_backgrounds(section_flow) == ~[(px_rect(30, 30, 50, 50), (0.0, 0.0, 1.0)),
+                                                    (px_rect(10, 10, 50, 50), (1.0, 0.0, 0.0))];
+    }
+}
@@ -0,0 +1,140 @@
+// Flexbox layout. Only single-line `flex-direction: row` containers are
+// supported so far; wrapping and `flex-grow` are not.
+
+use layout::block::BlockLayout;
+use layout::flow::{FlowContext, FlowTree};
+
+use au = gfx::geometry;
+use gfx::geometry::Au;
+use newcss::values::{CSSAlignItems, CSSAlignItemsStretch, CSSDisplayFlex};
+use newcss::values::{CSSJustifyContent, CSSJustifyContentCenter};
+use newcss::values::{CSSJustifyContentFlexStart, CSSJustifyContentSpaceBetween};
+
+/**
+Computes the x offset of each flex item in a row, given the width of the
+container and the widths of its items, distributing free space according to
+`justify-content`. Items that overflow the container are laid out from its
+start edge.
+*/
+pub pure fn flex_row_offsets(justify: CSSJustifyContent, container_width: Au,
+                             item_widths: &[Au]) -> ~[Au] {
+    let mut used_width = Au(0);
+    for item_widths.each |width| {
+        used_width += *width;
+    }
+    let free_space = au::max(Au(0), container_width - used_width);
+
+    let (mut x, gap) = match justify {
+        CSSJustifyContentFlexStart => (Au(0), Au(0)),
+        CSSJustifyContentCenter => (free_space / Au(2), Au(0)),
+        CSSJustifyContentSpaceBetween if item_widths.len() > 1 => {
+            (Au(0), free_space / Au((item_widths.len() - 1) as i32))
+        }
+        CSSJustifyContentSpaceBetween => (Au(0), Au(0))
+    };
+
+    let mut offsets = ~[];
+    for item_widths.each |width| {
+        offsets.push(x);
+        x += *width + gap;
+    }
+    offsets
+}
+
+pub trait FlexLayout {
+    fn is_flex_container(@self) -> bool;
+    fn assign_widths_flex(@self, justify: CSSJustifyContent, left: Au, width: Au);
+    fn assign_height_flex(@self, align: CSSAlignItems) -> Au;
+}
+
+impl FlowContext : FlexLayout {
+    fn is_flex_container(@self) -> bool {
+        let mut is_flex = false;
+        do self.with_block_box |box| {
+            is_flex = box.display() == CSSDisplayFlex;
+        }
+        is_flex
+    }
+
+    /* Lays the child flows out in a row starting at `left`. Each item is as
+       wide as its preferred width, but no wider than the container. */
+    fn assign_widths_flex(@self, justify: CSSJustifyContent, left: Au, width: Au) {
+        let mut item_widths = ~[];
+        for FlowTree.each_child(self) |child_ctx| {
+            item_widths.push(au::min(child_ctx.d().pref_width, width));
+        }
+
+        let offsets = flex_row_offsets(justify, width, item_widths);
+        let mut i = 0;
+        for FlowTree.each_child(self) |child_ctx| {
+            child_ctx.d().position.origin.x = left + offsets[i];
+            child_ctx.d().position.size.width = item_widths[i];
+            i += 1;
+        }
+    }
+
+    /* Places the child flows on the same line and returns the height of the
+       line, which is that of the tallest item. With `align-items: stretch`
+       every item is made that tall. */
+    fn assign_height_flex(@self, align: CSSAlignItems) -> Au {
+        let mut line_height = Au(0);
+        for FlowTree.each_child(self) |child_ctx| {
+            line_height = au::max(line_height, child_ctx.d().position.size.height);
+        }
+
+        for FlowTree.each_child(self) |child_ctx| {
+            child_ctx.d().position.origin.y = Au(0);
+            match align {
+                CSSAlignItemsStretch => child_ctx.d().position.size.height = line_height,
+                _ => ()
+            }
+        }
+        line_height
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use layout::block::BlockFlowData;
+    use layout::flow::{BlockFlow, FlowContext, FlowData, FlowTree};
+
+    use geom::point::Point2D;
+    use geom::rect::Rect;
+    use geom::size::Size2D;
+    use gfx::geometry::Au;
+
+    fn block_flow(id: int, pref_width: Au, height: Au) -> @FlowContext {
+        let flow = @BlockFlow(FlowData(id), BlockFlowData());
+        flow.d().pref_width = pref_width;
+        flow.d().position = Rect(Point2D(Au(0), Au(0)), Size2D(Au(0), height));
+        flow
+    }
+
+    #[test]
+    fn should_put_space_between_two_flex_items() {
+        let container = block_flow(0, Au::from_px(100), Au(0));
+        let first = block_flow(1, Au::from_px(20), Au::from_px(10));
+        let second = block_flow(2, Au::from_px(30), Au::from_px(40));
+        FlowTree.add_child(container, first);
+        FlowTree.add_child(container, second);
+
+        container.assign_widths_flex(CSSJustifyContentSpaceBetween, Au(0), Au::from_px(100));
+        let height = container.assign_height_flex(CSSAlignItemsStretch);
+
+        // The first item is at the left edge, the second ends at the right edge
+        assert first.d().position.origin.x == Au(0);
+        assert second.d().position.origin.x == Au::from_px(70);
+        assert second.d().position.origin.x + second.d().position.size.width
+            == Au::from_px(100);
+
+        assert height == Au::from_px(40);
+        assert first.d().position.size.height == Au::from_px(40);
+    }
+
+    #[test]
+    fn should_center_flex_items() {
+        let offsets = flex_row_offsets(CSSJustifyContentCenter, Au(100), [Au(20), Au(30)]);
+        assert offsets == ~[Au(25), Au(45)];
+    }
+}
@@ -0,0 +1,107 @@
+/**
+Hit testing: maps a point to the node whose render box is drawn there, for
+dispatching events such as clicks to their target element.
+*/
+
+use dom::node::Node;
+use layout::box::RenderBox;
+use layout::flow::{FlowContext, FlowTree};
+
+use geom::point::Point2D;
+use geom::rect::Rect;
+use gfx::geometry::Au;
+
+pub trait HitTestMethods {
+    fn hit_test(@self, point: &Point2D<Au>) -> Option<Node>;
+}
+
+impl FlowContext : HitTestMethods {
+    /**
+    Returns the node of the deepest render box containing `point`, which is
+    relative to the origin of this flow's parent, like this flow's position.
+
+    Boxes that are painted later are on top, so they are preferred.
+    TODO: consult z-order and clip rects once layout has them.
+    */
+    fn hit_test(@self, point: &Point2D<Au>) -> Option<Node> {
+        let position = self.d().position;
+        if !rect_contains(&position, point) {
+            return None;
+        }
+
+        // Boxes and child flows are positioned relative to this flow.
+        let point = Point2D(point.x - position.origin.x, point.y - position.origin.y);
+
+        // Child flows are painted after this flow's own box, in order.
+        let mut children = ~[];
+        for FlowTree.each_child(self) |child| {
+            children.push(child);
+        }
+        for vec::rev_each(children) |child| {
+            match child.hit_test(&point) {
+                Some(node) => return Some(node),
+                None => ()
+            }
+        }
+
+        do self.foldl_all_boxes(None) |hit, box| {
+            if rect_contains(&box.d().position, &point) { Some(box.d().node) } else { hit }
+        }
+    }
+}
+
+pure fn rect_contains(rect: &Rect<Au>, point: &Point2D<Au>) -> bool {
+    point.x >= rect.origin.x && point.x < rect.origin.x + rect.size.width &&
+        point.y >= rect.origin.y && point.y < rect.origin.y + rect.size.height
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use dom::element::{ElementData, HTMLDivElement};
+    use dom::node::{Element, Node, NodeScope, NodeScopeExtensions};
+    use layout::block::BlockFlowData;
+    use layout::box::{GenericBox, RenderBoxData};
+    use layout::flow::{BlockFlow, FlowContext, FlowData, FlowTree};
+
+    use geom::point::Point2D;
+    use geom::rect::Rect;
+    use geom::size::Size2D;
+    use gfx::geometry::Au;
+
+    fn px_rect(x: int, y: int, w: int, h: int) -> Rect<Au> {
+        Rect(Point2D(Au::from_px(x), Au::from_px(y)), Size2D(Au::from_px(w), Au::from_px(h)))
+    }
+
+    fn px_point(x: int, y: int) -> Point2D<Au> {
+        Point2D(Au::from_px(x), Au::from_px(y))
+    }
+
+    // A block flow at `position`, whose box fills it.
+    fn block_flow(node: Node, id: int, position: Rect<Au>) -> @FlowContext {
+        let flow = @BlockFlow(FlowData(id), BlockFlowData());
+        flow.d().node = Some(node);
+        flow.d().position = position;
+
+        let box = @GenericBox(RenderBoxData(node, flow, id));
+        box.d().position = Rect(Au::zero_point(), position.size);
+        flow.block().box = Some(box);
+        flow
+    }
+
+    #[test]
+    fn should_find_deepest_box_at_point() {
+        let scope = NodeScope();
+        let outer = scope.new_node(Element(ElementData(~"div", ~HTMLDivElement)));
+        let inner = scope.new_node(Element(ElementData(~"div", ~HTMLDivElement)));
+        scope.add_child(outer, inner);
+
+        let outer_flow = block_flow(outer, 0, px_rect(0, 0, 100, 100));
+        let inner_flow = block_flow(inner, 1, px_rect(10, 10, 20, 20));
+        FlowTree.add_child(outer_flow, inner_flow);
+
+        assert outer_flow.hit_test(&px_point(15, 15)) == Some(inner);
+        assert outer_flow.hit_test(&px_point(50, 50)) == Some(outer);
+        assert outer_flow.hit_test(&px_point(150, 50)) == None;
+    }
+}
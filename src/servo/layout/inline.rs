@@ -9,12 +9,15 @@ use util::tree;
 
 use core::dlist::DList;
 use core::dvec::DVec;
-use geom::{Point2D, Rect, Size2D};
+use geom::{Point2D, Rect};
 use gfx::font::FontStyle;
 use gfx::geometry::Au;
+use gfx::text::bidi::{Direction, LeftToRight, RightToLeft, reorder_runs};
 use gfx::text::util::*;
 use gfx::util::range::Range;
+use newcss::values::{CSSDirectionLtr, CSSDirectionRtl};
 use newcss::values::{CSSTextAlignCenter, CSSTextAlignJustify, CSSTextAlignLeft, CSSTextAlignRight};
+use newcss::values::{CSSWhiteSpace, CSSWhiteSpaceNowrap};
 use newcss::units::{BoxAuto, BoxLength, Px};
 use std::arc;
 
@@ -237,10 +240,10 @@ priv impl TextRunScanner {
             (true, true)  => {
                 let old_box = in_boxes[self.clump.begin()];
                 let text = old_box.raw_text();
-                let font_style = old_box.font_style();
+                let font_style = old_box.font_style_with_defaults(&ctx.font_ctx.defaults);
                 // TODO(Issue #115): use actual CSS 'white-space' property of relevant style.
                 let compression = CompressWhitespaceNewline;
-                let transformed_text = transform_text(text, compression);
+                let transformed_text = transform_text(to_valid_utf8(text), compression);
                 // TODO(Issue #177): text run creation must account for text-renderability by fontgroup fonts.
                 // this is probably achieved by creating fontgroup above, and then letting FontGroup decide
                 // which Font to stick into the TextRun.
@@ -263,7 +266,7 @@ priv impl TextRunScanner {
                     // starting/ending with whitespace &c can be
                     // compressed correctly w.r.t. the TextRun.
                     let idx = i + self.clump.begin();
-                    transform_text(in_boxes[idx].raw_text(), compression)
+                    transform_text(to_valid_utf8(in_boxes[idx].raw_text()), compression)
                 });
 
                 // next, concatenate all of the transformed strings together, saving the new char indices
@@ -282,7 +285,8 @@ priv impl TextRunScanner {
                 // TODO(Issue #177): text run creation must account for text-renderability by fontgroup fonts.
                 // this is probably achieved by creating fontgroup above, and then letting FontGroup decide
                 // which Font to stick into the TextRun.
-                let font_style = in_boxes[self.clump.begin()].font_style();
+                let font_style = in_boxes[self.clump.begin()]
+                    .font_style_with_defaults(&ctx.font_ctx.defaults);
                 let fontgroup = ctx.font_ctx.get_resolved_font_for_style(&font_style);
                 let run = @TextRun::new(fontgroup.fonts[0], move run_str);
                 debug!("TextRunScanner: pushing box(es) in range: %?", self.clump);
@@ -414,30 +418,39 @@ impl LineboxScanner {
         debug!("LineboxScanner: Flushing line %u: %?",
                self.line_spans.len(), self.pending_line);
         // set box horizontal offsets
-        let line_range = self.pending_line.range;
         let mut offset_x = Au(0);
-        // TODO(Issue #199): interpretation of CSS 'direction' will change how boxes are positioned.
-        debug!("LineboxScanner: Setting horizontal offsets for boxes in line %u range: %?",
-               self.line_spans.len(), line_range);
 
-        // Get the text alignment.
-        // TODO(Issue #222): use 'text-align' property from InlineFlow's 
+        // Get the text alignment and base direction.
+        // TODO(Issue #222): use 'text-align' and 'direction' properties from InlineFlow's
         // block container, not from the style of the first box child.
         let linebox_align;
+        let base_direction;
         if self.pending_line.range.begin() < self.new_boxes.len() {
             let first_box = self.new_boxes[self.pending_line.range.begin()];
             linebox_align = first_box.text_align();
+            base_direction = match first_box.direction() {
+                CSSDirectionLtr => LeftToRight,
+                CSSDirectionRtl => RightToLeft
+            };
         } else {
             // Nothing to lay out, so assume left alignment.
             linebox_align = CSSTextAlignLeft;
+            base_direction = LeftToRight;
         }
 
+        // TODO(Issue #199): 'text-align: start' should follow the base direction too.
+        let visual_order = self.visual_box_order(base_direction);
+        // Splitting boxes at level runs may have lengthened the line's range
+        let line_range = self.pending_line.range;
+        debug!("LineboxScanner: Setting horizontal offsets for boxes in line %u range: %?",
+               self.line_spans.len(), line_range);
+
         let slack_width = self.flow.d().position.size.width - self.pending_line.width;
         match linebox_align {
             // So sorry, but justified text is more complicated than shuffling linebox coordinates.
             // TODO(Issue #213): implement `text-align: justify`
             CSSTextAlignLeft | CSSTextAlignJustify => {
-                for line_range.eachi |i| {
+                for visual_order.each |&i| {
                     let box_data = &self.new_boxes[i].d();
                     box_data.position.origin.x = offset_x;
                     offset_x += box_data.position.size.width;
@@ -445,7 +458,7 @@ impl LineboxScanner {
             },
             CSSTextAlignCenter => {
                 offset_x = slack_width.scale_by(0.5f);
-                for line_range.eachi |i| {
+                for visual_order.each |&i| {
                     let box_data = &self.new_boxes[i].d();
                     box_data.position.origin.x = offset_x;
                     offset_x += box_data.position.size.width;
@@ -453,7 +466,7 @@ impl LineboxScanner {
             },
             CSSTextAlignRight => {
                 offset_x = slack_width;
-                for line_range.eachi |i| {
+                for visual_order.each |&i| {
                     let box_data = &self.new_boxes[i].d();
                     box_data.position.origin.x = offset_x;
                     offset_x += box_data.position.size.width;
@@ -466,6 +479,72 @@ impl LineboxScanner {
         self.reset_linebox();
     }
 
+    /**
+    The indices of the boxes of the current line in the order they are displayed from left
+    to right, which the bidirectional algorithm finds from their text. Boxes without text
+    count as a neutral character. Text boxes spanning runs at different embedding levels
+    are first split at the runs' boundaries, so that each box is displayed whole.
+    */
+    priv fn visual_box_order(&mut self, base_direction: Direction) -> ~[uint] {
+        let line_range = self.pending_line.range;
+        let mut text = ~"";
+        let mut box_chars = ~[];
+        for line_range.eachi |i| {
+            let start = str::char_len(text);
+            match self.new_boxes[i] {
+                @TextBox(_, data) => {
+                    let chars = str::chars(data.run.text);
+                    for data.range.eachi |j| {
+                        str::push_char(&mut text, chars[j]);
+                    }
+                }
+                // U+FFFC OBJECT REPLACEMENT CHARACTER
+                _ => str::push_char(&mut text, '\uFFFC')
+            }
+            box_chars.push(Range::new(start, str::char_len(text) - start));
+        }
+        let runs = reorder_runs(text, base_direction);
+
+        let mut boxes = ~[];
+        let mut piece_chars = ~[];
+        for line_range.eachi |i| {
+            let box = self.new_boxes[i];
+            let chars = box_chars[i - line_range.begin()];
+            let pieces = split_at_level_runs(&chars, runs);
+            match box {
+                @TextBox(_, data) if pieces.len() > 1 => {
+                    for pieces.each |piece| {
+                        let range = Range::new(data.range.begin() + piece.begin() - chars.begin(),
+                                               piece.length());
+                        boxes.push(layout::text::adapt_textbox_with_range(box.d(), data.run,
+                                                                          &const range));
+                    }
+                    piece_chars.push_all(pieces);
+                }
+                _ => {
+                    boxes.push(box);
+                    piece_chars.push(chars);
+                }
+            }
+        }
+
+        if boxes.len() > line_range.length() {
+            debug!("LineboxScanner: split line %u into %u boxes at level runs",
+                   self.line_spans.len(), boxes.len());
+            let begin = line_range.begin();
+            do self.new_boxes.swap |all| { vec::slice(all, 0, begin) };
+            self.pending_line.width = Au(0);
+            for boxes.each |box| {
+                self.pending_line.width += box.d().position.size.width;
+                self.new_boxes.push(*box);
+            }
+            self.pending_line.range.reset(begin, boxes.len());
+        }
+
+        let begin = self.pending_line.range.begin();
+        visual_order(piece_chars, runs, str::char_len(text)).map(|&k| begin + k)
+    }
+
     // return value: whether any box was appended.
     priv fn try_append_to_line(&mut self, ctx: &LayoutContext, in_box: @RenderBox) -> bool {
         let remaining_width = self.flow.d().position.size.width - self.pending_line.width;
@@ -475,7 +554,7 @@ impl LineboxScanner {
         debug!("LineboxScanner: Trying to append box to line %u (box width: %?, remaining width: %?): %s",
                self.line_spans.len(), in_box_width, remaining_width, in_box.debug_str());
 
-        if in_box_width <= remaining_width {
+        if fits_on_line(in_box.white_space(), in_box_width, remaining_width) {
             debug!("LineboxScanner: case=box fits without splitting");
             self.push_box_to_line(in_box);
             return true;
@@ -560,6 +639,111 @@ impl LineboxScanner {
     }
 }
 
+/// The character range of a box split where each level run in `runs` starts within it.
+pub fn split_at_level_runs(chars: &Range, runs: &[(Range, Direction)]) -> ~[Range] {
+    let mut cuts = ~[];
+    for runs.each |&(run, _)| {
+        if run.begin() > chars.begin() && run.begin() < chars.end() {
+            cuts.push(run.begin());
+        }
+    }
+    let cuts = std::sort::merge_sort(cuts, |a, b| *a <= *b);
+
+    let mut pieces = ~[];
+    let mut start = chars.begin();
+    for cuts.each |&cut| {
+        pieces.push(Range::new(start, cut - start));
+        start = cut;
+    }
+    pieces.push(Range::new(start, chars.end() - start));
+    pieces
+}
+
+/**
+The indices of boxes, given as the character ranges of the line's text they show, in the
+order they are displayed from left to right. `runs` are the level runs of the line's text
+in the order they are displayed; no box may span more than one. Characters in no run,
+such as embedding controls, sit at the left.
+*/
+pub fn visual_order(box_chars: &[Range], runs: &[(Range, Direction)], text_len: uint) -> ~[uint] {
+    let mut visual_positions = vec::from_elem(text_len, 0u);
+    let mut position = 0;
+    for runs.each |&(run, direction)| {
+        for run.eachi |i| {
+            let i = match direction {
+                LeftToRight => i,
+                RightToLeft => run.begin() + run.end() - 1 - i
+            };
+            visual_positions[i] = position;
+            position += 1;
+        }
+    }
+
+    // Each box goes where its leftmost character does
+    let mut keyed = ~[];
+    for box_chars.eachi |k, chars| {
+        let mut leftmost = uint::max_value;
+        for chars.eachi |i| {
+            leftmost = uint::min(leftmost, visual_positions[i]);
+        }
+        keyed.push((leftmost, k));
+    }
+    let sorted = do std::sort::merge_sort(keyed) |a, b| { a.first() <= b.first() };
+    sorted.map(|&(_, k)| k)
+}
+
+/// Whether a box `box_width` wide may be appended whole to a line with `remaining_width` left.
+/// Boxes whose text may not wrap always fit, overflowing the line if necessary.
+pure fn fits_on_line(white_space: CSSWhiteSpace, box_width: Au, remaining_width: Au) -> bool {
+    match white_space {
+        CSSWhiteSpaceNowrap => true,
+        _ => box_width <= remaining_width
+    }
+}
+
+/// The vertical extents of an inline box about its baseline, used to stack
+/// the boxes of a line into a line box (see CSS 2.1 section 10.8.1).
+pub struct InlineMetrics {
+    ascent: Au,
+    descent: Au,
+    line_height: Au,
+}
+
+impl InlineMetrics {
+    /// Half of the difference between the line height and the content height.
+    pure fn half_leading(&self) -> Au {
+        (self.line_height - self.ascent - self.descent).scale_by(0.5f)
+    }
+
+    /// Distance from the top of the box's inline box to its baseline.
+    pure fn height_above_baseline(&self) -> Au {
+        self.ascent + self.half_leading()
+    }
+
+    /// Distance from the baseline to the bottom of the box's inline box.
+    pure fn depth_below_baseline(&self) -> Au {
+        self.line_height - self.height_above_baseline()
+    }
+
+    /// Offset of the top of the box's content area from the top of a line box
+    /// whose baseline is `baseline` below its top.
+    pure fn content_top(&self, baseline: Au) -> Au {
+        baseline - self.ascent
+    }
+}
+
+/// Returns the height of a line box containing inline boxes with the given
+/// metrics, and the offset of the line's baseline from the top of the line box.
+pub pure fn line_box_height_and_baseline(metrics: &[InlineMetrics]) -> (Au, Au) {
+    let mut above = Au(0);
+    let mut below = Au(0);
+    for metrics.each |m| {
+        above = Au::max(above, m.height_above_baseline());
+        below = Au::max(below, m.depth_below_baseline());
+    }
+    (above + below, above)
+}
+
 pub struct InlineFlowData {
     // A vec of all inline render boxes. Several boxes may
     // correspond to one Node/Element.
@@ -624,9 +808,7 @@ impl FlowContext : InlineLayout {
         // over the box list, and/or put into RenderBox.
         for self.inline().boxes.each |box| {
             box.d().position.size.width = match *box {
-                @ImageBox(_, ref img) => {
-                    Au::from_px(img.get_size().get_or_default(Size2D(0,0)).width)
-                }
+                @ImageBox(*) => box.image_size(self.d().position.size.width).width,
                 @TextBox(*) => { /* text boxes are initialized with dimensions */
                                    box.d().position.size.width
                 },
@@ -649,26 +831,21 @@ impl FlowContext : InlineLayout {
     }
 
     fn assign_height_inline(@self, _ctx: &LayoutContext) {
-        // TODO(Issue #226): get CSS 'line-height' property from
-        // containing block's style to determine minimum linebox height.
-        // TODO(Issue #226): get CSS 'line-height' property from each non-replaced
-        // inline element to determine its height for computing linebox height.
-        let line_height = Au::from_px(20);
         let mut cur_y = Au(0);
 
         for self.inline().lines.eachi |i, line_span| {
             debug!("assign_height_inline: processing line %u with box span: %?", i, line_span);
-            // coords relative to left baseline
-            let mut linebox_bounding_box = Au::zero_rect();
             let boxes = &self.inline().boxes;
+            let mut line_metrics = ~[];
             for line_span.eachi |box_i| {
                 let cur_box = boxes[box_i];
 
                 // compute box height.
                 cur_box.d().position.size.height = match cur_box {
-                    @ImageBox(_, ref img) => Au::from_px(img.size().height),
-                    @TextBox(*) => { /* text boxes are initialized with dimensions */
-                        cur_box.d().position.size.height
+                    @ImageBox(*) => cur_box.image_size(self.d().position.size.width).height,
+                    @TextBox(_, data) => {
+                        let metrics = &data.run.font.metrics;
+                        metrics.ascent + metrics.descent
                     },
                     // TODO(Issue #225): different cases for 'inline-block', other replaced content
                     @GenericBox(*) => Au::from_px(30),
@@ -678,49 +855,45 @@ impl FlowContext : InlineLayout {
                     }
                 };
 
-                // compute bounding rect, with left baseline as origin.
-                // so, linebox height is a matter of lining up ideal baselines,
-                // and then using the union of all these rects.
-                let bounding_box = match cur_box {
-                    // adjust to baseline coords
-                    // TODO(Issue #227): use left/right margins, border, padding for nonreplaced content,
-                    // and also use top/bottom  margins, border, padding for replaced or inline-block content.
-                    // TODO(Issue #225): use height, width for 'inline-block', other replaced content
+                // compute the box's extents about its baseline.
+                // TODO(Issue #227): use top/bottom margins, border, padding for replaced
+                // or inline-block content.
+                let metrics = match cur_box {
+                    // replaced content sits on the baseline.
                     @ImageBox(*) | @GenericBox(*) => {
-                        let box_bounds = cur_box.d().position;
-                        box_bounds.translate(&Point2D(Au(0), -cur_box.d().position.size.height))
+                        let height = cur_box.d().position.size.height;
+                        InlineMetrics { ascent: height, descent: Au(0), line_height: height }
                     },
-                    // adjust bounding box metric to box's horizontal offset
-                    // TODO: we can use font metrics directly instead of re-measuring for the bounding box.
-                    @TextBox(_, data) => { 
-                        let text_bounds = data.run.metrics_for_range(&const data.range).bounding_box;
-                        text_bounds.translate(&Point2D(cur_box.d().position.origin.x, Au(0)))
+                    @TextBox(_, data) => {
+                        InlineMetrics {
+                            ascent: data.run.font.metrics.ascent,
+                            descent: data.run.font.metrics.descent,
+                            line_height: cur_box.line_height(),
+                        }
                     },
                     _ => {
-                        fail!(fmt!("Tried to compute bounding box of unknown Box variant: %s",
+                        fail!(fmt!("Tried to compute metrics of unknown Box variant: %s",
                                    cur_box.debug_str()))
                     }
                 };
-                debug!("assign_height_inline: bounding box for box b%d = %?", cur_box.d().id, bounding_box);
-                linebox_bounding_box = linebox_bounding_box.union(&bounding_box);
-                debug!("assign_height_inline: linebox bounding box = %?", linebox_bounding_box);
+                debug!("assign_height_inline: metrics for box b%d = %?", cur_box.d().id, metrics);
+                line_metrics.push(metrics);
             }
-            let linebox_height = linebox_bounding_box.size.height;
-            let baseline_offset = -linebox_bounding_box.origin.y;
-            // now go back and adjust y coordinates to match determined baseline
+
+            let (linebox_height, baseline) = line_box_height_and_baseline(line_metrics);
+            debug!("assign_height_inline: line %u has height %? and baseline %?",
+                   i, linebox_height, baseline);
+
+            // now go back and place each box so that its baseline lies on the line's baseline.
+            // TODO: honor the 'vertical-align' property of each box.
+            let mut j = 0;
             for line_span.eachi |box_i| {
                 let cur_box = boxes[box_i];
-                // TODO(Issue #226): this is completely wrong. Need to use element's 
-                // 'line-height' when calculating linebox height. Then, go back over 
-                // and set y offsets according to 'vertical-align' property of containing block.
-                let halfleading = match cur_box {
-                    @TextBox(_, data) => { (data.run.font.metrics.em_size - line_height).scale_by(0.5f) },
-                    _ => { Au(0) }
-                };
-                cur_box.d().position.origin.y = cur_y + halfleading + (baseline_offset - cur_box.d().position.size.height);
+                cur_box.d().position.origin.y = cur_y + line_metrics[j].content_top(baseline);
+                j += 1;
             }
-            
-            cur_y += Au::max(line_height, linebox_height);
+
+            cur_y += linebox_height;
         } // /lines.each |line_span|
 
         self.d().position.size.height = cur_y;
@@ -744,3 +917,132 @@ impl FlowContext : InlineLayout {
     }
 
 } // @FlowContext : InlineLayout
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use css::matching::MatchMethods;
+    use css::select::test_stylesheet;
+    use dom::element::{ElementData, HTMLDivElement};
+    use dom::node::{Element, NodeScope, NodeScopeExtensions, Text};
+    use layout::aux::LayoutAuxMethods;
+    use layout::box::RenderBoxData;
+    use layout::context::LayoutContext;
+    use layout::flow::{FlowData, InlineFlow};
+    use layout::text::adapt_textbox_with_range;
+
+    use azure::azure_hl::CairoBackend;
+    use core::dvec::DVec;
+    use geom::rect::Rect;
+    use geom::size::Size2D;
+    use gfx::font::Font;
+    use gfx::font_context::{FontContext, dummy_style, test_font_bin};
+    use gfx::geometry::Au;
+    use gfx::resource::image_cache_task::{ImageCacheTask, ImageCacheTaskClient};
+    use gfx::resource::local_image_cache::LocalImageCache;
+    use gfx::resource::resource_task;
+    use gfx::resource::resource_task::ResourceTask;
+    use gfx::text::bidi::{LeftToRight, RightToLeft, reorder_runs};
+    use gfx::text::text_run::TextRun;
+    use gfx::util::range::Range;
+    use newcss::select::SelectCtx;
+    use newcss::types::OriginAuthor;
+    use std::net::url;
+
+    fn metrics_for_size(px: int) -> InlineMetrics {
+        // a font whose ascent is 80% and descent 20% of the em, with 'line-height: 1.2'.
+        let em = Au::from_px(px);
+        InlineMetrics {
+            ascent: em.scale_by(0.8f),
+            descent: em.scale_by(0.2f),
+            line_height: em.scale_by(1.2f),
+        }
+    }
+
+    #[test]
+    fn should_use_tallest_line_height_and_align_baselines() {
+        let small = metrics_for_size(10);
+        let large = metrics_for_size(20);
+        let (height, baseline) = line_box_height_and_baseline(~[small, large]);
+
+        assert height == large.line_height;
+        assert small.content_top(baseline) + small.ascent == baseline;
+        assert large.content_top(baseline) + large.ascent == baseline;
+        assert small.content_top(baseline) > large.content_top(baseline);
+    }
+
+    #[test]
+    fn should_place_replaced_content_on_baseline() {
+        let text = metrics_for_size(10);
+        let image = InlineMetrics { ascent: Au::from_px(40), descent: Au(0),
+                                    line_height: Au::from_px(40) };
+        let (height, baseline) = line_box_height_and_baseline(~[text, image]);
+
+        assert baseline == Au::from_px(40);
+        assert height == Au::from_px(40) + text.depth_below_baseline();
+        assert image.content_top(baseline) == Au(0);
+    }
+
+    #[test]
+    fn should_split_boxes_at_level_runs_and_order_them_by_base_direction() {
+        // "abc " then three Hebrew letters, all in one box
+        let text = "abc \u05D0\u05D1\u05D2";
+        let line = Range::new(0, 7);
+
+        let runs = reorder_runs(text, LeftToRight);
+        let pieces = split_at_level_runs(&line, runs);
+        assert pieces == ~[Range::new(0, 4), Range::new(4, 3)];
+        assert visual_order(pieces, runs, 7) == ~[0, 1];
+
+        // The space takes the base direction, and the Latin run is displayed rightmost
+        let runs = reorder_runs(text, RightToLeft);
+        let pieces = split_at_level_runs(&line, runs);
+        assert pieces == ~[Range::new(0, 3), Range::new(3, 4)];
+        assert visual_order(pieces, runs, 7) == ~[1, 0];
+    }
+
+    // Lays out the text of a div styled by `css` in an inline flow half as wide as the
+    // text, and returns how many lines it takes
+    fn count_lines_at_half_width(css: &str) -> uint {
+        let scope = NodeScope();
+        let div = scope.new_node(Element(ElementData(~"div", ~HTMLDivElement)));
+        let text = scope.new_node(Text(~"hello hello hello hello"));
+        scope.add_child(div, text);
+        let refs = DVec();
+        div.initialize_style_for_subtree(&refs);
+        let mut select_ctx = SelectCtx::new();
+        select_ctx.append_sheet(test_stylesheet(css), OriginAuthor);
+        div.restyle_subtree(&select_ctx);
+
+        let resources = ResourceTask();
+        let image_cache_task = ImageCacheTask(resources.clone());
+        let ctx = LayoutContext {
+            font_ctx: @FontContext::new(CairoBackend, false),
+            image_cache: @LocalImageCache(image_cache_task.clone()),
+            doc_url: url::from_str(~"http://test").get(),
+            screen_size: Rect(Au::zero_point(), Size2D(Au::from_px(800), Au::from_px(600)))
+        };
+        let fctx = FontContext::new(CairoBackend, false);
+        let font = result::unwrap(Font::new_from_buffer(&fctx, test_font_bin(), &dummy_style(),
+                                                        CairoBackend));
+        let run = @TextRun::new(font, ~"hello hello hello hello");
+        let flow = @InlineFlow(FlowData(0), InlineFlowData());
+        let box = adapt_textbox_with_range(&RenderBoxData(text, flow, 0), run,
+                                           &Range::new(0, run.char_len()));
+        flow.inline().boxes.push(box);
+        flow.d().position.size.width = box.d().position.size.width.scale_by(0.5f);
+
+        flow.assign_widths_inline(&ctx);
+        let lines = flow.inline().lines.len();
+
+        image_cache_task.exit();
+        resources.send(resource_task::Exit);
+        lines
+    }
+
+    #[test]
+    fn should_not_wrap_nowrap_text() {
+        assert count_lines_at_half_width("div { white-space: nowrap }") == 1;
+        assert count_lines_at_half_width("div { white-space: normal }") > 1;
+    }
+}
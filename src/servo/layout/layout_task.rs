@@ -3,15 +3,21 @@
 
 use content::content_task;
 use css::matching::MatchMethods;
+use css::rem::{INITIAL_ROOT_FONT_SIZE_PX, has_rem_units, restyle_for_root_font_size};
 use css::select::new_css_select_ctx;
 use dom::event::{Event, ReflowEvent};
 use dom::node::{Node, LayoutData};
+use html::cssparse::{AuthorSheet, parse_stylesheet};
 use layout::aux::LayoutAuxMethods;
 use layout::box::RenderBox;
 use layout::box_builder::LayoutTreeBuilder;
 use layout::context::LayoutContext;
 use layout::debug::{BoxedDebugMethods, DebugMethods};
 use layout::display_list_builder::{DisplayListBuilder, FlowDisplayListBuilderMethods};
+use layout::flow::FlowContext;
+use layout::hit_test::HitTestMethods;
+use layout::lazy_images::LazyImageMethods;
+use layout::metrics::ElementMetricsMethods;
 use layout::traverse::*;
 use resource::image_cache_task::{ImageCacheTask, ImageResponseMsg};
 use resource::local_image_cache::LocalImageCache;
@@ -33,7 +39,6 @@ use gfx::opts::Opts;
 use gfx::render_layers::RenderLayer;
 use gfx::render_task::{RenderMsg, RenderTask};
 use newcss::select::SelectCtx;
-use newcss::stylesheet::Stylesheet;
 use newcss::types::OriginAuthor;
 use std::arc::ARC;
 use std::cell::Cell;
@@ -42,26 +47,42 @@ use std::net::url::Url;
 pub type LayoutTask = SharedChan<Msg>;
 
 pub enum LayoutQuery {
-    ContentBox(Node)
+    ContentBox(Node),
+    /// The size of a node's border box, as `offsetWidth`/`offsetHeight` report.
+    OffsetSize(Node),
+    /// The size of a node's content including overflow, as `scrollWidth`/`scrollHeight` report.
+    ScrollSize(Node),
+    /// The union of a node's border boxes, relative to the top left of the document.
+    BoundingClientRect(Node),
+    /// Finds the node drawn at a point, relative to the top left of the document.
+    HitTest(Point2D<Au>)
 }
 
 pub type LayoutQueryResponse = Result<LayoutQueryResponse_, ()>;
 
 enum LayoutQueryResponse_ {
-    ContentSize(Size2D<int>)
+    ContentSize(Size2D<int>),
+    ContentRect(Rect<Au>),
+    NodeAtPoint(Node)
 }
 
 pub enum Msg {
-    AddStylesheet(Stylesheet),
+    AddStylesheet(AuthorSheet),
     BuildMsg(BuildData),
+    /// Lays out a document that is still being parsed. Later messages may carry a larger tree.
+    AppendNodesMsg(BuildData),
+    /// Lays out a document once the parser has appended all of its nodes.
+    FinishMsg(BuildData),
     QueryMsg(LayoutQuery, Chan<LayoutQueryResponse>),
     ExitMsg
 }
 
 // Dirty bits for layout.
+#[deriving_eq]
 pub enum Damage {
     NoDamage,               // Document is clean; do nothing.
     ReflowDamage,           // Reflow; don't perform CSS selector matching.
+    RestyleDamage,          // Perform CSS selector matching on dirty nodes only, and reflow.
     MatchSelectorsDamage,   // Perform CSS selector matching and reflow.
 }
 
@@ -71,6 +92,8 @@ impl Damage {
             (NoDamage, _) => *self = new_damage,
             (ReflowDamage, NoDamage) => *self = ReflowDamage,
             (ReflowDamage, new_damage) => *self = new_damage,
+            (RestyleDamage, NoDamage) | (RestyleDamage, ReflowDamage) => *self = RestyleDamage,
+            (RestyleDamage, new_damage) => *self = new_damage,
             (MatchSelectorsDamage, _) => *self = MatchSelectorsDamage
         }
     }
@@ -85,6 +108,20 @@ pub struct BuildData {
     damage: Damage,
 }
 
+// Answers a size query about `node` from the flow its boxes were placed in
+fn size_query(node: Node, f: &fn(@FlowContext) -> Option<Size2D<Au>>) -> LayoutQueryResponse {
+    let size = do node.aux |a| {
+        match a.flow {
+            Some(flow) => f(flow),
+            None => None
+        }
+    };
+    match size {
+        Some(size) => Ok(ContentSize(Size2D(size.width.to_px(), size.height.to_px()))),
+        None => Err(())
+    }
+}
+
 pub fn LayoutTask(render_task: RenderTask,
                   img_cache_task: ImageCacheTask,
                   opts: Opts) -> LayoutTask {
@@ -103,6 +140,12 @@ struct Layout {
     // This is used to root auxilliary RCU reader data
     layout_refs: DVec<@LayoutData>,
     css_select_ctx: Mut<SelectCtx>,
+    /// The URL and text of each author sheet, in the order they were added
+    author_sheets: DVec<(Url, ~str)>,
+    /// The root font size the author sheets' rem lengths were resolved against
+    mut root_font_size: float,
+    // The flow tree built by the last layout, for queries
+    mut layout_root: Option<@FlowContext>,
 }
 
 fn Layout(render_task: RenderTask, 
@@ -119,7 +162,10 @@ fn Layout(render_task: RenderTask,
         from_content: from_content,
         font_ctx: fctx,
         layout_refs: DVec(),
-        css_select_ctx: Mut(new_css_select_ctx())
+        css_select_ctx: Mut(new_css_select_ctx()),
+        author_sheets: DVec(),
+        root_font_size: INITIAL_ROOT_FONT_SIZE_PX,
+        layout_root: None,
     }
 }
 
@@ -145,6 +191,20 @@ impl Layout {
                 }
 
             }
+            AppendNodesMsg(move data) => {
+                let data = Cell(move data);
+
+                do time("layout: performing progressive layout") {
+                    self.handle_build(data.take());
+                }
+            }
+            FinishMsg(move data) => {
+                let data = Cell(move data);
+
+                do time("layout: performing final layout") {
+                    self.handle_build(data.take());
+                }
+            }
             QueryMsg(query, chan) => {
                 let chan = Cell(chan);
                 do time("layout: querying layout") {
@@ -160,11 +220,33 @@ impl Layout {
         true
     }
 
-    fn handle_add_stylesheet(sheet: Stylesheet) {
+    fn handle_add_stylesheet(sheet: AuthorSheet) {
+        let AuthorSheet { sheet: sheet, url: url, source: source } = move sheet;
+        // Sheets are parsed for the initial root font size, which styling may have changed
+        let sheet = if self.root_font_size != INITIAL_ROOT_FONT_SIZE_PX && has_rem_units(source) {
+            parse_stylesheet(copy url, source, self.root_font_size)
+        } else {
+            move sheet
+        };
         let sheet = Cell(move sheet);
         do self.css_select_ctx.borrow_mut |ctx| {
             ctx.append_sheet(sheet.take(), OriginAuthor);
         }
+        self.author_sheets.push((move url, move source));
+    }
+
+    // Styles the tree again should the root font size the rem lengths depend on have changed
+    fn resolve_rem_lengths(node: &Node) {
+        match restyle_for_root_font_size(node, self.author_sheets.get(),
+                                         &mut self.root_font_size) {
+            Some(move ctx) => {
+                let ctx = Cell(move ctx);
+                do self.css_select_ctx.borrow_mut |select_ctx| {
+                    *select_ctx = ctx.take();
+                }
+            }
+            None => ()
+        }
     }
 
     fn handle_build(data: BuildData) {
@@ -205,6 +287,16 @@ impl Layout {
                     do self.css_select_ctx.borrow_imm |ctx| {
                         node.restyle_subtree(ctx);
                     }
+                    self.resolve_rem_lengths(node);
+                }
+            }
+            RestyleDamage => {
+                do time("layout: incremental selector matching") {
+                    do self.css_select_ctx.borrow_imm |ctx| {
+                        let restyled = node.restyle(ctx);
+                        debug!("layout: restyled %u elements", restyled);
+                    }
+                    self.resolve_rem_lengths(node);
                 }
             }
         }
@@ -230,6 +322,9 @@ impl Layout {
             do layout_root.traverse_postorder |f| { f.assign_height(&layout_ctx) }
         }
 
+        // Now that boxes are positioned, start loading the images near the viewport
+        layout_root.request_images_near_viewport(&Au::zero_point(), &layout_ctx.screen_size);
+
         do time("layout: display list building") {
             let builder = DisplayListBuilder {
                 ctx: &layout_ctx,
@@ -252,6 +347,8 @@ impl Layout {
             self.render_task.send(RenderMsg(move render_layer));
         } // time(layout: display list building)
 
+        self.layout_root = Some(layout_root);
+
         // Tell content we're done
         data.content_join_chan.send(());
     }
@@ -287,6 +384,34 @@ impl Layout {
 
                 reply_chan.send(response)
             }
+            OffsetSize(node) => {
+                reply_chan.send(size_query(node, |flow| flow.offset_size(node)))
+            }
+            ScrollSize(node) => {
+                reply_chan.send(size_query(node, |flow| flow.scroll_size(node)))
+            }
+            BoundingClientRect(node) => {
+                let rect = match self.layout_root {
+                    Some(root) => root.client_rect(node),
+                    None => None
+                };
+                let response = match rect {
+                    Some(rect) => Ok(ContentRect(rect)),
+                    None => Err(())
+                };
+                reply_chan.send(response)
+            }
+            HitTest(point) => {
+                let node = match self.layout_root {
+                    Some(root) => root.hit_test(&point),
+                    None => None
+                };
+                let response = match node {
+                    Some(node) => Ok(NodeAtPoint(node)),
+                    None => Err(())
+                };
+                reply_chan.send(response)
+            }
         }
     }
 
@@ -0,0 +1,146 @@
+/**
+Viewport-aware image loading. Images are not fetched when they are parsed;
+after each layout the flow tree is walked and only images that are in or
+near the viewport are requested, with those on screen decoded first.
+*/
+
+use layout::box::{ImageBox, RenderBox};
+use layout::flow::{FlowContext, FlowTree};
+
+use geom::point::Point2D;
+use geom::rect::Rect;
+use geom::size::Size2D;
+use gfx::geometry::Au;
+use gfx::image::holder::ImageHolder;
+use gfx::resource::image_cache_task::{DecodePriority, HighPriority, LowPriority};
+
+/// How far outside the viewport, in px, images are loaded ahead of being scrolled to
+pub const PREFETCH_MARGIN_PX: int = 1000;
+
+/**
+Returns the priority to load an image drawn at `rect` with: high if it is
+visible in `viewport`, low if it is within the prefetch margin, and None if
+it should not be loaded yet.
+*/
+pub pure fn load_priority(rect: &Rect<Au>, viewport: &Rect<Au>) -> Option<DecodePriority> {
+    if intersects(rect, viewport) {
+        return Some(HighPriority);
+    }
+
+    let margin = Au::from_px(PREFETCH_MARGIN_PX);
+    let extended = Rect(Point2D(viewport.origin.x - margin, viewport.origin.y - margin),
+                        Size2D(viewport.size.width + margin + margin,
+                               viewport.size.height + margin + margin));
+    if intersects(rect, &extended) { Some(LowPriority) } else { None }
+}
+
+/// Requests the image if it is drawn in or near the viewport
+pub fn request_if_near_viewport(holder: &ImageHolder, rect: &Rect<Au>, viewport: &Rect<Au>) {
+    match load_priority(rect, viewport) {
+        Some(priority) => holder.request(priority),
+        None => ()
+    }
+}
+
+pub trait LazyImageMethods {
+    fn request_images_near_viewport(@self, origin: &Point2D<Au>, viewport: &Rect<Au>);
+}
+
+impl FlowContext : LazyImageMethods {
+    /**
+    Requests the images of every image box in this flow and its children
+    that is near `viewport`. `origin` is the absolute position of this
+    flow's parent, which this flow's position is relative to.
+    */
+    fn request_images_near_viewport(@self, origin: &Point2D<Au>, viewport: &Rect<Au>) {
+        let position = self.d().position;
+        let origin = Point2D(origin.x + position.origin.x, origin.y + position.origin.y);
+
+        let boxes = do self.foldl_all_boxes(~[]) |boxes, box| { boxes + ~[box] };
+        for boxes.each |box| {
+            match *box {
+                @ImageBox(ref d, ref holder) => {
+                    let rect = Rect(Point2D(origin.x + d.position.origin.x,
+                                            origin.y + d.position.origin.y),
+                                    d.position.size);
+                    request_if_near_viewport(holder, &rect, viewport);
+                }
+                _ => ()
+            }
+        }
+
+        for FlowTree.each_child(self) |child| {
+            child.request_images_near_viewport(&origin, viewport);
+        }
+    }
+}
+
+pure fn intersects(a: &Rect<Au>, b: &Rect<Au>) -> bool {
+    a.origin.x < b.origin.x + b.size.width && b.origin.x < a.origin.x + a.size.width &&
+        a.origin.y < b.origin.y + b.size.height && b.origin.y < a.origin.y + a.size.height
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test_support::mock_resource_task;
+
+    use core::pipes::stream;
+    use geom::point::Point2D;
+    use geom::rect::Rect;
+    use geom::size::Size2D;
+    use gfx::geometry::Au;
+    use gfx::image::holder::ImageHolder;
+    use gfx::resource::image_cache_task::{Exit, ImageCacheTask, ImageResponseMsg};
+    use gfx::resource::image_cache_task::{ListUrls, Sync};
+    use gfx::resource::local_image_cache::LocalImageCache;
+    use gfx::resource::resource_task::Done;
+    use gfx::resource::resource_task;
+    use gfx::util::url::make_url;
+
+    fn px_rect(x: int, y: int, w: int, h: int) -> Rect<Au> {
+        Rect(Point2D(Au::from_px(x), Au::from_px(y)), Size2D(Au::from_px(w), Au::from_px(h)))
+    }
+
+    #[test]
+    fn should_prioritize_images_by_distance_from_viewport() {
+        let viewport = px_rect(0, 0, 800, 600);
+        assert load_priority(&px_rect(0, 500, 100, 200), &viewport) == Some(HighPriority);
+        assert load_priority(&px_rect(0, 1000, 100, 100), &viewport) == Some(LowPriority);
+        assert load_priority(&px_rect(0, 2000, 100, 100), &viewport) == None;
+    }
+
+    #[test]
+    fn should_only_prefetch_images_near_the_viewport() {
+        let resource_task = mock_resource_task(|_url, response| response.send(Done(Ok(()))));
+        let image_cache_task = ImageCacheTask(resource_task.clone());
+        let local_cache = @LocalImageCache(image_cache_task.clone());
+        local_cache.next_round(|| fn~(_response: ImageResponseMsg) { });
+
+        let viewport = px_rect(0, 0, 800, 600);
+        let images = ~[
+            (ImageHolder::new(make_url(~"file1", None), local_cache), px_rect(0, 0, 100, 100)),
+            (ImageHolder::new(make_url(~"file2", None), local_cache), px_rect(0, 2000, 100, 100)),
+            (ImageHolder::new(make_url(~"file3", None), local_cache), px_rect(0, 4000, 100, 100))
+        ];
+        for images.each |&(ref holder, ref rect)| {
+            request_if_near_viewport(holder, rect, &viewport);
+        }
+
+        let (sync_port, sync_chan) = stream();
+        image_cache_task.send(Sync(move sync_chan));
+        sync_port.recv();
+
+        let (list_port, list_chan) = stream();
+        image_cache_task.send(ListUrls(move list_chan));
+        let urls = list_port.recv();
+        assert urls.len() == 1;
+        let (url, _) = copy urls[0];
+        assert url == make_url(~"file1", None);
+
+        let (exit_port, exit_chan) = stream();
+        image_cache_task.send(Exit(move exit_chan));
+        exit_port.recv();
+        resource_task.send(resource_task::Exit);
+    }
+}
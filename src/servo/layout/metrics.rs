@@ -0,0 +1,196 @@
+/**
+Element metrics read by script, such as `offsetWidth` and `scrollWidth`,
+computed from the render boxes of a laid out element.
+*/
+
+use dom::node::Node;
+use layout::box::RenderBox;
+use layout::flow::{FlowContext, FlowTree};
+
+use geom::rect::Rect;
+use geom::size::Size2D;
+use au = gfx::geometry;
+use gfx::geometry::Au;
+
+pub trait ElementMetricsMethods {
+    fn offset_size(@self, node: Node) -> Option<Size2D<Au>>;
+    fn scroll_size(@self, node: Node) -> Option<Size2D<Au>>;
+    fn client_rect(@self, node: Node) -> Option<Rect<Au>>;
+}
+
+impl FlowContext : ElementMetricsMethods {
+    /**
+    The size of the border boxes of `node` within this flow, which is the
+    flow the node's boxes were placed in. None if the node has no boxes.
+    */
+    fn offset_size(@self, node: Node) -> Option<Size2D<Au>> {
+        let start: Option<Rect<Au>> = None;
+        let rect = do self.foldl_boxes_for_node(node, start) |acc, box| {
+            match acc {
+                Some(acc) => Some(acc.union(&box.border_box())),
+                None => Some(box.border_box())
+            }
+        };
+        rect.map(|rect| rect.size)
+    }
+
+    /**
+    The size of the content of `node`, including any that overflows it. If
+    `node` established this flow, its child flows are what may overflow.
+    */
+    fn scroll_size(@self, node: Node) -> Option<Size2D<Au>> {
+        let start: Option<Rect<Au>> = None;
+        let rect = do self.foldl_boxes_for_node(node, start) |acc, box| {
+            match acc {
+                Some(acc) => Some(acc.union(&box.content_box())),
+                None => Some(box.content_box())
+            }
+        };
+        let rect = match rect {
+            Some(rect) => rect,
+            None => return None
+        };
+
+        let mut width = rect.size.width;
+        let mut height = rect.size.height;
+        if self.d().node == Some(node) {
+            // Child flows are positioned relative to this flow, as are its boxes
+            for FlowTree.each_child(self) |child| {
+                let position = child.d().position;
+                width = au::max(width, position.origin.x + position.size.width -
+                                       rect.origin.x);
+                height = au::max(height, position.origin.y + position.size.height -
+                                         rect.origin.y);
+            }
+        }
+        Some(Size2D(width, height))
+    }
+
+    /**
+    The union of the border boxes of `node` in this flow and the flows below
+    it, relative to the origin of this flow's parent like this flow's
+    position. An inline broken across lines has a box on each of them.
+    */
+    fn client_rect(@self, node: Node) -> Option<Rect<Au>> {
+        let origin = self.d().position.origin;
+        let start: Option<Rect<Au>> = None;
+        let mut rect = do self.foldl_boxes_for_node(node, start) |acc, box| {
+            union_rect(acc, &box.border_box().translate(&origin))
+        };
+        for FlowTree.each_child(self) |child| {
+            match child.client_rect(node) {
+                // Child flows are positioned relative to this flow
+                Some(child_rect) => rect = union_rect(rect, &child_rect.translate(&origin)),
+                None => ()
+            }
+        }
+        rect
+    }
+}
+
+pure fn union_rect(acc: Option<Rect<Au>>, rect: &Rect<Au>) -> Option<Rect<Au>> {
+    match acc {
+        Some(acc) => Some(acc.union(rect)),
+        None => Some(*rect)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use dom::element::{ElementData, HTMLDivElement, HTMLSpanElement};
+    use dom::node::{Element, Node, NodeScope, NodeScopeExtensions};
+    use layout::block::BlockFlowData;
+    use layout::box::{GenericBox, RenderBoxData};
+    use layout::flow::{BlockFlow, FlowContext, FlowData, FlowTree, InlineFlow};
+    use layout::inline::InlineFlowData;
+
+    use geom::point::Point2D;
+    use geom::rect::Rect;
+    use geom::size::Size2D;
+    use gfx::geometry::Au;
+
+    fn px_rect(x: int, y: int, w: int, h: int) -> Rect<Au> {
+        Rect(Point2D(Au::from_px(x), Au::from_px(y)), Size2D(Au::from_px(w), Au::from_px(h)))
+    }
+
+    fn px_size(w: int, h: int) -> Size2D<Au> {
+        Size2D(Au::from_px(w), Au::from_px(h))
+    }
+
+    // A block flow at `position`, whose box fills it.
+    fn block_flow(node: Node, id: int, position: Rect<Au>) -> @FlowContext {
+        let flow = @BlockFlow(FlowData(id), BlockFlowData());
+        flow.d().node = Some(node);
+        flow.d().position = position;
+
+        let box = @GenericBox(RenderBoxData(node, flow, id));
+        box.d().position = Rect(Au::zero_point(), position.size);
+        flow.block().box = Some(box);
+        flow
+    }
+
+    #[test]
+    fn should_measure_sized_and_overflowing_elements() {
+        let scope = NodeScope();
+        let outer = scope.new_node(Element(ElementData(~"div", ~HTMLDivElement)));
+        let inner = scope.new_node(Element(ElementData(~"div", ~HTMLDivElement)));
+        scope.add_child(outer, inner);
+
+        let outer_flow = block_flow(outer, 0, px_rect(0, 0, 100, 50));
+        let inner_flow = block_flow(inner, 1, px_rect(10, 20, 150, 80));
+        FlowTree.add_child(outer_flow, inner_flow);
+
+        assert outer_flow.offset_size(outer) == Some(px_size(100, 50));
+        assert outer_flow.scroll_size(outer) == Some(px_size(160, 100));
+
+        // Content that fits doesn't change the scroll size
+        assert inner_flow.offset_size(inner) == Some(px_size(150, 80));
+        assert inner_flow.scroll_size(inner) == Some(px_size(150, 80));
+
+        assert inner_flow.offset_size(outer) == None;
+    }
+
+    #[test]
+    fn should_place_the_client_rect_of_a_positioned_element() {
+        let scope = NodeScope();
+        let body = scope.new_node(Element(ElementData(~"div", ~HTMLDivElement)));
+        let outer = scope.new_node(Element(ElementData(~"div", ~HTMLDivElement)));
+        let positioned = scope.new_node(Element(ElementData(~"div", ~HTMLDivElement)));
+        scope.add_child(body, outer);
+        scope.add_child(outer, positioned);
+
+        let body_flow = block_flow(body, 0, px_rect(0, 0, 800, 600));
+        let outer_flow = block_flow(outer, 1, px_rect(8, 8, 784, 300));
+        let positioned_flow = block_flow(positioned, 2, px_rect(30, 40, 120, 60));
+        FlowTree.add_child(body_flow, outer_flow);
+        FlowTree.add_child(outer_flow, positioned_flow);
+
+        assert body_flow.client_rect(positioned) == Some(px_rect(38, 48, 120, 60));
+        assert body_flow.client_rect(outer) == Some(px_rect(8, 8, 784, 300));
+        assert positioned_flow.client_rect(outer) == None;
+    }
+
+    #[test]
+    fn should_union_the_boxes_of_an_inline_across_lines() {
+        let scope = NodeScope();
+        let div = scope.new_node(Element(ElementData(~"div", ~HTMLDivElement)));
+        let span = scope.new_node(Element(ElementData(~"span", ~HTMLSpanElement)));
+        scope.add_child(div, span);
+
+        let div_flow = block_flow(div, 0, px_rect(0, 0, 200, 100));
+        let inline_flow = @InlineFlow(FlowData(1), InlineFlowData());
+        inline_flow.d().position = px_rect(10, 10, 180, 40);
+        FlowTree.add_child(div_flow, inline_flow);
+
+        // The span ends the first line and starts the second
+        let first = @GenericBox(RenderBoxData(span, inline_flow, 2));
+        first.d().position = px_rect(120, 0, 60, 20);
+        let second = @GenericBox(RenderBoxData(span, inline_flow, 3));
+        second.d().position = px_rect(0, 20, 50, 20);
+        inline_flow.inline().boxes.push(first);
+        inline_flow.inline().boxes.push(second);
+
+        assert div_flow.client_rect(span) == Some(px_rect(10, 10, 180, 40));
+    }
+}
@@ -0,0 +1,12 @@
+let items = document.querySelectorAll(".item");
+is(items instanceof NodeList, true);
+is(items.length, 3);
+is(items.item(0).tagName, "DIV");
+is(items.item(1).tagName, "P");
+is(items.item(2).tagName, "DIV");
+is(items.item(3), null);
+
+is(document.querySelectorAll("div.item").length, 2);
+is(document.querySelectorAll("#first").item(0).tagName, "DIV");
+is(document.querySelectorAll("span").length, 0);
+finish();